@@ -0,0 +1,66 @@
+extern crate redox_fatfs;
+extern crate syscall;
+
+use std::fs;
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+
+use redox_fatfs::prelude::*;
+use syscall::flag::{O_CREAT, O_DIRECTORY, O_RDONLY, O_RDWR};
+use syscall::scheme::Scheme;
+
+const FILES: usize = 200;
+const LISTINGS: u32 = 500;
+
+// A scheme over a copy of the FAT32 image with a directory of FILES entries
+fn scheme(options: MountOptions) -> FileScheme<Cursor<Vec<u8>>> {
+    let data = fs::read("images/fat32.img").expect("Failed to read image");
+    let fs = FileSystem::from_offset(0, Cursor::new(data), None).expect("Parsing Error");
+    let disk = FileScheme::new("bench".to_string(), fs, 0o777, 0, 0, options);
+    disk.open(b"/listed", O_CREAT | O_DIRECTORY, 0, 0).unwrap();
+    for i in 0..FILES {
+        let id = disk.open(format!("/listed/Entry number {}.txt", i).as_bytes(), O_CREAT | O_RDWR, 0, 0).unwrap();
+        disk.close(id).unwrap();
+    }
+    disk
+}
+
+// Opens, reads and closes the directory as a shell completing a name does
+fn list(disk: &FileScheme<Cursor<Vec<u8>>>) -> usize {
+    let id = disk.open(b"/listed", O_DIRECTORY | O_RDONLY, 0, 0).unwrap();
+    let mut buf = [0; 4096];
+    let mut total = 0;
+    loop {
+        match disk.read(id, &mut buf).unwrap() {
+            0 => break,
+            n => total += n
+        }
+    }
+    disk.close(id).unwrap();
+    total
+}
+
+// Average time of a listing, after one which fills the cache
+fn time_listings(disk: &FileScheme<Cursor<Vec<u8>>>) -> Duration {
+    let expected = list(disk);
+    let start = Instant::now();
+    for _ in 0..LISTINGS {
+        assert_eq!(list(disk), expected);
+    }
+    start.elapsed() / LISTINGS
+}
+
+// Repeated listings of one directory with and without MountOptions::listing_cache
+// Run with `cargo bench --bench listing`
+fn main() {
+    let uncached = time_listings(&scheme(MountOptions::default()));
+    let options = MountOptions { listing_cache: Some(1024 * 1024), ..Default::default() };
+    let disk = scheme(options);
+    let cached = time_listings(&disk);
+    let (hits, misses) = disk.listing_cache_stats().unwrap();
+
+    println!("listing of {} entries, {} times", FILES, LISTINGS);
+    println!("without cache {:?}", uncached);
+    println!("with cache    {:?} ({} hits, {} misses)", cached, hits, misses);
+    println!("speedup       {:.1}x", uncached.as_secs_f64() / cached.as_secs_f64());
+}
@@ -0,0 +1,32 @@
+extern crate redox_fatfs;
+
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::process;
+
+use redox_fatfs::prelude::*;
+
+// Writes a directory of a FAT image to a tar archive
+// Usage: archive <image> <output> [dir]
+fn main() {
+    let mut args = env::args().skip(1);
+    let (image, output) = match (args.next(), args.next()) {
+        (Some(image), Some(output)) => (image, output),
+        _ => {
+            eprintln!("Usage: archive <image> <output> [dir]");
+            process::exit(1);
+        }
+    };
+    let path = args.next().unwrap_or("/".to_string());
+
+    let disk = OpenOptions::new().read(true).write(true).open(&image).expect("Failed to open image");
+    let mut fs = FileSystem::from_offset(0, disk, None).expect("Parsing Error");
+    let dir = if path.trim_matches('/').is_empty() {
+        fs.root_dir()
+    } else {
+        Dir::get_entry_abs(&path, &mut fs).expect("Directory not found").to_dir()
+    };
+
+    let mut out = fs::File::create(&output).expect("Failed to create archive");
+    dir.archive_to(&mut fs, &mut out, ArchiveFormat::Tar).expect("Failed to write archive");
+}
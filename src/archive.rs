@@ -0,0 +1,115 @@
+use std::io::{Read, Write, Seek, Error, ErrorKind};
+
+use filesystem::FileSystem;
+use dir_entry::{Dir, DirEntry, File};
+
+use super::Result;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ArchiveFormat {
+    /// POSIX ustar
+    Tar
+}
+
+const TAR_BLOCK: usize = 512;
+const TAR_NAME_LEN: usize = 100;
+const TAR_PREFIX_LEN: usize = 155;
+
+impl Dir {
+    /// Writes every file and directory below this one to `w`, named relative to it
+    pub fn archive_to<D: Read + Write + Seek, W: Write>(&self, fs: &mut FileSystem<D>, w: &mut W,
+                                                        format: ArchiveFormat) -> Result<()> {
+        match format {
+            ArchiveFormat::Tar => {
+                tar_dir(self, "", fs, w)?;
+                // End of archive
+                w.write_all(&[0; 2 * TAR_BLOCK])
+            }
+        }
+    }
+}
+
+fn tar_dir<D: Read + Write + Seek, W: Write>(dir: &Dir, prefix: &str, fs: &mut FileSystem<D>, w: &mut W) -> Result<()> {
+    let entries: Vec<DirEntry> = dir.to_iter(fs).collect();
+    for e in entries {
+        if e.is_vol_id() {
+            continue;
+        }
+        let name = e.name();
+        if name == "." || name == ".." {
+            continue;
+        }
+
+        let path = format!("{}{}", prefix, name);
+        if e.is_dir() {
+            let path = format!("{}/", path);
+            w.write_all(&tar_header(&path, 0, b'5')?)?;
+            tar_dir(&e.to_dir(), &path, fs, w)?;
+        } else {
+            let f = e.to_file();
+            w.write_all(&tar_header(&path, f.size(), b'0')?)?;
+            tar_file(&f, fs, w)?;
+        }
+    }
+    Ok(())
+}
+
+fn tar_file<D: Read + Write + Seek, W: Write>(f: &File, fs: &mut FileSystem<D>, w: &mut W) -> Result<()> {
+    let mut buf = vec![0; fs.bytes_per_cluster() as usize];
+    let mut offset = 0;
+    while offset < f.size() {
+        let r = f.read(&mut buf, fs, offset)?;
+        if r == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "File shorter than its size"));
+        }
+        w.write_all(&buf[..r])?;
+        offset += r as u64;
+    }
+
+    let padding = (TAR_BLOCK - (f.size() as usize % TAR_BLOCK)) % TAR_BLOCK;
+    w.write_all(&vec![0; padding])
+}
+
+fn tar_header(path: &str, size: u64, type_flag: u8) -> Result<[u8; TAR_BLOCK]> {
+    let mut header = [0; TAR_BLOCK];
+    let (prefix, name) = split_tar_path(path)?;
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    let mode = if type_flag == b'5' { 0o755 } else { 0o644 };
+    write_octal(&mut header[100..108], mode);
+    write_octal(&mut header[108..116], 0);
+    write_octal(&mut header[116..124], 0);
+    write_octal(&mut header[124..136], size);
+    write_octal(&mut header[136..148], 0);
+    header[156] = type_flag;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+    // Computed with the checksum field itself set to spaces
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = header.iter().map(|&b| b as u32).sum();
+    write_octal(&mut header[148..155], checksum as u64);
+    Ok(header)
+}
+
+fn split_tar_path(path: &str) -> Result<(&str, &str)> {
+    if path.len() <= TAR_NAME_LEN {
+        return Ok(("", path))
+    }
+
+    // Long paths are split at a '/' between the prefix and name fields
+    for (idx, _) in path.trim_end_matches('/').rmatch_indices('/') {
+        if idx <= TAR_PREFIX_LEN && path.len() - idx - 1 <= TAR_NAME_LEN {
+            return Ok((&path[..idx], &path[idx + 1..]))
+        }
+    }
+    Err(Error::new(ErrorKind::InvalidInput, "Path too long for a tar header"))
+}
+
+// Zero padded octal terminated by a NUL
+fn write_octal(field: &mut [u8], value: u64) {
+    let len = field.len() - 1;
+    let digits = format!("{:0width$o}", value, width = len);
+    field[..len].copy_from_slice(digits.as_bytes());
+    field[len] = 0;
+}
@@ -8,11 +8,13 @@ extern crate syscall;
 
 extern crate redox_fatfs;
 extern crate byteorder;
+#[cfg(feature = "trace")]
+extern crate tracing_subscriber;
 //extern crate uuid;
 
 use std::env;
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Write, Cursor};
+use std::io::{Read, Write, Seek, Cursor};
 use std::os::unix::io::{FromRawFd, RawFd};
 use std::process;
 use std::sync::atomic::{AtomicUsize, Ordering};
@@ -20,7 +22,7 @@ use std::sync::atomic::{AtomicUsize, Ordering};
 use byteorder::{BigEndian, ReadBytesExt};
 
 //use uuid::Uuid;
-use redox_fatfs::mount;
+use redox_fatfs::prelude::{mount, enumerate_fat_volumes, FileSystem, MountOptions, NameTranslation, Overlay};
 
 #[cfg(target_os = "redox")]
 extern "C" fn unmount_handler(_s: usize) {
@@ -28,13 +30,25 @@ extern "C" fn unmount_handler(_s: usize) {
     redox_fatfs::IS_UMT.store(1, Ordering::SeqCst);
 }
 
+#[cfg(target_os = "redox")]
+extern "C" fn dump_handler(_s: usize) {
+    use std::sync::atomic::Ordering;
+    redox_fatfs::DUMP_RESOURCES.store(1, Ordering::SeqCst);
+}
+
+#[cfg(target_os = "redox")]
+extern "C" fn write_protect_handler(_s: usize) {
+    use std::sync::atomic::Ordering;
+    redox_fatfs::TOGGLE_WRITE_PROTECT.store(1, Ordering::SeqCst);
+}
+
 
 #[cfg(target_os = "redox")]
 //set up a signal handler on redox, this implements unmounting. I have no idea what sa_flags is
 //for, so I put 2. I don't think 0,0 is a valid sa_mask. I don't know what i'm doing here. When u
 //send it a sigkill, it shuts off the filesystem
 fn setsig() {
-    use syscall::{sigaction, SigAction, SIGTERM};
+    use syscall::{sigaction, SigAction, SIGTERM, SIGUSR1, SIGUSR2};
 
     let sig_action = SigAction {
         sa_handler: unmount_handler,
@@ -43,6 +57,24 @@ fn setsig() {
     };
 
     sigaction(SIGTERM, Some(&sig_action), None).unwrap();
+
+    // SIGUSR1 prints the open resource table
+    let dump_action = SigAction {
+        sa_handler: dump_handler,
+        sa_mask: [0,0],
+        sa_flags: 0,
+    };
+
+    sigaction(SIGUSR1, Some(&dump_action), None).unwrap();
+
+    // SIGUSR2 turns write protection on or off
+    let write_protect_action = SigAction {
+        sa_handler: write_protect_handler,
+        sa_mask: [0,0],
+        sa_flags: 0,
+    };
+
+    sigaction(SIGUSR2, Some(&write_protect_action), None).unwrap();
 }
 
 #[cfg(not(target_os = "redox"))]
@@ -83,8 +115,66 @@ fn capability_mode() {
 }
 
 
+#[cfg(feature = "trace")]
+fn enable_tracing() {
+    use tracing_subscriber::fmt::format::FmtSpan;
+    use tracing_subscriber::filter::LevelFilter;
+
+    // Closing spans report the time spent in each call
+    tracing_subscriber::fmt()
+        .with_span_events(FmtSpan::CLOSE)
+        .with_max_level(LevelFilter::DEBUG)
+        .init();
+}
+
+#[cfg(not(feature = "trace"))]
+fn enable_tracing() {
+    println!("redox-fatfs: built without the trace feature, ignoring --trace");
+}
+
+fn parse_limit(arg: &str, limit: Option<String>) -> usize {
+    match limit {
+        Some(l) => match l.parse::<usize>() {
+            Ok(i) => i,
+            Err(e) => {
+                println!("redox-fatfs: invalid limit '{}' for {}: {}", l, arg, e);
+                usage();
+                process::exit(1);
+            }
+        },
+        None => {
+            println!("redox-fatfs: no limit provided for {}", arg);
+            usage();
+            process::exit(1);
+        }
+    }
+}
+
+fn parse_translation(mode: Option<String>) -> NameTranslation {
+    match mode.as_ref().map(|m| m.as_str()) {
+        Some("replace") => NameTranslation::Replace('_'),
+        Some("percent") => NameTranslation::Percent,
+        _ => {
+            println!("redox-fatfs: --translate-names takes 'replace' or 'percent'");
+            usage();
+            process::exit(1);
+        }
+    }
+}
+
+fn parse_umask(mask: Option<String>) -> u16 {
+    match mask.as_ref().map(|m| u16::from_str_radix(m, 8)) {
+        Some(Ok(m)) if m <= 0o777 => m,
+        _ => {
+            println!("redox-fatfs: --umask takes an octal mask such as 022");
+            usage();
+            process::exit(1);
+        }
+    }
+}
+
 fn usage() {
-    println!("redox-fatfs [mountpoint_base] --serial [serial] --uid [uid] --gid [gid] --mode [mode]");
+    println!("redox-fatfs [mountpoint_base] --serial [serial] --uid [uid] --gid [gid] --mode [mode] [--max-files [count]] [--max-fmap [bytes]] [--reserved [clusters]] [--write-attempts [count]] [--case-sensitive] [--path-cache] [--file-cache [bytes]] [--listing-cache [bytes]] [--entry-cache [entries]] [--free-map-scan [clusters]] [--flush-interval [seconds]] [--hide-dot-files] [--hide-system] [--hide-hidden] [--read-only-dirs] [--short-names-only] [--sorted] [--translate-names [replace|percent]] [--strict-fat-mirror] [--strict-bounds] [--attribute-modes] [--umask [mask]] [--verify-writes] [--overlay] [--force] [--trace]");
 }
 
 /*
@@ -137,41 +227,59 @@ fn disk_paths(paths: &mut Vec<String>) {
     }
 }
 
-fn daemon(path: &String, mountpoint: &str, mut write: File, uid: u32, gid: u32, mode: u16, serial: Option<u32>) -> ! {
+fn mount_disk<D: Read + Write + Seek>(disk: D, path: &String, offset: u64, mountpoint: &str, write: &mut File, uid: u32, gid: u32, mode: u16,
+                                      serial: Option<u32>, options: MountOptions, force: bool) {
+    match FileSystem::from_offset(offset, disk, serial) {
+        Ok(mut filesystem) => {
+            println!("redox-fatfs: opened filesystem on {} at {:#x}", path, offset);
+            if let Err(err) = filesystem.lock(force) {
+                println!("redox-fatfs: not mounting {}: {}, use --force to mount anyway", path, err);
+                return;
+            }
+
+            /*let matches = if let Some(uuid) = uuid_opt {
+                if &filesystem.header.1.uuid == uuid.as_bytes() {
+                    println!("redoxfs: filesystem on {} matches uuid {}", path, uuid.hyphenated());
+                    true
+                } else {
+                    println!("redoxfs: filesystem on {} does not match uuid {}", path, uuid.hyphenated());
+                    false
+                }
+            } else {
+                true
+            };*/
+            match mount(filesystem, &mountpoint, || {
+                println!("redox-fatfs: mounted filesystem on {} to {}", path, mountpoint);
+                let _ = write.write(&[0]);
+            }, mode, uid, gid, options) {
+                Ok(()) => {
+                    process::exit(0);
+                },
+                Err(err) => {
+                    println!("redox-fatfs: failed to mount {} to {}: {}", path, mountpoint, err);
+                }
+            }
+
+        },
+        Err(err) => println!("redox-fatfs: failed to open filesystem {}: {}", path, err)
+    }
+}
+
+fn daemon(path: &String, offset: u64, mountpoint: &str, mut write: File, uid: u32, gid: u32, mode: u16, serial: Option<u32>,
+          options: MountOptions, overlay: bool, force: bool) -> ! {
     setsig();
 
     println!("redox-fatfs: opening {}", path);
     println!("redox-fatfs: using serial number: {:?}", serial);
-    match OpenOptions::new().read(true).write(true).open(path) {
-            Ok(disk) => match redox_fatfs::FileSystem::from_offset(0, disk, serial) {
-                Ok(filesystem) => {
-                    println!("redox-fatfs: opened filesystem on {}", path);
-
-                    /*let matches = if let Some(uuid) = uuid_opt {
-                        if &filesystem.header.1.uuid == uuid.as_bytes() {
-                            println!("redoxfs: filesystem on {} matches uuid {}", path, uuid.hyphenated());
-                            true
-                        } else {
-                            println!("redoxfs: filesystem on {} does not match uuid {}", path, uuid.hyphenated());
-                            false
-                        }
-                    } else {
-                        true
-                    };*/
-                    match mount(filesystem, &mountpoint, || {
-                        println!("redox-fatfs: mounted filesystem on {} to {}", path, mountpoint);
-                        let _ = write.write(&[0]);
-                    }, mode, uid, gid) {
-                        Ok(()) => {
-                            process::exit(0);
-                        },
-                        Err(err) => {
-                            println!("redox-fatfs: failed to mount {} to {}: {}", path, mountpoint, err);
-                        }
-                    }
-
-                },
-                Err(err) => println!("redox-fatfs: failed to open filesystem {}: {}", path, err)
+    // With an overlay the image is only read, changes are lost on unmount
+    match OpenOptions::new().read(true).write(!overlay).open(path) {
+            Ok(disk) => if overlay {
+                match Overlay::new(disk) {
+                    Ok(disk) => mount_disk(disk, path, offset, mountpoint, &mut write, uid, gid, mode, serial, options, force),
+                    Err(err) => println!("redox-fatfs: failed to open image {}: {}", path, err)
+                }
+            } else {
+                mount_disk(disk, path, offset, mountpoint, &mut write, uid, gid, mode, serial, options, force)
             },
             Err(err) => println!("redox-fatfs: failed to open image {}: {}", path, err)
     }
@@ -394,38 +502,96 @@ fn main() {
         }
     };
 
+    let mut options = MountOptions::default();
+    let mut overlay = false;
+    let mut force = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--max-files" => options.max_open_files = Some(parse_limit(&arg, args.next())),
+            "--max-fmap" => options.max_fmap_memory = Some(parse_limit(&arg, args.next())),
+            "--reserved" => options.reserved_clusters = Some(parse_limit(&arg, args.next()) as u64),
+            "--write-attempts" => options.write_attempts = Some(parse_limit(&arg, args.next()) as u32),
+            "--case-sensitive" => options.case_sensitive = true,
+            "--path-cache" => options.path_cache = true,
+            "--file-cache" => options.file_cache = Some(parse_limit(&arg, args.next())),
+            "--listing-cache" => options.listing_cache = Some(parse_limit(&arg, args.next())),
+            "--entry-cache" => options.entry_cache = Some(parse_limit(&arg, args.next())),
+            "--free-map-scan" => options.free_map_scan = Some(parse_limit(&arg, args.next()) as u64),
+            "--flush-interval" => options.flush_interval = Some(parse_limit(&arg, args.next()) as u64),
+            "--hide-dot-files" => options.hide_dot_files = true,
+            "--hide-system" => options.hide_system = true,
+            "--hide-hidden" => options.hide_hidden = true,
+            "--read-only-dirs" => options.read_only_dirs = true,
+            "--short-names-only" => options.short_names_only = true,
+            "--sorted" => options.sorted_listings = true,
+            "--translate-names" => options.name_translation = parse_translation(args.next()),
+            "--strict-fat-mirror" => options.strict_fat_mirroring = true,
+            "--strict-bounds" => options.strict_cluster_bounds = true,
+            "--attribute-modes" => options.attribute_modes = true,
+            "--umask" => options.umask = parse_umask(args.next()),
+            "--verify-writes" => options.verify_writes = true,
+            "--overlay" => overlay = true,
+            "--force" => force = true,
+            "--trace" => enable_tracing(),
+            _ => {
+                println!("redox-fatfs: unknown option '{}'", arg);
+                usage();
+                process::exit(1);
+            }
+        }
+    }
 
     let mut paths = vec![];
     disk_paths(&mut paths);
     let mut exit_code = 0;
 
     for path in paths {
-        let mut pipes = [0; 2];
-        if pipe(&mut pipes) == 0 {
-            let mut read = unsafe { File::from_raw_fd(pipes[0] as RawFd) };
-            let write = unsafe { File::from_raw_fd(pipes[1] as RawFd) };
-
-            let pid = fork();
-            if pid == 0 {
-                drop(read);
-                let id = MOUNT_COUNT.fetch_add(1, Ordering::SeqCst).to_string();
-                let mut mount_point = mountpoint_base.clone();
-                mount_point.push_str(&id);
-                daemon(&path, &mount_point, write, uid, gid, mode, serial);
-            } else if pid > 0 {
-                drop(write);
-
-                let mut res = [0];
-                read.read(&mut res).unwrap();
-                println!("Received errorcode {:?} from child", res[0]);
-                if res[0] > 0 {
-                    exit_code = res[0] as i32;
+        // Each FAT volume on the disk gets a daemon of its own
+        let volumes = match File::open(&path).and_then(|mut disk| enumerate_fat_volumes(&mut disk)) {
+            Ok(volumes) => volumes,
+            Err(err) => {
+                println!("redox-fatfs: failed to read partitions of {}: {}", path, err);
+                continue;
+            }
+        };
+        if volumes.is_empty() {
+            println!("redox-fatfs: no FAT volume found on {}", path);
+            continue;
+        }
+
+        let id = MOUNT_COUNT.fetch_add(1, Ordering::SeqCst).to_string();
+        for (offset, info) in volumes {
+            let mut mount_point = mountpoint_base.clone();
+            mount_point.push_str(&id);
+            // Unpartitioned disks keep the mount point of the whole disk
+            if let Some(n) = info.partition {
+                mount_point.push_str(&format!(".{}", n));
+            }
+
+            let mut pipes = [0; 2];
+            if pipe(&mut pipes) == 0 {
+                let mut read = unsafe { File::from_raw_fd(pipes[0] as RawFd) };
+                let write = unsafe { File::from_raw_fd(pipes[1] as RawFd) };
+
+                let pid = fork();
+                if pid == 0 {
+                    drop(read);
+                    daemon(&path, offset, &mount_point, write, uid, gid, mode, serial, options, overlay, force);
+                } else if pid > 0 {
+                    drop(write);
+
+                    let mut res = [0];
+                    read.read(&mut res).unwrap();
+                    println!("Received errorcode {:?} from child", res[0]);
+                    if res[0] > 0 {
+                        exit_code = res[0] as i32;
+                    }
+                } else {
+                    panic!("redox-fatfs: failed to fork");
                 }
             } else {
-                panic!("redox-fatfs: failed to fork");
+                panic!("redox-fatfs: failed to create pipe");
             }
-        } else {
-            panic!("redox-fatfs: failed to create pipe");
         }
     }
 
@@ -0,0 +1,289 @@
+extern crate redox_fatfs;
+
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Write, Seek, ErrorKind};
+use std::path::Path;
+use std::process;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use redox_fatfs::prelude::{check, ClusterState, CopyProgress, FileSystem, FileAttributes, Dir, DirEntry, FixPolicy, SortBy};
+use redox_fatfs::raw::civil_from_days;
+
+/// Characters per line and lines at most of the `map` output
+const MAP_WIDTH: u64 = 64;
+const MAP_LINES: u64 = 16;
+
+fn usage() -> ! {
+    eprintln!("Usage: fatfs-tool <command> <image> [args]");
+    eprintln!("Commands:");
+    eprintln!("    cp <pattern> <dest dir>    copy files matching a DOS wildcard pattern out of the image");
+    eprintln!("    put <file> <path>          copy a host file into the image, replacing what is at path");
+    eprintln!("    ls <dir> [name|size|mtime] list a directory, sorted by name unless told otherwise");
+    eprintln!("    fsck [free|extend [--dry-run]] list problems, fixing file sizes which don't match their chain,");
+    eprintln!("                               freeing or keeping the excess clusters");
+    eprintln!("    df                         show the total, used and free bytes of the data clusters");
+    eprintln!("    map                        summarize cluster usage and draw it, '.' free, '+' partly used,");
+    eprintln!("                               '#' allocated and 'X' containing bad clusters");
+    eprintln!("    attrib <path> [+a|-a]      show the attributes of an entry, or set or clear its archive bit");
+    eprintln!("    stat <path>                show the size, attributes and times of an entry");
+    eprintln!("    touch <path> [seconds]     set the write and access times of an entry to now or to");
+    eprintln!("                               seconds since 1970 UTC, creating a missing file");
+    process::exit(1);
+}
+
+fn fail(msg: String) -> ! {
+    eprintln!("fatfs-tool: {}", msg);
+    process::exit(1);
+}
+
+// Copies the files matching `pattern` into the host directory `dest`
+fn cp<D: Read + Write + Seek>(fs: &mut FileSystem<D>, pattern: &str, dest: &str) {
+    let root = fs.root_dir();
+    let entries = root.glob(fs, pattern).unwrap_or_else(|e| fail(format!("invalid pattern '{}': {}", pattern, e)));
+    if entries.is_empty() {
+        fail(format!("no match for '{}'", pattern));
+    }
+
+    fs::create_dir_all(dest).unwrap_or_else(|e| fail(format!("failed to create {}: {}", dest, e)));
+    for e in entries {
+        if e.is_dir() {
+            eprintln!("fatfs-tool: skipping directory {}", e.dir_path().trim_matches('/'));
+            continue;
+        }
+        copy_out(fs, &e, &Path::new(dest).join(e.name()));
+    }
+}
+
+// Copies the host file `src` to `path` in the image
+fn put<D: Read + Write + Seek>(fs: &mut FileSystem<D>, src: &str, path: &str) {
+    let mut file = fs::File::open(src).unwrap_or_else(|e| fail(format!("failed to open {}: {}", src, e)));
+    let len = file.metadata().unwrap_or_else(|e| fail(format!("failed to stat {}: {}", src, e))).len();
+    let copied = fs.copy_in(&mut file, path, len, |p| show_progress(path, p))
+        .unwrap_or_else(|e| fail(format!("failed to copy {} to {}: {}", src, path, e)));
+    end_progress(&copied);
+}
+
+// Lists the directory at `path`, one entry per line with its size
+fn ls<D: Read + Write + Seek>(fs: &mut FileSystem<D>, path: &str, order: Option<&str>) {
+    let sort_by = match order {
+        None | Some("name") => SortBy::Name,
+        Some("size") => SortBy::Size,
+        Some("mtime") => SortBy::Mtime,
+        Some(o) => fail(format!("unknown order '{}'", o))
+    };
+    let root = fs.root_dir();
+    let dir = if path.trim_matches('/').is_empty() {
+        root
+    } else {
+        root.open_dir(path, fs).unwrap_or_else(|e| fail(format!("failed to open {}: {}", path, e)))
+    };
+    for e in dir.entries_sorted(fs, sort_by) {
+        if e.is_vol_id() {
+            continue;
+        } else if e.is_dir() {
+            println!("{:>10} {}/", "", e.name());
+        } else {
+            println!("{:>10} {}", e.size(), e.name());
+        }
+    }
+}
+
+// Lists the problems on the volume and applies the fixes for them when given a policy
+fn fsck<D: Read + Write + Seek>(fs: &mut FileSystem<D>, policy: Option<&str>, dry_run: bool) {
+    let report = check(fs).unwrap_or_else(|e| fail(format!("check failed: {}", e)));
+    for p in &report.problems {
+        println!("{:?}", p);
+    }
+    let policy = match policy {
+        None => {
+            if !report.is_clean() {
+                process::exit(1);
+            }
+            return
+        },
+        Some("free") => FixPolicy::FreeExcess,
+        Some("extend") => FixPolicy::ExtendSize,
+        Some(p) => fail(format!("unknown policy '{}'", p))
+    };
+    let changes = report.apply_fixes(fs, policy, dry_run).unwrap_or_else(|e| fail(format!("fixing failed: {}", e)));
+    if dry_run {
+        println!("Dry run, nothing was written:");
+    }
+    for c in changes {
+        println!("{}", c);
+    }
+}
+
+// Prints the size of the data area and how much of it is used
+fn df<D: Read + Write + Seek>(fs: &mut FileSystem<D>) {
+    let total = fs.bytes_total();
+    let free = fs.bytes_free().unwrap_or_else(|e| fail(format!("failed to count free clusters: {}", e)));
+    let used = total - free;
+    println!("{:>14} {:>14} {:>14} {:>4}", "Total", "Used", "Free", "Use%");
+    println!("{:>14} {:>14} {:>14} {:>3}%", total, used, free, if total > 0 { (used * 100 + total - 1) / total } else { 0 });
+}
+
+// Prints the cluster counts and a map with a character for each group of clusters
+fn map<D: Read + Write + Seek>(fs: &mut FileSystem<D>) {
+    let usage = fs.usage_bitmap().unwrap_or_else(|e| fail(format!("failed to read the FAT: {}", e)));
+    println!("{} clusters of {} bytes: {} allocated, {} free, {} bad, {} fragments",
+             usage.len(), fs.bytes_per_cluster(), usage.allocated, usage.free, usage.bad, usage.fragments);
+
+    let per_char = (usage.len() + MAP_WIDTH * MAP_LINES - 1) / (MAP_WIDTH * MAP_LINES);
+    let states: Vec<ClusterState> = usage.iter().collect();
+    let mut line = String::new();
+    for group in states.chunks(per_char.max(1) as usize) {
+        let used = group.iter().filter(|&&s| s == ClusterState::Allocated).count();
+        line.push(if group.contains(&ClusterState::Bad) {
+            'X'
+        } else if used == group.len() {
+            '#'
+        } else if used > 0 {
+            '+'
+        } else {
+            '.'
+        });
+        if line.len() as u64 == MAP_WIDTH {
+            println!("{}", line);
+            line.clear();
+        }
+    }
+    if !line.is_empty() {
+        println!("{}", line);
+    }
+}
+
+// Shows the attributes of the entry at `path` as DOS attrib does, or changes its archive bit
+fn attrib<D: Read + Write + Seek>(fs: &mut FileSystem<D>, path: &str, change: Option<&str>) {
+    let mut e = Dir::get_entry_abs(path, fs).unwrap_or_else(|e| fail(format!("failed to open {}: {}", path, e)));
+    let archive = match change {
+        None => {
+            let attrs = e.attributes();
+            let flag = |a: FileAttributes, c: char| if attrs.contains(a) { c } else { ' ' };
+            println!("{}  {}{}{}     {}", flag(FileAttributes::ARCHIVE, 'A'), flag(FileAttributes::SYSTEM, 'S'),
+                     flag(FileAttributes::HIDDEN, 'H'), flag(FileAttributes::RD_ONLY, 'R'), path);
+            return
+        },
+        Some("+a") | Some("+A") => true,
+        Some("-a") | Some("-A") => false,
+        Some(c) => fail(format!("unsupported attribute change '{}'", c))
+    };
+    e.set_archive(fs, archive).unwrap_or_else(|e| fail(format!("failed to change the attributes of {}: {}", path, e)));
+}
+
+// Prints what `metadata` has on the entry at `path`
+fn stat<D: Read + Write + Seek>(fs: &mut FileSystem<D>, path: &str) {
+    let meta = fs.metadata(path).unwrap_or_else(|e| fail(format!("failed to stat {}: {}", path, e)));
+    println!("    Path: {}", path);
+    println!("    Type: {}", if meta.is_dir() { "directory" } else { "file" });
+    println!("    Size: {}", meta.len());
+    println!("    Used: {}", meta.allocated_len());
+    println!("Clusters: {} from {}", meta.clusters(), meta.first_cluster().cluster_number);
+    println!("   Attrs: {:?}", meta.attributes());
+    println!("Modified: {}", format_time(meta.modified().ok()));
+    println!(" Created: {}", format_time(meta.created().ok()));
+    println!("Accessed: {}", format_time(meta.accessed().ok()));
+}
+
+// Sets the write and access times of the entry at `path`, creating a file if there is none
+fn touch<D: Read + Write + Seek>(fs: &mut FileSystem<D>, path: &str, secs: Option<&str>) {
+    let time = match secs {
+        None => fs.now(),
+        Some(s) => match s.parse::<u64>() {
+            Ok(s) => UNIX_EPOCH + Duration::from_secs(s),
+            Err(e) => fail(format!("invalid time '{}': {}", s, e))
+        }
+    };
+    match Dir::get_entry_abs(path, fs) {
+        Ok(_) => (),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => {
+            let root = fs.root_dir();
+            root.create_file(path, fs).unwrap_or_else(|e| fail(format!("failed to create {}: {}", path, e)));
+        },
+        Err(e) => fail(format!("failed to open {}: {}", path, e))
+    }
+    fs.set_times(path, Some(time), Some(time))
+        .unwrap_or_else(|e| fail(format!("failed to set the times of {}: {}", path, e)));
+}
+
+// A time as "YYYY-MM-DD HH:MM:SS (seconds since 1970)", FAT times are taken to be UTC
+fn format_time(time: Option<SystemTime>) -> String {
+    let secs = match time.and_then(|t| t.duration_since(UNIX_EPOCH).ok()) {
+        Some(d) => d.as_secs() as i64,
+        None => return "-".to_string()
+    };
+    let (year, month, day) = civil_from_days(secs / 86400);
+    let s = secs % 86400;
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} ({})", year, month, day, s / 3600, s / 60 % 60, s % 60, secs)
+}
+
+fn copy_out<D: Read + Write + Seek>(fs: &mut FileSystem<D>, e: &DirEntry, dest: &Path) {
+    let f = e.to_file();
+    let mut out = fs::File::create(dest).unwrap_or_else(|err| fail(format!("failed to create {}: {}", dest.display(), err)));
+    let copied = fs.copy_out(&f.file_path, &mut out, |p| show_progress(&e.name(), p))
+        .unwrap_or_else(|err| fail(format!("failed to copy {} to {}: {}", e.name(), dest.display(), err)));
+    end_progress(&copied);
+}
+
+// Rewrites the progress line of a copy on stderr
+fn show_progress(name: &str, p: &CopyProgress) -> bool {
+    eprint!("\r{}: {}/{} bytes", name, p.done, p.total);
+    true
+}
+
+fn end_progress(p: &CopyProgress) {
+    if p.done > 0 {
+        eprintln!();
+    }
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let (command, image) = match (args.next(), args.next()) {
+        (Some(command), Some(image)) => (command, image),
+        _ => usage()
+    };
+    let args: Vec<String> = args.collect();
+
+    // Commands which only read leave the image alone, it may be mounted elsewhere
+    let read_only = match command.as_str() {
+        "cp" | "df" | "ls" | "map" | "stat" => true,
+        "fsck" => args.len() != 1,
+        "attrib" => args.len() == 1,
+        _ => false
+    };
+    let disk = OpenOptions::new().read(true).write(!read_only).open(&image)
+        .unwrap_or_else(|e| fail(format!("failed to open image {}: {}", image, e)));
+    let opened = if read_only {
+        FileSystem::open_read_only(0, disk, None)
+    } else {
+        FileSystem::from_offset(0, disk, None)
+    };
+    let mut fs = opened.unwrap_or_else(|e| fail(format!("failed to open filesystem {}: {}", image, e)));
+    if read_only {
+        if fs.in_use().unwrap_or(false) {
+            eprintln!("fatfs-tool: warning: {} is in use or was not unmounted cleanly", image);
+        }
+    } else {
+        fs.lock(false).unwrap_or_else(|e| fail(format!("failed to lock {}: {}", image, e)));
+    }
+
+    match (command.as_str(), args.len()) {
+        ("cp", 2) => cp(&mut fs, &args[0], &args[1]),
+        ("put", 2) => put(&mut fs, &args[0], &args[1]),
+        ("ls", 1) => ls(&mut fs, &args[0], None),
+        ("ls", 2) => ls(&mut fs, &args[0], Some(&args[1])),
+        ("fsck", 0) => fsck(&mut fs, None, false),
+        ("fsck", 1) => fsck(&mut fs, Some(&args[0]), false),
+        ("fsck", 2) if args[1] == "--dry-run" => fsck(&mut fs, Some(&args[0]), true),
+        ("df", 0) => df(&mut fs),
+        ("map", 0) => map(&mut fs),
+        ("attrib", 1) => attrib(&mut fs, &args[0], None),
+        ("attrib", 2) => attrib(&mut fs, &args[0], Some(&args[1])),
+        ("stat", 1) => stat(&mut fs, &args[0]),
+        ("touch", 1) => touch(&mut fs, &args[0], None),
+        ("touch", 2) => touch(&mut fs, &args[0], Some(&args[1])),
+        _ => usage()
+    }
+}
@@ -0,0 +1,102 @@
+use std::io::{Read, Write, Seek, Error, ErrorKind};
+
+use filesystem::FileSystem;
+use bpb::FATType;
+
+use super::Result;
+
+/// Bytes at `offset` in the boot sector which differ from its backup, see
+/// `FileSystem::compare_backup_boot_sector`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SectorDifference {
+    pub offset: u64,
+    pub primary: Vec<u8>,
+    pub backup: Vec<u8>
+}
+
+impl<D: Read + Write + Seek> FileSystem<D> {
+    /// Offset of BS_Reserved1, where Windows and Linux keep the volume dirty flags
+    fn dirty_flags_offset(&self) -> usize {
+        match self.bpb.fat_type {
+            FATType::FAT32(_) => 65,
+            _ => 37
+        }
+    }
+
+    /// Sector of the backup boot sector, None on FAT12/16 and FAT32 volumes without one
+    pub fn backup_boot_sector(&self) -> Option<u64> {
+        match self.bpb.fat_type {
+            FATType::FAT32(s) if s.bk_boot_sec != 0 => Some(s.bk_boot_sec as u64),
+            _ => None
+        }
+    }
+
+    /// The whole boot sector as it is on disk
+    pub fn boot_sector_bytes(&mut self) -> Result<Vec<u8>> {
+        let mut buf = vec![0; self.bytes_per_sec() as usize];
+        self.read_at(0, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// The boot code between the BPB and the signature, which the filesystem never uses
+    pub fn boot_code(&mut self) -> Result<Vec<u8>> {
+        let start = match self.bpb.fat_type {
+            FATType::FAT32(_) => 90,
+            _ => 62
+        };
+        let sector = self.boot_sector_bytes()?;
+        Ok(sector[start..510].to_vec())
+    }
+
+    /// Runs of bytes in which the backup boot sector differs from the boot sector, None
+    /// if there is no backup
+    ///
+    /// The dirty flags are left out, they are only ever set in the boot sector itself.
+    pub fn compare_backup_boot_sector(&mut self) -> Result<Option<Vec<SectorDifference>>> {
+        let backup_sec = match self.backup_boot_sector() {
+            Some(s) => s,
+            None => return Ok(None)
+        };
+        let primary = self.boot_sector_bytes()?;
+        let mut backup = vec![0; primary.len()];
+        self.read_at(backup_sec * self.bytes_per_sec(), &mut backup)?;
+
+        let dirty = self.dirty_flags_offset();
+        let mut differences: Vec<SectorDifference> = Vec::new();
+        for i in 0..primary.len() {
+            if primary[i] == backup[i] || i == dirty {
+                continue;
+            }
+            if let Some(d) = differences.last_mut() {
+                if d.offset + d.primary.len() as u64 == i as u64 {
+                    d.primary.push(primary[i]);
+                    d.backup.push(backup[i]);
+                    continue;
+                }
+            }
+            differences.push(SectorDifference {
+                offset: i as u64,
+                primary: vec![primary[i]],
+                backup: vec![backup[i]]
+            });
+        }
+        Ok(Some(differences))
+    }
+
+    /// Copies the boot sector over its backup, e.g. after another tool changed the BPB
+    /// and left the backup as it was. Fails with InvalidInput if there is no backup
+    pub fn repair_backup(&mut self) -> Result<()> {
+        let backup_sec = match self.backup_boot_sector() {
+            Some(s) => s,
+            None => return Err(Error::new(ErrorKind::InvalidInput, "Volume has no backup boot sector"))
+        };
+        let mut sector = self.boot_sector_bytes()?;
+        // Keep the flags the backup has
+        let dirty = self.dirty_flags_offset();
+        let mut flags = [0u8; 1];
+        self.read_at(backup_sec * self.bytes_per_sec() + dirty as u64, &mut flags)?;
+        sector[dirty] = flags[0];
+        self.write_to(backup_sec * self.bytes_per_sec(), &sector)?;
+        self.disk.borrow_mut().flush()
+    }
+}
@@ -88,7 +88,7 @@ pub struct BiosParameterBlockLegacy {
     pub volume_label: [u8; 11],
     /// File System Type
     /// BS_FilSysType
-    pub file_sys_type: u32,
+    pub file_sys_type: [u8; 8],
     //// Boot Code
     //pub code : [u8; 452]
 }
@@ -147,7 +147,22 @@ pub struct BiosParameterBlockFAT32 {
     //pub code: [u8; 420]
 }
 
+/// Cylinder/head/sector layout recorded for BIOS INT 0x13
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Geometry {
+    pub cylinders: u32,
+    pub heads: u16,
+    pub sectors_per_track: u16
+}
+
 impl BiosParameterBlock {
+    /// Byte offset of BS_DrvNum in a FAT12/16 boot sector
+    const LEGACY_DRIVE_NUM_OFFSET: u64 = 36;
+    /// BS_BootSig when the volume ID, label and file system type follow
+    const EXT_BOOT_SIG: u8 = 0x29;
+    /// BS_BootSig of older volumes, which only have the volume ID
+    const EXT_BOOT_SIG_SERIAL_ONLY: u8 = 0x28;
+
     pub fn populate<D: Read+Seek>(disk: &mut D) -> Result<BiosParameterBlock> {
         let mut cursor = Cursor::new(vec![0u8; BLOCK_SIZE as usize]);
         let mut bpb  = BiosParameterBlock::default();
@@ -191,12 +206,25 @@ impl BiosParameterBlock {
         bpb.validate(&bpb32)?;
         let root_sectors = ((bpb.root_entries_cnt as u32 * 32) + (bpb.bytes_per_sector as u32) - 1) / (bpb.bytes_per_sector as u32);
         let fat_sz = if bpb.fat_size_16 != 0 { bpb.fat_size_16 as u32 } else { bpb32.fat_size };
-        let tot_sec = if bpb.total_sectors_16 != 0 { bpb.total_sectors_16 as u32 } else { bpb.total_sectors_32 };
+        let tot_sec = bpb.total_sectors() as u32;
         let data_sec = tot_sec - ((bpb.rsvd_sec_cnt as u32) + (bpb.num_fats as u32) * fat_sz + root_sectors);
 
         let count_clusters = data_sec / (bpb.sectors_per_cluster as u32);
-        bpb.fat_type = if count_clusters < 4085 { FATType::FAT12(BiosParameterBlockLegacy::default()) }
-                       else if count_clusters < 65525 { FATType::FAT16(BiosParameterBlockLegacy::default()) }
+        let mut legacy = BiosParameterBlockLegacy::default();
+        cursor.seek(SeekFrom::Start(Self::LEGACY_DRIVE_NUM_OFFSET))?;
+        legacy.drive_num = cursor.read_u8()?;
+        legacy.reserved = cursor.read_u8()?;
+        legacy.boot_sig = cursor.read_u8()?;
+        // Without the extended boot signature the rest is boot code
+        if legacy.boot_sig == Self::EXT_BOOT_SIG || legacy.boot_sig == Self::EXT_BOOT_SIG_SERIAL_ONLY {
+            legacy.vol_id = cursor.read_u32::<LittleEndian>()?;
+        }
+        if legacy.boot_sig == Self::EXT_BOOT_SIG {
+            cursor.read_exact(&mut legacy.volume_label)?;
+            cursor.read_exact(&mut legacy.file_sys_type)?;
+        }
+        bpb.fat_type = if count_clusters < 4085 { FATType::FAT12(legacy) }
+                       else if count_clusters < 65525 { FATType::FAT16(legacy) }
                        else { FATType::FAT32(bpb32) };
 
         Ok(bpb)
@@ -213,7 +241,12 @@ impl BiosParameterBlock {
             return Err(Error::new(ErrorKind::Other, "Invalid bytes per sector (value > 4096)"))
         }
 
-        let is_fat32 = self.is_fat32();
+        if self.sectors_per_cluster.count_ones() != 1 {
+            return Err(Error::new(ErrorKind::Other, "Invalid sectors per cluster (not a power of 2)"))
+        } else if self.bytes_per_sector as u32 * self.sectors_per_cluster as u32 > 64 * 1024 {
+            return Err(Error::new(ErrorKind::Other, "Invalid cluster size (value > 64KiB)"))
+        }
+
         if self.rsvd_sec_cnt < 1 {
             return Err(Error::new(ErrorKind::Other, "Invalid rsvd_sec_cnt value in BPB"));
         }
@@ -222,63 +255,142 @@ impl BiosParameterBlock {
             return Err(Error::new(ErrorKind::Other, "invalid fats value in BPB"));
         }
 
-        if is_fat32 && self.root_entries_cnt != 0 {
+        // 0xF0 for removable media and 0xF8 - 0xFF otherwise
+        if self.media != 0xF0 && self.media < 0xF8 {
+            return Err(Error::new(ErrorKind::Other, "Invalid media descriptor in BPB"));
+        }
+
+        // Some cameras fill in both, in which case the 16 bit count is the one used
+        if self.total_sectors_16 == 0 && self.total_sectors_32 == 0 {
             return Err(Error::new(
                 ErrorKind::Other,
-                "Invalid root_entries value in BPB (should be zero for FAT32)",
+                "Invalid BPB (total_sectors_16 or total_sectors_32 should be non-zero)",
             ));
         }
 
-        if is_fat32 && self.total_sectors_16 != 0 {
+        if self.fat_size_16 == 0 && bpb32.fat_size == 0 {
             return Err(Error::new(
                 ErrorKind::Other,
-                "Invalid total_sectors_16 value in BPB (should be zero for FAT32)",
+                "Invalid sectors_per_fat_32 value in BPB (should be non-zero for FAT32)",
             ));
         }
 
-        if (self.total_sectors_16 == 0) == (self.total_sectors_32 == 0) {
+        let root_sectors = ((self.root_entries_cnt as u32 * 32) + (self.bytes_per_sector as u32) - 1) / (self.bytes_per_sector as u32);
+        let fat_sz = if self.fat_size_16 != 0 { self.fat_size_16 as u32 } else { bpb32.fat_size };
+        let tot_sec = self.total_sectors() as u32;
+        let first_data_sec = (self.rsvd_sec_cnt as u64) + (self.num_fats as u64) * fat_sz as u64 + root_sectors as u64;
+        if tot_sec as u64 <= first_data_sec {
+            return Err(Error::new(ErrorKind::Other, "Total sectors lesser than first data sector"))
+        }
+
+        let data_sec = tot_sec - first_data_sec as u32;
+        let count_clusters = data_sec / (self.sectors_per_cluster as u32);
+
+        // The cluster count alone decides the FAT type, the FAT32 only fields just have
+        // to agree with it
+        let is_fat32 = count_clusters >= 65525;
+        if is_fat32 != (self.fat_size_16 == 0) {
+            return Err(Error::new(ErrorKind::Other, "FAT determination using fat_size_16 and count_cluster differs"))
+        }
+
+        if is_fat32 && self.root_entries_cnt != 0 {
             return Err(Error::new(
                 ErrorKind::Other,
-                "Invalid BPB (total_sectors_16 or total_sectors_32 should be non-zero)",
+                "Invalid root_entries value in BPB (should be zero for FAT32)",
             ));
         }
 
-
-        if is_fat32 && bpb32.fat_size == 0 {
+        if is_fat32 && self.total_sectors_16 != 0 {
             return Err(Error::new(
                 ErrorKind::Other,
-                "Invalid sectors_per_fat_32 value in BPB (should be non-zero for FAT32)",
+                "Invalid total_sectors_16 value in BPB (should be zero for FAT32)",
             ));
         }
 
-        if bpb32.fs_ver != 0 {
+        if is_fat32 && bpb32.fs_ver != 0 {
             return Err(Error::new(ErrorKind::Other, "Unknown FS version"));
         }
 
+        // Every cluster needs an entry in the FAT, 2 more are reserved
+        let bits_per_entry = if is_fat32 { 32 } else if count_clusters < 4085 { 12 } else { 16 };
+        if (count_clusters as u64 + 2) * bits_per_entry > fat_sz as u64 * self.bytes_per_sector as u64 * 8 {
+            return Err(Error::new(ErrorKind::Other, "FAT is too small for the number of clusters"))
+        }
 
-        let root_sectors = ((self.root_entries_cnt as u32 * 32) + (self.bytes_per_sector as u32) - 1) / (self.bytes_per_sector as u32);
-        let fat_sz = if self.fat_size_16 != 0 { self.fat_size_16 as u32 } else { bpb32.fat_size };
-        let tot_sec = if self.total_sectors_16 != 0 { self.total_sectors_16 as u32 } else { self.total_sectors_32 };
-        let first_data_sec = (self.rsvd_sec_cnt as u32) + (self.num_fats as u32) * fat_sz + root_sectors;
-        let data_sec = tot_sec - ((self.rsvd_sec_cnt as u32) + (self.num_fats as u32) * fat_sz + root_sectors);
+        if is_fat32 && (bpb32.root_cluster < 2 || bpb32.root_cluster > count_clusters + 1) {
+            return Err(Error::new(ErrorKind::Other, "Invalid root cluster in BPB"))
+        }
+        Ok(())
+    }
 
-        let count_clusters = data_sec / (self.sectors_per_cluster as u32);
+    /// BPB_Media, also stored in the low byte of the first FAT entry
+    pub fn media_descriptor(&self) -> u8 {
+        self.media
+    }
 
-        if tot_sec <= first_data_sec {
-            return Err(Error::new(ErrorKind::Other, "Total sectors lesser than first data sector"))
+    /// Everything except 0xF8, which marks fixed (non-removable) media
+    pub fn is_removable(&self) -> bool {
+        self.media != 0xF8
+    }
+
+    /// BS_DrvNum, 0x00 for floppies and 0x80 for hard disks
+    pub fn drive_number(&self) -> u8 {
+        match self.fat_type {
+            FATType::FAT12(b) | FATType::FAT16(b) => b.drive_num,
+            FATType::FAT32(b) => b.drv_num
         }
+    }
 
-        if is_fat32 != (count_clusters >= 65525) {
-            return Err(Error::new(ErrorKind::Other, "FAT determination using tot_sec_16 and count_cluster differs"))
+    /// None if the heads or sectors per track are outside what INT 0x13 can address
+    pub fn geometry(&self) -> Option<Geometry> {
+        if self.number_of_heads == 0 || self.number_of_heads > 255 ||
+            self.sectors_per_track == 0 || self.sectors_per_track > 63 {
+            return None
         }
-        Ok(())
+
+        let tot_sec = self.total_sectors();
+        let track_sectors = self.number_of_heads as u64 * self.sectors_per_track as u64;
+        Some(Geometry {
+            cylinders: ((self.hidden_sectors as u64 + tot_sec + track_sectors - 1) / track_sectors) as u32,
+            heads: self.number_of_heads,
+            sectors_per_track: self.sectors_per_track
+        })
     }
 
-    fn is_fat32(&self) -> bool {
-        //Seemingly the best way
-        self.total_sectors_16 == 0
+    /// Sectors in the volume, from whichever of the 16 and 32 bit fields is set
+    pub fn total_sectors(&self) -> u64 {
+        if self.total_sectors_16 != 0 { self.total_sectors_16 as u64 } else { self.total_sectors_32 as u64 }
+    }
+
+    /// BS_VolLab without the padding, None without the extended boot signature
+    ///
+    /// This copy is informational, the label shown by most systems is the one in the
+    /// root directory.
+    pub fn volume_label(&self) -> Option<String> {
+        let (sig, label) = match self.fat_type {
+            FATType::FAT12(b) | FATType::FAT16(b) => (b.boot_sig, b.volume_label),
+            FATType::FAT32(b) => (b.boot_sig, b.volume_label)
+        };
+        if sig != Self::EXT_BOOT_SIG {
+            return None
+        }
+        Some(String::from_utf8_lossy(&label).trim_end().to_string())
+    }
+
+    /// BS_FilSysType without the padding, e.g. "FAT16", None without the extended boot
+    /// signature. It doesn't decide the FAT type, the cluster count does
+    pub fn fs_type_label(&self) -> Option<String> {
+        let (sig, fs_type) = match self.fat_type {
+            FATType::FAT12(b) | FATType::FAT16(b) => (b.boot_sig, b.file_sys_type),
+            FATType::FAT32(b) => (b.boot_sig, b.file_sys_type)
+        };
+        if sig != Self::EXT_BOOT_SIG {
+            return None
+        }
+        Some(String::from_utf8_lossy(&fs_type).trim_end().to_string())
     }
 
+    /// BS_VolID, 0 on FAT12/16 volumes without the extended boot signature
     pub fn get_serial(&self) -> u32 {
         match self.fat_type {
             FATType::FAT12(b) | FATType::FAT16(b) => {
@@ -324,7 +436,9 @@ impl fmt::Debug for BiosParameterBlockLegacy {
             reserved: {},
             boot_sig: {},
             vol_id: {:X},
-            file_sys_type: {}
-        }}", self.drive_num, self.reserved, self.boot_sig, self.vol_id, self.file_sys_type)
+            volume_label: {:?},
+            file_sys_type: {:?}
+        }}", self.drive_num, self.reserved, self.boot_sig, self.vol_id,
+           String::from_utf8_lossy(&self.volume_label), String::from_utf8_lossy(&self.file_sys_type))
     }
 }
@@ -0,0 +1,214 @@
+use std::io::{Read, Write, Seek};
+
+use filesystem::Cluster;
+use filesystem::FileSystem;
+use dir_entry::{Dir, DirEntry, MAX_FILE_SIZE};
+use table::{FatEntry, get_entry, set_entry, RESERVED_CLUSTERS};
+
+use super::Result;
+
+/// An inconsistency found on the volume
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Problem {
+    /// A zero length file which still has clusters allocated
+    EmptyFileWithClusters { path: String, first_cluster: Cluster },
+    /// A file whose size needs more clusters than its chain has
+    SizeExceedsChain { path: String, size: u64, clusters: u64 },
+    /// A file whose chain has more clusters than its size needs
+    ChainExceedsSize { path: String, size: u64, clusters: u64 },
+    /// Long name entries at byte `offset` of the volume in the directory `path` which
+    /// don't make up a valid name, the entry they belong to isn't listed
+    DamagedLongName { path: String, offset: u64, checksum_mismatch: bool },
+    /// The backup boot sector differs from the boot sector in `bytes` bytes
+    StaleBackupBootSector { bytes: u64 },
+    /// The chain of the file or directory at `path` goes from `cluster` to `next`, which
+    /// is not a data cluster of the volume
+    ClusterOutOfRange { path: String, cluster: Cluster, next: u64 }
+}
+
+impl Problem {
+    /// Byte offset on the volume the problem was found at, if it is about raw entries
+    pub fn offset(&self) -> Option<u64> {
+        match *self {
+            Problem::DamagedLongName { offset, .. } => Some(offset),
+            _ => None
+        }
+    }
+}
+
+/// How `CheckReport::apply_fixes` repairs files with more clusters than their size needs
+/// Sizes larger than the chain are always cut down to it
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FixPolicy {
+    /// Free the clusters past the size
+    FreeExcess,
+    /// Grow the size to cover the whole chain, keeping whatever data is there
+    ExtendSize
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CheckReport {
+    pub problems: Vec<Problem>
+}
+
+impl CheckReport {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// Repairs the problems there is a fix for and describes each change, one per line
+    /// With `dry_run` nothing is written and the lines tell what would be changed
+    pub fn apply_fixes<D: Read + Write + Seek>(&self, fs: &mut FileSystem<D>, policy: FixPolicy, dry_run: bool) -> Result<Vec<String>> {
+        let bpc = fs.bytes_per_cluster();
+        let mut changes = Vec::new();
+        for p in &self.problems {
+            let (path, size, clusters) = match *p {
+                Problem::EmptyFileWithClusters { ref path, first_cluster } => {
+                    match chain_len(fs, first_cluster) {
+                        Some(ChainLen::Clusters(clusters)) => (path, 0, clusters),
+                        _ => continue
+                    }
+                },
+                Problem::SizeExceedsChain { ref path, size, clusters } |
+                Problem::ChainExceedsSize { ref path, size, clusters } => (path, size, clusters),
+                Problem::DamagedLongName { .. } => continue,
+                Problem::ClusterOutOfRange { ref path, cluster, next } => {
+                    changes.push(format!("{}: end the chain at cluster {} instead of {}", path, cluster.cluster_number, next));
+                    if !dry_run {
+                        set_entry(fs, cluster, FatEntry::EndOfChain)?;
+                    }
+                    continue
+                },
+                Problem::StaleBackupBootSector { bytes } => {
+                    changes.push(format!("backup boot sector: copy the boot sector over {} differing bytes", bytes));
+                    if !dry_run {
+                        fs.repair_backup()?;
+                    }
+                    continue
+                }
+            };
+
+            let new_size = if size > clusters * bpc
+                || (policy == FixPolicy::ExtendSize && clusters * bpc <= MAX_FILE_SIZE) {
+                Some(clusters * bpc)
+            } else {
+                None
+            };
+            changes.push(match new_size {
+                Some(n) => format!("{}: set the size from {} to {} bytes", path, size, n),
+                None => format!("{}: free {} clusters past the size of {} bytes", path, clusters - (size + bpc - 1) / bpc, size)
+            });
+            if dry_run {
+                continue;
+            }
+
+            let mut f = Dir::get_entry_abs(path, fs)?.to_file();
+            match new_size {
+                Some(n) => {
+                    f.set_size(n as u32);
+                    f.size_dirty = true;
+                    f.flush(fs)?;
+                },
+                None => f.free_excess_clusters(fs)?
+            }
+        }
+        Ok(changes)
+    }
+}
+
+/// Walks every directory on the volume and reports the problems found
+/// Nothing is written to the volume. Warnings kept by strict mode are moved into the report
+pub fn check<D: Read + Write + Seek>(fs: &mut FileSystem<D>) -> Result<CheckReport> {
+    let mut report = CheckReport::default();
+    if let Some(differences) = fs.compare_backup_boot_sector()? {
+        let bytes: u64 = differences.iter().map(|d| d.primary.len() as u64).sum();
+        if bytes > 0 {
+            report.problems.push(Problem::StaleBackupBootSector { bytes: bytes });
+        }
+    }
+    let root = fs.root_dir();
+    let strict = fs.strict_lfn;
+    fs.strict_lfn = true;
+    // Chains are followed as they are, to find where they leave the volume
+    let strict_bounds = fs.strict_cluster_bounds;
+    fs.strict_cluster_bounds = false;
+    let res = match chain_len(fs, root.first_cluster) {
+        Some(ChainLen::OutOfRange(cluster, next)) => {
+            report.problems.push(Problem::ClusterOutOfRange { path: root.dir_path.clone(), cluster: cluster, next: next });
+            Ok(())
+        },
+        _ => check_dir(&root, fs, &mut report)
+    };
+    fs.strict_lfn = strict;
+    fs.strict_cluster_bounds = strict_bounds;
+    report.problems.extend(fs.take_lfn_warnings());
+    res.map(|_| report)
+}
+
+fn check_dir<D: Read + Write + Seek>(dir: &Dir, fs: &mut FileSystem<D>, report: &mut CheckReport) -> Result<()> {
+    let entries: Vec<DirEntry> = dir.to_iter(fs).collect();
+    for e in entries {
+        let short_name = e.short_name();
+        if short_name == "." || short_name == ".." {
+            continue;
+        }
+
+        if let Some(ChainLen::OutOfRange(cluster, next)) = chain_len(fs, e.first_cluster()) {
+            report.problems.push(Problem::ClusterOutOfRange { path: e.dir_path(), cluster: cluster, next: next });
+            continue;
+        }
+
+        if e.is_dir() {
+            check_dir(&e.to_dir(), fs, report)?;
+        } else if !e.is_vol_id() {
+            let f = e.to_file();
+            if f.size() == 0 && f.first_cluster.cluster_number != 0 {
+                report.problems.push(Problem::EmptyFileWithClusters {
+                    path: f.file_path.clone(),
+                    first_cluster: f.first_cluster
+                });
+                continue;
+            }
+
+            // Chains running into a loop are left alone
+            let clusters = match chain_len(fs, f.first_cluster) {
+                Some(ChainLen::Clusters(c)) => c,
+                _ => continue
+            };
+            let needed = (f.size() + fs.bytes_per_cluster() - 1) / fs.bytes_per_cluster();
+            if needed > clusters {
+                report.problems.push(Problem::SizeExceedsChain { path: f.file_path.clone(), size: f.size(), clusters: clusters });
+            } else if needed < clusters {
+                report.problems.push(Problem::ChainExceedsSize { path: f.file_path.clone(), size: f.size(), clusters: clusters });
+            }
+        }
+    }
+    Ok(())
+}
+
+enum ChainLen {
+    Clusters(u64),
+    /// The cluster pointing outside the volume and where it points to
+    OutOfRange(Cluster, u64)
+}
+
+// Clusters in the chain starting at `first`, None if it is longer than the volume
+fn chain_len<D: Read + Write + Seek>(fs: &mut FileSystem<D>, first: Cluster) -> Option<ChainLen> {
+    if first.cluster_number < 2 {
+        return Some(ChainLen::Clusters(0))
+    }
+    let max = fs.max_cluster_number().cluster_number;
+    let mut current = first;
+    let mut count = 1;
+    while let Ok(FatEntry::Next(next)) = get_entry(fs, current) {
+        if next.cluster_number < RESERVED_CLUSTERS || next.cluster_number > max {
+            return Some(ChainLen::OutOfRange(current, next.cluster_number))
+        }
+        count += 1;
+        if count > max {
+            return None
+        }
+        current = next;
+    }
+    Some(ChainLen::Clusters(count))
+}
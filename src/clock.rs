@@ -0,0 +1,27 @@
+use std::time::SystemTime;
+
+/// Where the current time for the timestamps the filesystem writes comes from, see
+/// `FileSystem::set_clock`
+pub trait Clock {
+    fn now(&self) -> SystemTime;
+}
+
+/// The system time, used unless another clock is set
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Always the same time, for tests and images which have to come out byte for byte the same
+#[derive(Clone, Copy, Debug)]
+pub struct FixedClock(pub SystemTime);
+
+impl Clock for FixedClock {
+    fn now(&self) -> SystemTime {
+        self.0
+    }
+}
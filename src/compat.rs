@@ -0,0 +1,236 @@
+//! Types shaped like those of the rust-fatfs crate, for code written against its API
+//!
+//! Handles borrow the filesystem immutably as in rust-fatfs, which keeps this crate's
+//! `FileSystem` in a `RefCell` and borrows it for the length of each call. Directory
+//! iterators read the whole directory up front. Only the common subset of the rust-fatfs
+//! API is covered, `raw` and `prelude` remain the way to reach everything else.
+
+use std::cell::RefCell;
+use std::io::{self, Read, Write, Seek, SeekFrom, Error, ErrorKind};
+use std::vec;
+
+use filesystem;
+use dir_entry::{self, FileAttributes};
+
+use super::Result;
+
+/// Options for `FileSystem::new`
+#[derive(Copy, Clone, Debug, Default)]
+pub struct FsOptions {
+    update_accessed_date: bool
+}
+
+impl FsOptions {
+    pub fn new() -> FsOptions {
+        FsOptions::default()
+    }
+
+    /// Accepted for compatibility, access dates are never written
+    pub fn update_accessed_date(mut self, enabled: bool) -> FsOptions {
+        self.update_accessed_date = enabled;
+        self
+    }
+}
+
+pub struct FileSystem<D: Read + Write + Seek> {
+    inner: RefCell<filesystem::FileSystem<D>>
+}
+
+impl<D: Read + Write + Seek> FileSystem<D> {
+    pub fn new(disk: D, _options: FsOptions) -> Result<FileSystem<D>> {
+        let fs = filesystem::FileSystem::from_offset(0, disk, None)?;
+        Ok(FileSystem { inner: RefCell::new(fs) })
+    }
+
+    pub fn root_dir<'a>(&'a self) -> Dir<'a, D> {
+        let dir = self.inner.borrow_mut().root_dir();
+        Dir { fs: self, dir: dir }
+    }
+
+    /// Marks the volume as cleanly unmounted, which also happens on drop
+    pub fn unmount(self) -> Result<()> {
+        self.inner.borrow_mut().unmount()
+    }
+
+    /// The filesystem of this crate underneath
+    pub fn into_inner(self) -> filesystem::FileSystem<D> {
+        self.inner.into_inner()
+    }
+}
+
+pub struct Dir<'a, D: Read + Write + Seek + 'a> {
+    fs: &'a FileSystem<D>,
+    dir: dir_entry::Dir
+}
+
+impl<'a, D: Read + Write + Seek> Dir<'a, D> {
+    /// Entries of the directory including "." and "..", volume labels are left out
+    pub fn iter(&self) -> DirIter<'a, D> {
+        let entries: Vec<dir_entry::DirEntry> = {
+            let mut fs = self.fs.inner.borrow_mut();
+            self.dir.to_iter(&mut fs).filter(|e| !e.is_vol_id()).collect()
+        };
+        DirIter { fs: self.fs, entries: entries.into_iter() }
+    }
+
+    pub fn open_dir(&self, path: &str) -> Result<Dir<'a, D>> {
+        let dir = self.dir.open_dir(path, &mut self.fs.inner.borrow_mut())?;
+        Ok(Dir { fs: self.fs, dir: dir })
+    }
+
+    pub fn open_file(&self, path: &str) -> Result<File<'a, D>> {
+        let file = self.dir.open_file(path, &mut self.fs.inner.borrow_mut())?;
+        Ok(File::new(self.fs, file))
+    }
+
+    /// Opens the file at `path`, creating it if it doesn't exist
+    pub fn create_file(&self, path: &str) -> Result<File<'a, D>> {
+        let file = self.dir.create_file(path, &mut self.fs.inner.borrow_mut())?;
+        Ok(File::new(self.fs, file))
+    }
+
+    /// Opens the directory at `path`, creating it if it doesn't exist
+    pub fn create_dir(&self, path: &str) -> Result<Dir<'a, D>> {
+        let dir = self.dir.create_dir(path, &mut self.fs.inner.borrow_mut())?;
+        Ok(Dir { fs: self.fs, dir: dir })
+    }
+
+    /// Removes a file or an empty directory
+    pub fn remove(&self, path: &str) -> Result<()> {
+        self.dir.remove(path, &mut self.fs.inner.borrow_mut(), true)
+    }
+
+    /// Moves `src_path` below this directory to `dst_path` below `dst_dir`
+    pub fn rename(&self, src_path: &str, dst_dir: &Dir<D>, dst_path: &str) -> Result<()> {
+        let mut fs = self.fs.inner.borrow_mut();
+        let mut e = self.dir.get_entry(src_path, &mut fs)?;
+        let dst = format!("{}/{}", dst_dir.dir.dir_path.trim_end_matches('/'), dst_path.trim_matches('/'));
+        dir_entry::Dir::rename(&mut e, &dst, &mut fs)
+    }
+}
+
+pub struct DirIter<'a, D: Read + Write + Seek + 'a> {
+    fs: &'a FileSystem<D>,
+    entries: vec::IntoIter<dir_entry::DirEntry>
+}
+
+impl<'a, D: Read + Write + Seek> Iterator for DirIter<'a, D> {
+    type Item = Result<DirEntry<'a, D>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let fs = self.fs;
+        self.entries.next().map(|e| Ok(DirEntry { fs: fs, entry: e }))
+    }
+}
+
+pub struct DirEntry<'a, D: Read + Write + Seek + 'a> {
+    fs: &'a FileSystem<D>,
+    entry: dir_entry::DirEntry
+}
+
+impl<'a, D: Read + Write + Seek> DirEntry<'a, D> {
+    pub fn file_name(&self) -> String {
+        self.entry.name()
+    }
+
+    pub fn short_file_name(&self) -> String {
+        self.entry.short_name()
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.entry.is_dir()
+    }
+
+    pub fn is_file(&self) -> bool {
+        !self.entry.is_dir()
+    }
+
+    pub fn len(&self) -> u64 {
+        self.entry.size()
+    }
+
+    pub fn attributes(&self) -> FileAttributes {
+        self.entry.attributes()
+    }
+
+    /// Panics if the entry is a directory
+    pub fn to_file(&self) -> File<'a, D> {
+        File::new(self.fs, self.entry.to_file())
+    }
+
+    /// Panics if the entry is a file
+    pub fn to_dir(&self) -> Dir<'a, D> {
+        Dir { fs: self.fs, dir: self.entry.to_dir() }
+    }
+}
+
+/// A file with a position, written back to its directory entry on `flush` and drop
+pub struct File<'a, D: Read + Write + Seek + 'a> {
+    fs: &'a FileSystem<D>,
+    file: dir_entry::File,
+    offset: u64
+}
+
+impl<'a, D: Read + Write + Seek> File<'a, D> {
+    fn new(fs: &'a FileSystem<D>, file: dir_entry::File) -> File<'a, D> {
+        File { fs: fs, file: file, offset: 0 }
+    }
+
+    /// Cuts the file off at the current position
+    pub fn truncate(&mut self) -> Result<()> {
+        self.file.truncate(&mut self.fs.inner.borrow_mut(), self.offset)
+    }
+}
+
+impl<'a, D: Read + Write + Seek> Read for File<'a, D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.file.read(buf, &mut self.fs.inner.borrow_mut(), self.offset)?;
+        self.offset += count as u64;
+        Ok(count)
+    }
+}
+
+impl<'a, D: Read + Write + Seek> Write for File<'a, D> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = self.file.write(buf, &mut self.fs.inner.borrow_mut(), self.offset)?;
+        self.offset += count as u64;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush(&mut self.fs.inner.borrow_mut())
+    }
+}
+
+impl<'a, D: Read + Write + Seek> Seek for File<'a, D> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let offset = match pos {
+            SeekFrom::Start(o) => Some(o),
+            SeekFrom::End(d) => add_signed(self.file.size(), d),
+            SeekFrom::Current(d) => add_signed(self.offset, d)
+        };
+        match offset {
+            Some(o) => {
+                self.offset = o;
+                Ok(o)
+            },
+            None => Err(Error::new(ErrorKind::InvalidInput, "Seek to a negative offset"))
+        }
+    }
+}
+
+fn add_signed(base: u64, delta: i64) -> Option<u64> {
+    if delta < 0 {
+        base.checked_sub(delta.wrapping_neg() as u64)
+    } else {
+        base.checked_add(delta as u64)
+    }
+}
+
+impl<'a, D: Read + Write + Seek> Drop for File<'a, D> {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            warn!("Failed to flush file on drop: {}", e);
+        }
+    }
+}
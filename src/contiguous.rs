@@ -0,0 +1,64 @@
+use std::io::{Read, Write, Seek, Error, ErrorKind};
+
+use filesystem::{FileSystem, Cluster};
+use dir_entry::{File, FileAttributes, MAX_FILE_SIZE};
+use table::{set_entry, deallocate_cluster_chain, FatEntry, RESERVED_CLUSTERS};
+use usage::ClusterState;
+
+use super::Result;
+
+impl<D: Read + Write + Seek> FileSystem<D> {
+    /// Creates the file at the absolute `path` with `size` bytes in consecutive clusters
+    ///
+    /// For swap files and firmware staging areas, which need their data at a fixed place.
+    /// The clusters are zeroed and the file created at its full size, with SYSTEM set as
+    /// well as ARCHIVE if `system`. Writes within `size` never allocate, so the file stays
+    /// contiguous until it is grown or truncated. Fails with AlreadyExists if `path`
+    /// exists and with Other if no run of free clusters is long enough.
+    pub fn create_contiguous(&mut self, path: &str, size: u64, system: bool) -> Result<File> {
+        self.check_writable()?;
+        if size == 0 || size > MAX_FILE_SIZE {
+            return Err(Error::new(ErrorKind::InvalidInput, "Size must be between 1 byte and 4GiB - 1"))
+        }
+        let bpc = self.bytes_per_cluster();
+        let count = (size + bpc - 1) / bpc;
+
+        let usage = self.usage_bitmap()?;
+        let mut run_start = RESERVED_CLUSTERS;
+        let mut first = None;
+        for (i, state) in usage.iter().enumerate() {
+            let cluster = i as u64 + RESERVED_CLUSTERS;
+            if state != ClusterState::Free {
+                run_start = cluster + 1;
+            } else if cluster + 1 - run_start == count {
+                first = Some(run_start);
+                break
+            }
+        }
+        let first = match first {
+            Some(c) => c,
+            None => return Err(Error::new(ErrorKind::Other, "No run of free clusters long enough"))
+        };
+
+        // The chain is complete before the entry refers to it, a crash only loses it
+        let offset = self.cluster_offset(Cluster::new(first));
+        self.zero_range(offset, count * bpc)?;
+        let last = first + count - 1;
+        for c in first..last {
+            set_entry(self, Cluster::new(c), FatEntry::Next(Cluster::new(c + 1)))?;
+        }
+        set_entry(self, Cluster::new(last), FatEntry::EndOfChain)?;
+        self.fs_info.borrow_mut().delta_free_count(-(count as i32));
+        self.fs_info.borrow_mut().update_next_free(last + 1);
+
+        let attrs = if system { FileAttributes::SYSTEM } else { FileAttributes::empty() };
+        let root = self.root_dir();
+        match root.create_file_on(path, Cluster::new(first), size as u32, attrs, self) {
+            Ok(f) => Ok(f),
+            Err(e) => {
+                deallocate_cluster_chain(self, Cluster::new(first))?;
+                Err(e)
+            }
+        }
+    }
+}
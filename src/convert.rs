@@ -0,0 +1,295 @@
+use std::collections::BTreeSet;
+use std::io::{Read, Write, Seek, SeekFrom, Error, ErrorKind};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use filesystem::{FileSystem, FsInfo, retry_interrupted};
+use bpb::{BiosParameterBlock, FATType};
+use table::RESERVED_CLUSTERS;
+
+use super::Result;
+
+/// Fewest clusters a FAT32 volume can have, below it the volume would be read as FAT16
+const MIN_FAT32_CLUSTERS: u64 = 65525;
+/// Reserved sectors of a converted volume, room for FSInfo and the backup boot sector
+const FAT32_RESERVED_SECTORS: u64 = 32;
+const FS_INFO_SECTOR: u64 = 1;
+const BACKUP_BOOT_SECTOR: u64 = 6;
+const FAT32_EOC: u32 = 0x0FFFFFFF;
+const FAT32_BAD: u32 = 0x0FFFFFF7;
+
+/// Layout of the volume once converted
+struct Fat32Layout {
+    sectors_per_cluster: u64,
+    rsvd: u64,
+    fat_sz: u64,
+    clusters: u64,
+    /// Clusters taken by the former root directory, which come first
+    root_clusters: u64,
+    /// New clusters each old cluster is split into
+    ratio: u64
+}
+
+impl Fat32Layout {
+    fn first_data_sec(&self, num_fats: u64) -> u64 {
+        self.rsvd + num_fats * self.fat_sz
+    }
+
+    /// First new cluster of old cluster `n`
+    fn map(&self, n: u64) -> u64 {
+        RESERVED_CLUSTERS + self.root_clusters + (n - RESERVED_CLUSTERS) * self.ratio
+    }
+
+    fn max_cluster(&self) -> u64 {
+        self.clusters + RESERVED_CLUSTERS - 1
+    }
+}
+
+impl<D: Read + Write + Seek> FileSystem<D> {
+    /// Converts a FAT16 volume to FAT32 in place
+    ///
+    /// The FATs are rebuilt with 32 bit entries and the root directory becomes a cluster
+    /// chain starting at cluster 2, followed by the data area. Clusters are made as small
+    /// as needed for the volume to have the clusters FAT32 requires, so every cluster
+    /// number changes and the directory entries are rewritten to match. Boot code is
+    /// dropped, volume ID and label are kept. Fails with InvalidInput on FAT12 and FAT32
+    /// volumes, if the volume is too small for FAT32 or if clusters at its end are in use,
+    /// as the larger FATs push the data area up. The volume must not be in use elsewhere,
+    /// and a crash halfway through leaves it corrupt.
+    pub fn convert_to_fat32(&mut self) -> Result<()> {
+        self.check_writable()?;
+        match self.bpb.fat_type {
+            FATType::FAT16(_) => (),
+            _ => return Err(Error::new(ErrorKind::InvalidInput, "Only FAT16 volumes can be converted"))
+        }
+        let bps = self.bytes_per_sec();
+        let spc = self.sectors_per_cluster();
+        let num_fats = self.bpb.num_fats as u64;
+        let total = self.bpb.total_sectors();
+        let old_root_start = self.fat_start_sector() + num_fats * self.fat_size();
+        let root_sectors = self.first_data_sec - old_root_start;
+        let old_max = self.max_cluster_number().cluster_number;
+
+        self.sync_fat_mirrors()?;
+        let mut fat16 = vec![0; (self.fat_size() * bps) as usize];
+        let fat_start = self.fat_start_sector() * bps;
+        self.read_at(fat_start, &mut fat16)?;
+        let entry = |n: u64| LittleEndian::read_u16(&fat16[(n * 2) as usize..]) as u64;
+
+        let layout = match fat32_layout(total, bps, spc, num_fats, self.bpb.rsvd_sec_cnt as u64, root_sectors) {
+            Some(l) => l,
+            None => return Err(Error::new(ErrorKind::InvalidInput, "Volume is too small for FAT32"))
+        };
+        let last_used = (RESERVED_CLUSTERS..old_max + 1).filter(|&n| {
+            let e = entry(n);
+            e != 0 && e != 0xFFF7
+        }).last();
+        if last_used.map_or(false, |n| layout.map(n) + layout.ratio - 1 > layout.max_cluster()) {
+            return Err(Error::new(ErrorKind::InvalidInput, "Clusters at the end of the volume are in use"))
+        }
+
+        let mut fat32 = vec![0; (layout.fat_sz * bps) as usize];
+        let mut set = |n: u64, val: u32| {
+            if n <= layout.max_cluster() {
+                LittleEndian::write_u32(&mut fat32[(n * 4) as usize..], val);
+            }
+        };
+        set(0, 0x0FFFFF00 | self.bpb.media as u32);
+        // Carry over the clean shutdown and hard error bits
+        let flags = entry(1);
+        set(1, FAT32_EOC & !(if flags & 0x8000 == 0 { 0x08000000 } else { 0 })
+                         & !(if flags & 0x4000 == 0 { 0x04000000 } else { 0 }));
+        for c in 0..layout.root_clusters {
+            let n = RESERVED_CLUSTERS + c;
+            set(n, if c + 1 == layout.root_clusters { FAT32_EOC } else { n as u32 + 1 });
+        }
+        for n in RESERVED_CLUSTERS..old_max + 1 {
+            let first = layout.map(n);
+            let last = first + layout.ratio - 1;
+            match entry(n) {
+                0 => continue,
+                0xFFF7 => {
+                    for c in first..last + 1 {
+                        set(c, FAT32_BAD);
+                    }
+                    continue
+                },
+                _ => ()
+            }
+            for c in first..last {
+                set(c, c as u32 + 1);
+            }
+            set(last, match entry(n) {
+                m if m >= RESERVED_CLUSTERS && m <= old_max => layout.map(m) as u32,
+                _ => FAT32_EOC
+            });
+        }
+
+        // Entries are rewritten where they are, before anything moves
+        let root = (old_root_start * bps, root_sectors * bps);
+        let mut dirs = vec![vec![root]];
+        let mut seen = BTreeSet::new();
+        let mut files = Vec::new();
+        while let Some(extents) = dirs.pop() {
+            for (offset, len) in extents {
+                let mut buf = vec![0; len as usize];
+                self.read_at(offset, &mut buf)?;
+                for slot in buf.chunks_mut(32) {
+                    if slot[0] == 0 {
+                        break
+                    }
+                    let attrs = slot[11];
+                    if slot[0] == 0xE5 || attrs & 0x0F == 0x0F || attrs & 0x08 != 0 {
+                        continue
+                    }
+                    let n = LittleEndian::read_u16(&slot[26..]) as u64;
+                    if n < RESERVED_CLUSTERS || n > old_max {
+                        continue
+                    }
+                    let new = layout.map(n) as u32;
+                    LittleEndian::write_u16(&mut slot[20..], (new >> 16) as u16);
+                    LittleEndian::write_u16(&mut slot[26..], new as u16);
+                    if attrs & 0x10 == 0 {
+                        files.push((new as u64, LittleEndian::read_u32(&slot[28..]) as u64));
+                    } else if slot[0] != b'.' && seen.insert(n) {
+                        // The old chain, as the entries in it still have to be found
+                        let mut chain = Vec::new();
+                        let mut c = n;
+                        while c >= RESERVED_CLUSTERS && c <= old_max && chain.len() as u64 <= old_max {
+                            chain.push((self.first_data_sec + (c - RESERVED_CLUSTERS) * spc) * bps);
+                            c = entry(c);
+                        }
+                        dirs.push(chain.into_iter().map(|o| (o, spc * bps)).collect());
+                    }
+                }
+                self.write_to(offset, &buf)?;
+            }
+        }
+
+        // Split clusters leave files with more of them than their size needs
+        let new_bpc = layout.sectors_per_cluster * bps;
+        for (first, size) in files {
+            let keep = (size + new_bpc - 1) / new_bpc;
+            let mut c = first;
+            for i in 0..layout.ratio * ((size + spc * bps - 1) / (spc * bps)) {
+                let next = LittleEndian::read_u32(&fat32[(c * 4) as usize..]) as u64;
+                if i + 1 == keep {
+                    LittleEndian::write_u32(&mut fat32[(c * 4) as usize..], FAT32_EOC);
+                } else if i + 1 > keep {
+                    LittleEndian::write_u32(&mut fat32[(c * 4) as usize..], 0);
+                }
+                if next < RESERVED_CLUSTERS || next > layout.max_cluster() {
+                    break
+                }
+                c = next;
+            }
+        }
+
+        // The data area first, it lands past where the root directory goes
+        let new_data = layout.first_data_sec(num_fats);
+        let root_area = layout.root_clusters * layout.sectors_per_cluster;
+        if let Some(n) = last_used {
+            let start = self.first_data_sec * bps;
+            let end = start + (n - 1) * spc * bps;
+            self.move_range_up(start, end, (new_data + root_area - self.first_data_sec) * bps)?;
+        }
+        self.move_range_up(old_root_start * bps, self.first_data_sec * bps, (new_data - old_root_start) * bps)?;
+        self.zero_range((new_data + root_sectors) * bps, (root_area - root_sectors) * bps)?;
+        let old_rsvd = self.bpb.rsvd_sec_cnt as u64;
+        if layout.rsvd > old_rsvd {
+            self.zero_range(old_rsvd * bps, (layout.rsvd - old_rsvd) * bps)?;
+        }
+        for i in 0..num_fats {
+            self.write_to((layout.rsvd + i * layout.fat_sz) * bps, &fat32)?;
+        }
+
+        let mut boot = vec![0; bps as usize];
+        self.read_at(0, &mut boot[..62])?;
+        let mut sector = vec![0; bps as usize];
+        sector[..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+        sector[3..36].copy_from_slice(&boot[3..36]);
+        sector[13] = layout.sectors_per_cluster as u8;
+        LittleEndian::write_u16(&mut sector[14..], layout.rsvd as u16);
+        LittleEndian::write_u16(&mut sector[17..], 0);
+        LittleEndian::write_u16(&mut sector[19..], 0);
+        LittleEndian::write_u16(&mut sector[22..], 0);
+        LittleEndian::write_u32(&mut sector[32..], total as u32);
+        LittleEndian::write_u32(&mut sector[36..], layout.fat_sz as u32);
+        LittleEndian::write_u32(&mut sector[44..], RESERVED_CLUSTERS as u32);
+        LittleEndian::write_u16(&mut sector[48..], FS_INFO_SECTOR as u16);
+        LittleEndian::write_u16(&mut sector[50..], BACKUP_BOOT_SECTOR as u16);
+        sector[64] = boot[36];
+        sector[65] = boot[37];
+        sector[66] = 0x29;
+        if boot[38] == 0x29 {
+            sector[67..82].copy_from_slice(&boot[39..54]);
+        } else {
+            sector[71..82].copy_from_slice(b"NO NAME    ");
+        }
+        sector[82..90].copy_from_slice(b"FAT32   ");
+        sector[510] = 0x55;
+        sector[511] = 0xAA;
+
+        let mut fs_info = vec![0; bps as usize];
+        LittleEndian::write_u32(&mut fs_info[0..], 0x41615252);
+        LittleEndian::write_u32(&mut fs_info[484..], 0x61417272);
+        LittleEndian::write_u32(&mut fs_info[488..], 0xFFFFFFFF);
+        LittleEndian::write_u32(&mut fs_info[492..], 0xFFFFFFFF);
+        LittleEndian::write_u32(&mut fs_info[508..], 0xAA550000);
+        for &base in &[0, BACKUP_BOOT_SECTOR] {
+            self.write_to((base + FS_INFO_SECTOR) * bps, &fs_info)?;
+            self.write_to(base * bps, &sector)?;
+        }
+        self.disk.borrow_mut().flush()?;
+
+        let partition_offset = self.partition_offset;
+        let bpb = {
+            let disk = self.disk.get_mut();
+            retry_interrupted(|| {
+                disk.seek(SeekFrom::Start(partition_offset))?;
+                BiosParameterBlock::populate(disk)
+            })?
+        };
+        self.bpb = bpb;
+        self.first_data_sec = new_data;
+        let fs_info = FsInfo::populate(self.disk.get_mut(), partition_offset + FS_INFO_SECTOR * bps)?;
+        *self.fs_info.borrow_mut() = fs_info;
+        self.clear_entry_cache();
+        self.restart_free_map();
+        self.rebuild_fsinfo()?;
+        self.disk.borrow_mut().flush()
+    }
+}
+
+// The largest clusters, no larger than the current ones, which still make enough of them
+fn fat32_layout(total: u64, bps: u64, spc: u64, num_fats: u64, rsvd: u64, root_sectors: u64) -> Option<Fat32Layout> {
+    let rsvd = if rsvd < FAT32_RESERVED_SECTORS { FAT32_RESERVED_SECTORS } else { rsvd };
+    let mut sectors_per_cluster = spc;
+    while sectors_per_cluster > 0 {
+        let root_clusters = (root_sectors + sectors_per_cluster - 1) / sectors_per_cluster;
+        let mut fat_sz = 1;
+        let clusters = loop {
+            let meta = rsvd + num_fats * fat_sz;
+            if total <= meta {
+                return None
+            }
+            let clusters = (total - meta) / sectors_per_cluster;
+            if (clusters + RESERVED_CLUSTERS) * 4 <= fat_sz * bps {
+                break clusters
+            }
+            fat_sz += 1;
+        };
+        if clusters >= MIN_FAT32_CLUSTERS && clusters > root_clusters {
+            return Some(Fat32Layout {
+                sectors_per_cluster: sectors_per_cluster,
+                rsvd: rsvd,
+                fat_sz: fat_sz,
+                clusters: clusters,
+                root_clusters: root_clusters,
+                ratio: spc / sectors_per_cluster
+            })
+        }
+        sectors_per_cluster /= 2;
+    }
+    None
+}
@@ -0,0 +1,78 @@
+use std::cmp::min;
+use std::io::{Read, Write, Seek, Error, ErrorKind};
+
+use filesystem::FileSystem;
+
+use super::Result;
+
+/// Most bytes copied between two progress reports
+const COPY_CHUNK: u64 = 64 * 1024;
+
+/// How far `FileSystem::copy_in` or `copy_out` got, passed on after every chunk
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CopyProgress {
+    /// Bytes copied so far and to copy in all
+    pub done: u64,
+    pub total: u64
+}
+
+impl<D: Read + Write + Seek> FileSystem<D> {
+    /// Copies `len` bytes from `r` into the file at `path`, creating it or replacing what
+    /// it held
+    ///
+    /// `progress` is called after each chunk of up to 64KiB, returning false cancels the
+    /// copy with Interrupted. The file then keeps the chunks written so far. Fails with
+    /// UnexpectedEof if `r` ends before `len` bytes.
+    pub fn copy_in<R: Read, F: FnMut(&CopyProgress) -> bool>(&mut self, r: &mut R, path: &str, len: u64,
+                                                             mut progress: F) -> Result<CopyProgress> {
+        let root = self.root_dir();
+        let mut f = root.create_file(path, self)?;
+        f.truncate(self, 0)?;
+        let mut state = CopyProgress { done: 0, total: len };
+        let mut buf = vec![0; min(COPY_CHUNK, len) as usize];
+        while state.done < len {
+            let chunk = &mut buf[..min(COPY_CHUNK, len - state.done) as usize];
+            if let Err(e) = r.read_exact(chunk) {
+                f.flush(self)?;
+                return Err(e)
+            }
+            if f.write(chunk, self, state.done)? < chunk.len() {
+                f.flush(self)?;
+                return Err(Error::new(ErrorKind::WriteZero, "Short write to the file"))
+            }
+            state.done += chunk.len() as u64;
+            if !progress(&state) {
+                f.flush(self)?;
+                return Err(Error::new(ErrorKind::Interrupted, "Copy cancelled"))
+            }
+        }
+        f.flush(self)?;
+        Ok(state)
+    }
+
+    /// Copies the file at `path` to `w`
+    ///
+    /// `progress` is called after each chunk of up to 64KiB, returning false cancels the
+    /// copy with Interrupted after what was written to `w` so far.
+    pub fn copy_out<W: Write, F: FnMut(&CopyProgress) -> bool>(&mut self, path: &str, w: &mut W,
+                                                               mut progress: F) -> Result<CopyProgress> {
+        let root = self.root_dir();
+        let f = root.open_file(path, self)?;
+        let mut state = CopyProgress { done: 0, total: f.size() };
+        let mut buf = vec![0; min(COPY_CHUNK, f.size()) as usize];
+        while state.done < state.total {
+            let len = min(COPY_CHUNK, state.total - state.done) as usize;
+            let r = f.read(&mut buf[..len], self, state.done)?;
+            if r == 0 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "File shorter than its size"));
+            }
+            w.write_all(&buf[..r])?;
+            state.done += r as u64;
+            if !progress(&state) {
+                return Err(Error::new(ErrorKind::Interrupted, "Copy cancelled"))
+            }
+        }
+        w.flush()?;
+        Ok(state)
+    }
+}
@@ -1,15 +1,20 @@
-use std::io::{Read, Write, Seek, SeekFrom, Cursor};
+use std::io::{Read, Write, Seek, SeekFrom, Cursor, IoSlice, IoSliceMut};
 use std::iter::{Iterator, FromIterator};
 use std::io::{ErrorKind, Error};
 use std::{num, str};
 use std::cmp::min;
 use std::char;
+use std::collections::BTreeSet;
+use std::borrow::Cow;
 
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
 
-use Cluster;
+use filesystem::Cluster;
 use filesystem::{FileSystem, get_block_buffer};
-use table::{FatEntry, get_entry, allocate_cluster, deallocate_cluster_chain};
+use entry_cache::CachedEntry;
+use BLOCK_SIZE;
+use table::{FatEntry, RESERVED_CLUSTERS, get_entry, set_entry, allocate_cluster, allocate_data_cluster, find_free_cluster, claim_cluster,
+            deallocate_cluster_chain, split_chain};
 
 use super::Result;
 
@@ -17,6 +22,16 @@ pub const DIR_ENTRY_LEN: u64 = 32;
 pub const LFN_PART_LEN: usize = 13;
 // Max 32-bit unsigned value
 pub const MAX_FILE_SIZE: u64 = 0xffffffff;
+/// Long entries holding a name of 255 characters
+const MAX_LFN_ENTRIES: usize = 0x14;
+/// Slots taken by the longest name, its long entries and the short one
+const MAX_ENTRY_SLOTS: usize = MAX_LFN_ENTRIES + 1;
+/// Bits of LDIR_Ord holding the position of a long entry, 0x40 marks the last one
+const LFN_ORDER_MASK: u8 = 0x3f;
+/// Stored in place of a leading 0xE5 in short names
+pub const ESCAPED_E5: u8 = 0x05;
+/// 0xE5 in code page 437
+const SIGMA: char = '\u{3c3}';
 
 bitflags! {
     #[derive(Default)]
@@ -32,6 +47,109 @@ bitflags! {
    }
 }
 
+/// How short names are derived from long names which don't fit 8.3
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ShortNames {
+    /// "TEXTFI~1.TXT" for the first few collisions, then a checksum of the long name
+    /// as in "TE021F~1.TXT". Depends on which names the directory held before
+    Checksum,
+    /// The lowest free numeric tail, "TEXTFI~1.TXT" up to "T~999999.TXT". Creating the
+    /// same names in the same order always yields the same short names
+    Sequential
+}
+
+impl Default for ShortNames {
+    fn default() -> Self {
+        ShortNames::Checksum
+    }
+}
+
+/// What happens to characters FAT doesn't allow in names, such as ':', '\\' and '?'
+///
+/// Translation applies to names given for lookups and new entries alike, so a file created
+/// as "a:b" is found again as "a:b".
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum NameTranslation {
+    /// Names containing them are refused
+    Reject,
+    /// Each is replaced by the given character, so "a:b" and "a?b" name the same entry
+    Replace(char),
+    /// Each is stored as '%' and its UTF-8 bytes in hex, "a:b" becomes "a%3Ab". This can be
+    /// reversed with `to_shown`, '%' is itself escaped where it would read as an escape
+    Percent
+}
+
+impl Default for NameTranslation {
+    fn default() -> Self {
+        NameTranslation::Reject
+    }
+}
+
+impl NameTranslation {
+    /// Form of `name` kept on disk
+    pub fn to_stored<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        let chars: Vec<char> = name.chars().collect();
+        let reads_as_escape = |i: usize| {
+            chars[i] == '%' && i + 2 < chars.len() && chars[i + 1].is_digit(16) && chars[i + 2].is_digit(16)
+        };
+        let needs_escape = |i: usize| {
+            invalid_name_char(chars[i]) || (*self == NameTranslation::Percent && reads_as_escape(i))
+        };
+        if *self == NameTranslation::Reject || !(0..chars.len()).any(|i| needs_escape(i)) {
+            return Cow::Borrowed(name)
+        }
+
+        let mut stored = String::with_capacity(name.len());
+        for (i, &c) in chars.iter().enumerate() {
+            match *self {
+                NameTranslation::Replace(r) if invalid_name_char(c) => stored.push(r),
+                NameTranslation::Percent if needs_escape(i) => {
+                    let mut bytes = [0; 4];
+                    for b in c.encode_utf8(&mut bytes).bytes() {
+                        stored.push_str(&format!("%{:02X}", b));
+                    }
+                },
+                _ => stored.push(c)
+            }
+        }
+        Cow::Owned(stored)
+    }
+
+    /// Name as shown to users, only percent escapes can be undone
+    pub fn to_shown<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        if *self != NameTranslation::Percent || !name.contains('%') {
+            return Cow::Borrowed(name)
+        }
+        let bytes = name.as_bytes();
+        let hex = |b: u8| (b as char).to_digit(16);
+        let mut shown = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            match (bytes[i], bytes.get(i + 1).and_then(|&b| hex(b)), bytes.get(i + 2).and_then(|&b| hex(b))) {
+                (b'%', Some(hi), Some(lo)) => {
+                    shown.push((hi * 16 + lo) as u8);
+                    i += 3;
+                },
+                (b, _, _) => {
+                    shown.push(b);
+                    i += 1;
+                }
+            }
+        }
+        String::from_utf8(shown).map(Cow::Owned).unwrap_or(Cow::Borrowed(name))
+    }
+}
+
+// Characters the LFN spec doesn't allow, which `valid_long_name` refuses
+fn invalid_name_char(c: char) -> bool {
+    c < ' ' || c == '\x7f' || c > '\u{ffff}' || "\"*/:<>?\\|".contains(c)
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct CreateOptions {
+    pub short_names: ShortNames
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct File {
     pub first_cluster : Cluster,
@@ -39,7 +157,13 @@ pub struct File {
     pub fname: String,
     pub short_dir_entry: ShortDirEntry,
     /// Starting and ending offsets of directory entries
-    pub loc: ((Cluster, u64), (Cluster, u64))
+    pub loc: ((Cluster, u64), (Cluster, u64)),
+    /// First cluster of the directory holding the entries
+    pub parent_dir: Cluster,
+    /// The size in the short entry has changed but not been written yet
+    pub size_dirty: bool,
+    /// The file was modified since the short entry was last written
+    pub times_dirty: bool
     // FIXME: Add pointer to directory entry
 }
 
@@ -50,17 +174,23 @@ pub struct Dir {
     pub dir_path: String,
     pub dir_name: String,
     pub short_dir_entry: Option<ShortDirEntry>,
-    pub loc: Option<((Cluster, u64), (Cluster, u64))>
+    pub loc: Option<((Cluster, u64), (Cluster, u64))>,
+    /// First cluster of the directory holding the entries, unused for the root
+    pub parent_dir: Cluster
 }
 
 impl Dir {
     pub fn to_iter<'a, D: Read + Write + Seek>(&self, fs: &'a mut FileSystem<D>) -> DirIter<'a, D> {
         DirIter {
             current_cluster: self.first_cluster,
+            dir_cluster: self.first_cluster,
             dir_path: self.dir_path.clone(),
             offset: self.root_offset.unwrap_or(0),
             is_root: self.is_root(),
-            fs: fs
+            fs: fs,
+            buf: Vec::new(),
+            buf_start: 0,
+            skip: FileAttributes::empty()
         }
     }
 
@@ -69,6 +199,49 @@ impl Dir {
         self.root_offset.is_some()
     }
 
+    /// Every slot of the directory with its byte offset, up to and including the first
+    /// one marking the end, e.g. to dump it and restore it with `write_raw_entry`
+    ///
+    /// Slots which don't parse come back as errors and iteration goes on after them.
+    pub fn raw_entries<'a, D: Read + Write + Seek>(&self, fs: &'a mut FileSystem<D>) -> RawDirIter<'a, D> {
+        let ranges = self.slot_ranges(fs);
+        RawDirIter {
+            offset: ranges.first().map_or(0, |r| r.0),
+            ranges: ranges,
+            range: 0,
+            done: false,
+            fs: fs
+        }
+    }
+
+    /// Writes `entry` over the slot at `offset`, as given by `raw_entries`
+    ///
+    /// Fails with InvalidInput if `offset` isn't a slot of this directory. Only the slot
+    /// is written, the FAT and the rest of a long name set it belongs to are left alone.
+    pub fn write_raw_entry<D: Read + Write + Seek>(&self, offset: u64, entry: &DirEntryRaw, fs: &mut FileSystem<D>) -> Result<()> {
+        fs.check_writable()?;
+        let is_slot = self.slot_ranges(fs).iter()
+            .any(|&(start, end)| offset >= start && offset + DIR_ENTRY_LEN <= end && (offset - start) % DIR_ENTRY_LEN == 0);
+        if !is_slot {
+            return Err(Error::new(ErrorKind::InvalidInput, "Offset is not a slot of the directory"))
+        }
+        write_dir_slots(&[(offset, entry.clone())], fs)
+    }
+
+    // Byte ranges holding the slots of the directory, in order
+    fn slot_ranges<D: Read + Write + Seek>(&self, fs: &mut FileSystem<D>) -> Vec<(u64, u64)> {
+        match self.root_offset {
+            Some(start) => vec![(start, fs.root_dir_end_offset().unwrap_or(start))],
+            None => {
+                let bpc = fs.bytes_per_cluster();
+                fs.clusters(self.first_cluster).into_iter()
+                    .map(|c| fs.cluster_offset(c))
+                    .map(|start| (start, start + bpc))
+                    .collect()
+            }
+        }
+    }
+
     pub fn size<D: Read + Write + Seek>(&self, fs: &mut FileSystem<D>) -> u64 {
 
         fs.num_clusters_chain(self.first_cluster) * fs.bytes_per_cluster()
@@ -141,32 +314,55 @@ impl Dir {
     }
      //pub fn find_entry(&self, name: &str, )
     // TODO: open, create_file, create_dir, find_entry
+    /// Entry named `name` in this directory, after `FileSystem::name_translation`
     pub fn find_entry<D: Read + Write + Seek>(&self, name: &str,
+                      expected_dir: Option<bool>,
+                      short_name_gen: Option<&mut ShortNameGen>, fs: &mut FileSystem<D>) -> Result<DirEntry> {
+        let translation = fs.name_translation;
+        self.find_stored_entry(&translation.to_stored(name), expected_dir, short_name_gen, fs)
+    }
+
+    fn find_stored_entry<D: Read + Write + Seek>(&self, name: &str,
                       expected_dir: Option<bool>,
                       mut short_name_gen: Option<&mut ShortNameGen>, fs: &mut FileSystem<D>) -> Result<DirEntry> {
          valid_long_name(name)?;
+         let case_sensitive = fs.case_sensitive;
+         // A name stored exactly as asked for wins over one which only matches once normalized
+         let mut normalized_match = None;
+         let mut found = None;
          for e in self.to_iter(fs) {
-             if e.eq_name(name) {
-                 if expected_dir.is_some() && Some(e.is_dir()) != expected_dir {
-                     let msg = if e.is_dir() { "Is a directory" } else { "Is a file" };
-                     return Err(Error::new(ErrorKind::Other, msg));
-                 }
-                 return Ok(e);
+             if e.eq_name(name, case_sensitive, false) {
+                 found = Some(e);
+                 break;
+             }
+             if normalized_match.is_none() && e.eq_name(name, case_sensitive, true) {
+                 normalized_match = Some(e.clone());
              }
 
              if let Some(ref mut sng) = short_name_gen {
                  sng.add_name(&e.short_name_raw())
              }
          }
-         Err(Error::new(ErrorKind::NotFound, "No such file or directory"))
+         match found.or(normalized_match) {
+             Some(e) => {
+                 if expected_dir.is_some() && Some(e.is_dir()) != expected_dir {
+                     if e.is_dir() {
+                         return Err(Error::new(ErrorKind::IsADirectory, "Is a directory"));
+                     }
+                     return Err(Error::new(ErrorKind::NotADirectory, "Is a file"));
+                 }
+                 Ok(e)
+             },
+             None => Err(Error::new(ErrorKind::NotFound, "No such file or directory"))
+         }
      }
 
     pub fn open_file<D: Read + Write + Seek>(&self, path: &str, fs: &mut FileSystem<D>) -> Result<File> {
         let (name, rest) = split_path(path);
         match rest {
-            Some(_) => {
+            Some(r) => {
                 let e = self.find_entry(name, Some(true), None, fs)?;
-                e.to_dir().open_file(path, fs)
+                e.to_dir().open_file(r, fs)
             },
             None => {
                 let e = self.find_entry(name, Some(false), None, fs)?;
@@ -189,66 +385,132 @@ impl Dir {
     }
 
     pub fn create_file<D: Read + Write + Seek>(&self, path: &str, fs: &mut FileSystem<D>) -> Result<File> {
+        self.create_file_with(path, &CreateOptions::default(), fs)
+    }
+
+    /// Creates the file `path`, or opens it if it already exists
+    ///
+    /// The entry set is written with the short entry last, in one write when it fits in
+    /// a block. The file has no clusters until written to, and those are allocated before
+    /// the entry refers to them. A crash leaves either no entry, perhaps with long entries
+    /// listings skip, or an entry whose size and chain agree.
+    pub fn create_file_with<D: Read + Write + Seek>(&self, path: &str, options: &CreateOptions,
+                                                    fs: &mut FileSystem<D>) -> Result<File> {
         let (name , rest) = split_path(path);
         if let Some(r) = rest {
-            return self.find_entry(name, Some(true), None, fs)?.to_dir().create_file(r, fs);
+            return self.find_entry(name, Some(true), None, fs)?.to_dir().create_file_with(r, options, fs);
         }
 
-        let r = self.check_existence(name, Some(false), fs)?;
+        let translation = fs.name_translation;
+        let name = &*translation.to_stored(name);
+        valid_new_name(name)?;
+        let r = self.check_existence(name, Some(false), options.short_names, fs)?;
         match r {
             DirEntryOrShortName::ShortName(short_name) => {
-                valid_long_name(name)?;
-                self.create_dir_entries(name.trim(), &short_name, None,
-                                              FileAttributes::ARCHIVE, fs).map(|e| e.to_file())
+                let attrs = FileAttributes::ARCHIVE | created_attributes(name, fs);
+                self.create_dir_entries(name, &short_name, None, attrs, fs).map(|e| e.to_file())
             },
             DirEntryOrShortName::DirEntry(e) => Ok(e.to_file())
         }
 
     }
 
+    /// Creates the file `path` holding `size` bytes in the allocated chain at `first_cluster`
+    ///
+    /// The entry is written once, already referring to the chain. Fails with AlreadyExists
+    /// if `path` exists, the chain is left to the caller then.
+    pub fn create_file_on<D: Read + Write + Seek>(&self, path: &str, first_cluster: Cluster, size: u32,
+                                                  attrs: FileAttributes, fs: &mut FileSystem<D>) -> Result<File> {
+        let (name , rest) = split_path(path);
+        if let Some(r) = rest {
+            return self.find_entry(name, Some(true), None, fs)?.to_dir().create_file_on(r, first_cluster, size, attrs, fs);
+        }
+
+        let translation = fs.name_translation;
+        let name = &*translation.to_stored(name);
+        valid_new_name(name)?;
+        match self.check_existence(name, Some(false), ShortNames::default(), fs)? {
+            DirEntryOrShortName::ShortName(short_name) => {
+                let mut short_entry = ShortDirEntry::default();
+                short_entry.set_first_cluster(first_cluster);
+                short_entry.file_size = size;
+                let attrs = attrs | FileAttributes::ARCHIVE | created_attributes(name, fs);
+                self.create_dir_entries(name, &short_name, Some(short_entry), attrs, fs).map(|e| e.to_file())
+            },
+            DirEntryOrShortName::DirEntry(_) => Err(Error::new(ErrorKind::AlreadyExists, "File exists"))
+        }
+    }
+
     pub fn create_dir<D: Read + Write + Seek>(&self, path: &str, fs: &mut FileSystem<D>) -> Result<Dir> {
+        self.create_dir_with(path, &CreateOptions::default(), fs)
+    }
+
+    /// Creates the directory `path`, or opens it if it already exists
+    ///
+    /// Its cluster is zeroed and given the "." and ".." entries while still free, then
+    /// allocated, and only then is the entry set written as by `create_file_with`. A crash
+    /// leaves either no entry, at worst with the cluster lost, or a complete directory.
+    pub fn create_dir_with<D: Read + Write + Seek>(&self, path: &str, options: &CreateOptions,
+                                                   fs: &mut FileSystem<D>) -> Result<Dir> {
         let (name , rest) = split_path(path);
         if let Some(r) = rest {
-            return self.find_entry(name, Some(true), None, fs)?.to_dir().create_dir(r, fs);
+            return self.find_entry(name, Some(true), None, fs)?.to_dir().create_dir_with(r, options, fs);
         }
 
-        let r = self.check_existence(name, Some(true), fs)?;
+        let translation = fs.name_translation;
+        let name = &*translation.to_stored(name);
+        valid_new_name(name)?;
+        let r = self.check_existence(name, Some(true), options.short_names, fs)?;
         match r {
             DirEntryOrShortName::ShortName(short_name) => {
-                valid_long_name(name)?;
+                self.check_entries_writable(fs)?;
                 let mut short_entry = ShortDirEntry::default();
-                let f_cluster = allocate_cluster(fs, None)?;
+                let f_cluster = find_free_cluster(fs)?;
+                self.write_dot_entries(f_cluster, fs)?;
+                claim_cluster(fs, f_cluster, None)?;
                 short_entry.set_first_cluster(f_cluster);
 
-                let mut offset = 0;
-                let mut dot_entry = ShortDirEntry::default();
-                dot_entry.dir_name = ShortNameGen::new(".").generate().unwrap();
-                dot_entry.file_attrs = FileAttributes::DIRECTORY;
-                dot_entry.set_first_cluster(f_cluster);
-                dot_entry.flush(fs.cluster_offset(f_cluster) + offset, fs)?;
-                //TODO Set time
-                offset += DIR_ENTRY_LEN;
-
-                let mut dot_entry = ShortDirEntry::default();
-                dot_entry.dir_name = ShortNameGen::new("..").generate().unwrap();
-                dot_entry.file_attrs = FileAttributes::DIRECTORY;
-                dot_entry.set_first_cluster(self.first_cluster);
-                //TODO Set Time
-                dot_entry.flush(fs.cluster_offset(f_cluster) + offset, fs)?;
-
-
-                self.create_dir_entries(name.trim(), &short_name, Some(short_entry),
-                                        FileAttributes::DIRECTORY, fs).map(|e| e.to_dir())
+                let attrs = FileAttributes::DIRECTORY | created_attributes(name, fs);
+                let res = self.create_dir_entries(name, &short_name, Some(short_entry), attrs, fs);
+                match res {
+                    Ok(e) => Ok(e.to_dir()),
+                    Err(err) => {
+                        // Nothing points at the new cluster yet
+                        deallocate_cluster_chain(fs, f_cluster)?;
+                        Err(err)
+                    }
+                }
             },
             DirEntryOrShortName::DirEntry(e) => Ok(e.to_dir())
         }
     }
 
-    fn check_existence<D: Read + Write + Seek>(&self, name: &str, expected_dir: Option<bool>,
+    // Zeroes `f_cluster` and writes the "." and ".." entries at its start
+    fn write_dot_entries<D: Read + Write + Seek>(&self, f_cluster: Cluster, fs: &mut FileSystem<D>) -> Result<()> {
+        let mut dot_entry = ShortDirEntry::default();
+        dot_entry.dir_name = ShortNameGen::new(".").generate().unwrap();
+        dot_entry.file_attrs = FileAttributes::DIRECTORY;
+        dot_entry.set_first_cluster(f_cluster);
+        //TODO Set time
+
+        let mut dot_dot_entry = ShortDirEntry::default();
+        dot_dot_entry.dir_name = ShortNameGen::new("..").generate().unwrap();
+        dot_dot_entry.file_attrs = FileAttributes::DIRECTORY;
+        dot_dot_entry.set_first_cluster(self.first_cluster);
+        //TODO Set Time
+
+        fs.zero_cluster(f_cluster)?;
+        let offset = fs.cluster_offset(f_cluster);
+        write_dir_slots(&[(offset, DirEntryRaw::Short(dot_entry)),
+                          (offset + DIR_ENTRY_LEN, DirEntryRaw::Short(dot_dot_entry))], fs)
+    }
+
+    fn check_existence<D: Read + Write + Seek>(&self, name: &str, expected_dir: Option<bool>, short_names: ShortNames,
                                                fs: &mut FileSystem<D>) -> Result<DirEntryOrShortName> {
         let mut sng = ShortNameGen::new(name);
+        sng.sequential = short_names == ShortNames::Sequential;
         loop {
-            let e = self.find_entry(name, expected_dir, Some(&mut sng), fs);
+            let e = self.find_stored_entry(name, expected_dir, Some(&mut sng), fs);
             match e {
                 Err(ref e) if e.kind() == ErrorKind::NotFound => {},
                 Err(err) => return Err(err),
@@ -265,11 +527,31 @@ impl Dir {
     fn create_dir_entries<D: Read + Write + Seek>(&self, lname: &str, sname: &[u8; 11],
                                                   short_entry: Option<ShortDirEntry>,
                                                   fattrs: FileAttributes, fs: &mut FileSystem<D>) -> Result<DirEntry> {
+        let exact_case_flags = ShortNameGen::new(lname).exact_case_flags(sname);
+        if exact_case_flags.is_none() && fs.short_names_only {
+            return Err(Error::new(ErrorKind::InvalidInput, "Name has no 8.3 form and long names are turned off"));
+        }
+        self.check_entries_writable(fs)?;
         let mut short_entry = short_entry.unwrap_or(ShortDirEntry::default());
         short_entry.dir_name = sname.clone();
         short_entry.file_attrs = fattrs;
+        short_entry.nt_res &= !ShortDirEntry::CASE_MASK;
         //TODO: Modification/Creation Time
 
+        // Names which fit 8.3 exactly only need the short entry
+        if let Some(case_flags) = exact_case_flags {
+            short_entry.nt_res |= case_flags;
+            let loc = match self.find_free_entries(1, fs)? {
+                Some(c) => c,
+                None => return Err(Error::new(ErrorKind::Other, "No space left in dir/disk"))
+            };
+            let offset = fs.cluster_offset(loc.0) + loc.1;
+            short_entry.flush(offset, fs)?;
+            let mut e = short_entry.to_dir_entry(loc, &self.dir_path);
+            e.set_parent_dir(self.first_cluster);
+            return Ok(e)
+        }
+
         let mut lng = LongNameEntryGenerator::new(lname, short_entry.compute_checksum());
         let num_entries = lng.num_entries() as u64 + 1;
         let free_entries = self.find_free_entries(num_entries, fs)?;
@@ -279,17 +561,44 @@ impl Dir {
         };
 
         let offsets: Vec<(Cluster, u64)> = DirEntryOffsetIter::new(start_loc, fs, num_entries, None).collect();
-        for off in &offsets.as_slice()[..offsets.len() - 1] {
-            let le: LongDirEntry = lng.next().unwrap(); // SAFE
-            let offset = fs.cluster_offset(off.0) + off.1;
-            le.flush(offset, fs)?;
-        }
+        // The short entry goes last, so that the set isn't listed until it is complete
+        let slots: Vec<(u64, DirEntryRaw)> = offsets.iter().map(|&(c, off)| {
+            let entry = match lng.next() {
+                Some(le) => DirEntryRaw::Long(le),
+                None => DirEntryRaw::Short(short_entry)
+            };
+            (fs.cluster_offset(c) + off, entry)
+        }).collect();
+        write_dir_slots(&slots, fs)?;
 
         let start = offsets[0];
         let end = *offsets.last().unwrap();
-        let offset = fs.cluster_offset(end.0) + end.1;
-        short_entry.flush(offset, fs)?;
-        Ok(short_entry.to_dir_entry_lfn(lname.to_string(), (start, end), &self.dir_path))
+        let mut e = short_entry.to_dir_entry_lfn(lname.to_string(), (start, end), &self.dir_path);
+        e.set_parent_dir(self.first_cluster);
+        Ok(e)
+    }
+
+    /// The VOLUME_ID entry of this directory, which only the root should have
+    pub fn volume_id_entry<D: Read + Write + Seek>(&self, fs: &mut FileSystem<D>) -> Option<File> {
+        self.to_iter(fs).find(|e| e.is_vol_id()).map(|e| e.to_file())
+    }
+
+    /// Renames the VOLUME_ID entry to the padded `label`, adding one if there is none
+    pub fn write_volume_id_entry<D: Read + Write + Seek>(&self, label: &[u8; 11], fs: &mut FileSystem<D>) -> Result<()> {
+        if let Some(mut f) = self.volume_id_entry(fs) {
+            f.short_dir_entry.dir_name = *label;
+            let offset = fs.cluster_offset((f.loc.1).0) + (f.loc.1).1;
+            return f.short_dir_entry.flush(offset, fs)
+        }
+        let mut short_entry = ShortDirEntry::default();
+        short_entry.dir_name = *label;
+        short_entry.file_attrs = FileAttributes::VOLUME_ID;
+        let loc = match self.find_free_entries(1, fs)? {
+            Some(c) => c,
+            None => return Err(Error::new(ErrorKind::Other, "No space left in dir/disk"))
+        };
+        let offset = fs.cluster_offset(loc.0) + loc.1;
+        short_entry.flush(offset, fs)
     }
 
     fn is_empty<D: Read + Write + Seek>(&self, fs: &mut FileSystem<D>) -> bool {
@@ -305,38 +614,73 @@ impl Dir {
 
 
     pub fn remove<D: Read + Write + Seek>(&self, path: &str, fs: &mut FileSystem<D>, remove_clusters: bool) -> Result<()> {
+        if self.short_dir_entry.is_none() && Self::is_root_path(path) {
+            return Err(Error::new(ErrorKind::ResourceBusy, "Cannot remove the root directory"));
+        }
         let (name, rest) = split_path(path);
         if let Some(r) = rest {
             return self.find_entry(name, Some(true), None, fs)?.to_dir().remove(r, fs, remove_clusters);
         }
 
         let e = self.find_entry(name, None, None, fs)?;
-        if e.is_dir() && !e.to_dir().is_empty(fs) {
-            return Err(Error::new(ErrorKind::Other, "Directory not empty"));
+        Self::remove_entry(&e, fs, remove_clusters)
+    }
+
+    /// Removes `e` from the directory holding it, found through the entry's location
+    /// rather than its path
+    pub fn remove_entry<D: Read + Write + Seek>(e: &DirEntry, fs: &mut FileSystem<D>, remove_clusters: bool) -> Result<()> {
+        if e.is_root() {
+            return Err(Error::new(ErrorKind::ResourceBusy, "Cannot remove the root directory"));
+        }
+        if e.is_vol_id() {
+            return Err(Error::new(ErrorKind::PermissionDenied, "The volume label can't be removed"));
+        }
+        Self::check_parent_writable(e, fs)?;
+        // Entries are also removed without their clusters when they are moved elsewhere
+        if e.is_dir() && remove_clusters && !e.to_dir().is_empty(fs) {
+            return Err(Error::new(ErrorKind::DirectoryNotEmpty, "Directory not empty"));
         }
 
         if e.first_cluster().cluster_number >= 2 && remove_clusters {
             deallocate_cluster_chain(fs, e.first_cluster())?
         }
 
-        if e.get_dir_range().is_some() {
-            self.remove_dir_entries(e.get_dir_range().unwrap(), fs)?
+        if let Some(rng) = e.get_dir_range() {
+            Self::remove_dir_entries(rng, fs)?
         }
 
         Ok(())
-
     }
 
-    fn remove_dir_entries<D: Read + Write + Seek>(&self, rng: ((Cluster, u64), (Cluster, u64)),
+    // Marks the entries from `rng.0` through `rng.1` free, with one write for each block
+    // they share. The FAT is only consulted when the range crosses into another cluster
+    fn remove_dir_entries<D: Read + Write + Seek>(rng: ((Cluster, u64), (Cluster, u64)),
                                                   fs: &mut FileSystem<D>) -> Result<()> {
-        let offsets: Vec<(Cluster, u64)> = DirEntryOffsetIter::new(rng.0, fs, 15, Some(rng.1)).collect();
-        for off in offsets {
-            let offset = fs.cluster_offset(off.0) + off.1;
-            let mut s_entry = ShortDirEntry::default();
-            s_entry.dir_name[0] = 0xe5;
-            s_entry.flush(offset, fs)?;
+        let (end_cluster, end_offset) = rng.1;
+        let (mut cluster, mut off) = rng.0;
+        let mut offsets = Vec::new();
+        loop {
+            let last = if cluster == end_cluster { end_offset } else { fs.bytes_per_cluster() - DIR_ENTRY_LEN };
+            let base = fs.cluster_offset(cluster);
+            while off <= last {
+                offsets.push(base + off);
+                off += DIR_ENTRY_LEN;
+            }
+            if cluster == end_cluster {
+                break;
+            }
+            if offsets.len() > MAX_ENTRY_SLOTS {
+                return Err(Error::new(ErrorKind::InvalidData, "Directory entry range is longer than any entry"));
+            }
+            cluster = match get_entry(fs, cluster)? {
+                FatEntry::Next(c) => c,
+                _ => return Err(Error::new(ErrorKind::InvalidData, "Directory entry range runs past its cluster chain"))
+            };
+            off = 0;
         }
-        Ok(())
+
+        let slots: Vec<(u64, DirEntryRaw)> = offsets.into_iter().map(|o| (o, DirEntryRaw::Free)).collect();
+        write_dir_slots(&slots, fs)
     }
 
     pub fn get_entry<D: Read + Write + Seek>(&self, path: &str, fs: &mut FileSystem<D>) -> Result<DirEntry> {
@@ -352,7 +696,6 @@ impl Dir {
                 if name.len() == 0 {
                     return Ok(DirEntry::Dir(self.clone()))
                 }
-                valid_long_name(name)?;
                 self.find_entry(name, None, None, fs)
             }
         }
@@ -364,7 +707,46 @@ impl Dir {
         root_dir.get_entry(path, fs)
     }
 
-    //TODO: Check if src_path is an ancestor of dst_path
+    /// Fails with PermissionDenied if the directory has RD_ONLY set and
+    /// `FileSystem::read_only_dirs` is on, checked before entries are added or removed
+    pub fn check_entries_writable<D: Read + Write + Seek>(&self, fs: &FileSystem<D>) -> Result<()> {
+        match self.short_dir_entry {
+            Some(ref se) if fs.read_only_dirs && se.file_attrs.contains(FileAttributes::RD_ONLY) => {
+                Err(Error::new(ErrorKind::PermissionDenied, "Directory is read-only"))
+            },
+            _ => Ok(())
+        }
+    }
+
+    // The directory holding `e` has to allow removing it, looked up only when it matters
+    fn check_parent_writable<D: Read + Write + Seek>(e: &DirEntry, fs: &mut FileSystem<D>) -> Result<()> {
+        if !fs.read_only_dirs {
+            return Ok(())
+        }
+        let path = e.dir_path();
+        match rsplit_path(&path).1 {
+            Some(parent) => Self::get_entry_abs(parent, fs)?.to_dir().check_entries_writable(fs),
+            None => Ok(())
+        }
+    }
+
+    /// Whether `path` names the root directory, taking "." and ".." into account without
+    /// looking anything up, so "/a/.." is the root whether or not "a" exists
+    pub fn is_root_path(path: &str) -> bool {
+        let mut depth = 0;
+        for comp in path.split('/') {
+            match comp {
+                "" | "." => (),
+                ".." => depth = if depth > 0 { depth - 1 } else { 0 },
+                _ => depth += 1
+            }
+        }
+        depth == 0
+    }
+
+    /// Moves `src_entry` to `dst_path`, following POSIX for an existing destination:
+    /// files and empty directories are replaced by an entry of the same type,
+    /// renaming an entry onto itself does nothing
     pub fn rename<D: Read + Write + Seek>(src_entry: &mut DirEntry, dst_path: &str, fs: &mut FileSystem<D>) -> Result<()> {
         /*let (src_file, src_dir_path) = rsplit_path(src_path);
         let src_entry = self.get_entry(src_path, fs)?;
@@ -379,7 +761,16 @@ impl Dir {
             }
         };*/
         println!("Renaming src_entry: {:?} to dest_path: {:?}", src_entry, dst_path);
+        if src_entry.is_root() || Self::is_root_path(dst_path) {
+            return Err(Error::new(ErrorKind::PermissionDenied, "Cannot move or replace the root directory"));
+        }
+        if src_entry.is_vol_id() {
+            return Err(Error::new(ErrorKind::PermissionDenied, "The volume label can't be renamed"));
+        }
         let (dst_name, dst_dir_path) = rsplit_path(dst_path);
+        let translation = fs.name_translation;
+        let dst_name = &*translation.to_stored(dst_name);
+        valid_new_name(dst_name)?;
 
 
         let dst_dir = match dst_dir_path {
@@ -396,68 +787,61 @@ impl Dir {
         };
 
         println!("Dst dir entry: {:?}", dst_dir);
-        let parent_dir_path = src_entry.dir_path();
-        println!("Parent dir path: {:?}", parent_dir_path);
-        let src_dir = match Self::get_parent(parent_dir_path.as_str(), fs) {
-            Ok(Some(d)) => d,
-            _ => return Err(Error::new(ErrorKind::NotFound, "Src directory not found"))
-        };
+        let src_parent = src_entry.parent_dir();
+        Self::check_parent_writable(src_entry, fs)?;
+        dst_dir.check_entries_writable(fs)?;
+        if src_entry.is_dir() && is_ancestor(src_entry.first_cluster(), &dst_dir, fs)? {
+            return Err(Error::new(ErrorKind::InvalidInput, "Cannot move a directory into itself"));
+        }
 
-        println!("src dir entry: {:?}", src_dir);
+        let short_entry = match src_entry.short_dir_entry() {
+            Some(se) => se,
+            None => return Err(Error::new(ErrorKind::PermissionDenied, "Cannot move root dir"))
+        };
         // Ensures src and dst are of the same type
-        let dir_ent_updated  = match dst_dir.check_existence(dst_name, Some(src_entry.is_dir()), fs)? {
+        let (replaced, s_name) = match dst_dir.check_existence(dst_name, Some(src_entry.is_dir()), ShortNames::default(), fs)? {
+            DirEntryOrShortName::DirEntry(ref e) if e.get_dir_range() == src_entry.get_dir_range() => {
+                return Ok(())
+            },
             DirEntryOrShortName::DirEntry(e) => {
-                let s_name = e.short_name_raw();
-                dst_dir.remove(dst_name, fs, true)?;
-                println!("Removing {:?} from dst_dir: {:?}", dst_name, dst_dir);
-                match e {
-                    DirEntry::File(_) | DirEntry::VolID(_) => {
-                        let short_entry = src_entry.short_dir_entry().unwrap();
-                        println!("Source Short Dir Entry: {:?}", short_entry);
-                        //TODO: Modification time
-                        src_dir.remove(src_entry.name().as_str(), fs, false)?;
-                        println!("Removing {:?} from src_dir: {:?}", src_entry.name(), src_dir);
-                        let dirent= dst_dir.create_dir_entries(dst_name, &s_name, Some(short_entry), short_entry.file_attrs, fs)?;
-                        println!("Final Short Dir Entry: {:?}", dirent.short_dir_entry());
-                        dirent
-
-                    },
-                    DirEntry::Dir(_) => {
-                        let short_entry = src_entry.short_dir_entry();
-                        if let Some(se) = short_entry {
-                            src_dir.remove(src_entry.name().as_str(), fs, false)?;
-                            println!("Removing {:?} from src_dir: {:?}", src_entry.name(), src_dir);
-                            let dirent = dst_dir.create_dir_entries(dst_name, &s_name, Some(se), se.file_attrs, fs)?;
-                            println!("Final Short Dir Entry: {:?}", dirent.short_dir_entry());
-                            dirent
-                        }
-                        else {
-                            return Err(Error::new(ErrorKind::PermissionDenied, "Cannot move root dir"));
-                        }
-
-
-                    }
-
+                if e.is_vol_id() {
+                    return Err(Error::new(ErrorKind::PermissionDenied, "The volume label can't be replaced"));
+                }
+                if e.is_dir() && !e.to_dir().is_empty(fs) {
+                    return Err(Error::new(ErrorKind::DirectoryNotEmpty, "Directory not empty"));
                 }
+                let s_name = e.short_name_raw();
+                (Some(e), s_name)
+            },
+            DirEntryOrShortName::ShortName(s) => (None, s)
+        };
 
+        // All the entry writes are undone if one of them fails, rather than leaving the
+        // source removed without a destination
+        let dir_ent_updated = fs.with_entry_transaction(|fs| {
+            if let Some(ref e) = replaced {
+                Self::remove_entry(e, fs, false)?;
+            }
+            Self::remove_entry(src_entry, fs, false)?;
+            //TODO: Modification time
+            let dirent = dst_dir.create_dir_entries(dst_name, &s_name, Some(short_entry), short_entry.file_attrs, fs)?;
 
-            },
-            DirEntryOrShortName::ShortName(s) => {
-                //println!("Creating a new Entry");
-                let short_entry = src_entry.short_dir_entry();
-                if let Some(se) = short_entry {
-                    valid_long_name(dst_name)?;
-                    src_dir.remove(src_entry.name().as_str(), fs, false)?;
-                    println!("Removing {:?} from src_dir: {:?}", src_entry.name(), src_dir);
-                    let dirent = dst_dir.create_dir_entries(dst_name, &s, Some(se), se.file_attrs, fs)?;
-                    println!("Final Short Dir Entry: {:?}", dirent.short_dir_entry());
-                    dirent
-                }
-                else {
-                    return Err(Error::new(ErrorKind::PermissionDenied, "Cannot move root dir"));
+            // A directory moved to another parent has to point its ".." entry there
+            if dirent.is_dir() && src_parent.cluster_number != dst_dir.first_cluster.cluster_number {
+                dirent.to_dir().set_parent(&dst_dir, fs)?;
+            }
+            Ok(dirent)
+        })?;
+
+        // The replaced entry's clusters are only freed once nothing refers to them. The
+        // rename is done by now, at worst they are lost until the volume is checked
+        if let Some(e) = replaced {
+            if e.first_cluster().cluster_number >= 2 {
+                if let Err(err) = deallocate_cluster_chain(fs, e.first_cluster()) {
+                    warn!("Failed to free the clusters of the replaced {:?}: {}", dst_name, err);
                 }
             }
-        };
+        }
 
         *src_entry = dir_ent_updated;
         //src_entry.set_fname(dst_name, &short_name);
@@ -490,6 +874,116 @@ impl Dir {
 
     }
 
+    fn set_parent<D: Read + Write + Seek>(&self, parent: &Dir, fs: &mut FileSystem<D>) -> Result<()> {
+        let offset = fs.cluster_offset(self.first_cluster) + DIR_ENTRY_LEN;
+        match get_dir_entry_raw(fs, offset)? {
+            DirEntryRaw::Short(mut dot_dot) => {
+                // ".." of a directory in the root holds cluster 0
+                let cluster = if parent.is_root() || parent.dir_path == "/" { Cluster::new(0) } else { parent.first_cluster };
+                dot_dot.set_first_cluster(cluster);
+                dot_dot.flush(offset, fs)
+            },
+            _ => Err(Error::new(ErrorKind::InvalidData, "Missing .. entry"))
+        }
+    }
+
+    /// Deleted entries still present in this directory, in directory order
+    ///
+    /// Only entries deleted by other implementations can be found, removing an entry here
+    /// wipes it completely. Volume labels are skipped.
+    pub fn deleted_entries<D: Read + Write + Seek>(&self, fs: &mut FileSystem<D>) -> Result<Vec<DeletedEntry>> {
+        let mut deleted = Vec::new();
+        let mut lfn_entries: Vec<LongDirEntry> = Vec::new();
+        let mut cluster = self.first_cluster;
+        let mut offset = self.root_offset.unwrap_or(0);
+        loop {
+            if self.is_root() {
+                if offset >= fs.root_dir_end_offset().unwrap() {
+                    break;
+                }
+            } else if offset >= fs.bytes_per_cluster() {
+                match get_entry(fs, cluster)? {
+                    FatEntry::Next(c) => {
+                        cluster = c;
+                        offset = 0;
+                    },
+                    _ => break
+                }
+            }
+
+            let (dir_0, mut cursor, blk_offset) = read_dir_slot(fs, fs.cluster_offset(cluster) + offset)?;
+            offset += DIR_ENTRY_LEN;
+            match dir_0 {
+                0x00 => break,
+                0xe5 => match parse_dir_entry(&mut cursor, blk_offset) {
+                    Ok(DirEntryRaw::Long(l)) => {
+                        if lfn_entries.last().map_or(false, |prev| prev.chksum != l.chksum) {
+                            lfn_entries.clear();
+                        }
+                        lfn_entries.push(l);
+                    },
+                    Ok(DirEntryRaw::Short(s)) => {
+                        if !s.is_vol_id() {
+                            deleted.push(DeletedEntry::recover(s, &lfn_entries, &self.dir_path));
+                        }
+                        lfn_entries.clear();
+                    },
+                    _ => lfn_entries.clear()
+                },
+                _ => lfn_entries.clear()
+            }
+        }
+        Ok(deleted)
+    }
+
+    /// Restores a deleted entry of this directory as `new_name`
+    ///
+    /// Deletion frees the cluster chain, so it can only be rebuilt when the data was stored
+    /// in consecutive clusters that are all still free. Directories only get their first
+    /// cluster back. Whether the data is intact cannot be known, with the "secure" feature
+    /// clusters freed here are zeroed.
+    pub fn undelete<D: Read + Write + Seek>(&self, entry: &DeletedEntry, new_name: &str, fs: &mut FileSystem<D>) -> Result<DirEntry> {
+        let first = entry.first_cluster().cluster_number;
+        let num_clusters = entry.num_clusters(fs);
+        if num_clusters > 0 {
+            if first < 2 || first + num_clusters - 1 > fs.max_cluster_number().cluster_number {
+                return Err(Error::new(ErrorKind::InvalidData, "Deleted entry points outside the data region"))
+            }
+            for c in first..first + num_clusters {
+                if get_entry(fs, Cluster::new(c))? != FatEntry::Unused {
+                    return Err(Error::new(ErrorKind::Other, "Clusters of the deleted entry are in use"))
+                }
+            }
+        }
+
+        let translation = fs.name_translation;
+        let name = &*translation.to_stored(new_name);
+        valid_new_name(name)?;
+        let short_name = match self.check_existence(name, None, ShortNames::default(), fs)? {
+            DirEntryOrShortName::ShortName(s) => s,
+            DirEntryOrShortName::DirEntry(_) => return Err(Error::new(ErrorKind::AlreadyExists, "Entry already exists"))
+        };
+
+        for c in first..first + num_clusters {
+            let next = if c + 1 < first + num_clusters { FatEntry::Next(Cluster::new(c + 1)) } else { FatEntry::EndOfChain };
+            set_entry(fs, Cluster::new(c), next)?;
+        }
+        fs.fs_info.borrow_mut().delta_free_count(-(num_clusters as i32));
+
+        let attrs = entry.short_entry.file_attrs;
+        match self.create_dir_entries(name, &short_name, Some(entry.short_entry), attrs, fs) {
+            Ok(e) => Ok(e),
+            Err(err) => {
+                // Give the clusters back without touching the data
+                for c in first..first + num_clusters {
+                    set_entry(fs, Cluster::new(c), FatEntry::Unused)?;
+                }
+                fs.fs_info.borrow_mut().delta_free_count(num_clusters as i32);
+                Err(err)
+            }
+        }
+    }
+
     pub fn get_parent<D: Read + Write + Seek>(abs_path: &str, fs: &mut FileSystem<D>) -> Result<Option<Dir>> {
         let root_dir = fs.root_dir();
         let (_, parent_path) = rsplit_path(abs_path);
@@ -503,6 +997,78 @@ impl Dir {
     }
 }
 
+/// Remnant of a deleted entry, as returned by `Dir::deleted_entries`
+#[derive(Debug, Clone)]
+pub struct DeletedEntry {
+    name: String,
+    short_entry: ShortDirEntry,
+    dir_path: String
+}
+
+impl DeletedEntry {
+    /// Rebuilds the name from the LFN entries preceding `short_entry` on disk
+    /// The first byte of the short name is lost on deletion, so it is taken from
+    /// the LFN checksum if there is one and replaced by '_' otherwise.
+    fn recover(mut short_entry: ShortDirEntry, lfn_entries: &[LongDirEntry], dir_path: &str) -> DeletedEntry {
+        let first_byte = lfn_entries.first().and_then(|l| {
+            (1..=0xffu8).filter(|b| *b != 0xe5).find(|b| {
+                short_entry.dir_name[0] = *b;
+                short_entry.compute_checksum() == l.chksum
+            })
+        });
+        let name = match first_byte {
+            Some(b) if b > ShortDirEntry::PADDING || b == ESCAPED_E5 => {
+                short_entry.dir_name[0] = b;
+                // The entry closest to the short entry holds the start of the name
+                let mut name = vec![0; lfn_entries.len() * LFN_PART_LEN];
+                for (i, l) in lfn_entries.iter().rev().enumerate() {
+                    l.copy_name_to_slice(&mut name[i * LFN_PART_LEN..(i + 1) * LFN_PART_LEN]);
+                }
+                let mut s = String::from_utf16_lossy(&name);
+                let len = s.find('\u{0}').unwrap_or(s.len());
+                s.truncate(len);
+                s
+            },
+            _ => {
+                short_entry.dir_name[0] = '_' as u8;
+                short_entry.display_name()
+            }
+        };
+        DeletedEntry { name, short_entry, dir_path: dir_path.to_string() }
+    }
+
+    /// Best-effort name, from the long name when it survived
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    pub fn size(&self) -> u64 {
+        self.short_entry.file_size as u64
+    }
+
+    pub fn first_cluster(&self) -> Cluster {
+        Cluster::new((self.short_entry.fst_clus_lo as u64) | ((self.short_entry.fst_clst_hi as u64) << 16))
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.short_entry.is_dir()
+    }
+
+    /// Path the entry had before it was deleted
+    pub fn path(&self) -> String {
+        format!("{}{}", self.dir_path, self.name)
+    }
+
+    // Clusters the data occupied, assuming it was stored contiguously
+    fn num_clusters<D: Read + Write + Seek>(&self, fs: &FileSystem<D>) -> u64 {
+        if self.is_dir() {
+            1
+        } else {
+            (self.size() + fs.bytes_per_cluster() - 1) / fs.bytes_per_cluster()
+        }
+    }
+}
+
 struct DirEntryOffsetIter<'a, D: Read + Write + Seek> {
     current_offset: (Cluster, u64),
     end_offset: Option<(Cluster, u64)>,
@@ -536,7 +1102,11 @@ impl<'a, D: Read + Write + Seek> Iterator for DirEntryOffsetIter<'a, D> {
         let r = self.current_offset;
         let mut new_offset = r.1 + DIR_ENTRY_LEN;
         let mut new_cluster = r.0;
-        if new_offset >= self.fs.bytes_per_cluster() {
+        if let Some(off) = self.end_offset {
+            self.fin = off == self.current_offset;
+        }
+        // Offsets in the FAT12 and FAT16 root directory are from the start of the volume
+        if new_offset >= self.fs.bytes_per_cluster() && r.0.cluster_number >= RESERVED_CLUSTERS {
             new_offset = new_offset % self.fs.bytes_per_cluster();
             match get_entry(self.fs, new_cluster) {
                 Ok(FatEntry::Next(c)) => {
@@ -544,13 +1114,11 @@ impl<'a, D: Read + Write + Seek> Iterator for DirEntryOffsetIter<'a, D> {
 
                 },
                 _ => {
-                    return None;
+                    // `r` is the last slot of the chain
+                    self.fin = true;
                 }
             }
         }
-        if let Some(off) = self.end_offset {
-            self.fin = off == self.current_offset;
-        }
 
         self.current_offset = (new_cluster, new_offset);
         self.idx += 1;
@@ -572,6 +1140,31 @@ impl File {
         self.short_dir_entry.file_size = sz;
     }
 
+    pub fn is_dirty(&self) -> bool {
+        self.size_dirty || self.times_dirty
+    }
+
+    // The contents changed, ARCHIVE is set with the times so backup software picks the
+    // file up again
+    fn mark_modified(&mut self) {
+        self.times_dirty = true;
+        self.short_dir_entry.file_attrs.insert(FileAttributes::ARCHIVE);
+    }
+
+    /// Writes the short entry back if its size or times changed
+    /// Called on fsync and close, extending writes only mark the entry dirty
+    pub fn flush<D: Read + Write + Seek>(&mut self, fs: &mut FileSystem<D>) -> Result<()> {
+        if !self.is_dirty() {
+            return Ok(())
+        }
+        //TODO: Modification time
+        let short_entry_offset = fs.cluster_offset((self.loc.1).0) + (self.loc.1).1;
+        self.short_dir_entry.flush(short_entry_offset, fs)?;
+        self.size_dirty = false;
+        self.times_dirty = false;
+        Ok(())
+    }
+
     pub fn read<D: Read + Write + Seek>(&self, buf: &mut [u8], fs: &mut FileSystem<D>, offset: u64) -> Result<usize> {
         if offset >= self.size() {
             return Ok(0)
@@ -592,8 +1185,8 @@ impl File {
 
         loop {
             if cluster_offset >= fs.bytes_per_cluster() {
-                match get_entry(fs, current_cluster).ok() {
-                    Some(FatEntry::Next(c)) => {
+                match get_entry(fs, current_cluster)? {
+                    FatEntry::Next(c) => {
                         current_cluster = c;
                         cluster_offset = cluster_offset % fs.bytes_per_cluster();
                     },
@@ -602,12 +1195,13 @@ impl File {
                     }
                 }
             }
-            let end_len = min(min((fs.bytes_per_cluster() - cluster_offset) as usize, buf.len() - read), read_size - read);
             let offset = fs.cluster_offset(current_cluster) + cluster_offset;
+            let run_len = contiguous_run(fs, &mut current_cluster, cluster_offset, (read_size - read) as u64);
+            let end_len = min(run_len as usize, read_size - read);
             let r = fs.read_at(offset, &mut buf[start..start + end_len])?;
             read += r;
             start += r;
-            cluster_offset += r as u64;
+            cluster_offset = fs.bytes_per_cluster() - (run_len - r as u64);
             if read == read_size {
                 break;
             }
@@ -618,7 +1212,9 @@ impl File {
     }
 
     pub fn write<D: Read + Write + Seek>(&mut self, buf: &[u8], fs: &mut FileSystem<D>, offset: u64) -> Result<usize> {
+        self.check_not_vol_id()?;
         self.ensure_len(offset, buf.len() as u64, fs)?;
+        self.mark_modified();
 
         //FIXME
         let start_cluster_number = offset / fs.bytes_per_cluster();
@@ -646,15 +1242,16 @@ impl File {
                 }
             }
 
-            let end_len = min((fs.bytes_per_cluster() - cluster_offset) as usize, buf.len() - written);
+            let offset = fs.cluster_offset(current_cluster) + cluster_offset;
+            let run_len = contiguous_run(fs, &mut current_cluster, cluster_offset, (buf.len() - written) as u64);
+            let end_len = min(run_len as usize, buf.len() - written);
             //println!("Cluster = {:?}, Cluster Offset = {:?}, Cluster Size = {:?}, start = {:?}, end = {:?}",
             //         current_cluster, cluster_offset, fs.bytes_per_cluster(), start, start + end_len);
-            let offset = fs.cluster_offset(current_cluster) + cluster_offset;
             let w = fs.write_to(offset, &buf[start..start + end_len])?;
 
             written += w;
             start += w;
-            cluster_offset += w as u64;
+            cluster_offset = fs.bytes_per_cluster() - (run_len - w as u64);
             if written == buf.len() {
                 break;
             }
@@ -664,19 +1261,58 @@ impl File {
 
     }
 
+    /// Reads into each buffer in turn, stopping early at the end of the file
+    pub fn read_vectored_at<D: Read + Write + Seek>(&self, bufs: &mut [IoSliceMut], fs: &mut FileSystem<D>, offset: u64) -> Result<usize> {
+        let mut read = 0;
+        for buf in bufs.iter_mut() {
+            let r = self.read(buf, fs, offset + read as u64)?;
+            read += r;
+            if r < buf.len() {
+                break;
+            }
+        }
+        Ok(read)
+    }
+
+    /// Writes the buffers back to back, growing the file once for the whole write
+    pub fn write_vectored_at<D: Read + Write + Seek>(&mut self, bufs: &[IoSlice], fs: &mut FileSystem<D>, offset: u64) -> Result<usize> {
+        let len = bufs.iter().map(|b| b.len() as u64).sum();
+        self.ensure_len(offset, len, fs)?;
+
+        let mut written = 0;
+        for buf in bufs.iter() {
+            if buf.is_empty() {
+                continue;
+            }
+            let w = self.write(buf, fs, offset + written as u64)?;
+            written += w;
+            if w < buf.len() {
+                break;
+            }
+        }
+        Ok(written)
+    }
+
     fn ensure_len<D: Read + Write + Seek>(&mut self, offset: u64, len: u64, fs: &mut FileSystem<D>) -> Result<()> {
         if offset + len <= self.size() {
             return Ok(())
         }
 
-        if self.size() == 0 {
-            self.first_cluster = allocate_cluster(fs, None)?;
+        if self.first_cluster.cluster_number < 2 {
+            self.first_cluster = allocate_data_cluster(fs, None)?;
+            // The entry is written with the size once the data is in place, until then
+            // a crash only leaks the new cluster
             self.short_dir_entry.set_first_cluster(self.first_cluster);
         }
 
         //Compute space available in last cluster
         let cluster_offset = self.size() % fs.bytes_per_cluster();
-        let bytes_remaining_cluster = fs.bytes_per_cluster() - cluster_offset;
+        // A non-empty file ending on a cluster boundary has no room left in its last cluster
+        let bytes_remaining_cluster = if cluster_offset == 0 && self.size() > 0 {
+            0
+        } else {
+            fs.bytes_per_cluster() - cluster_offset
+        };
 
         //Compute bytes to be allocated
         let extra_bytes = min((offset + len) - self.size(), MAX_FILE_SIZE - self.size());
@@ -692,22 +1328,30 @@ impl File {
             let mut current_cluster = last_cluster;
             for _i in 0..clusters_req {
                 //println!("[info] Allocating Cluster for length req");
-                current_cluster = allocate_cluster(fs, Some(current_cluster))?;
+                current_cluster = allocate_data_cluster(fs, Some(current_cluster))?;
             }
         }
 
-        //TODO: Optimize
+        // Data clusters are not zeroed when allocated, so the gap between the old end
+        // and the start of the write is cleared here. The write covers the rest
         if offset > self.size() {
-            let cluster_start = self.size() / fs.bytes_per_cluster();
-            let s_cluster = fs.get_cluster_relative(self.first_cluster, cluster_start as usize).unwrap();
-            let start_offset = fs.cluster_offset(s_cluster) + self.size() % fs.bytes_per_cluster();
-            let b_remaining = fs.bytes_per_cluster() - (self.size() % fs.bytes_per_cluster());
-            let offset_start = offset / fs.bytes_per_cluster();
-            let offset_cluster = fs.get_cluster_relative(self.first_cluster, offset_start as usize).unwrap();
-            if s_cluster != offset_cluster {
-                self.zero_range(fs, start_offset, start_offset + b_remaining - 1)?;
-            } else {
-                self.zero_range(fs, start_offset, start_offset + offset - self.size() - 1)?;
+            let bpc = fs.bytes_per_cluster();
+            let end = min(offset, MAX_FILE_SIZE);
+            let mut pos = self.size();
+            let mut cluster = fs.get_cluster_relative(self.first_cluster, (pos / bpc) as usize);
+            while pos < end {
+                let c = match cluster {
+                    Some(c) => c,
+                    None => return Err(Error::new(ErrorKind::InvalidData, "Cluster chain shorter than the file"))
+                };
+                let zero_len = min(bpc - pos % bpc, end - pos);
+                let disk_offset = fs.cluster_offset(c) + pos % bpc;
+                fs.zero_range(disk_offset, zero_len)?;
+                pos += zero_len;
+                cluster = match get_entry(fs, c)? {
+                    FatEntry::Next(n) => Some(n),
+                    _ => None
+                };
             }
         }
 
@@ -715,46 +1359,78 @@ impl File {
         let new_size = self.size() + extra_bytes;
         // TODO: Add mod time and other stuff
         self.set_size(new_size as u32);
-        let short_entry_offset = fs.cluster_offset((self.loc.1).0) + (self.loc.1).1;
-        self.short_dir_entry.flush(short_entry_offset, fs)?;
+        self.size_dirty = true;
 
         Ok(())
 
     }
 
-    // Range start and range end are absolute disk offsets
-    // Range start and range end must be in the same cluster
-    fn zero_range<D: Read + Write + Seek>(&self, fs: &mut FileSystem<D>, range_start: u64, range_end: u64) -> Result<()> {
-        if range_end < range_start {
-            return Ok(())
+    // The volume label entry has no contents to change
+    fn check_not_vol_id(&self) -> Result<()> {
+        if self.short_dir_entry.is_vol_id() {
+            return Err(Error::new(ErrorKind::PermissionDenied, "The volume label can't be written"));
         }
-
-        //println!("Zeroing Range: {} - {}", range_start, range_end);
-        let zeroes = vec![0; (range_end - range_start + 1) as usize];
-        fs.write_to(range_start, zeroes.as_slice())?;
         Ok(())
     }
 
+    /// Shrinks or grows the file to `len` bytes, the grown part reads back as zeroes
+    pub fn set_len<D: Read + Write + Seek>(&mut self, fs: &mut FileSystem<D>, len: u64) -> Result<()> {
+        self.check_not_vol_id()?;
+        if len > MAX_FILE_SIZE {
+            return Err(Error::new(ErrorKind::InvalidInput, "File size too large"));
+        }
 
+        if len < self.size() {
+            self.truncate(fs, len)
+        } else if len > self.size() {
+            self.ensure_len(len, 0, fs)?;
+            self.mark_modified();
+            self.flush(fs)
+        } else {
+            Ok(())
+        }
+    }
 
     pub fn truncate<D: Read + Write + Seek>(&mut self, fs: &mut FileSystem<D>, new_size: u64) -> Result<()> {
+        self.check_not_vol_id()?;
         if new_size >= self.size() {
             return Ok(())
         }
 
-        let new_last_cluster = new_size / fs.bytes_per_cluster();
-        match fs.get_cluster_relative(self.first_cluster, (new_last_cluster + 1) as usize) {
-            Some(c) => {
-                deallocate_cluster_chain(fs, c)?;
-            },
-            None => { }
-        }
-
+        self.free_clusters_past(fs, new_size)?;
         self.set_size(new_size as u32);
-        let short_entry_offset = fs.cluster_offset((self.loc.1).0) + (self.loc.1).1;
-        self.short_dir_entry.flush(short_entry_offset, fs)?;
-        Ok(())
+        self.size_dirty = true;
+        self.mark_modified();
+        self.flush(fs)
+
+    }
+
+    /// Frees the clusters of the chain past those holding the size of the file
+    /// A repair for chains left longer than the file, the size is kept
+    pub fn free_excess_clusters<D: Read + Write + Seek>(&mut self, fs: &mut FileSystem<D>) -> Result<()> {
+        let size = self.size();
+        self.free_clusters_past(fs, size)?;
+        self.size_dirty = true;
+        self.flush(fs)
+    }
 
+    fn free_clusters_past<D: Read + Write + Seek>(&mut self, fs: &mut FileSystem<D>, len: u64) -> Result<()> {
+        // Zero length files own no clusters
+        if len == 0 {
+            if self.first_cluster.cluster_number >= 2 {
+                deallocate_cluster_chain(fs, self.first_cluster)?;
+            }
+            self.first_cluster = Cluster::new(0);
+            self.short_dir_entry.set_first_cluster(self.first_cluster);
+        } else {
+            let clusters_kept = (len + fs.bytes_per_cluster() - 1) / fs.bytes_per_cluster();
+            if let Some(last) = fs.get_cluster_relative(self.first_cluster, (clusters_kept - 1) as usize) {
+                if let Some(tail) = split_chain(fs, last)? {
+                    deallocate_cluster_chain(fs, tail)?;
+                }
+            }
+        }
+        Ok(())
     }
 }
 
@@ -846,47 +1522,14 @@ impl LongDirEntry {
         lentry.first_clus_low = 0;
         lentry
     }
-
-    fn flush<D: Read + Write + Seek>(&self, offset: u64, fs: &mut FileSystem<D>) -> Result<()> {
-        //fs.seek_to(offset)?;
-
-        let blk_offset = fs.get_block_offset(offset);
-
-        let block_buf = get_block_buffer(fs.get_raw_offset(offset), DIR_ENTRY_LEN);
-        let mut cursor = Cursor::new(block_buf);
-
-        fs.seek_to_block(offset)?;
-        fs.disk.borrow_mut().read(cursor.get_mut())?;
-        cursor.seek(SeekFrom::Start(blk_offset))?;
-
-        cursor.write_u8(self.ord)?;
-        //fs.disk.borrow_mut().write_u16_into::<LittleEndian>(&self.name1)?;
-        for b in &self.name1 {
-            cursor.write_u16::<LittleEndian>(*b)?;
-        }
-
-        cursor.write_u8(self.file_attrs.bits)?;
-        cursor.write_u8(self.dirent_type)?;
-        cursor.write_u8(self.chksum)?;
-        //fs.disk.borrow_mut().write_u16_into::<LittleEndian>(&self.name2)?;
-        for b in &self.name2 {
-            cursor.write_u16::<LittleEndian>(*b)?;
-        }
-        cursor.write_u16::<LittleEndian>(self.first_clus_low)?;
-        //fs.disk.borrow_mut().write_u16_into::<LittleEndian>(&self.name3)?;
-        for b in &self.name3 {
-            cursor.write_u16::<LittleEndian>(*b)?;
-        }
-
-        fs.seek_to_block(offset)?;
-        fs.disk.borrow_mut().write(cursor.get_ref())?;
-        fs.disk.borrow_mut().flush()?;
-        Ok(())
-    }
 }
 
 impl ShortDirEntry {
     const PADDING: u8 = ' ' as u8;
+    /// NT reserved flags marking the base name and extension as lowercase
+    const LOWERCASE_BASE: u8 = 0x08;
+    const LOWERCASE_EXT: u8 = 0x10;
+    const CASE_MASK: u8 = Self::LOWERCASE_BASE | Self::LOWERCASE_EXT;
 
     pub fn is_dir(&self) -> bool {
         self.file_attrs.contains(FileAttributes::DIRECTORY) &&
@@ -922,17 +1565,38 @@ impl ShortDirEntry {
             sname_len
         };
 
-        if name[0] == 0x05 {
+        if name[0] == ESCAPED_E5 {
             name[0] = 0xe5;
         }
         let iter = name[..tot_len].iter().cloned().map(|c| char_decode(c));
         String::from_iter(iter)
     }
 
+    /// Short name with the NT lowercase flags applied, used when no LFN is present
+    fn display_name(&self) -> String {
+        let name = self.name_to_string();
+        let (base, ext) = match name.rfind('.') {
+            Some(idx) if name != "." && name != ".." => name.split_at(idx),
+            _ => (name.as_str(), "")
+        };
+        let mut s = String::with_capacity(name.len());
+        if self.nt_res & Self::LOWERCASE_BASE > 0 {
+            s.push_str(&base.to_ascii_lowercase());
+        } else {
+            s.push_str(base);
+        }
+        if self.nt_res & Self::LOWERCASE_EXT > 0 {
+            s.push_str(&ext.to_ascii_lowercase());
+        } else {
+            s.push_str(ext);
+        }
+        s
+    }
+
     pub fn to_dir_entry(&self, loc: (Cluster, u64), dir_path: &String) -> DirEntry {
         if self.is_file() || self.is_vol_id() {
             let mut file = File::default();
-            let f_name = self.name_to_string();
+            let f_name = self.display_name();
             let mut f_path = dir_path.clone();
 
             f_path.push_str(&f_name.clone());
@@ -953,7 +1617,7 @@ impl ShortDirEntry {
             let mut dir = Dir::default();
             let cluster = Cluster::new((self.fst_clus_lo as u64) | ((self.fst_clst_hi as u64) << 16));
             dir.first_cluster = cluster;
-            let dir_name = self.name_to_string();
+            let dir_name = self.display_name();
             let mut d_path = dir_path.clone();
 
             d_path.push_str(&dir_name.clone());
@@ -1014,6 +1678,7 @@ impl ShortDirEntry {
     }
 
     pub fn flush<D: Read + Write + Seek>(&self, offset: u64, fs: &mut FileSystem<D>) -> Result<()> {
+        fs.check_writable()?;
         //fs.seek_to(offset)?;
         //let fat_offset = get_fat_offset(fs.bpb.fat_type, Cluster::new(cluster), fs.fat_start_sector(), fs.bytes_per_sec());
         let blk_offset = fs.get_block_offset(offset);
@@ -1021,8 +1686,8 @@ impl ShortDirEntry {
         let block_buf = get_block_buffer(fs.get_raw_offset(offset), DIR_ENTRY_LEN);
         let mut cursor = Cursor::new(block_buf);
 
-        fs.seek_to_block(offset)?;
-        fs.disk.borrow_mut().read(cursor.get_mut())?;
+        fs.read_block(offset, cursor.get_mut())?;
+        fs.journal_slot(offset, &cursor.get_ref()[blk_offset as usize..(blk_offset + DIR_ENTRY_LEN) as usize]);
 
         cursor.seek(SeekFrom::Start(blk_offset))?;
         cursor.write(&self.dir_name)?;
@@ -1038,12 +1703,20 @@ impl ShortDirEntry {
         cursor.write_u16::<LittleEndian>(self.fst_clus_lo)?;
         cursor.write_u32::<LittleEndian>(self.file_size)?;
 
-        fs.seek_to_block(offset)?;
-        fs.disk.borrow_mut().write(cursor.get_ref())?;
+        fs.write_metadata_block(offset, cursor.get_ref())?;
         fs.disk.borrow_mut().flush()?;
         Ok(())
     }
 
+    pub fn first_cluster(&self) -> Cluster {
+        Cluster::new((self.fst_clus_lo as u64) | ((self.fst_clst_hi as u64) << 16))
+    }
+
+    /// DIR_Name as stored, base and extension padded with spaces
+    pub fn name_raw(&self) -> [u8; 11] {
+        self.dir_name
+    }
+
     pub fn set_first_cluster(&mut self, cluster: Cluster) {
         self.fst_clus_lo = (cluster.cluster_number & 0x0000ffff) as u16;
         self.fst_clst_hi = ((cluster.cluster_number & 0xffff0000) >> 16) as u16;
@@ -1054,6 +1727,9 @@ impl ShortDirEntry {
 fn char_decode(c: u8) -> char {
     if c <= 0x7f {
         c as char
+    } else if c == 0xe5 {
+        // Needs to round trip through the 0x05 escape of the first byte
+        SIGMA
     } else {
         '\u{FFFD}'
     }
@@ -1091,32 +1767,121 @@ impl DirEntryRaw {
         }
     }
 
+    /// Parses a slot as stored on disk, fails with InvalidInput if it is shorter than 32 bytes
+    pub fn from_bytes(slot: &[u8]) -> Result<DirEntryRaw> {
+        if slot.len() < DIR_ENTRY_LEN as usize {
+            return Err(Error::new(ErrorKind::InvalidInput, "Directory slots are 32 bytes"))
+        }
+        parse_dir_slot(slot)
+    }
+
+    /// The slot as stored on disk, free ones are zeroed apart from their first byte
+    pub fn to_bytes(&self) -> [u8; DIR_ENTRY_LEN as usize] {
+        let mut slot = [0u8; DIR_ENTRY_LEN as usize];
+        match self {
+            &DirEntryRaw::Short(ref s) => {
+                slot[..11].copy_from_slice(&s.dir_name);
+                slot[11] = s.file_attrs.bits;
+                slot[12] = s.nt_res;
+                slot[13] = s.crt_time_tenth;
+                LittleEndian::write_u16(&mut slot[14..], s.crt_time);
+                LittleEndian::write_u16(&mut slot[16..], s.crt_date);
+                LittleEndian::write_u16(&mut slot[18..], s.lst_acc_date);
+                LittleEndian::write_u16(&mut slot[20..], s.fst_clst_hi);
+                LittleEndian::write_u16(&mut slot[22..], s.wrt_time);
+                LittleEndian::write_u16(&mut slot[24..], s.wrt_date);
+                LittleEndian::write_u16(&mut slot[26..], s.fst_clus_lo);
+                LittleEndian::write_u32(&mut slot[28..], s.file_size);
+            },
+            &DirEntryRaw::Long(ref l) => {
+                slot[0] = l.ord;
+                LittleEndian::write_u16_into(&l.name1, &mut slot[1..11]);
+                slot[11] = l.file_attrs.bits;
+                slot[12] = l.dirent_type;
+                slot[13] = l.chksum;
+                LittleEndian::write_u16_into(&l.name2, &mut slot[14..26]);
+                LittleEndian::write_u16(&mut slot[26..], l.first_clus_low);
+                LittleEndian::write_u16_into(&l.name3, &mut slot[28..32]);
+            },
+            &DirEntryRaw::Free => slot[0] = 0xe5,
+            &DirEntryRaw::FreeRest => {}
+        }
+        slot
+    }
+
 }
 
 pub struct DirIter<'a, D: Read + Write + Seek> {
     current_cluster: Cluster,
+    /// First cluster of the directory, the parent of every entry returned
+    dir_cluster: Cluster,
     dir_path: String,
     offset: u64,
     /// True for the root directories of FAT12 and FAT16
     is_root: bool,
     fs: &'a mut FileSystem<D>,
+    /// Slots read ahead from `buf_start`, see `raw_entry`
+    buf: Vec<u8>,
+    buf_start: u64,
+    /// Entries with any of these attributes are left out, see `skip_attributes`
+    skip: FileAttributes
 }
 
 impl<'a, D: Read + Write + Seek> Iterator for DirIter<'a, D> {
     type Item = DirEntry;
     fn next(&mut self) -> Option<Self::Item> {
-        match self.get_dir_entry() {
-            Ok((offset, cluster, ret)) => {
-                self.offset = offset;
-                self.current_cluster = cluster;
-                ret
-            },
-            Err(_) => None
+        loop {
+            match self.get_dir_entry() {
+                Ok((offset, cluster, ret)) => {
+                    self.offset = offset;
+                    self.current_cluster = cluster;
+                    match ret {
+                        Some(ref e) if e.attributes().intersects(self.skip) => continue,
+                        Some(mut e) => {
+                            e.set_parent_dir(self.dir_cluster);
+                            return Some(e)
+                        },
+                        None => return None
+                    }
+                },
+                Err(_) => return None
+            }
         }
     }
 }
 
 impl<'a, D: Read + Write + Seek> DirIter <'a, D>{
+    /// Leaves out entries with any of `attrs` set, `HIDDEN | SYSTEM` gives what Windows
+    /// lists by default
+    pub fn skip_attributes(mut self, attrs: FileAttributes) -> Self {
+        self.skip = attrs;
+        self
+    }
+
+    // The slot at `offset`, parsed from a buffer holding the rest of its cluster, or the
+    // next block of a FAT12 or FAT16 root directory, so the disk is read once for all of them
+    fn raw_entry(&mut self, offset: u64) -> Result<DirEntryRaw> {
+        let buf_end = self.buf_start + self.buf.len() as u64;
+        if offset < self.buf_start || offset + DIR_ENTRY_LEN > buf_end {
+            let end = if self.is_root() {
+                min(offset + BLOCK_SIZE, self.fs.root_dir_end_offset().unwrap())
+            } else {
+                self.fs.cluster_offset(self.current_cluster) + self.fs.bytes_per_cluster()
+            };
+            if end < offset + DIR_ENTRY_LEN {
+                return get_dir_entry_raw(self.fs, offset)
+            }
+            self.buf.resize((end - offset) as usize, 0);
+            self.buf_start = offset;
+            if let Err(e) = self.fs.read_at(offset, &mut self.buf) {
+                self.buf.clear();
+                return Err(e)
+            }
+        }
+        let start = (offset - self.buf_start) as usize;
+        parse_dir_slot(&self.buf[start..start + DIR_ENTRY_LEN as usize])
+    }
+
     fn get_dir_entry(&mut self) -> Result<(u64, Cluster, Option<DirEntry>)> {
 
         loop {
@@ -1135,22 +1900,30 @@ impl<'a, D: Read + Write + Seek> DirIter <'a, D>{
             }
 
             let offset = self.fs.cluster_offset(self.current_cluster) + self.offset;
-            let dentry = get_dir_entry_raw(self.fs, offset)?;
+            if let Some(cached) = self.fs.cached_dir_entry(offset) {
+                self.current_cluster = (cached.loc.1).0;
+                self.offset = (cached.loc.1).1 + DIR_ENTRY_LEN;
+                return Ok((self.offset, self.current_cluster, Some(cached.to_dir_entry(&self.dir_path))))
+            }
+            let dentry = self.raw_entry(offset)?;
             match dentry {
                 DirEntryRaw::Short(s) => {
+                    let loc = (self.current_cluster, self.offset);
                     self.offset = self.offset + DIR_ENTRY_LEN;
-                    return Ok((self.offset, self.current_cluster, Some(s.to_dir_entry((self.current_cluster, self.offset - DIR_ENTRY_LEN), &self.dir_path))))
+                    let e = s.to_dir_entry(loc, &self.dir_path);
+                    self.fs.cache_dir_entry(offset, CachedEntry::new(s, e.name(), (loc, loc), vec![(offset, offset + DIR_ENTRY_LEN)]));
+                    return Ok((self.offset, self.current_cluster, Some(e)))
                 },
                 DirEntryRaw::Long(_) => {
-                    // Iterate till a short entry or a free entry
-                    // Iterate only till 20 entries as the max file name size is 255
+                    // Iterate till a short entry or a free entry, which is at most
+                    // MAX_LFN_ENTRIES long entries and the short one
                     let mut lfn_entries = vec![dentry];
                     let start_offset = self.offset;
                     let start_cluster = self.current_cluster;
 
                     self.offset += DIR_ENTRY_LEN;
 
-                    for _i in 1..20 {
+                    for _i in 1..MAX_ENTRY_SLOTS {
                         if self.offset >= self.fs.bytes_per_cluster() && !self.is_root() {
                             match get_entry(self.fs, self.current_cluster).ok() {
                                 Some(FatEntry::Next(c)) => {
@@ -1166,7 +1939,7 @@ impl<'a, D: Read + Write + Seek> DirIter <'a, D>{
                         }
 
                         let offset = self.fs.cluster_offset(self.current_cluster) + self.offset;
-                        let dentry = get_dir_entry_raw(self.fs, offset)?;
+                        let dentry = self.raw_entry(offset)?;
                         match dentry {
                             DirEntryRaw::Short(_) => {
                                 lfn_entries.push(dentry);
@@ -1182,13 +1955,30 @@ impl<'a, D: Read + Write + Seek> DirIter <'a, D>{
                         }
                     }
 
+                    let entry_slots = lfn_entries.len() as u64;
                     let dir_entry = construct_dentry(lfn_entries, &self.dir_path, ((start_cluster, start_offset), (self.current_cluster, self.offset)));
                     match dir_entry {
                         Ok(d) => {
+                            let set_offset = self.fs.cluster_offset(start_cluster) + start_offset;
+                            let end = self.fs.cluster_offset(self.current_cluster) + self.offset + DIR_ENTRY_LEN;
+                            let slots = if start_cluster == self.current_cluster {
+                                vec![(set_offset, end)]
+                            } else {
+                                let first_end = self.fs.cluster_offset(start_cluster) + self.fs.bytes_per_cluster();
+                                vec![(set_offset, first_end), (self.fs.cluster_offset(self.current_cluster), end)]
+                            };
+                            // Sets running through a cluster of their own aren't cached
+                            let covered: u64 = slots.iter().map(|&(start, end)| end - start).sum();
+                            if let (Some(short), true) = (d.short_dir_entry(), covered == entry_slots * DIR_ENTRY_LEN) {
+                                let loc = ((start_cluster, start_offset), (self.current_cluster, self.offset));
+                                self.fs.cache_dir_entry(set_offset, CachedEntry::new(short, d.name(), loc, slots));
+                            }
                             self.offset = self.offset + DIR_ENTRY_LEN;
                             return Ok((self.offset, self.current_cluster, Some(d)))
                         },
-                        Err(_) => {
+                        Err(e) => {
+                            let set_offset = self.fs.cluster_offset(start_cluster) + start_offset;
+                            self.fs.record_damaged_lfn(&self.dir_path, set_offset, e.kind() == ErrorKind::InvalidData);
                             self.offset = self.offset + DIR_ENTRY_LEN;
                             //return self.get_dir_entry()
                         }
@@ -1210,6 +2000,63 @@ impl<'a, D: Read + Write + Seek> DirIter <'a, D>{
     }
 }
 
+/// Slots of a directory with their byte offsets, see `Dir::raw_entries`
+pub struct RawDirIter<'a, D: Read + Write + Seek> {
+    fs: &'a mut FileSystem<D>,
+    /// Byte ranges of the clusters, or of the FAT12 and FAT16 root directory
+    ranges: Vec<(u64, u64)>,
+    range: usize,
+    offset: u64,
+    done: bool
+}
+
+impl<'a, D: Read + Write + Seek> Iterator for RawDirIter<'a, D> {
+    type Item = Result<(u64, DirEntryRaw)>;
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done && self.offset + DIR_ENTRY_LEN > self.ranges.get(self.range).map_or(0, |r| r.1) {
+            self.range += 1;
+            match self.ranges.get(self.range) {
+                Some(&(start, _)) => self.offset = start,
+                None => self.done = true
+            }
+        }
+        if self.done {
+            return None
+        }
+        let offset = self.offset;
+        self.offset += DIR_ENTRY_LEN;
+        match get_dir_entry_raw(self.fs, offset) {
+            Ok(DirEntryRaw::FreeRest) => {
+                self.done = true;
+                Some(Ok((offset, DirEntryRaw::FreeRest)))
+            },
+            res => Some(res.map(|e| (offset, e)))
+        }
+    }
+}
+
+// Writes the slots in order, with one write for each block they share. Only a set of
+// entries spread over several blocks can be left half written by a crash
+fn write_dir_slots<D: Read + Write + Seek>(slots: &[(u64, DirEntryRaw)], fs: &mut FileSystem<D>) -> Result<()> {
+    fs.check_writable()?;
+    let mut i = 0;
+    while i < slots.len() {
+        let first = slots[i].0;
+        let block = fs.get_raw_offset(first) / BLOCK_SIZE;
+        let mut buf = get_block_buffer(fs.get_raw_offset(first), DIR_ENTRY_LEN);
+        fs.read_block(first, &mut buf)?;
+        while i < slots.len() && fs.get_raw_offset(slots[i].0) / BLOCK_SIZE == block {
+            let start = fs.get_block_offset(slots[i].0) as usize;
+            fs.journal_slot(slots[i].0, &buf[start..start + DIR_ENTRY_LEN as usize]);
+            buf[start..start + DIR_ENTRY_LEN as usize].copy_from_slice(&slots[i].1.to_bytes());
+            i += 1;
+        }
+        fs.write_metadata_block(first, &buf)?;
+    }
+    fs.disk.borrow_mut().flush()
+}
+
+// Fails with InvalidData if only the checksum of the long name is wrong
 fn construct_dentry(mut lfn_entries: Vec<DirEntryRaw>, dir_path: &String, loc: ((Cluster, u64), (Cluster, u64))) -> Result<DirEntry> {
     if lfn_entries.len() == 0 {
         return Err(Error::new(ErrorKind::Other, "Empty lfn entries"))
@@ -1245,6 +2092,16 @@ fn construct_dentry(mut lfn_entries: Vec<DirEntryRaw>, dir_path: &String, loc: (
 }
 
 pub fn get_dir_entry_raw<D: Read + Write + Seek>(fs: &mut FileSystem<D>, offset: u64) -> Result<DirEntryRaw> {
+    let (dir_0, mut cursor, blk_offset) = read_dir_slot(fs, offset)?;
+    match dir_0 {
+        0x00 => Ok(DirEntryRaw::FreeRest),
+        0xe5 => Ok(DirEntryRaw::Free),
+        _ => parse_dir_entry(&mut cursor, blk_offset)
+    }
+}
+
+// First byte of the entry at `offset` and the block holding it
+fn read_dir_slot<D: Read + Write + Seek>(fs: &mut FileSystem<D>, offset: u64) -> Result<(u8, Cursor<Vec<u8>>, u64)> {
     //fs.seek_to(offset)?;
     //let fat_offset = get_fat_offset(fs.bpb.fat_type, Cluster::new(cluster), fs.fat_start_sector(), fs.bytes_per_sec());
     let blk_offset = fs.get_block_offset(offset);
@@ -1252,54 +2109,56 @@ pub fn get_dir_entry_raw<D: Read + Write + Seek>(fs: &mut FileSystem<D>, offset:
     let block_buf = get_block_buffer(fs.get_raw_offset(offset), DIR_ENTRY_LEN);
     let mut cursor = Cursor::new(block_buf);
 
-    fs.seek_to_block(offset)?;
-    fs.disk.borrow_mut().read(cursor.get_mut())?;
+    fs.read_block(offset, cursor.get_mut())?;
     cursor.seek(SeekFrom::Start(blk_offset))?;
-
     let dir_0 = cursor.read_u8()?;
-    match dir_0 {
+    Ok((dir_0, cursor, blk_offset))
+}
+
+// Parses a slot already read into memory
+fn parse_dir_slot(slot: &[u8]) -> Result<DirEntryRaw> {
+    match slot[0] {
         0x00 => Ok(DirEntryRaw::FreeRest),
         0xe5 => Ok(DirEntryRaw::Free),
-        _ => {
-            cursor.seek(SeekFrom::Current(10))?;
-            let f_attr: FileAttributes = FileAttributes::from_bits(cursor.read_u8()?)
-                .ok_or(Error::new(ErrorKind::Other, "Error Reading File Attr"))?;
-            //fs.seek_to(offset)?;
-            cursor.seek(SeekFrom::Start(blk_offset))?;
-            if f_attr.contains(FileAttributes::LFN) {
-                let mut ldr = LongDirEntry::default();
-                ldr.ord = cursor.read_u8()?;
-                cursor.read_u16_into::<LittleEndian>(&mut ldr.name1)?;
-                ldr.file_attrs = FileAttributes::from_bits(cursor.read_u8()?)
-                    .ok_or(Error::new(ErrorKind::Other, "Error Reading File Attr"))?;
-                ldr.dirent_type = cursor.read_u8()?;
-                ldr.chksum = cursor.read_u8()?;
-                cursor.read_u16_into::<LittleEndian>(&mut ldr.name2)?;
-                ldr.first_clus_low = cursor.read_u16::<LittleEndian>()?;
-                cursor.read_u16_into::<LittleEndian>(&mut ldr.name3)?;
-                Ok(DirEntryRaw::Long(ldr))
-            } else {
-                let mut sdr = ShortDirEntry::default();
-                cursor.read(&mut sdr.dir_name)?;
-                sdr.file_attrs = FileAttributes::from_bits(cursor.read_u8()?)
-                    .ok_or(Error::new(ErrorKind::Other, "Error Reading File Attr"))?;
-                sdr.nt_res = cursor.read_u8()?;
-                sdr.crt_time_tenth = cursor.read_u8()?;
-                sdr.crt_time = cursor.read_u16::<LittleEndian>()?;
-                sdr.crt_date = cursor.read_u16::<LittleEndian>()?;
-                sdr.lst_acc_date = cursor.read_u16::<LittleEndian>()?;
-                sdr.fst_clst_hi = cursor.read_u16::<LittleEndian>()?;
-                sdr.wrt_time = cursor.read_u16::<LittleEndian>()?;
-                sdr.wrt_date = cursor.read_u16::<LittleEndian>()?;
-                sdr.fst_clus_lo = cursor.read_u16::<LittleEndian>()?;
-                sdr.file_size = cursor.read_u32::<LittleEndian>()?;
-                Ok(DirEntryRaw::Short(sdr))
-            }
-
-        }
+        _ => parse_dir_entry(&mut Cursor::new(slot), 0)
     }
+}
 
-
+fn parse_dir_entry<T: AsRef<[u8]>>(cursor: &mut Cursor<T>, blk_offset: u64) -> Result<DirEntryRaw> {
+    cursor.seek(SeekFrom::Start(blk_offset + 11))?;
+    let f_attr: FileAttributes = FileAttributes::from_bits(cursor.read_u8()?)
+        .ok_or(Error::new(ErrorKind::Other, "Error Reading File Attr"))?;
+    //fs.seek_to(offset)?;
+    cursor.seek(SeekFrom::Start(blk_offset))?;
+    if f_attr.contains(FileAttributes::LFN) {
+        let mut ldr = LongDirEntry::default();
+        ldr.ord = cursor.read_u8()?;
+        cursor.read_u16_into::<LittleEndian>(&mut ldr.name1)?;
+        ldr.file_attrs = FileAttributes::from_bits(cursor.read_u8()?)
+            .ok_or(Error::new(ErrorKind::Other, "Error Reading File Attr"))?;
+        ldr.dirent_type = cursor.read_u8()?;
+        ldr.chksum = cursor.read_u8()?;
+        cursor.read_u16_into::<LittleEndian>(&mut ldr.name2)?;
+        ldr.first_clus_low = cursor.read_u16::<LittleEndian>()?;
+        cursor.read_u16_into::<LittleEndian>(&mut ldr.name3)?;
+        Ok(DirEntryRaw::Long(ldr))
+    } else {
+        let mut sdr = ShortDirEntry::default();
+        cursor.read(&mut sdr.dir_name)?;
+        sdr.file_attrs = FileAttributes::from_bits(cursor.read_u8()?)
+            .ok_or(Error::new(ErrorKind::Other, "Error Reading File Attr"))?;
+        sdr.nt_res = cursor.read_u8()?;
+        sdr.crt_time_tenth = cursor.read_u8()?;
+        sdr.crt_time = cursor.read_u16::<LittleEndian>()?;
+        sdr.crt_date = cursor.read_u16::<LittleEndian>()?;
+        sdr.lst_acc_date = cursor.read_u16::<LittleEndian>()?;
+        sdr.fst_clst_hi = cursor.read_u16::<LittleEndian>()?;
+        sdr.wrt_time = cursor.read_u16::<LittleEndian>()?;
+        sdr.wrt_date = cursor.read_u16::<LittleEndian>()?;
+        sdr.fst_clus_lo = cursor.read_u16::<LittleEndian>()?;
+        sdr.file_size = cursor.read_u32::<LittleEndian>()?;
+        Ok(DirEntryRaw::Short(sdr))
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -1377,7 +2236,7 @@ impl DirEntry {
         }
     }
 
-    fn first_cluster(&self) -> Cluster {
+    pub fn first_cluster(&self) -> Cluster {
         match &self {
             &DirEntry::File(f) => {
                 f.first_cluster
@@ -1391,6 +2250,21 @@ impl DirEntry {
         }
     }
 
+    /// First cluster of the directory holding the entry
+    pub fn parent_dir(&self) -> Cluster {
+        match &self {
+            &DirEntry::File(f) | &DirEntry::VolID(f) => f.parent_dir,
+            &DirEntry::Dir(d) => d.parent_dir
+        }
+    }
+
+    fn set_parent_dir(&mut self, cluster: Cluster) {
+        match self {
+            &mut DirEntry::File(ref mut f) | &mut DirEntry::VolID(ref mut f) => f.parent_dir = cluster,
+            &mut DirEntry::Dir(ref mut d) => d.parent_dir = cluster
+        }
+    }
+
     fn get_dir_range(&self) -> Option<((Cluster, u64), (Cluster, u64))>{
         match &self {
             &DirEntry::File(f) => {
@@ -1444,6 +2318,14 @@ impl DirEntry {
         }
     }
 
+    /// Whether this is the root directory, the only one without a short entry
+    pub fn is_root(&self) -> bool {
+        match self {
+            &DirEntry::Dir(ref d) => d.short_dir_entry.is_none(),
+            _ => false
+        }
+    }
+
     pub fn is_vol_id(&self) -> bool {
         match self {
             &DirEntry::VolID(_) => true,
@@ -1451,6 +2333,86 @@ impl DirEntry {
         }
     }
 
+    pub fn attributes(&self) -> FileAttributes {
+        match self.short_dir_entry() {
+            Some(s) => s.file_attrs,
+            None => FileAttributes::DIRECTORY
+        }
+    }
+
+    /// Size from the short entry, always 0 for directories
+    pub fn size(&self) -> u64 {
+        self.short_dir_entry().map_or(0, |s| s.file_size as u64)
+    }
+
+    /// Date and time of the last write as stored on disk, `(date << 16) | time`
+    /// Later writes compare greater, 0 for the root directory
+    pub fn modified_raw(&self) -> u32 {
+        self.short_dir_entry().map_or(0, |s| (s.wrt_date as u32) << 16 | s.wrt_time as u32)
+    }
+
+    /// Creation date and time as stored on disk, `(date << 16) | time`
+    pub fn created_raw(&self) -> u32 {
+        self.short_dir_entry().map_or(0, |s| (s.crt_date as u32) << 16 | s.crt_time as u32)
+    }
+
+    /// Date of the last access as stored on disk
+    pub fn accessed_raw(&self) -> u16 {
+        self.short_dir_entry().map_or(0, |s| s.lst_acc_date)
+    }
+
+    /// Replaces the date and time of the last write and the date of the last access with
+    /// raw values as returned by `modified_raw` and `accessed_raw`, `None` keeps one
+    pub fn set_times_raw<D: Read + Write + Seek>(&mut self, fs: &mut FileSystem<D>, modified: Option<u32>, accessed: Option<u16>) -> Result<()> {
+        let (short_entry, loc) = match self.short_entry_mut() {
+            Some(s) => s,
+            None => return Err(Error::new(ErrorKind::InvalidInput, "The root directory has no times"))
+        };
+        if let Some(m) = modified {
+            short_entry.wrt_date = (m >> 16) as u16;
+            short_entry.wrt_time = m as u16;
+        }
+        if let Some(a) = accessed {
+            short_entry.lst_acc_date = a;
+        }
+        short_entry.flush(fs.cluster_offset(loc.0) + loc.1, fs)
+    }
+
+    /// Sets or clears the ARCHIVE attribute, which writes to a file set again
+    pub fn set_archive<D: Read + Write + Seek>(&mut self, fs: &mut FileSystem<D>, archive: bool) -> Result<()> {
+        let (short_entry, loc) = match self.short_entry_mut() {
+            Some(s) => s,
+            None => return Err(Error::new(ErrorKind::InvalidInput, "The root directory has no attributes"))
+        };
+        if archive {
+            short_entry.file_attrs.insert(FileAttributes::ARCHIVE);
+        } else {
+            short_entry.file_attrs.remove(FileAttributes::ARCHIVE);
+        }
+        short_entry.flush(fs.cluster_offset(loc.0) + loc.1, fs)
+    }
+
+    /// Clears the ARCHIVE attribute, as backup software does once it has copied the file
+    pub fn clear_archive<D: Read + Write + Seek>(&mut self, fs: &mut FileSystem<D>) -> Result<()> {
+        self.set_archive(fs, false)
+    }
+
+    // The short entry with its location, None for the root directory
+    fn short_entry_mut(&mut self) -> Option<(&mut ShortDirEntry, (Cluster, u64))> {
+        match *self {
+            DirEntry::File(ref mut f) | DirEntry::VolID(ref mut f) => Some((&mut f.short_dir_entry, f.loc.1)),
+            DirEntry::Dir(ref mut d) => match (d.short_dir_entry.as_mut(), d.loc) {
+                (Some(s), Some(l)) => Some((s, l.1)),
+                _ => None
+            }
+        }
+    }
+
+    /// Hidden or system entries, which Windows leaves out of listings by default
+    pub fn is_hidden(&self) -> bool {
+        self.attributes().intersects(FileAttributes::HIDDEN | FileAttributes::SYSTEM)
+    }
+
     pub fn to_file(&self) -> File {
         assert!(self.is_file(), "Not a file");
         match &self {
@@ -1467,18 +2429,17 @@ impl DirEntry {
         }
     }
 
-    fn eq_name(&self, name: &str) -> bool {
-        let short_name = self.short_name();
-        let long_name = self.name();
-        let long_name_upper = long_name.chars().flat_map(|c| c.to_uppercase());
-        let name_upper = name.chars().flat_map(|c| c.to_uppercase());
-        let long_name_matches = long_name_upper.eq(name_upper.clone());
-        let short_name_upper = short_name.chars().flat_map(|c| c.to_uppercase());
-        let short_name_matches = short_name_upper.eq(name_upper);
-        long_name_matches || short_name_matches
+    // With `normalized` the names are compared as `names_match` does, otherwise exactly
+    // apart from case
+    fn eq_name(&self, name: &str, case_sensitive: bool, normalized: bool) -> bool {
+        let eq = if normalized { names_match } else { same_name };
+        if case_sensitive {
+            return eq(&self.name(), name, true)
+        }
+        eq(&self.name(), name, false) || eq(&self.short_name(), name, false)
     }
 
-    fn dir_path(&self) -> String {
+    pub fn dir_path(&self) -> String {
         match &self {
             &DirEntry::File(f) => f.file_path.clone(),
             &DirEntry::Dir(d) => d.dir_path.clone(),
@@ -1506,8 +2467,8 @@ impl LongNameGen {
 
     fn process(&mut self, lfn: LongDirEntry) -> Result<()> {
         let is_last = lfn.is_last();
-        let index = lfn.order() & 0x1f;
-        if index == 0 {
+        let index = lfn.order() & LFN_ORDER_MASK;
+        if index == 0 || index as usize > MAX_LFN_ENTRIES {
             self.name.clear();
             return Err(Error::new(ErrorKind::Other, "Orphaned Entries"))
         }
@@ -1541,13 +2502,90 @@ impl LongNameGen {
 
     fn validate_checksum(&self, short_entry: &ShortDirEntry) -> Result<()> {
         if self.chksum != short_entry.compute_checksum() {
-            Err(Error::new(ErrorKind::Other, "Invalid Checksum"))
+            Err(Error::new(ErrorKind::InvalidData, "Invalid Checksum"))
         } else {
             Ok(())
         }
     }
 }
 
+/// Every entry name comparison goes through here
+/// Names match once both are normalized, lookups prefer a name stored exactly as given
+pub fn names_match(entry_name: &str, name: &str, case_sensitive: bool) -> bool {
+    same_name(normalize_name(entry_name), normalize_name(name), case_sensitive)
+}
+
+fn same_name(entry_name: &str, name: &str, case_sensitive: bool) -> bool {
+    if case_sensitive {
+        entry_name == name
+    } else {
+        let entry_upper = entry_name.chars().flat_map(|c| c.to_uppercase());
+        let name_upper = name.chars().flat_map(|c| c.to_uppercase());
+        entry_upper.eq(name_upper)
+    }
+}
+
+// Whether the directory starting at `ancestor` is `dir` or one of its parents, found by
+// following ".." entries up to the root so that any path leading to `dir` is caught
+fn is_ancestor<D: Read + Write + Seek>(ancestor: Cluster, dir: &Dir, fs: &mut FileSystem<D>) -> Result<bool> {
+    if dir.is_root() || dir.dir_path == "/" {
+        return Ok(false)
+    }
+    let root_cluster = fs.root_dir().first_cluster.cluster_number;
+    let max_depth = fs.max_cluster_number().cluster_number;
+    let mut cluster = dir.first_cluster;
+    for _ in 0..max_depth {
+        if cluster.cluster_number == ancestor.cluster_number {
+            return Ok(true)
+        }
+        // ".." of a directory in the root holds cluster 0
+        if cluster.cluster_number < 2 || cluster.cluster_number == root_cluster {
+            return Ok(false)
+        }
+        cluster = match get_dir_entry_raw(fs, fs.cluster_offset(cluster) + DIR_ENTRY_LEN)? {
+            DirEntryRaw::Short(dot_dot) => dot_dot.first_cluster(),
+            _ => return Err(Error::new(ErrorKind::InvalidData, "Missing .. entry"))
+        };
+    }
+    Err(Error::new(ErrorKind::InvalidData, "Directory tree contains a loop"))
+}
+
+// Bytes from `cluster_offset` in `cluster` to the end of the run of physically consecutive
+// clusters following it, stopping once `len` bytes are covered. `cluster` is left at the
+// last cluster of the run, so that large transfers go to the disk in one piece and are
+// split at block rather than cluster boundaries
+fn contiguous_run<D: Read + Write + Seek>(fs: &mut FileSystem<D>, cluster: &mut Cluster, cluster_offset: u64, len: u64) -> u64 {
+    let mut run_len = fs.bytes_per_cluster() - cluster_offset;
+    while run_len < len {
+        match get_entry(fs, *cluster) {
+            Ok(FatEntry::Next(c)) if c.cluster_number == cluster.cluster_number + 1 => {
+                *cluster = c;
+                run_len += fs.bytes_per_cluster();
+            },
+            _ => break
+        }
+    }
+    run_len
+}
+
+// Unix hides names starting with '.', mark them so that Windows does too
+fn created_attributes<D: Read + Write + Seek>(name: &str, fs: &FileSystem<D>) -> FileAttributes {
+    if fs.hide_dot_files && name.starts_with('.') {
+        FileAttributes::HIDDEN
+    } else {
+        FileAttributes::empty()
+    }
+}
+
+/// Form of a name used for lookups
+/// Leading spaces and trailing dots and spaces are dropped as Windows does, "." and ".." are kept
+pub fn normalize_name(name: &str) -> &str {
+    if name == "." || name == ".." {
+        return name
+    }
+    name.trim_start_matches(' ').trim_end_matches(|c| c == ' ' || c == '.')
+}
+
 /// Taken from rust-fatfs: https://github.com/rafalh/rust-fatfs
 fn split_path(path: &str) -> (&str, Option<&str>) {
     println!("Splitting path : {:?}", path);
@@ -1567,25 +2605,32 @@ fn rsplit_path(path: &str) -> (&str, Option<&str>) {
     (comp, rest_opt)
 }
 
-fn valid_long_name(mut name: &str) -> Result<()> {
-    name = name.trim();
+/// Name of a new entry, which can't be "." or ".."
+fn valid_new_name(name: &str) -> Result<()> {
+    if name == "." || name == ".." {
+        return Err(Error::new(ErrorKind::InvalidInput, "Name is reserved"));
+    }
+    valid_long_name(name)
+}
+
+/// Names are stored as given in the LFN entries, including leading and trailing
+/// dots and spaces which Windows only allows through "\\?\" paths
+fn valid_long_name(name: &str) -> Result<()> {
     //println!("Validating name: {:?}", name);
     if name.len() == 0 {
         return Err(Error::new(ErrorKind::InvalidInput, "Empty name"));
     }
+    // Other than "." and "..", which lookups go through, these could not be told apart from them or from nothing
+    if normalize_name(name).is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "Name consists of dots and spaces only"));
+    }
     if name.len() > 255 {
         return Err(Error::new(ErrorKind::InvalidInput, "Filename too long"));
     }
 
-    for c in name.chars() {
-        match c {
-            'a'..='z' | 'A'..='Z' | '0'..='9' => {},
-            '\u{80}'..='\u{ffff}' => {},
-            '$' |'%' | '\''| '-' | '_' | '@' | '~' | '`' | '!' | '(' | ')' | '{' | '}' | '^'
-            | '#' | '&' => {},
-            '+' | ',' | ';' | '=' | '[' | ']' | '.' | ' ' => {},
-            _ => return Err(Error::new(ErrorKind::InvalidInput, "Filename contains invalid chars"))
-        }
+    // The stricter 8.3 set only applies to the generated short name
+    if name.chars().any(invalid_name_char) {
+        return Err(Error::new(ErrorKind::InvalidInput, "Filename contains invalid chars"))
     }
     Ok(())
 
@@ -1603,15 +2648,21 @@ pub struct ShortNameGen {
     name_fits: bool,
     exact_match: bool,
     is_dot: bool,
-    is_dotdot: bool
+    is_dotdot: bool,
+    /// NT case flags if the name is stored exactly by its short name
+    case_flags: Option<u8>,
+    /// Generate `ShortNames::Sequential` tails
+    pub sequential: bool,
+    /// Sequential tails taken by other entries
+    used_tails: BTreeSet<u32>
 }
 
 /// Adapted from rust-fatfs: https://github.com/rafalh/rust-fatfs
 impl ShortNameGen {
 
     const FNAME_LEN: usize = 8;
-    pub fn new(mut name: &str) -> Self {
-        name = name.trim();
+    const MAX_TAIL: u32 = 999999;
+    pub fn new(name: &str) -> Self {
         let mut short_name = [0x20u8; 11];
         if name == "." {
             short_name[0] = '.' as u8;
@@ -1621,17 +2672,35 @@ impl ShortNameGen {
             short_name[1] = '.' as u8;
         }
 
-        let (name_fits, basename_len, is_lossy) = match name.rfind('.') {
+        let (name_fits, basename_len, is_lossy, case_flags) = match name.rfind('.') {
             Some(idx) => {
                 let (b_len, fits, b_lossy) = Self::copy_part(&mut short_name[..Self::FNAME_LEN], &name[..idx]);
-                let (_, ext_fits, ext_lossy) = Self::copy_part(&mut short_name[Self::FNAME_LEN..Self::FNAME_LEN + 3], &name[idx + 1..]);
-                (fits && ext_fits, b_len, b_lossy || ext_lossy)
+                let (e_len, ext_fits, ext_lossy) = Self::copy_part(&mut short_name[Self::FNAME_LEN..Self::FNAME_LEN + 3], &name[idx + 1..]);
+                let case_flags = match (Self::lowercase_part(&name[..idx]), Self::lowercase_part(&name[idx + 1..])) {
+                    (Some(b), Some(e)) if b_len > 0 && e_len > 0 => {
+                        let b_flag = if b { ShortDirEntry::LOWERCASE_BASE } else { 0 };
+                        let e_flag = if e { ShortDirEntry::LOWERCASE_EXT } else { 0 };
+                        Some(b_flag | e_flag)
+                    },
+                    _ => None
+                };
+                // A trailing dot has no place in 8.3 and is dropped
+                let dot_lossy = idx + 1 == name.len();
+                (fits && ext_fits, b_len, b_lossy || ext_lossy || dot_lossy, case_flags)
             },
             None => {
                 let (b_len, fits, b_lossy) = Self::copy_part(&mut short_name[..Self::FNAME_LEN], &name);
-                (fits, b_len, b_lossy)
+                let case_flags = match Self::lowercase_part(name) {
+                    Some(b) if b_len > 0 => Some(if b { ShortDirEntry::LOWERCASE_BASE } else { 0 }),
+                    _ => None
+                };
+                (fits, b_len, b_lossy, case_flags)
             }
         };
+        // A leading 0xE5 would mark the entry as free
+        if short_name[0] == 0xe5 {
+            short_name[0] = ESCAPED_E5;
+        }
         let checksum = Self::checksum(name);
         ShortNameGen {
 
@@ -1642,6 +2711,7 @@ impl ShortNameGen {
             basename_len: basename_len,
             name_fits: name_fits,
             checksum: checksum,
+            case_flags: case_flags,
             ..Default::default()
         }
 
@@ -1661,6 +2731,12 @@ impl ShortNameGen {
                 continue;
             }
 
+            if c == SIGMA {
+                dest[dest_len] = 0xe5;
+                dest_len += 1;
+                continue;
+            }
+
             let cp = match c {
                 'a'..='z' | 'A'..='Z' | '0'..='9' => c,
                 '$' |'%' | '\''| '-' | '_' | '@' | '~' | '`' | '!' | '(' | ')' | '{' | '}' | '^'
@@ -1668,13 +2744,33 @@ impl ShortNameGen {
                 _ => '_'
             };
             lossy_conv = lossy_conv || c != cp;
-            let upper =  c.to_ascii_uppercase();
+            let upper =  cp.to_ascii_uppercase();
             dest[dest_len] = upper as u8;
             dest_len += 1;
         }
         (dest_len as u8, true, lossy_conv)
     }
 
+    // Some(true) if all letters are lowercase, Some(false) if none are and None for mixed case
+    fn lowercase_part(part: &str) -> Option<bool> {
+        let has_lower = part.chars().any(|c| c.is_ascii_lowercase());
+        let has_upper = part.chars().any(|c| c.is_ascii_uppercase());
+        if has_lower && has_upper {
+            None
+        } else {
+            Some(has_lower)
+        }
+    }
+
+    /// Returns the NT case flags to be stored if the short name `sname`
+    /// represents the name exactly, in which case no LFN entries are needed
+    pub fn exact_case_flags(&self, sname: &[u8; 11]) -> Option<u8> {
+        if self.is_lossy || !self.name_fits || self.is_dot || self.is_dotdot || sname != &self.name {
+            return None
+        }
+        self.case_flags
+    }
+
     // Fletcher-16 Checksum
     fn checksum(name: &str) -> u16 {
         let mut sum1: u16 = 0;
@@ -1694,6 +2790,13 @@ impl ShortNameGen {
             self.exact_match = true;
         }
 
+        if self.sequential {
+            if let Some(num) = self.sequential_tail(name) {
+                self.used_tails.insert(num);
+            }
+            return
+        }
+
         // check for long prefix form collision (TEXTFI~1.TXT)
         let prefix_len = min(self.basename_len, 6) as usize;
         let num_suffix = if name[prefix_len] as char == '~' {
@@ -1734,6 +2837,13 @@ impl ShortNameGen {
             // 8.3 convention and there is no collision return it as is
             return Ok(self.name);
         }
+        if self.sequential {
+            return match (1..=Self::MAX_TAIL).find(|n| !self.used_tails.contains(n)) {
+                Some(n) => Ok(self.build_sequential_name(n)),
+                None => Err(Error::new(ErrorKind::AlreadyExists, "short name already exists"))
+            }
+        }
+
         // Try using long 6-characters prefix
         for i in 1..5 {
             if self.suffix_bitmask & (1 << i) == 0 {
@@ -1776,6 +2886,27 @@ impl ShortNameGen {
         buf
     }
 
+    // The basename is shortened as the tail grows, "TEXTFI~9" is followed by "TEXTF~10"
+    fn build_sequential_name(&self, num: u32) -> [u8; 11] {
+        let tail = format!("~{}", num);
+        let prefix_len = min(self.basename_len as usize, Self::FNAME_LEN - tail.len());
+        let mut buf = [0x20u8; 11];
+        buf[..prefix_len].copy_from_slice(&self.name[..prefix_len]);
+        buf[prefix_len..prefix_len + tail.len()].copy_from_slice(tail.as_bytes());
+        buf[8..].copy_from_slice(&self.name[8..]);
+        buf
+    }
+
+    // The tail of `name` if it is one this generator could have built
+    fn sequential_tail(&self, name: &[u8; 11]) -> Option<u32> {
+        let base = str::from_utf8(&name[..Self::FNAME_LEN]).ok()?.trim_end_matches(' ');
+        let num = base[base.rfind('~')? + 1..].parse::<u32>().ok()?;
+        if num == 0 || num > Self::MAX_TAIL || &self.build_sequential_name(num) != name {
+            return None
+        }
+        Some(num)
+    }
+
     fn u16_to_u8_array(x: u16) -> [u8; 4] {
         let c1 = char::from_digit((x as u32 >> 12) & 0xF, 16).unwrap().to_ascii_uppercase() as u8;
         let c2 = char::from_digit((x as u32 >> 8) & 0xF, 16).unwrap().to_ascii_uppercase() as u8;
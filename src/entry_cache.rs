@@ -0,0 +1,108 @@
+use std::collections::BTreeMap;
+
+use filesystem::Cluster;
+use dir_entry::{DirEntry, ShortDirEntry};
+
+/// A directory entry as `DirIter` parsed it, see `EntryCache`
+#[derive(Clone, Debug)]
+pub struct CachedEntry {
+    pub short_entry: ShortDirEntry,
+    pub name: String,
+    /// Locations of the first and the short slot, as in `File::loc`
+    pub loc: ((Cluster, u64), (Cluster, u64)),
+    /// Start and end volume offsets of the slots, one range per cluster they lie in
+    pub slots: Vec<(u64, u64)>,
+    last_used: u64
+}
+
+impl CachedEntry {
+    pub fn new(short_entry: ShortDirEntry, name: String, loc: ((Cluster, u64), (Cluster, u64)), slots: Vec<(u64, u64)>) -> CachedEntry {
+        CachedEntry {
+            short_entry: short_entry,
+            name: name,
+            loc: loc,
+            slots: slots,
+            last_used: 0
+        }
+    }
+
+    /// The entry for the directory at `dir_path`
+    pub fn to_dir_entry(&self, dir_path: &String) -> DirEntry {
+        self.short_entry.to_dir_entry_lfn(self.name.clone(), self.loc, dir_path)
+    }
+}
+
+/// Parsed directory entries keyed by the volume offset of their first slot
+///
+/// Listing a directory fills it, so that looking up entries of the same directory right
+/// after doesn't parse or read them again. Every block write drops the entries with a
+/// slot in it, and once `capacity` entries are held the least recently used one is dropped.
+pub struct EntryCache {
+    capacity: usize,
+    tick: u64,
+    entries: BTreeMap<u64, CachedEntry>,
+    hits: u64,
+    misses: u64
+}
+
+impl EntryCache {
+    pub fn new(capacity: usize) -> EntryCache {
+        EntryCache {
+            capacity: capacity,
+            tick: 0,
+            entries: BTreeMap::new(),
+            hits: 0,
+            misses: 0
+        }
+    }
+
+    pub fn get(&mut self, offset: u64) -> Option<CachedEntry> {
+        self.tick += 1;
+        match self.entries.get_mut(&offset) {
+            Some(e) => {
+                self.hits += 1;
+                e.last_used = self.tick;
+                Some(e.clone())
+            },
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, offset: u64, mut entry: CachedEntry) {
+        if self.capacity == 0 {
+            return;
+        }
+        if !self.entries.contains_key(&offset) && self.entries.len() >= self.capacity {
+            let oldest = self.entries.iter().min_by_key(|&(_, e)| e.last_used).map(|(&o, _)| o);
+            if let Some(o) = oldest {
+                self.entries.remove(&o);
+            }
+        }
+        self.tick += 1;
+        entry.last_used = self.tick;
+        self.entries.insert(offset, entry);
+    }
+
+    /// Drops the entries with a slot in the `len` bytes at `offset`
+    pub fn invalidate(&mut self, offset: u64, len: u64) {
+        let end = offset + len;
+        self.entries.retain(|_, e| e.slots.iter().all(|&(start, slots_end)| slots_end <= offset || start >= end));
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Number of entries held
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Number of lookups served from the cache and from the disk
+    pub fn stats(&self) -> (u64, u64) {
+        (self.hits, self.misses)
+    }
+}
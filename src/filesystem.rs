@@ -5,14 +5,22 @@ use std::io::{Read, Write, Seek, SeekFrom, Error, ErrorKind, Cursor};
 use std::default::Default;
 use std::iter::Iterator;
 use std::cell::{RefCell};
+use std::collections::BTreeSet;
 use std::cmp::{Eq, PartialEq, PartialOrd, Ordering, min};
+use std::thread;
+use std::time::{Duration, SystemTime};
 
-use BiosParameterBlock;
+use bpb::BiosParameterBlock;
 //use disk::Disk;
 use bpb::FATType;
-use table::{FatEntry, get_entry, get_entry_raw, set_entry, RESERVED_CLUSTERS};
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use dir_entry::Dir;
+use table::{FatEntry, get_entry, get_entry_raw, get_free_cluster, get_free_count, set_entry, RESERVED_CLUSTERS};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use dir_entry::{Dir, NameTranslation};
+use check::Problem;
+use entry_cache::{EntryCache, CachedEntry};
+use transaction::EntryJournal;
+use free_map::FreeMap;
+use clock::{Clock, SystemClock};
 
 #[derive(Copy, Clone, Debug)]
 pub struct Cluster {
@@ -61,6 +69,11 @@ impl<'a, D: Read + Write + Seek> Iterator for ClusterIter<'a, D> {
 }
 
 
+/// Bytes copied at a time by `FileSystem::move_range_up`
+const MOVE_CHUNK: u64 = 64 * 1024;
+
+static ZEROES: [u8; BLOCK_SIZE as usize] = [0; BLOCK_SIZE as usize];
+
 /// An in-memory copy of FsInfo Struct for FAT32
 /// Flushed out to disk on unmounting the volume
 #[derive(Debug, Copy, Clone)]
@@ -76,6 +89,7 @@ pub struct FsInfo {
     /// 0xAA550000
     trail_sig: u32,
     /// Dirty flag to flush to disk
+    /// Set when the structure on disk was invalid and has to be rebuilt
     dirty: bool,
     /// Relative Offset of FsInfo Structure
     /// Not present for FAT12 and FAT16
@@ -122,6 +136,19 @@ impl FsInfo {
         }
     }
 
+    /// Stands in for an FSInfo sector with bad signatures until it is rebuilt
+    fn invalid_at(offset: u64) -> Self {
+        FsInfo {
+            dirty: true,
+            offset: Some(offset),
+            ..Default::default()
+        }
+    }
+
+    pub fn needs_rebuild(&self) -> bool {
+        self.dirty
+    }
+
     pub fn update<D: Read + Seek>(&mut self, disk: &mut D) -> Result<()> {
         if let Some(off) = self.offset {
             let block_vec = get_block_buffer(off, Self::FS_INFO_SIZE);
@@ -211,24 +238,136 @@ impl Default for FsInfo {
         }
     }
 }
+/// Times an operation interrupted by a signal is restarted by `retry_interrupted`
+pub const MAX_INTERRUPTED_RETRIES: u32 = 16;
+
+/// Runs `op` again while it fails with Interrupted, up to MAX_INTERRUPTED_RETRIES times
+/// `op` has to be safe to repeat from the start, seeking before it reads or writes
+pub fn retry_interrupted<T, F: FnMut() -> Result<T>>(mut op: F) -> Result<T> {
+    let mut retries = 0;
+    loop {
+        match op() {
+            Err(ref e) if e.kind() == ErrorKind::Interrupted && retries < MAX_INTERRUPTED_RETRIES => retries += 1,
+            res => return res
+        }
+    }
+}
+
+/// How often a failed block write is tried again before the volume is given up on
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts per write including the first one
+    pub attempts: u32,
+    /// Pause between attempts
+    pub delay: Duration
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 3,
+            delay: Duration::from_millis(10)
+        }
+    }
+}
+
+/// Read back checks of FAT and directory entry writes, see `FileSystem::verify_writes`
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct WriteVerification {
+    /// Blocks read back after being written
+    pub verified: u64,
+    /// Read backs which differed from what was written
+    pub mismatches: u64
+}
+
+/// Sets of long name entries skipped while listing directories, see `FileSystem::lfn_corruption`
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct LfnCorruption {
+    /// The long name doesn't belong to the short entry following it
+    pub checksum_mismatches: u64,
+    /// Long name entries out of order or without a short entry
+    pub orphaned: u64
+}
+
 pub struct FileSystem<D: Read + Write + Seek> {
     pub disk: RefCell<D>,
     pub bpb: BiosParameterBlock,
     pub partition_offset: u64,
+    /// Size of the volume in bytes, nothing at or past it is read or written
+    pub partition_len: u64,
     pub first_data_sec: u64,
-    pub fs_info: RefCell<FsInfo>
+    pub fs_info: RefCell<FsInfo>,
+    /// Only match names which are exactly equal, so that "Makefile" and "makefile"
+    /// can coexist. Other FAT implementations look names up case-insensitively and
+    /// will only reach one of such entries, and short aliases are no longer matched
+    pub case_sensitive: bool,
+    /// Set the HIDDEN attribute on entries created with a name starting with '.'
+    pub hide_dot_files: bool,
+    /// Handling of characters FAT doesn't allow in names
+    pub name_translation: NameTranslation,
+    /// Write no long name entries, for devices which only read 8.3 names. Creating or
+    /// renaming to a name without an exact short form fails with InvalidInput
+    pub short_names_only: bool,
+    /// Retries of failed writes, see `write_block`
+    pub retry_policy: RetryPolicy,
+    /// Read FAT and directory entry blocks back after writing them, see `write_metadata_block`
+    pub verify_writes: bool,
+    write_verification: WriteVerification,
+    /// Write FAT changes to the active copy only, the others are brought up to date by
+    /// `sync_fat_mirrors`, on unmount and once many sectors differ
+    pub lazy_fat_mirroring: bool,
+    /// Sectors of the active FAT, counted from its start, not yet copied to the others
+    stale_fat_sectors: BTreeSet<u64>,
+    /// Keep a warning for each damaged long name found, see `take_lfn_warnings`
+    pub strict_lfn: bool,
+    /// Refuse to add or remove entries in directories with the RD_ONLY attribute, which
+    /// Windows ignores on directories and uses to mark customized folders
+    pub read_only_dirs: bool,
+    /// Fail with InvalidData on FAT entries pointing outside the data clusters instead of
+    /// following them, see `get_entry`
+    pub strict_cluster_bounds: bool,
+    lfn_corruption: LfnCorruption,
+    lfn_warnings: Vec<Problem>,
+    /// Parsed directory entries, see `set_entry_cache`
+    entry_cache: Option<EntryCache>,
+    /// Free clusters for the allocator, see `start_free_map`
+    free_map: Option<FreeMap>,
+    /// Slots overwritten by the open transaction, see `with_entry_transaction`
+    entry_journal: Option<EntryJournal>,
+    /// Every write fails and nothing is written on drop, see `open_read_only`,
+    /// `lock` and `write_block`
+    read_only: bool,
+    /// A write failed for good and the volume became read-only
+    degraded: bool,
+    /// Writes are refused until turned off again, see `set_write_protect`
+    write_protected: bool,
+    /// Source of the time for timestamps, see `set_clock`
+    clock: Box<dyn Clock>
 }
 
 impl<D: Read + Write + Seek> FileSystem<D> {
+    /// Byte offset of BPB_ExtFlags in a FAT32 boot sector
+    const EXT_FLAGS_OFFSET: u64 = 40;
+    /// Stale FAT sectors after which lazy mirroring syncs the copies anyway
+    const MAX_STALE_FAT_SECTORS: usize = 64;
 
     pub fn from_offset(partition_offset: u64, mut disk: D, serial: Option<u32>) -> Result<FileSystem<D>> {
-        disk.seek(SeekFrom::Start((partition_offset / BLOCK_SIZE) * BLOCK_SIZE))?;
-        let bpb = BiosParameterBlock::populate(&mut disk)?;
+        let bpb = retry_interrupted(|| {
+            disk.seek(SeekFrom::Start((partition_offset / BLOCK_SIZE) * BLOCK_SIZE))?;
+            BiosParameterBlock::populate(&mut disk)
+        })?;
 
         let fsinfo = match bpb.fat_type {
             FATType::FAT32(s) => {
                 let offset = partition_offset + s.fs_info as u64 * bpb.bytes_per_sector as u64;
-                FsInfo::populate(&mut disk, offset)?
+                match retry_interrupted(|| FsInfo::populate(&mut disk, offset)) {
+                    Ok(fsinfo) => fsinfo,
+                    Err(ref e) if e.kind() == ErrorKind::InvalidData => {
+                        warn!("Invalid FSInfo signature, it will be rebuilt");
+                        FsInfo::invalid_at(offset)
+                    },
+                    Err(e) => return Err(e)
+                }
             },
             _ => FsInfo::default()
         };
@@ -253,11 +392,379 @@ impl<D: Read + Write + Seek> FileSystem<D> {
             disk: RefCell::new(disk),
             bpb: bpb,
             partition_offset: partition_offset,
+            partition_len: bpb.total_sectors() * bpb.bytes_per_sector as u64,
             first_data_sec: first_data_sec,
-            fs_info: RefCell::new(fsinfo)
+            fs_info: RefCell::new(fsinfo),
+            case_sensitive: false,
+            hide_dot_files: false,
+            name_translation: NameTranslation::default(),
+            retry_policy: RetryPolicy::default(),
+            verify_writes: false,
+            write_verification: WriteVerification::default(),
+            lazy_fat_mirroring: false,
+            stale_fat_sectors: BTreeSet::new(),
+            strict_lfn: false,
+            read_only_dirs: false,
+            short_names_only: false,
+            strict_cluster_bounds: false,
+            lfn_corruption: LfnCorruption::default(),
+            lfn_warnings: Vec::new(),
+            entry_cache: None,
+            free_map: None,
+            entry_journal: None,
+            read_only: false,
+            degraded: false,
+            write_protected: false,
+            clock: Box::new(SystemClock)
         })
     }
 
+    /// Opens the volume without ever writing to it, not even FSInfo and the
+    /// clean shutdown bit on drop
+    ///
+    /// Several read-only instances can safely share an image, also while it is mounted
+    /// elsewhere. Use `in_use` to find out whether it might change underneath.
+    pub fn open_read_only(partition_offset: u64, disk: D, serial: Option<u32>) -> Result<FileSystem<D>> {
+        let mut fs = FileSystem::from_offset(partition_offset, disk, serial)?;
+        fs.read_only = true;
+        Ok(fs)
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Whether a write failed even after retrying, see `write_block`
+    pub fn is_degraded(&self) -> bool {
+        self.degraded
+    }
+
+    /// Fails with ReadOnlyFilesystem on read-only, degraded and write protected
+    /// instances, checked before anything is written
+    pub fn check_writable(&self) -> Result<()> {
+        if self.read_only && self.degraded {
+            Err(Error::new(ErrorKind::ReadOnlyFilesystem, "Filesystem is read-only after a write error"))
+        } else if self.read_only {
+            Err(Error::new(ErrorKind::ReadOnlyFilesystem, "Filesystem is opened read-only"))
+        } else if self.write_protected {
+            Err(Error::new(ErrorKind::ReadOnlyFilesystem, "Filesystem is write protected"))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Refuses or allows writes from now on without reopening the volume
+    ///
+    /// Turning it on first writes out the FAT copies and FSInfo, so the image is
+    /// consistent while protected, e.g. to take a copy of it. Turning it off leaves
+    /// read-only and degraded instances read-only. Unmounting while protected only
+    /// releases the lock taken by `lock`.
+    pub fn set_write_protect(&mut self, protect: bool) -> Result<()> {
+        if protect && !self.write_protected && self.check_writable().is_ok() {
+            self.sync_fat_mirrors()?;
+            if self.fs_info.borrow().needs_rebuild() {
+                self.rebuild_fsinfo()?;
+            }
+            self.fs_info.borrow_mut().flush(self.disk.get_mut())?;
+            self.disk.borrow_mut().flush()?;
+        }
+        self.write_protected = protect;
+        Ok(())
+    }
+
+    pub fn is_write_protected(&self) -> bool {
+        self.write_protected
+    }
+
+    /// Writes `buf` at the start of the block containing `offset`, every write to the
+    /// volume goes through here
+    ///
+    /// Failed writes are tried again as `retry_policy` says. Once the attempts are used up
+    /// the hard error bit is set and the filesystem turns read-only, rather than
+    /// continuing on top of metadata which may be half written.
+    pub fn write_block(&mut self, offset: u64, buf: &[u8]) -> Result<()> {
+        self.check_writable()?;
+        let block_start = offset.saturating_sub(self.get_block_offset(offset));
+        // The last block of a partition whose length isn't a multiple of BLOCK_SIZE is
+        // written only up to the partition end
+        let len = if block_start < self.partition_len {
+            min(buf.len() as u64, self.partition_len - block_start)
+        } else {
+            buf.len() as u64
+        };
+        self.check_bounds(block_start, len)?;
+        let buf = &buf[..len as usize];
+        self.invalidate_cached_entries(block_start, buf.len() as u64);
+        let mut attempt = 1;
+        loop {
+            // Interrupted writes are restarted without counting as a failed attempt
+            let res = retry_interrupted(|| {
+                self.seek_to_block(offset)?;
+                self.disk.borrow_mut().write_all(buf)
+            });
+            match res {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    if attempt >= self.retry_policy.attempts {
+                        warn!("Write at {:x} failed after {} attempts: {}", offset, attempt, e);
+                        self.degrade();
+                        return Err(e)
+                    }
+                    debug!("Write at {:x} failed, retrying: {}", offset, e);
+                    attempt += 1;
+                    thread::sleep(self.retry_policy.delay);
+                }
+            }
+        }
+    }
+
+    /// Writes a block of FAT or directory entries with `write_block`, reading it back
+    /// when `verify_writes` is set
+    ///
+    /// A block which reads back different is written again as `retry_policy` says, if it
+    /// still differs the volume turns read-only as for a failed write.
+    pub fn write_metadata_block(&mut self, offset: u64, buf: &[u8]) -> Result<()> {
+        self.write_block(offset, buf)?;
+        if !self.verify_writes {
+            return Ok(())
+        }
+        let mut written = vec![0; buf.len()];
+        let mut attempt = 1;
+        loop {
+            self.disk.borrow_mut().flush()?;
+            self.read_block(offset, &mut written)?;
+            self.write_verification.verified += 1;
+            if written.as_slice() == buf {
+                return Ok(())
+            }
+            self.write_verification.mismatches += 1;
+            if attempt >= self.retry_policy.attempts {
+                warn!("Block at {:x} still differs after {} attempts", offset, attempt);
+                self.degrade();
+                return Err(Error::new(ErrorKind::Other, "Block read back differs from what was written"))
+            }
+            debug!("Block at {:x} reads back different, rewriting", offset);
+            attempt += 1;
+            thread::sleep(self.retry_policy.delay);
+            self.write_block(offset, buf)?;
+        }
+    }
+
+    /// Blocks checked and found different since the volume was opened, see `verify_writes`
+    pub fn write_verification(&self) -> WriteVerification {
+        self.write_verification
+    }
+
+    fn degrade(&mut self) {
+        // Also stops the recursion when marking the hard error fails
+        if self.degraded {
+            return
+        }
+        self.degraded = true;
+        // Best effort, the disk is likely to refuse this as well
+        if let Err(e) = self.mark_hard_error() {
+            warn!("Failed to set the hard error bit: {}", e);
+        }
+        self.read_only = true;
+    }
+
+    /// Takes the advisory lock of the volume by clearing the clean shutdown bit until unmount
+    ///
+    /// Fails with WouldBlock if another instance holds the lock or the volume was not
+    /// unmounted cleanly, unless `force` is set. The instance is then read-only, so that
+    /// dropping it doesn't release the other lock. FAT12 has no such bit and is never locked.
+    pub fn lock(&mut self, force: bool) -> Result<()> {
+        if self.in_use()? && !force {
+            self.read_only = true;
+            return Err(Error::new(ErrorKind::WouldBlock, "Volume is in use or was not unmounted cleanly"))
+        }
+        match self.bpb.fat_type {
+            FATType::FAT32(_) => {
+                let raw_entry = get_entry_raw(self, Cluster::new(1))? & !0x08000000;
+                set_entry(self, Cluster::new(1), FatEntry::Next(Cluster::new(raw_entry)))
+            },
+            FATType::FAT16(_) => {
+                let raw_entry = get_entry_raw(self, Cluster::new(1))? & !0x8000;
+                set_entry(self, Cluster::new(1), FatEntry::Next(Cluster::new(raw_entry)))
+            },
+            _ => Ok(())
+        }
+    }
+
+    /// Whether the volume is locked by another instance or was not unmounted cleanly
+    pub fn in_use(&mut self) -> Result<bool> {
+        self.clean_shut_bit().map(|clean| !clean)
+    }
+
+    /// Damaged long names skipped by directory listings and lookups since the volume was opened
+    ///
+    /// A set is counted each time it is come across, so repeated lookups count it again.
+    pub fn lfn_corruption(&self) -> LfnCorruption {
+        self.lfn_corruption
+    }
+
+    /// Warnings kept in strict mode since the last call, one per damaged set of entries
+    pub fn take_lfn_warnings(&mut self) -> Vec<Problem> {
+        ::std::mem::replace(&mut self.lfn_warnings, Vec::new())
+    }
+
+    /// Keeps up to `capacity` directory entries parsed by listings and lookups, none if 0
+    ///
+    /// Entries are dropped when a block holding them is written through this instance.
+    /// Leave it off when something else may change the volume while it is open.
+    pub fn set_entry_cache(&mut self, capacity: usize) {
+        self.entry_cache = if capacity > 0 { Some(EntryCache::new(capacity)) } else { None };
+    }
+
+    /// Takes the time for the timestamps written from now on from `clock`
+    pub fn set_clock<C: Clock + 'static>(&mut self, clock: C) {
+        self.clock = Box::new(clock);
+    }
+
+    /// The current time as the clock tells it
+    pub fn now(&self) -> SystemTime {
+        self.clock.now()
+    }
+
+    /// Lookups served from the entry cache and from the disk, if the cache is enabled
+    pub fn entry_cache_stats(&self) -> Option<(u64, u64)> {
+        self.entry_cache.as_ref().map(|c| c.stats())
+    }
+
+    /// Starts a map of the free clusters, which the allocator takes from instead of
+    /// searching the FAT
+    ///
+    /// The map starts out empty and is filled in by `scan_free_map`. Like the entry cache
+    /// it only sees changes made through this instance, clusters it hands out are checked
+    /// against the FAT though.
+    pub fn start_free_map(&mut self) {
+        self.free_map = Some(FreeMap::new(self.max_cluster_number().cluster_number + 1));
+    }
+
+    /// Starts the free map over, for changes to the FAT made other than through `set_entry`
+    pub fn restart_free_map(&mut self) {
+        if self.free_map.is_some() {
+            self.start_free_map();
+        }
+    }
+
+    pub fn free_map(&self) -> Option<&FreeMap> {
+        self.free_map.as_ref()
+    }
+
+    pub fn free_map_mut(&mut self) -> Option<&mut FreeMap> {
+        self.free_map.as_mut()
+    }
+
+    /// The cached entry whose first slot is at `offset`, see `set_entry_cache`
+    pub fn cached_dir_entry(&mut self, offset: u64) -> Option<CachedEntry> {
+        self.entry_cache.as_mut().and_then(|c| c.get(offset))
+    }
+
+    /// Caches `entry` if the entry cache is enabled
+    pub fn cache_dir_entry(&mut self, offset: u64, entry: CachedEntry) {
+        if let Some(ref mut c) = self.entry_cache {
+            c.insert(offset, entry);
+        }
+    }
+
+    // Drops the cached entries with a slot in the `len` bytes at `offset`, before they are
+    // written over
+    fn invalidate_cached_entries(&mut self, offset: u64, len: u64) {
+        if let Some(ref mut c) = self.entry_cache {
+            c.invalidate(offset, len);
+        }
+    }
+
+    /// Drops every cached entry, for changes made other than through `write_block`
+    pub fn clear_entry_cache(&mut self) {
+        if let Some(ref mut c) = self.entry_cache {
+            c.clear();
+        }
+    }
+
+    /// Runs `op`, putting back every directory slot it overwrote if it fails
+    ///
+    /// For updates made of several entry writes, like a rename, which would otherwise be
+    /// left half done. Only directory entries are put back, so `op` should change the FAT
+    /// after its last fallible step. They are put back both after logical failures such
+    /// as running out of space and after a write which failed for good, see
+    /// `undo_failed_writes`. A transaction opened within another is part of the outer one.
+    pub fn with_entry_transaction<T, F: FnOnce(&mut Self) -> Result<T>>(&mut self, op: F) -> Result<T> {
+        if self.entry_journal.is_some() {
+            return op(self)
+        }
+        let was_degraded = self.degraded;
+        self.entry_journal = Some(EntryJournal::new());
+        let res = op(self);
+        let journal = self.entry_journal.take().unwrap();
+        if res.is_err() && !journal.is_empty() {
+            if let Err(e) = self.undo_failed_writes(was_degraded, |fs| fs.undo_entry_writes(journal)) {
+                warn!("Failed to put back directory entries: {}", e);
+            }
+        }
+        res
+    }
+
+    /// Runs `undo` to put back what a failed update already wrote
+    ///
+    /// If the failure used up the write attempts and turned the volume read-only since
+    /// `was_degraded` was taken, `undo` may still write, the volume stays read-only
+    /// afterwards. Otherwise it is refused as any write would be.
+    pub fn undo_failed_writes<T, F: FnOnce(&mut Self) -> Result<T>>(&mut self, was_degraded: bool, undo: F) -> Result<T> {
+        let read_only = self.read_only;
+        if self.degraded && !was_degraded {
+            self.read_only = false;
+        }
+        let res = undo(self);
+        self.read_only = read_only;
+        res
+    }
+
+    // Puts back as many slots as it can, the slot whose write failed is likely to fail
+    // again and shouldn't keep the others from being put back
+    fn undo_entry_writes(&mut self, journal: EntryJournal) -> Result<()> {
+        let mut res = Ok(());
+        for (offset, slot) in journal.into_undo() {
+            let mut buf = get_block_buffer(self.get_raw_offset(offset), slot.len() as u64);
+            let put_back = self.read_block(offset, &mut buf).and_then(|_| {
+                let start = self.get_block_offset(offset) as usize;
+                buf[start..start + slot.len()].copy_from_slice(&slot);
+                self.write_metadata_block(offset, &buf)
+            });
+            if res.is_ok() {
+                res = put_back;
+            }
+        }
+        res.and(self.disk.borrow_mut().flush())
+    }
+
+    /// Records `old`, what the directory slot at `offset` holds before it is overwritten,
+    /// if a transaction is open
+    pub fn journal_slot(&mut self, offset: u64, old: &[u8]) {
+        if let Some(ref mut j) = self.entry_journal {
+            j.record(offset, old);
+        }
+    }
+
+    /// Counts a set of long name entries at `offset` in `dir_path` which was skipped
+    pub fn record_damaged_lfn(&mut self, dir_path: &str, offset: u64, checksum_mismatch: bool) {
+        if checksum_mismatch {
+            self.lfn_corruption.checksum_mismatches += 1;
+        } else {
+            self.lfn_corruption.orphaned += 1;
+        }
+        if !self.strict_lfn || self.lfn_warnings.iter().any(|p| p.offset() == Some(offset)) {
+            return;
+        }
+        warn!("Damaged long name entries at {:#x} in {}", offset, dir_path);
+        self.lfn_warnings.push(Problem::DamagedLongName {
+            path: dir_path.to_string(),
+            offset: offset,
+            checksum_mismatch: checksum_mismatch
+        });
+    }
+
     pub fn read_cluster(&mut self, cluster: Cluster, buf: &mut [u8]) -> Result<usize> {
         /*let root_dir_sec = ((self.bpb.root_entries_cnt as u64 * 32) + (self.bpb.bytes_per_sector as u64 - 1)) / (self.bpb.bytes_per_sector as u64);
         let fat_sz = if self.bpb.fat_size_16 != 0 { self.bpb.fat_size_16 as u64}
@@ -291,20 +798,17 @@ impl<D: Read + Write + Seek> FileSystem<D> {
         //let partition_offset = self.partition_offset;
         //self.disk.borrow_mut().seek(SeekFrom::Start(partition_offset + offset))?;
         //self.disk.borrow_mut().read(buf)
-        let num_blocks = (buf.len() + BLOCK_SIZE as usize - 1) / BLOCK_SIZE as usize;
-        let blk_offset = self.get_block_offset(offset);
-
-        let block_buf = get_block_buffer(self.get_raw_offset(offset), BLOCK_SIZE);
-        let mut cursor = Cursor::new(block_buf);
+        self.check_bounds(offset, buf.len() as u64)?;
+        let mut cursor = Cursor::new(vec![0; BLOCK_SIZE as usize]);
         let mut start = 0;
 
-        for _i in 0..num_blocks {
-            self.seek_to_block(offset)?;
-            self.disk.borrow_mut().read_exact(cursor.get_mut())?;
+        while start < buf.len() {
+            let blk_offset = self.get_block_offset(offset);
+            self.read_block(offset, cursor.get_mut())?;
             cursor.seek(SeekFrom::Start(blk_offset))?;
             let bytes_remaining_block = BLOCK_SIZE - blk_offset;
             let read_len = min(bytes_remaining_block as usize, buf.len() - start);
-            cursor.read(&mut buf[start.. start + read_len])?;
+            cursor.read_exact(&mut buf[start.. start + read_len])?;
             start += read_len;
             offset += read_len as u64;
         }
@@ -312,7 +816,17 @@ impl<D: Read + Write + Seek> FileSystem<D> {
         Ok(start)
     }
 
+    /// Fails with InvalidData if `len` bytes at `offset` don't lie within the partition,
+    /// which only happens when cluster numbers or FAT offsets come from a corrupt volume
+    pub fn check_bounds(&self, offset: u64, len: u64) -> Result<()> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.partition_len => Ok(()),
+            _ => Err(Error::new(ErrorKind::InvalidData, "Corrupt filesystem: access beyond the end of the partition"))
+        }
+    }
+
     pub fn seek_to(&mut self, offset: u64) -> Result<usize> {
+        self.check_bounds(offset, 0)?;
         match self.disk.borrow_mut().seek(SeekFrom::Start(self.partition_offset + offset)) {
             Ok(s) => Ok(s as usize),
             Err(e) => Err(e)
@@ -320,11 +834,41 @@ impl<D: Read + Write + Seek> FileSystem<D> {
     }
 
     pub fn seek_to_block(&mut self, offset: u64) -> Result<usize> {
+        self.check_bounds(offset, 1)?;
         let off = self.partition_offset + offset;
         let block = off / BLOCK_SIZE;
         self.disk.borrow_mut().seek(SeekFrom::Start(block * BLOCK_SIZE)).map(|s| s as usize)
     }
 
+    /// Fills `buf` from the start of the block containing `offset`, every read of the
+    /// volume goes through here
+    ///
+    /// Reads interrupted by a signal are restarted. A disk which ends within the partition
+    /// fails with UnexpectedEof, what lies past the end of the partition reads as zeroes.
+    /// The number of bytes actually read is returned.
+    pub fn read_block(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let block_start = offset.saturating_sub(self.get_block_offset(offset));
+        let needed = min(buf.len() as u64, self.partition_len.saturating_sub(block_start)) as usize;
+        retry_interrupted(|| {
+            self.seek_to_block(offset)?;
+            let mut disk = self.disk.borrow_mut();
+            let mut read = 0;
+            while read < buf.len() {
+                match disk.read(&mut buf[read..])? {
+                    0 => break,
+                    n => read += n
+                }
+            }
+            if read < needed {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "Disk ends within the partition"))
+            }
+            for b in &mut buf[read..] {
+                *b = 0;
+            }
+            Ok(read)
+        })
+    }
+
     pub fn get_block_offset(&self, offset: u64) -> u64 {
         (self.partition_offset + offset) % BLOCK_SIZE
     }
@@ -339,26 +883,33 @@ impl<D: Read + Write + Seek> FileSystem<D> {
         //self.disk.borrow_mut().flush()?;
         //println!("Write Success");
         //Ok(written)
-        let num_blocks = (buf.len() + BLOCK_SIZE as usize- 1) / BLOCK_SIZE as usize;
-        let blk_offset = self.get_block_offset(offset);
-
-        let block_buf = get_block_buffer(self.get_raw_offset(offset), BLOCK_SIZE);
-        let mut cursor = Cursor::new(block_buf);
+        self.check_writable()?;
+        self.check_bounds(offset, buf.len() as u64)?;
+        self.invalidate_cached_entries(offset, buf.len() as u64);
+        let mut cursor = Cursor::new(vec![0; BLOCK_SIZE as usize]);
         let mut start = 0;
 
-        for _i in 0..num_blocks {
-            self.seek_to_block(offset)?;
-            self.disk.borrow_mut().read_exact(cursor.get_mut())?;
-            cursor.seek(SeekFrom::Start(blk_offset))?;
-
+        while start < buf.len() {
+            let blk_offset = self.get_block_offset(offset);
             let bytes_remaining_block = BLOCK_SIZE - blk_offset;
             let write_len = min(bytes_remaining_block as usize, buf.len() - start);
-            cursor.write(&buf[start .. start + write_len])?;
+
+            // Whole blocks are written straight from the buffer
+            if write_len as u64 == BLOCK_SIZE {
+                self.write_block(offset, &buf[start .. start + write_len])?;
+                start += write_len;
+                offset += write_len as u64;
+                continue;
+            }
+
+            self.read_block(offset, cursor.get_mut())?;
+            cursor.seek(SeekFrom::Start(blk_offset))?;
+            cursor.write_all(&buf[start .. start + write_len])?;
+
+            // Write back the block before moving on to the next one
+            self.write_block(offset, cursor.get_ref())?;
             start += write_len;
             offset += write_len as u64;
-
-            self.seek_to_block(offset)?;
-            self.disk.borrow_mut().write(cursor.get_ref())?;
         }
 
         Ok(start)
@@ -372,9 +923,35 @@ impl<D: Read + Write + Seek> FileSystem<D> {
     }
 
     pub fn zero_cluster(&mut self, cluster: Cluster) -> Result<()> {
-        let zeroes = vec![0; self.bytes_per_cluster() as usize];
         let offset = self.cluster_offset(cluster);
-        self.write_to(offset, zeroes.as_slice())?;
+        let len = self.bytes_per_cluster();
+        self.zero_range(offset, len)
+    }
+
+    /// Zeroes `len` bytes at `offset`, a block at a time
+    pub fn zero_range(&mut self, mut offset: u64, len: u64) -> Result<()> {
+        let end = offset + len;
+        self.invalidate_cached_entries(offset, len);
+        while offset < end {
+            // Stay within a block so that aligned chunks skip the read
+            let chunk = min(BLOCK_SIZE - self.get_block_offset(offset), end - offset);
+            self.write_to(offset, &ZEROES[..chunk as usize])?;
+            offset += chunk;
+        }
+        Ok(())
+    }
+
+    /// Moves the bytes from `start` to `end` up by `shift`, a chunk at a time from the top
+    /// down so that overlapping ranges are copied intact
+    pub fn move_range_up(&mut self, start: u64, mut end: u64, shift: u64) -> Result<()> {
+        let mut buf = vec![0; MOVE_CHUNK as usize];
+        while end > start {
+            let len = min(MOVE_CHUNK, end - start);
+            let chunk = &mut buf[..len as usize];
+            self.read_at(end - len, chunk)?;
+            self.write_to(end - len + shift, chunk)?;
+            end -= len;
+        }
         Ok(())
     }
 
@@ -437,7 +1014,8 @@ impl<D: Read + Write + Seek> FileSystem<D> {
                     dir_name: String::from("/"),
                     root_offset: None,
                     short_dir_entry: None,
-                    loc: None
+                    loc: None,
+                    parent_dir: Cluster::default()
                 }
             },
             _ => {
@@ -447,7 +1025,8 @@ impl<D: Read + Write + Seek> FileSystem<D> {
                     dir_name: String::from("/"),
                     root_offset: Some(self.root_dir_offset()),
                     short_dir_entry: None,
-                    loc: None
+                    loc: None,
+                    parent_dir: Cluster::default()
                 }
             }
         }
@@ -467,7 +1046,8 @@ impl<D: Read + Write + Seek> FileSystem<D> {
     pub fn mirroring_enabled(&self) -> bool {
         match self.bpb.fat_type {
             FATType::FAT32(s) => s.ext_flags & 0x80 == 0,
-            _ => false
+            // FAT12 and FAT16 always keep every copy in sync
+            _ => true
         }
     }
 
@@ -483,16 +1063,172 @@ impl<D: Read + Write + Seek> FileSystem<D> {
         }
     }
 
+    pub fn ext_flags(&self) -> Option<u16> {
+        match self.bpb.fat_type {
+            FATType::FAT32(s) => Some(s.ext_flags),
+            _ => None
+        }
+    }
+
+    /// Makes FAT copy `n` the only active FAT, disabling mirroring
+    /// Refused if the copy does not look like a valid FAT
+    pub fn set_active_fat(&mut self, n: u8) -> Result<()> {
+        self.sync_fat_mirrors()?;
+        let flags = self.ext_flags().ok_or(Error::new(ErrorKind::Other, "ext_flags are only present on FAT32 volumes"))?;
+        if n >= self.bpb.num_fats {
+            return Err(Error::new(ErrorKind::InvalidInput, "FAT copy does not exist"));
+        }
+        if !self.fat_copy_consistent(n as u64)? {
+            return Err(Error::new(ErrorKind::InvalidData, "FAT copy is inconsistent"));
+        }
+        self.write_ext_flags((flags & !0x008F) | 0x0080 | n as u16)
+    }
+
+    /// Enables or disables runtime mirroring of the active FAT into all copies
+    /// When enabling, the active FAT is first copied over every other copy
+    pub fn set_mirroring(&mut self, enabled: bool) -> Result<()> {
+        self.sync_fat_mirrors()?;
+        let flags = self.ext_flags().ok_or(Error::new(ErrorKind::Other, "ext_flags are only present on FAT32 volumes"))?;
+        if enabled {
+            let active = self.active_fat();
+            if !self.fat_copy_consistent(active)? {
+                return Err(Error::new(ErrorKind::InvalidData, "Active FAT is inconsistent"));
+            }
+            self.copy_fat(active)?;
+            self.write_ext_flags(flags & !0x008F)
+        } else {
+            let active = self.active_fat() as u16;
+            self.write_ext_flags((flags & !0x008F) | 0x0080 | active)
+        }
+    }
+
+    /// Records that `len` bytes at `offset` in the active FAT were written to it alone
+    pub fn mark_fat_mirrors_stale(&mut self, offset: u64, len: u64) -> Result<()> {
+        let bps = self.bytes_per_sec();
+        let fat_start = self.fat_start_sector() * bps;
+        for sector in (offset - fat_start) / bps..=(offset - fat_start + len - 1) / bps {
+            self.stale_fat_sectors.insert(sector);
+        }
+        if self.stale_fat_sectors.len() > Self::MAX_STALE_FAT_SECTORS {
+            self.sync_fat_mirrors()?;
+        }
+        Ok(())
+    }
+
+    /// Copies the sectors of the active FAT changed under `lazy_fat_mirroring` to the other copies
+    pub fn sync_fat_mirrors(&mut self) -> Result<()> {
+        let bps = self.bytes_per_sec();
+        let fat_bytes = self.fat_size() * bps;
+        let fat_start = self.fat_start_sector() * bps;
+        let mut buf = vec![0; bps as usize];
+        let sectors: Vec<u64> = self.stale_fat_sectors.iter().cloned().collect();
+        for sector in sectors {
+            self.read_at(fat_start + sector * bps, &mut buf)?;
+            for i in 1..self.bpb.num_fats as u64 {
+                self.write_to(fat_start + i * fat_bytes + sector * bps, &buf)?;
+            }
+            self.stale_fat_sectors.remove(&sector);
+        }
+        Ok(())
+    }
+
+    // Writes ext_flags to the boot sector and its backup
+    fn write_ext_flags(&mut self, flags: u16) -> Result<()> {
+        let bk_boot_sec = match self.bpb.fat_type {
+            FATType::FAT32(ref mut s) => {
+                s.ext_flags = flags;
+                s.bk_boot_sec as u64
+            },
+            _ => return Err(Error::new(ErrorKind::Other, "ext_flags are only present on FAT32 volumes"))
+        };
+        // Another copy may be the active FAT now
+        self.restart_free_map();
+        let mut buf = [0u8; 2];
+        LittleEndian::write_u16(&mut buf, flags);
+        self.write_to(Self::EXT_FLAGS_OFFSET, &buf)?;
+        if bk_boot_sec != 0 {
+            let offset = bk_boot_sec * self.bytes_per_sec() + Self::EXT_FLAGS_OFFSET;
+            self.write_to(offset, &buf)?;
+        }
+        self.disk.borrow_mut().flush()
+    }
+
+    fn read_fat_copy(&mut self, n: u64) -> Result<Vec<u8>> {
+        let fat_bytes = self.fat_size() * self.bytes_per_sec();
+        let offset = (self.bpb.rsvd_sec_cnt as u64 + n * self.fat_size()) * self.bytes_per_sec();
+        let mut buf = vec![0; fat_bytes as usize];
+        self.read_at(offset, buf.as_mut_slice())?;
+        Ok(buf)
+    }
+
+    // Copies FAT copy `src` over all the other copies
+    fn copy_fat(&mut self, src: u64) -> Result<()> {
+        let buf = self.read_fat_copy(src)?;
+        for i in 0..self.bpb.num_fats as u64 {
+            if i != src {
+                let offset = (self.bpb.rsvd_sec_cnt as u64 + i * self.fat_size()) * self.bytes_per_sec();
+                self.write_to(offset, buf.as_slice())?;
+            }
+        }
+        Ok(())
+    }
+
+    // A FAT32 copy is consistent if the media byte matches the BPB and
+    // every entry is free, bad, end of chain or a valid cluster number
+    fn fat_copy_consistent(&mut self, n: u64) -> Result<bool> {
+        let buf = self.read_fat_copy(n)?;
+        let max_cluster = self.max_cluster_number().cluster_number;
+        if buf[0] != self.bpb.media {
+            return Ok(false)
+        }
+        let entries = min(buf.len() as u64 / 4, max_cluster + 1);
+        for i in RESERVED_CLUSTERS..entries {
+            let val = LittleEndian::read_u32(&buf[(i * 4) as usize..]) as u64 & 0x0FFFFFFF;
+            match val {
+                0 | 0x0FFFFFF7 | 0x0FFFFFF8..=0x0FFFFFFF => {},
+                n if n >= RESERVED_CLUSTERS && n <= max_cluster => {},
+                _ => return Ok(false)
+            }
+        }
+        Ok(true)
+    }
+
+    /// Free cluster count from FSInfo or a complete free map, counting free FAT entries
+    /// if neither knows it
+    pub fn free_clusters(&mut self) -> Result<u64> {
+        let max_cluster = self.max_cluster_number();
+        let free_count = self.fs_info.borrow().get_free_count(max_cluster);
+        let mapped = self.free_map.as_ref().and_then(|m| if m.is_complete() { Some(m.progress().free) } else { None });
+        match (free_count, mapped) {
+            (Some(n), _) => Ok(n),
+            (None, Some(n)) => {
+                self.fs_info.borrow_mut().update_free_count(n);
+                Ok(n)
+            },
+            (None, None) => get_free_count(self, max_cluster)
+        }
+    }
+
+    /// Bytes in free clusters, see `free_clusters`
+    pub fn bytes_free(&mut self) -> Result<u64> {
+        Ok(self.free_clusters()? * self.bytes_per_cluster())
+    }
+
+    /// Bytes in all the data clusters, free or not
+    pub fn bytes_total(&self) -> u64 {
+        (self.max_cluster_number().cluster_number + 1 - RESERVED_CLUSTERS) * self.bytes_per_cluster()
+    }
+
     pub fn max_cluster_number(&self) -> Cluster {
         match self.bpb.fat_type {
             FATType::FAT32(s) => {
-                let data_sec = self.bpb.total_sectors_32 as u64 - (self.bpb.rsvd_sec_cnt as u64 + (self.bpb.num_fats as u64 * s.fat_size as u64));
+                let data_sec = self.bpb.total_sectors().saturating_sub(self.bpb.rsvd_sec_cnt as u64 + (self.bpb.num_fats as u64 * s.fat_size as u64));
                 let tot_clusters = data_sec / self.bpb.sectors_per_cluster as u64;
                 Cluster::new(tot_clusters + RESERVED_CLUSTERS - 1)
             },
             _ => {
                 let root_dir_sectors = ((self.bpb.root_entries_cnt as u64 * 32) + self.bytes_per_sec() - 1) / self.bytes_per_sec();
-                let data_sec = self.bpb.total_sectors_16 as u64 - (self.bpb.rsvd_sec_cnt as u64 + (self.bpb.num_fats as u64 * self.bpb.fat_size_16 as u64) + root_dir_sectors);
+                let data_sec = self.bpb.total_sectors().saturating_sub(self.bpb.rsvd_sec_cnt as u64 + (self.bpb.num_fats as u64 * self.bpb.fat_size_16 as u64) + root_dir_sectors);
                 let tot_clusters = data_sec / self.bpb.sectors_per_cluster as u64;
                 Cluster::new(tot_clusters + RESERVED_CLUSTERS - 1)
             }
@@ -558,6 +1294,22 @@ impl<D: Read + Write + Seek> FileSystem<D> {
         }
     }
 
+    /// Records a hard error by clearing the hard error bit, which is set while
+    /// the volume has none
+    pub fn mark_hard_error(&mut self) -> Result<()> {
+        match self.bpb.fat_type {
+            FATType::FAT32(_) => {
+                let raw_entry = get_entry_raw(self, Cluster::new(1))? & !0x04000000;
+                set_entry(self, Cluster::new(1), FatEntry::Next(Cluster::new(raw_entry)))
+            },
+            FATType::FAT16(_) => {
+                let raw_entry = get_entry_raw(self, Cluster::new(1))? & !0x4000;
+                set_entry(self, Cluster::new(1), FatEntry::Next(Cluster::new(raw_entry)))
+            },
+            _ => Ok(())
+        }
+    }
+
     pub fn set_hard_error_bit(&mut self) -> Result<()> {
         match self.bpb.fat_type {
             FATType::FAT32(_) => {
@@ -574,7 +1326,35 @@ impl<D: Read + Write + Seek> FileSystem<D> {
         }
     }
 
+    /// Recounts the free clusters and the next free hint from the FAT and writes FSInfo back
+    pub fn rebuild_fsinfo(&mut self) -> Result<()> {
+        self.check_writable()?;
+        let max_cluster = self.max_cluster_number();
+        let count = get_free_count(self, max_cluster)?;
+        let next_free = match get_free_cluster(self, Cluster::new(RESERVED_CLUSTERS), max_cluster) {
+            Ok(c) => c.cluster_number,
+            Err(_) => 0xFFFFFFFF
+        };
+
+        let mut fs_info = self.fs_info.borrow_mut();
+        fs_info.update_free_count(count);
+        fs_info.update_next_free(next_free);
+        fs_info.flush(self.disk.get_mut())?;
+        fs_info.dirty = false;
+        Ok(())
+    }
+
     pub fn unmount(&mut self) -> Result<()> {
+        if self.read_only {
+            return Ok(())
+        }
+        if self.write_protected {
+            return self.release_protected_lock()
+        }
+        self.sync_fat_mirrors()?;
+        if self.fs_info.borrow().needs_rebuild() {
+            self.rebuild_fsinfo()?;
+        }
         self.fs_info.borrow_mut().flush(self.disk.get_mut())?;
         self.set_clean_shut_bit()?;
         self.set_hard_error_bit()?;
@@ -582,6 +1362,20 @@ impl<D: Read + Write + Seek> FileSystem<D> {
         Ok(())
     }
 
+    // Everything else was written out when write protection was turned on, so only the
+    // lock is released, leaving the volume usable by the next instance
+    fn release_protected_lock(&mut self) -> Result<()> {
+        if !self.in_use()? {
+            return Ok(())
+        }
+        self.write_protected = false;
+        let res = self.set_clean_shut_bit()
+            .and_then(|_| self.sync_fat_mirrors())
+            .and_then(|_| self.disk.borrow_mut().flush());
+        self.write_protected = true;
+        res
+    }
+
     //pub fn flush()
 
 }
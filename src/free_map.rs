@@ -0,0 +1,181 @@
+use std::cmp::{min, max};
+use std::io::{Read, Write, Seek};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use bpb::FATType;
+use filesystem::{FileSystem, Cluster};
+use table::{get_entry, FatEntry, RESERVED_CLUSTERS};
+
+use super::Result;
+
+/// How far `FileSystem::scan_free_map` got
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct FreeMapProgress {
+    /// Data clusters scanned so far and in all
+    pub scanned: u64,
+    pub total: u64,
+    /// Free clusters among those scanned
+    pub free: u64
+}
+
+/// Which data clusters are free, for the allocator to take from without reading the FAT
+///
+/// The map is built up from the FAT some entries at a time, see `FileSystem::scan_free_map`,
+/// and covers the clusters from 2 up to `scanned`, one bit each set when free. `set_entry`
+/// keeps the covered part up to date.
+pub struct FreeMap {
+    bits: Vec<u64>,
+    scanned: u64,
+    end: u64,
+    free: u64
+}
+
+impl FreeMap {
+    /// An empty map of the clusters below `end`
+    pub fn new(end: u64) -> FreeMap {
+        FreeMap {
+            bits: vec![0; ((end + 63) / 64) as usize],
+            scanned: RESERVED_CLUSTERS,
+            end: end,
+            free: 0
+        }
+    }
+
+    /// The clusters below this one are covered
+    pub fn scanned(&self) -> u64 {
+        self.scanned
+    }
+
+    pub fn end(&self) -> u64 {
+        self.end
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.scanned >= self.end
+    }
+
+    pub fn progress(&self) -> FreeMapProgress {
+        FreeMapProgress {
+            scanned: self.scanned - RESERVED_CLUSTERS,
+            total: self.end.saturating_sub(RESERVED_CLUSTERS),
+            free: self.free
+        }
+    }
+
+    fn is_free(&self, cluster: u64) -> bool {
+        self.bits[(cluster / 64) as usize] & (1 << (cluster % 64)) != 0
+    }
+
+    /// Records the state of the first cluster not covered yet
+    pub fn push(&mut self, free: bool) {
+        if self.is_complete() {
+            return;
+        }
+        if free {
+            self.bits[(self.scanned / 64) as usize] |= 1 << (self.scanned % 64);
+            self.free += 1;
+        }
+        self.scanned += 1;
+    }
+
+    /// Records that `cluster` was freed or taken, if it is covered
+    pub fn set(&mut self, cluster: u64, free: bool) {
+        if cluster < RESERVED_CLUSTERS || cluster >= self.scanned || self.is_free(cluster) == free {
+            return;
+        }
+        self.bits[(cluster / 64) as usize] ^= 1 << (cluster % 64);
+        if free {
+            self.free += 1;
+        } else {
+            self.free -= 1;
+        }
+    }
+
+    /// The first free covered cluster from `from` on, wrapping around to cluster 2
+    pub fn first_free(&self, from: u64) -> Option<u64> {
+        let from = max(from, RESERVED_CLUSTERS);
+        self.find(from, self.scanned).or_else(|| self.find(RESERVED_CLUSTERS, min(from, self.scanned)))
+    }
+
+    fn find(&self, start: u64, end: u64) -> Option<u64> {
+        let mut cluster = start;
+        while cluster < end {
+            let word = self.bits[(cluster / 64) as usize] >> (cluster % 64);
+            if word == 0 {
+                cluster = (cluster / 64 + 1) * 64;
+                continue;
+            }
+            let found = cluster + word.trailing_zeros() as u64;
+            return if found < end { Some(found) } else { None };
+        }
+        None
+    }
+}
+
+impl<D: Read + Write + Seek> FileSystem<D> {
+    /// Reads up to `clusters` more FAT entries into the free map, see `start_free_map`
+    ///
+    /// Meant to be called between operations after mounting, so the first allocations on a
+    /// large volume don't wait for the FAT to be searched. Returns None without a map.
+    pub fn scan_free_map(&mut self, clusters: u64) -> Result<Option<FreeMapProgress>> {
+        let (start, end) = match self.free_map() {
+            Some(m) => (m.scanned(), min(m.scanned().saturating_add(clusters), m.end())),
+            None => return Ok(None)
+        };
+        if start < end {
+            let entry_bits = match self.bpb.fat_type {
+                FATType::FAT12(_) => 12,
+                FATType::FAT16(_) => 16,
+                FATType::FAT32(_) => 32
+            };
+            let first_byte = start * entry_bits / 8;
+            let mut fat = vec![0; ((end * entry_bits + 7) / 8 - first_byte) as usize];
+            let offset = self.fat_start_sector() * self.bytes_per_sec() + first_byte;
+            self.read_at(offset, &mut fat)?;
+
+            if let Some(map) = self.free_map_mut() {
+                for cluster in start..end {
+                    let i = (cluster * entry_bits / 8 - first_byte) as usize;
+                    let val = match entry_bits {
+                        12 => {
+                            let packed = LittleEndian::read_u16(&fat[i..]) as u64;
+                            if cluster & 1 > 0 { packed >> 4 } else { packed & 0x0fff }
+                        },
+                        16 => LittleEndian::read_u16(&fat[i..]) as u64,
+                        _ => LittleEndian::read_u32(&fat[i..]) as u64 & 0x0fffffff
+                    };
+                    map.push(val == 0);
+                }
+            }
+        }
+        Ok(self.free_map_progress())
+    }
+
+    pub fn free_map_progress(&self) -> Option<FreeMapProgress> {
+        self.free_map().map(|m| m.progress())
+    }
+
+    /// A free cluster from the free map, searched from `from` on and checked against the FAT
+    ///
+    /// None if there is no map or every cluster it covers is taken.
+    pub fn free_map_cluster(&mut self, from: u64) -> Result<Option<Cluster>> {
+        loop {
+            let found = match self.free_map() {
+                Some(m) => m.first_free(from),
+                None => None
+            };
+            let cluster = match found {
+                Some(c) => Cluster::new(c),
+                None => return Ok(None)
+            };
+            if get_entry(self, cluster)? == FatEntry::Unused {
+                return Ok(Some(cluster));
+            }
+            // Taken other than through set_entry
+            if let Some(map) = self.free_map_mut() {
+                map.set(cluster.cluster_number, false);
+            }
+        }
+    }
+}
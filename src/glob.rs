@@ -0,0 +1,72 @@
+use std::io::{Read, Write, Seek, Error, ErrorKind};
+
+use filesystem::FileSystem;
+use dir_entry::{Dir, DirEntry};
+
+use super::Result;
+
+impl Dir {
+    /// Entries below this directory matching `pattern`, in directory order
+    ///
+    /// Each component of the '/' separated pattern may use the DOS wildcards `*` for any
+    /// run of characters and `?` for a single one. Both the long and the short name of an
+    /// entry are matched, ignoring case unless the volume is case sensitive. As in DOS,
+    /// "*.*" matches names without an extension too.
+    pub fn glob<D: Read + Write + Seek>(&self, fs: &mut FileSystem<D>, pattern: &str) -> Result<Vec<DirEntry>> {
+        let parts: Vec<&str> = pattern.split('/').filter(|p| !p.is_empty()).collect();
+        if parts.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Empty pattern"))
+        }
+        let mut matches = Vec::new();
+        glob_dir(self, &parts, fs, &mut matches);
+        Ok(matches)
+    }
+}
+
+fn glob_dir<D: Read + Write + Seek>(dir: &Dir, parts: &[&str], fs: &mut FileSystem<D>, matches: &mut Vec<DirEntry>) {
+    let case_sensitive = fs.case_sensitive;
+    let entries: Vec<DirEntry> = dir.to_iter(fs).filter(|e| {
+        let name = e.name();
+        !e.is_vol_id() && name != "." && name != ".." &&
+            (wildcard_match(parts[0], &name, case_sensitive) ||
+                (!case_sensitive && wildcard_match(parts[0], &e.short_name(), false)))
+    }).collect();
+
+    for e in entries {
+        if parts.len() == 1 {
+            matches.push(e);
+        } else if e.is_dir() {
+            glob_dir(&e.to_dir(), &parts[1..], fs, matches);
+        }
+    }
+}
+
+/// Whether `name` matches a single pattern component with `*` and `?` wildcards
+pub fn wildcard_match(pattern: &str, name: &str, case_sensitive: bool) -> bool {
+    let pattern = if pattern == "*.*" { "*" } else { pattern };
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let char_matches = |p: char, n: char| {
+        p == '?' || p == n || (!case_sensitive && p.to_uppercase().eq(n.to_uppercase()))
+    };
+
+    // Greedy matching, backtracking to the last '*' on a mismatch
+    let (mut p, mut n) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if p < pattern.len() && char_matches(pattern[p], name[n]) {
+            p += 1;
+            n += 1;
+        } else if let Some((star_p, star_n)) = star {
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
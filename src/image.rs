@@ -0,0 +1,156 @@
+use std::cmp::min;
+use std::io::{Read, Write, Seek, SeekFrom, Error, ErrorKind};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use filesystem::{FileSystem, Cluster};
+use table::RESERVED_CLUSTERS;
+use usage::ClusterState;
+
+use super::Result;
+
+const IMAGE_MAGIC: &[u8; 8] = b"FATIMG01";
+/// Most bytes in one chunk of an image
+const IMAGE_CHUNK: u64 = 64 * 1024;
+/// Offset of the record closing an image
+const END_OF_IMAGE: u64 = u64::max_value();
+const RECORD_HEADER_LEN: u64 = 12;
+const IMAGE_HEADER_LEN: u64 = 16;
+
+/// How far `FileSystem::image_used_blocks` got, passed on after every chunk
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ImageProgress {
+    /// Volume offset the next chunk starts at, where an interrupted image is resumed from
+    pub offset: u64,
+    /// Bytes of the volume copied so far and to copy in all
+    pub copied: u64,
+    pub total: u64,
+    /// Bytes of the image written so far, all of them complete chunks
+    pub image_len: u64
+}
+
+impl<D: Read + Write + Seek> FileSystem<D> {
+    /// Writes the volume to `w`, leaving out free and bad clusters
+    ///
+    /// The image holds the reserved sectors, FATs and root directory and every allocated
+    /// cluster, in chunks of up to 64KiB tagged with their offset. `progress` is called
+    /// after each chunk, see `image_used_blocks_from` to resume from there. Lazily mirrored
+    /// FATs and FSInfo are written out first, so the image is consistent.
+    pub fn image_used_blocks<W: Write, F: FnMut(&ImageProgress)>(&mut self, w: &mut W, progress: F) -> Result<ImageProgress> {
+        let mut header = [0; IMAGE_HEADER_LEN as usize];
+        header[..8].copy_from_slice(IMAGE_MAGIC);
+        LittleEndian::write_u64(&mut header[8..], self.partition_len);
+        w.write_all(&header)?;
+        self.write_image_chunks(w, 0, IMAGE_HEADER_LEN, progress)
+    }
+
+    /// Continues an image cut short, with the chunks from volume offset `resume` on
+    ///
+    /// `resume` and `image_len` are those of the last progress report. Truncate the image
+    /// to `image_len` and append what this writes, the volume must not have changed since.
+    pub fn image_used_blocks_from<W: Write, F: FnMut(&ImageProgress)>(&mut self, w: &mut W, resume: u64, image_len: u64,
+                                                                       progress: F) -> Result<ImageProgress> {
+        self.write_image_chunks(w, resume, image_len, progress)
+    }
+
+    fn write_image_chunks<W: Write, F: FnMut(&ImageProgress)>(&mut self, w: &mut W, resume: u64, image_len: u64,
+                                                              mut progress: F) -> Result<ImageProgress> {
+        if self.check_writable().is_ok() {
+            self.sync_fat_mirrors()?;
+            if self.fs_info.borrow().needs_rebuild() {
+                self.rebuild_fsinfo()?;
+            }
+            self.fs_info.borrow_mut().flush(self.disk.get_mut())?;
+            self.disk.borrow_mut().flush()?;
+        }
+
+        let regions = self.used_regions()?;
+        let mut state = ImageProgress {
+            offset: resume,
+            copied: regions.iter().map(|&(start, end)| min(end, resume).saturating_sub(start)).sum(),
+            total: regions.iter().map(|&(start, end)| end - start).sum(),
+            image_len: image_len
+        };
+        let mut buf = vec![0; IMAGE_CHUNK as usize];
+        for (start, end) in regions {
+            let mut offset = if start < resume { resume } else { start };
+            while offset < end {
+                let len = min(IMAGE_CHUNK, end - offset);
+                let chunk = &mut buf[..len as usize];
+                self.read_at(offset, chunk)?;
+                let mut record = [0; RECORD_HEADER_LEN as usize];
+                LittleEndian::write_u64(&mut record, offset);
+                LittleEndian::write_u32(&mut record[8..], len as u32);
+                w.write_all(&record)?;
+                w.write_all(chunk)?;
+
+                offset += len;
+                state.offset = offset;
+                state.copied += len;
+                state.image_len += RECORD_HEADER_LEN + len;
+                progress(&state);
+            }
+        }
+
+        let mut record = [0; RECORD_HEADER_LEN as usize];
+        LittleEndian::write_u64(&mut record, END_OF_IMAGE);
+        w.write_all(&record)?;
+        w.flush()?;
+        state.image_len += RECORD_HEADER_LEN;
+        Ok(state)
+    }
+
+    // Byte ranges of the metadata and of each run of allocated clusters, in order
+    fn used_regions(&mut self) -> Result<Vec<(u64, u64)>> {
+        let bpc = self.bytes_per_cluster();
+        let mut regions = vec![(0, self.first_data_sec * self.bytes_per_sec())];
+        let usage = self.usage_bitmap()?;
+        for (i, state) in usage.iter().enumerate() {
+            if state != ClusterState::Allocated {
+                continue
+            }
+            let start = self.cluster_offset(Cluster::new(i as u64 + RESERVED_CLUSTERS));
+            match regions.last_mut() {
+                Some(r) if r.1 == start => r.1 += bpc,
+                _ => regions.push((start, start + bpc))
+            }
+        }
+        Ok(regions)
+    }
+}
+
+/// Writes an image made by `FileSystem::image_used_blocks` to the partition at
+/// `partition_offset` of `disk`, returning the number of volume bytes written
+///
+/// Only what is in the image is written, the free clusters keep whatever `disk` held.
+/// Fails with InvalidData if `r` is not such an image and with UnexpectedEof if it ends early.
+pub fn restore_image<R: Read, D: Write + Seek>(r: &mut R, disk: &mut D, partition_offset: u64) -> Result<u64> {
+    let mut header = [0; IMAGE_HEADER_LEN as usize];
+    r.read_exact(&mut header)?;
+    if &header[..8] != IMAGE_MAGIC {
+        return Err(Error::new(ErrorKind::InvalidData, "Not a volume image"))
+    }
+    let volume_len = LittleEndian::read_u64(&header[8..]);
+
+    let mut buf = vec![0; IMAGE_CHUNK as usize];
+    let mut written = 0;
+    loop {
+        let mut record = [0; RECORD_HEADER_LEN as usize];
+        r.read_exact(&mut record)?;
+        let offset = LittleEndian::read_u64(&record);
+        if offset == END_OF_IMAGE {
+            break
+        }
+        let len = LittleEndian::read_u32(&record[8..]) as u64;
+        if len > IMAGE_CHUNK || offset.checked_add(len).map_or(true, |end| end > volume_len) {
+            return Err(Error::new(ErrorKind::InvalidData, "Image chunk out of range"))
+        }
+        let chunk = &mut buf[..len as usize];
+        r.read_exact(chunk)?;
+        disk.seek(SeekFrom::Start(partition_offset + offset))?;
+        disk.write_all(chunk)?;
+        written += len;
+    }
+    disk.flush()?;
+    Ok(written)
+}
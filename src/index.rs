@@ -0,0 +1,70 @@
+use std::io::{Read, Write, Seek};
+
+use filesystem::{FileSystem, Cluster};
+
+/// A file or directory of a `TreeIndex`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct IndexEntry {
+    /// Byte range of the path in the shared buffer, see `TreeIndex::path`
+    path_start: u32,
+    path_end: u32,
+    pub first_cluster: Cluster,
+    /// Always 0 for directories
+    pub size: u32,
+    /// Date and time of the last write as stored on disk, see `DirEntry::modified_raw`
+    pub modified: u32,
+    pub is_dir: bool
+}
+
+/// Every file and directory on the volume as it was when `FileSystem::snapshot_index`
+/// walked it, in the order of `Dir::walk`
+///
+/// The paths are kept in one buffer rather than a string each.
+#[derive(Clone, Debug, Default)]
+pub struct TreeIndex {
+    paths: String,
+    entries: Vec<IndexEntry>
+}
+
+impl TreeIndex {
+    pub fn entries(&self) -> &[IndexEntry] {
+        &self.entries
+    }
+
+    /// Absolute path of `entry`, without a trailing '/'
+    pub fn path(&self, entry: &IndexEntry) -> &str {
+        &self.paths[entry.path_start as usize..entry.path_end as usize]
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl<D: Read + Write + Seek> FileSystem<D> {
+    /// Walks the whole tree once and collects the path, size, write time and first
+    /// cluster of each entry, e.g. for a search index. Nothing is written to the volume
+    pub fn snapshot_index(&mut self) -> TreeIndex {
+        let mut index = TreeIndex::default();
+        let root = self.root_dir();
+        for e in root.walk(self) {
+            let path_start = index.paths.len() as u32;
+            index.paths.push_str(e.dir_path().trim_end_matches('/'));
+            index.entries.push(IndexEntry {
+                path_start: path_start,
+                path_end: index.paths.len() as u32,
+                first_cluster: e.first_cluster(),
+                size: e.size() as u32,
+                modified: e.modified_raw(),
+                is_dir: e.is_dir()
+            });
+        }
+        index.paths.shrink_to_fit();
+        index.entries.shrink_to_fit();
+        index
+    }
+}
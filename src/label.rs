@@ -0,0 +1,94 @@
+use std::io::{Read, Write, Seek, Error, ErrorKind};
+
+use bpb::FATType;
+use filesystem::FileSystem;
+
+use super::Result;
+
+/// Length of a volume label, padded with spaces
+const LABEL_LEN: usize = 11;
+
+impl<D: Read + Write + Seek> FileSystem<D> {
+    /// Offset of BS_VolLab in the boot sector
+    fn label_offset(&self) -> u64 {
+        match self.bpb.fat_type {
+            FATType::FAT32(_) => 71,
+            _ => 43
+        }
+    }
+
+    /// The label in the VOLUME_ID entry of the root directory without the padding, None
+    /// if there is no such entry
+    pub fn volume_label(&mut self) -> Option<String> {
+        let root = self.root_dir();
+        root.volume_id_entry(self).map(|f| {
+            String::from_utf8_lossy(&f.short_dir_entry.name_raw()).trim_end().to_string()
+        })
+    }
+
+    /// Sets the volume label, kept in the VOLUME_ID entry of the root directory and in
+    /// BS_VolLab of the boot sector and its backup
+    ///
+    /// Lowercase letters are stored uppercase, as Windows does. The entry is written first,
+    /// then the backup boot sector and the boot sector, and if a boot sector can't be written
+    /// the entry and the backup are put back, also when the failed write turned the volume
+    /// read-only.
+    /// Without the extended boot signature there is no BS_VolLab and only the entry is
+    /// written. Fails with InvalidInput on an empty label, one longer than 11 bytes or one
+    /// with characters short names can't hold.
+    pub fn set_volume_label(&mut self, label: &str) -> Result<()> {
+        self.check_writable()?;
+        let stored = stored_label(label)?;
+        let old = match self.bpb.fat_type {
+            FATType::FAT12(b) | FATType::FAT16(b) => b.volume_label,
+            FATType::FAT32(b) => b.volume_label
+        };
+        let in_boot_sector = self.bpb.volume_label().is_some();
+
+        self.with_entry_transaction(|fs| {
+            let root = fs.root_dir();
+            root.write_volume_id_entry(&stored, fs)?;
+            if in_boot_sector {
+                fs.write_boot_label(&stored, &old)?;
+            }
+            fs.disk.borrow_mut().flush()
+        })
+    }
+
+    // Writes `label` to BS_VolLab of the backup boot sector and then the boot sector,
+    // putting back `old` in the backup if the boot sector can't be written
+    fn write_boot_label(&mut self, label: &[u8; LABEL_LEN], old: &[u8; LABEL_LEN]) -> Result<()> {
+        let was_degraded = self.is_degraded();
+        let backup = self.backup_boot_sector().map(|s| s * self.bytes_per_sec() + self.label_offset());
+        if let Some(offset) = backup {
+            self.write_to(offset, label)?;
+        }
+        let offset = self.label_offset();
+        if let Err(e) = self.write_to(offset, label) {
+            if let Some(offset) = backup {
+                if let Err(e) = self.undo_failed_writes(was_degraded, |fs| fs.write_to(offset, old)) {
+                    warn!("Failed to put back the label in the backup boot sector: {}", e);
+                }
+            }
+            return Err(e)
+        }
+        match self.bpb.fat_type {
+            FATType::FAT12(ref mut b) | FATType::FAT16(ref mut b) => b.volume_label = *label,
+            FATType::FAT32(ref mut b) => b.volume_label = *label
+        }
+        Ok(())
+    }
+}
+
+// `label` uppercased and padded with spaces as it is stored
+fn stored_label(label: &str) -> Result<[u8; LABEL_LEN]> {
+    if label.is_empty() || label.len() > LABEL_LEN {
+        return Err(Error::new(ErrorKind::InvalidInput, "Volume labels are 1 to 11 characters long"));
+    }
+    if label.chars().any(|c| !c.is_ascii() || c < ' ' || c == '\x7f' || "\"*+,./:;<=>?[\\]|".contains(c)) {
+        return Err(Error::new(ErrorKind::InvalidInput, "Invalid character in volume label"));
+    }
+    let mut stored = [b' '; LABEL_LEN];
+    stored[..label.len()].copy_from_slice(label.to_ascii_uppercase().as_bytes());
+    Ok(stored)
+}
@@ -1,3 +1,14 @@
+//! FAT12/16/32 filesystem library and Redox scheme
+//!
+//! The supported API is the one in `prelude`, it follows semantic versioning: items are
+//! only removed or changed incompatibly when the major version (the minor one before 1.0)
+//! is bumped. `raw` exposes the on-disk structures and FAT helpers the library is built
+//! from, these follow the implementation and may change in any release.
+//!
+//! The contents of every internal module used to be re-exported at the crate root. Those
+//! re-exports are deprecated, kept behind the default "legacy-api" feature and will be
+//! removed in 0.2. Switch to `prelude` and, where low-level access is needed, `raw`.
+
 #![crate_type="lib"]
 #![allow(dead_code)]
 
@@ -11,22 +22,106 @@ extern crate hex;
 extern crate byteorder;
 #[macro_use]
 extern crate bitflags;
+#[cfg(feature = "trace")]
+extern crate tracing;
 
 use std::sync::atomic::AtomicUsize;
 pub static IS_UMT: AtomicUsize = AtomicUsize::new(0);
+/// Set to have the mount loop print its open resource table
+pub static DUMP_RESOURCES: AtomicUsize = AtomicUsize::new(0);
+/// Set to have the mount loop turn write protection on or off, see `FileSystem::set_write_protect`
+pub static TOGGLE_WRITE_PROTECT: AtomicUsize = AtomicUsize::new(0);
 pub type Result<T> = std::io::Result<T>;
 pub const BLOCK_SIZE: u64 = 4096;
 //pub use self::disk::{Disk, DiskCache, DiskFile};
-pub use self::mount::mount;
 
 mod bpb;
 mod filesystem;
 mod dir_entry;
 mod table;
 mod mount;
+mod check;
+mod overlay;
+mod glob;
+mod sort;
+mod metadata;
+mod stream;
+mod volume;
+mod partition;
+mod usage;
+mod resize;
+mod contiguous;
+mod entry_cache;
+mod transaction;
+mod boot;
+mod convert;
+mod image;
+mod free_map;
+mod relocate;
+mod copy;
+mod label;
+mod walk;
+mod index;
+mod clock;
+pub mod compat;
+#[cfg(feature = "archive")]
+mod archive;
 
 //pub use disk::*;
+
+/// Deprecated, use `prelude` and `raw` instead
+#[cfg(feature = "legacy-api")]
+#[doc(hidden)]
+pub use prelude::*;
+#[cfg(feature = "legacy-api")]
+#[doc(hidden)]
 pub use bpb::*;
+#[cfg(feature = "legacy-api")]
+#[doc(hidden)]
 pub use filesystem::*;
+#[cfg(feature = "legacy-api")]
+#[doc(hidden)]
 pub use dir_entry::*;
-pub use table::*;
\ No newline at end of file
+#[cfg(feature = "legacy-api")]
+#[doc(hidden)]
+pub use table::*;
+#[cfg(feature = "legacy-api")]
+#[doc(hidden)]
+pub use check::*;
+
+/// The stable API: opening volumes, working with files and directories, checking and mounting
+pub mod prelude {
+    pub use {Result, BLOCK_SIZE};
+    pub use filesystem::{FileSystem, Cluster, LfnCorruption, WriteVerification};
+    pub use dir_entry::{Dir, DirEntry, DirIter, File, FileAttributes, DeletedEntry, CreateOptions, ShortNames, NameTranslation};
+    pub use check::{check, CheckReport, FixPolicy, Problem};
+    pub use overlay::Overlay;
+    pub use glob::wildcard_match;
+    pub use sort::SortBy;
+    pub use metadata::Metadata;
+    pub use stream::{FileReader, FileWriter};
+    pub use volume::FatVolume;
+    pub use partition::{enumerate_fat_volumes, probe_fat, FatProbeInfo};
+    pub use usage::{UsageMap, ClusterState, RootEntries};
+    pub use boot::SectorDifference;
+    pub use image::{ImageProgress, restore_image};
+    pub use free_map::FreeMapProgress;
+    pub use copy::CopyProgress;
+    pub use walk::Walk;
+    pub use index::{TreeIndex, IndexEntry};
+    pub use clock::{Clock, SystemClock, FixedClock};
+    pub use mount::{mount, MountOptions, Features, FileScheme, Transport, OpenResource, ShutdownSummary, API_VERSION, CAPABILITIES, RESOURCES, STATS};
+    #[cfg(feature = "archive")]
+    pub use archive::ArchiveFormat;
+}
+
+/// On-disk structures and low-level helpers, without stability guarantees
+pub mod raw {
+    pub use bpb::*;
+    pub use filesystem::*;
+    pub use dir_entry::*;
+    pub use table::*;
+    pub use entry_cache::*;
+    pub use free_map::FreeMap;
+    pub use metadata::civil_from_days;
+}
\ No newline at end of file
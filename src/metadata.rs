@@ -0,0 +1,194 @@
+use std::io::{Read, Write, Seek, Error, ErrorKind};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use filesystem::{FileSystem, Cluster};
+use dir_entry::{Dir, DirEntry, FileAttributes};
+
+use super::Result;
+
+/// What `FileSystem::metadata` finds out about an entry, like `std::fs::Metadata`
+///
+/// Times are stored by FAT in local time without a zone, they are taken to be UTC.
+#[derive(Clone, Debug)]
+pub struct Metadata {
+    attributes: FileAttributes,
+    len: u64,
+    first_cluster: Cluster,
+    clusters: u64,
+    cluster_size: u64,
+    modified: u32,
+    created: u32,
+    accessed: u16
+}
+
+impl Metadata {
+    pub fn is_dir(&self) -> bool {
+        self.attributes.contains(FileAttributes::DIRECTORY)
+    }
+
+    pub fn is_file(&self) -> bool {
+        !self.is_dir()
+    }
+
+    /// Size of a file, or the space taken by the entries of a directory
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn readonly(&self) -> bool {
+        self.attributes.contains(FileAttributes::RD_ONLY)
+    }
+
+    pub fn attributes(&self) -> FileAttributes {
+        self.attributes
+    }
+
+    /// First cluster of the data, 0 for empty files and the FAT12/16 root directory
+    pub fn first_cluster(&self) -> Cluster {
+        self.first_cluster
+    }
+
+    /// Number of clusters in the chain of the entry
+    pub fn clusters(&self) -> u64 {
+        self.clusters
+    }
+
+    /// Bytes taken on the volume, the length rounded up to whole clusters unless the chain
+    /// is longer than the file needs
+    pub fn allocated_len(&self) -> u64 {
+        self.clusters * self.cluster_size
+    }
+
+    pub fn modified(&self) -> Result<SystemTime> {
+        fat_time((self.modified >> 16) as u16, self.modified as u16)
+    }
+
+    pub fn created(&self) -> Result<SystemTime> {
+        fat_time((self.created >> 16) as u16, self.created as u16)
+    }
+
+    /// Only the date of the last access is kept, this is midnight of that day
+    pub fn accessed(&self) -> Result<SystemTime> {
+        fat_time(self.accessed, 0)
+    }
+}
+
+impl DirEntry {
+    pub fn metadata<D: Read + Write + Seek>(&self, fs: &mut FileSystem<D>) -> Metadata {
+        let bpc = fs.bytes_per_cluster();
+        let (len, clusters) = if self.is_dir() {
+            let size = self.to_dir().size(fs);
+            (size, size / bpc)
+        } else if self.first_cluster().cluster_number == 0 {
+            (self.size(), 0)
+        } else {
+            (self.size(), fs.num_clusters_chain(self.first_cluster()))
+        };
+        Metadata {
+            attributes: self.attributes(),
+            len: len,
+            first_cluster: self.first_cluster(),
+            clusters: clusters,
+            cluster_size: bpc,
+            modified: self.modified_raw(),
+            created: self.created_raw(),
+            accessed: self.accessed_raw()
+        }
+    }
+}
+
+impl DirEntry {
+    /// Sets the time of the last write and the day of the last access, `None` keeps one
+    ///
+    /// Times are rounded down to the 2 seconds FAT keeps, they have to be within 1980 to 2107.
+    pub fn set_times<D: Read + Write + Seek>(&mut self, fs: &mut FileSystem<D>, modified: Option<SystemTime>, accessed: Option<SystemTime>) -> Result<()> {
+        let modified = match modified {
+            Some(t) => Some(to_fat_time(t).map(|(date, time)| (date as u32) << 16 | time as u32)?),
+            None => None
+        };
+        let accessed = match accessed {
+            Some(t) => Some(to_fat_time(t)?.0),
+            None => None
+        };
+        self.set_times_raw(fs, modified, accessed)
+    }
+
+    /// Sets the time of the last write and the day of the last access to the current
+    /// time of the volume's clock, see `FileSystem::set_clock`
+    pub fn touch<D: Read + Write + Seek>(&mut self, fs: &mut FileSystem<D>) -> Result<()> {
+        let now = fs.now();
+        self.set_times(fs, Some(now), Some(now))
+    }
+}
+
+impl<D: Read + Write + Seek> FileSystem<D> {
+    /// Metadata of the entry at the absolute `path`, without opening it
+    pub fn metadata(&mut self, path: &str) -> Result<Metadata> {
+        let e = Dir::get_entry_abs(path, self)?;
+        Ok(e.metadata(self))
+    }
+
+    /// Sets the times of the entry at the absolute `path`, see `DirEntry::set_times`
+    pub fn set_times(&mut self, path: &str, modified: Option<SystemTime>, accessed: Option<SystemTime>) -> Result<()> {
+        let mut e = Dir::get_entry_abs(path, self)?;
+        e.set_times(self, modified, accessed)
+    }
+}
+
+// Days between 1970-01-01 and the given date, from Howard Hinnant's days_from_civil.
+// Only used for years from 1980 on, so the era never goes negative
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y / 400;
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Year, month and day of a count of days since 1970-01-01 in the proleptic Gregorian
+/// calendar, Howard Hinnant's algorithm
+pub fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// The FAT date and time of `t`
+fn to_fat_time(t: SystemTime) -> Result<(u16, u16)> {
+    let secs = match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(_) => return Err(Error::new(ErrorKind::InvalidInput, "Time is before 1980"))
+    };
+    let (year, month, day) = civil_from_days(secs / 86400);
+    if year < 1980 || year > 2107 {
+        return Err(Error::new(ErrorKind::InvalidInput, "Time is outside of 1980 to 2107"));
+    }
+
+    let date = ((year - 1980) << 9 | month << 5 | day) as u16;
+    let secs_of_day = secs % 86400;
+    let time = ((secs_of_day / 3600) << 11 | (secs_of_day / 60 % 60) << 5 | (secs_of_day % 60) / 2) as u16;
+    Ok((date, time))
+}
+
+// A FAT date and time, 0 dates are left by implementations which don't keep the time
+fn fat_time(date: u16, time: u16) -> Result<SystemTime> {
+    let year = 1980 + (date >> 9) as i64;
+    let month = ((date >> 5) & 0xf) as i64;
+    let day = (date & 0x1f) as i64;
+    if date == 0 || month < 1 || month > 12 || day < 1 {
+        return Err(Error::new(ErrorKind::Other, "Time not recorded"));
+    }
+
+    let secs = days_from_civil(year, month, day) * 86400
+        + (time >> 11) as i64 * 3600 + ((time >> 5) & 0x3f) as i64 * 60 + (time & 0x1f) as i64 * 2;
+    Ok(UNIX_EPOCH + Duration::from_secs(secs as u64))
+}
@@ -3,13 +3,85 @@ use std::path::Path;
 use std::io::{Read, Write, Seek};
 
 use filesystem::FileSystem;
+use dir_entry::NameTranslation;
 
 //#[cfg(target_os = "redox")]
 mod redox;
 
+pub use self::redox::scheme::FileScheme;
+pub use self::redox::capabilities::{Features, API_VERSION, CAPABILITIES};
+pub use self::redox::debug::{OpenResource, ShutdownSummary, RESOURCES, STATS};
+pub use self::redox::transport::Transport;
+
+/// Limits and behaviour of a mounted filesystem
+#[derive(Copy, Clone, Debug, Default)]
+pub struct MountOptions {
+    /// Maximum number of open file descriptors, EMFILE once reached
+    pub max_open_files: Option<usize>,
+    /// Maximum bytes held by fmap buffers, ENOMEM once exceeded
+    pub max_fmap_memory: Option<usize>,
+    /// See `FileSystem::case_sensitive`
+    pub case_sensitive: bool,
+    /// Free clusters kept for the mount owner, other users get ENOSPC
+    /// on writes and creates once fewer than this many are free
+    pub reserved_clusters: Option<u64>,
+    /// Cache the directory entries of opened paths
+    pub path_cache: bool,
+    /// See `FileSystem::hide_dot_files`, hidden and system entries are also
+    /// left out of directory listings. They can still be opened by name
+    pub hide_dot_files: bool,
+    /// Leave entries with the SYSTEM attribute out of directory listings
+    pub hide_system: bool,
+    /// Leave entries with the HIDDEN attribute out of directory listings
+    pub hide_hidden: bool,
+    /// See `FileSystem::read_only_dirs`, creating, removing and renaming entries in
+    /// read-only directories fails with EPERM
+    pub read_only_dirs: bool,
+    /// See `FileSystem::short_names_only`, names without an 8.3 form get EINVAL
+    pub short_names_only: bool,
+    /// Attempts per block write, see `FileSystem::write_block`. Once a write fails
+    /// for good further writes return EROFS
+    pub write_attempts: Option<u32>,
+    /// List directories sorted by name instead of in on-disk order
+    pub sorted_listings: bool,
+    /// See `FileSystem::name_translation`, listings and paths show names percent
+    /// escaped by `NameTranslation::Percent` as they were given
+    pub name_translation: NameTranslation,
+    /// Write every FAT copy on each change instead of copying changed sectors over on
+    /// fsync and unmount, see `FileSystem::lazy_fat_mirroring`
+    pub strict_fat_mirroring: bool,
+    /// See `FileSystem::strict_cluster_bounds`, reads through a FAT entry
+    /// pointing outside the volume fail with EINVAL
+    pub strict_cluster_bounds: bool,
+    /// Derive the mode of each entry from its attributes instead of using the mount mode:
+    /// 0o755 for directories, 0o444 for files with RD_ONLY and 0o644 for other files.
+    /// Opening for writing checks the mode of the file
+    pub attribute_modes: bool,
+    /// Bits cleared from the modes derived with `attribute_modes`
+    pub umask: u16,
+    /// Bytes of small file contents kept in memory for repeated reads, none when unset
+    pub file_cache: Option<usize>,
+    /// Bytes of directory listings kept in memory for repeated opens, none when unset.
+    /// Listings over 64KiB are always read from the disk
+    pub listing_cache: Option<usize>,
+    /// Read FAT and directory entry blocks back after writing them, see
+    /// `FileSystem::verify_writes`. Counts show up in the "?stats" node
+    pub verify_writes: bool,
+    /// Directory entries kept parsed after listings, none when unset. See
+    /// `FileSystem::set_entry_cache`
+    pub entry_cache: Option<usize>,
+    /// Build the map of free clusters after mounting, reading this many FAT entries after
+    /// each request until it is complete. See `FileSystem::start_free_map`, progress shows
+    /// up in the "?stats" node
+    pub free_map_scan: Option<u64>,
+    /// Seconds a file size changed by writes may wait before it is written back to the short
+    /// entry between requests. Unset, it waits for fsync or close
+    pub flush_interval: Option<u64>
+}
 
 //#[cfg(target_os = "redox")]
-pub fn mount<D: Read + Write + Seek, P: AsRef<Path>, F: FnMut()>(filesystem: FileSystem<D>, mountpoint: &P, callback: F, mount_mode: u16, mount_uid: u32, mount_gid: u32) -> io::Result<()> {
-    redox::mount(filesystem, mountpoint, callback, mount_uid, mount_gid, mount_mode)
+pub fn mount<D: Read + Write + Seek, P: AsRef<Path>, F: FnMut()>(filesystem: FileSystem<D>, mountpoint: &P, callback: F, mount_mode: u16, mount_uid: u32, mount_gid: u32,
+                                                                    options: MountOptions) -> io::Result<()> {
+    redox::mount(filesystem, mountpoint, callback, mount_uid, mount_gid, mount_mode, options)
 }
 
@@ -0,0 +1,86 @@
+use std::cmp::min;
+
+use dir_entry::NameTranslation;
+use super::super::MountOptions;
+
+/// Duplicating any handle with this path opens the capabilities of the mount
+pub const CAPABILITIES: &[u8] = b"capabilities";
+
+/// Version of the wire formats read back from the scheme
+/// Version 1 directory listings are the entry names, one per line
+/// New formats are only served to clients which checked for them here
+pub const API_VERSION: u32 = 1;
+
+bitflags! {
+    #[derive(Default)]
+    pub struct Features: u32 {
+        /// Opening "?watch/<path>" queues events under path
+        const WATCH          = 0x01;
+        /// fmap and funmap of files
+        const FMAP           = 0x02;
+        /// Names are compared case sensitively
+        const CASE_SENSITIVE = 0x04;
+        /// Some space is kept for the mount owner
+        const RESERVED_SPACE = 0x08;
+        /// Names starting with '.' are created hidden and hidden entries are not listed
+        const HIDE_DOT_FILES = 0x10;
+        /// Directory listings are sorted by name
+        const SORTED_LISTINGS = 0x20;
+        /// Characters FAT doesn't allow in names are translated instead of refused
+        const NAME_TRANSLATION = 0x40;
+        /// System entries are not listed
+        const HIDE_SYSTEM = 0x80;
+        /// Hidden entries are not listed
+        const HIDE_HIDDEN = 0x100;
+    }
+}
+
+impl Features {
+    pub fn of_mount(options: &MountOptions) -> Features {
+        let mut features = Features::WATCH | Features::FMAP;
+        if options.case_sensitive {
+            features |= Features::CASE_SENSITIVE;
+        }
+        if options.reserved_clusters.is_some() {
+            features |= Features::RESERVED_SPACE;
+        }
+        if options.hide_dot_files {
+            features |= Features::HIDE_DOT_FILES;
+        }
+        if options.hide_system {
+            features |= Features::HIDE_SYSTEM;
+        }
+        if options.hide_hidden {
+            features |= Features::HIDE_HIDDEN;
+        }
+        if options.sorted_listings {
+            features |= Features::SORTED_LISTINGS;
+        }
+        if options.name_translation != NameTranslation::Reject {
+            features |= Features::NAME_TRANSLATION;
+        }
+        features
+    }
+}
+
+/// Read back as "version <API_VERSION>\nfeatures <hex bitmap>\n"
+pub struct Capabilities {
+    data: Vec<u8>,
+    pos: usize
+}
+
+impl Capabilities {
+    pub fn new(features: Features) -> Capabilities {
+        Capabilities {
+            data: format!("version {}\nfeatures {:x}\n", API_VERSION, features.bits()).into_bytes(),
+            pos: 0
+        }
+    }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let count = min(buf.len(), self.data.len() - self.pos);
+        buf[..count].copy_from_slice(&self.data[self.pos..self.pos + count]);
+        self.pos += count;
+        count
+    }
+}
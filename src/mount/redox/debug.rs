@@ -0,0 +1,57 @@
+use std::cmp::min;
+
+use syscall::error::Error;
+
+/// Duplicating any handle with this path opens a dump of the open resource table
+/// Only the mount owner may open it, paths of other users' files show up in it
+pub const RESOURCES: &[u8] = b"resources";
+
+/// Opening this path reads back the statistics of the mount, one "<name> <value>" per line
+/// '?' is not allowed in FAT names so this never shadows a real entry
+pub const STATS: &str = "?stats";
+
+/// An open file or directory, see `FileScheme::open_resources`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OpenResource {
+    pub id: usize,
+    /// Uid of the caller which opened it, None for handles made with dup
+    pub uid: Option<u32>,
+    pub flags: usize,
+    pub seek: u64,
+    /// First cluster and path of the entry, 0 and empty if it can't be told
+    pub cluster: u64,
+    pub path: String
+}
+
+/// What `FileScheme::shutdown` closed
+#[derive(Debug, Default)]
+pub struct ShutdownSummary {
+    /// Files and directories still open, closed as though their owners had
+    pub closed: Vec<OpenResource>,
+    /// Those among them whose changes failed to be written back, by id
+    pub failed: Vec<(usize, Error)>,
+    /// Watchers and capability and dump handles dropped
+    pub other_handles: usize
+}
+
+/// Text captured when the handle was opened, read back like a file
+pub struct Snapshot {
+    data: Vec<u8>,
+    pos: usize
+}
+
+impl Snapshot {
+    pub fn new(data: String) -> Snapshot {
+        Snapshot {
+            data: data.into_bytes(),
+            pos: 0
+        }
+    }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let count = min(buf.len(), self.data.len() - self.pos);
+        buf[..count].copy_from_slice(&self.data[self.pos..self.pos + count]);
+        self.pos += count;
+        count
+    }
+}
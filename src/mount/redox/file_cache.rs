@@ -0,0 +1,99 @@
+use std::cmp::min;
+use std::collections::BTreeMap;
+
+/// Largest file whose contents are cached, bigger ones are always read from the disk
+pub const MAX_CACHED_FILE: usize = 64 * 1024;
+
+/// Caches the whole contents of small files read through the scheme
+///
+/// Files are keyed by their first cluster, which stays the same when they are renamed.
+/// Each copy keeps the generation it was read under, see `Generations`, and is only
+/// served while the file is still at that generation. Once the cached bytes go over the
+/// capacity the least recently used files are dropped.
+pub struct FileCache {
+    capacity: usize,
+    used: usize,
+    tick: u64,
+    entries: BTreeMap<u64, CachedFile>,
+    hits: u64,
+    misses: u64
+}
+
+struct CachedFile {
+    generation: u64,
+    data: Vec<u8>,
+    last_used: u64
+}
+
+impl FileCache {
+    pub fn new(capacity: usize) -> FileCache {
+        FileCache {
+            capacity: capacity,
+            used: 0,
+            tick: 0,
+            entries: BTreeMap::new(),
+            hits: 0,
+            misses: 0
+        }
+    }
+
+    /// Size of the largest file worth caching
+    pub fn max_file(&self) -> usize {
+        min(MAX_CACHED_FILE, self.capacity)
+    }
+
+    /// The contents of the file at `cluster`, if they were read under `generation`
+    pub fn get(&mut self, cluster: u64, generation: u64) -> Option<&[u8]> {
+        self.tick += 1;
+        match self.entries.get_mut(&cluster) {
+            Some(file) if file.generation == generation => {
+                self.hits += 1;
+                file.last_used = self.tick;
+                Some(&file.data)
+            },
+            _ => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Keeps `data` as the contents of the file at `cluster`, read under `generation`
+    pub fn insert(&mut self, cluster: u64, generation: u64, data: Vec<u8>) {
+        if data.len() > self.max_file() {
+            return;
+        }
+        self.remove(cluster);
+        while self.used + data.len() > self.capacity {
+            let oldest = self.entries.iter().min_by_key(|&(_, f)| f.last_used).map(|(&c, _)| c);
+            match oldest {
+                Some(c) => self.remove(c),
+                None => break
+            }
+        }
+        self.tick += 1;
+        self.used += data.len();
+        self.entries.insert(cluster, CachedFile {
+            generation: generation,
+            data: data,
+            last_used: self.tick
+        });
+    }
+
+    /// Drops the contents of the file at `cluster`, which are stale once it changes
+    pub fn remove(&mut self, cluster: u64) {
+        if let Some(file) = self.entries.remove(&cluster) {
+            self.used -= file.data.len();
+        }
+    }
+
+    /// Bytes of file contents held
+    pub fn memory(&self) -> usize {
+        self.used
+    }
+
+    /// Number of reads served from the cache and from the disk
+    pub fn stats(&self) -> (u64, u64) {
+        (self.hits, self.misses)
+    }
+}
@@ -0,0 +1,52 @@
+use std::collections::BTreeMap;
+
+/// Counts the changes made to each file through the scheme
+///
+/// Files are keyed by their first cluster, which stays the same when they are renamed.
+/// The generation is bumped whenever a file is written, truncated, renamed or removed,
+/// whichever handle did it, so anything read from a file under an older generation is
+/// stale. Empty files all share cluster 0 and with it their generation.
+///
+/// Removed files are dropped, so that only the files still around are counted. Every
+/// generation is taken from one counter, and files without a count of their own are at
+/// the one taken by the last removal, so that no file's generation ever goes back.
+#[derive(Default)]
+pub struct Generations {
+    counts: BTreeMap<u64, u64>,
+    last: u64,
+    base: u64
+}
+
+impl Generations {
+    pub fn new() -> Generations {
+        Generations {
+            counts: BTreeMap::new(),
+            last: 0,
+            base: 0
+        }
+    }
+
+    pub fn get(&self, cluster: u64) -> u64 {
+        self.counts.get(&cluster).cloned().unwrap_or(self.base)
+    }
+
+    /// Bumps the generation of the file at `cluster`, returning the new one
+    pub fn bump(&mut self, cluster: u64) -> u64 {
+        self.last += 1;
+        self.counts.insert(cluster, self.last);
+        self.last
+    }
+
+    /// Drops the file at `cluster` once it was removed, its clusters may be taken by
+    /// another file which must not see what was read from this one
+    pub fn forget(&mut self, cluster: u64) {
+        self.counts.remove(&cluster);
+        self.last += 1;
+        self.base = self.last;
+    }
+
+    /// Files with a generation of their own
+    pub fn len(&self) -> usize {
+        self.counts.len()
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::BTreeMap;
+
+use dir_entry::DirEntry;
+use super::generation::Generations;
+
+/// Caches the directory entries of absolute paths opened through the scheme
+///
+/// Entries are keyed by the path as FAT would compare it, so "a.txt" and "A.TXT" share
+/// one entry holding the name as stored on disk. Each entry remembers the generation of
+/// its parent directory, which is bumped whenever something is created, removed or renamed
+/// in that directory, and that of the file itself, see `Generations`, so a size or first
+/// cluster changed through another handle is read again.
+pub struct PathCache {
+    case_sensitive: bool,
+    entries: BTreeMap<String, CachedEntry>,
+    generations: BTreeMap<String, u64>,
+    hits: u64,
+    misses: u64
+}
+
+struct CachedEntry {
+    entry: DirEntry,
+    parent_generation: u64,
+    file_generation: u64
+}
+
+impl PathCache {
+    pub fn new(case_sensitive: bool) -> PathCache {
+        PathCache {
+            case_sensitive: case_sensitive,
+            entries: BTreeMap::new(),
+            generations: BTreeMap::new(),
+            hits: 0,
+            misses: 0
+        }
+    }
+
+    fn key(&self, path: &str) -> String {
+        let path = path.trim_matches('/');
+        if self.case_sensitive {
+            path.to_string()
+        } else {
+            path.chars().flat_map(|c| c.to_uppercase()).collect()
+        }
+    }
+
+    fn generation(&self, key: &str) -> u64 {
+        let parent = key.rsplitn(2, '/').nth(1).unwrap_or("");
+        self.generations.get(parent).cloned().unwrap_or(0)
+    }
+
+    pub fn get(&mut self, path: &str, generations: &Generations) -> Option<DirEntry> {
+        let key = self.key(path);
+        let generation = self.generation(&key);
+        let entry = match self.entries.get(&key) {
+            Some(c) if c.parent_generation == generation &&
+                c.file_generation == generations.get(c.entry.first_cluster().cluster_number) => Some(c.entry.clone()),
+            _ => None
+        };
+        if entry.is_some() {
+            self.hits += 1;
+        } else {
+            self.misses += 1;
+        }
+        entry
+    }
+
+    pub fn insert(&mut self, path: &str, entry: DirEntry, generations: &Generations) {
+        let key = self.key(path);
+        let cached = CachedEntry {
+            parent_generation: self.generation(&key),
+            file_generation: generations.get(entry.first_cluster().cluster_number),
+            entry: entry
+        };
+        self.entries.insert(key, cached);
+    }
+
+    /// Replaces the entry of a path which is already cached, leaving others alone
+    pub fn update(&mut self, path: &str, entry: DirEntry, generations: &Generations) {
+        let key = self.key(path);
+        if let Some(cached) = self.entries.get_mut(&key) {
+            cached.file_generation = generations.get(entry.first_cluster().cluster_number);
+            cached.entry = entry;
+        }
+    }
+
+    /// Drops the entry of a single path, used once its size or clusters change
+    pub fn remove(&mut self, path: &str) {
+        let key = self.key(path);
+        self.entries.remove(&key);
+    }
+
+    /// Marks every cached child of the directory containing `path` as stale
+    pub fn invalidate_parent(&mut self, path: &str) {
+        let key = self.key(path);
+        let parent = key.rsplitn(2, '/').nth(1).unwrap_or("").to_string();
+        *self.generations.entry(parent).or_insert(0) += 1;
+    }
+
+    /// Drops `path` and everything below it, used when a directory is renamed or removed
+    pub fn invalidate_tree(&mut self, path: &str) {
+        let key = self.key(path);
+        let prefix = format!("{}/", key);
+        self.entries.retain(|k, _| *k != key && !k.starts_with(&prefix));
+        self.invalidate_parent(path);
+    }
+
+    /// Number of lookups served from the cache and from the disk
+    pub fn stats(&self) -> (u64, u64) {
+        (self.hits, self.misses)
+    }
+}
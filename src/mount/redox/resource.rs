@@ -1,16 +1,19 @@
 use std::cmp::{min, max};
-//use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::io::{Read, Write, Seek};
 
 use syscall::data::{Map, Stat, TimeSpec};
-use syscall::error::{Error, Result, EBADF, EINVAL, EISDIR, EPERM};
-use syscall::flag::{O_ACCMODE, O_RDONLY, O_WRONLY, O_RDWR, F_GETFL, F_SETFL, SEEK_SET, SEEK_CUR, SEEK_END};
+use syscall::error::{Error, Result, EBADF, EBUSY, EINVAL, EISDIR, EPERM, ESTALE};
+use syscall::flag::{O_ACCMODE, O_RDONLY, O_WRONLY, O_RDWR, F_GETFL, F_SETFL, SEEK_SET, SEEK_CUR, SEEK_END, PROT_EXEC, PROT_READ, PROT_WRITE};
 
 use filesystem::FileSystem;
 use dir_entry::{Dir, File, DirEntry};
+use metadata::Metadata;
 use super::result;
 
-use super::scheme::{Fmaps};
+use super::scheme::{Fmaps, FmapKey, FmapValue, PAGE_SIZE};
+use super::file_cache::FileCache;
+use super::generation::Generations;
 
 pub const MODE_TYPE: u16 = 0xF000;
 pub const MODE_FILE: u16 = 0x8000;
@@ -22,23 +25,51 @@ pub trait Resource<D: Read + Write + Seek> {
     fn get_dirent(&self) -> Result<DirEntry>;
     fn set_dirent(&mut self, dirent: DirEntry) -> Result<usize>;
     fn dup(&self) -> Result<Box<dyn Resource<D>>>;
-    fn read(&mut self, buf: &mut [u8], fs: &mut FileSystem<D>) -> Result<usize>;
+    /// Files may be served from `cache` while their generation matches, other resources ignore it
+    fn read(&mut self, buf: &mut [u8], cache: Option<&mut FileCache>, generations: &Generations, fs: &mut FileSystem<D>) -> Result<usize>;
     fn write(&mut self, buf: &[u8], fs: &mut FileSystem<D>) -> Result<usize>;
     fn seek(&mut self, offset: usize, whence: usize, fs: &mut FileSystem<D>) -> Result<usize>;
-    fn fmap(&mut self, map: &Map, maps: &mut Fmaps, fs: &mut FileSystem<D>) -> Result<usize>;
-    fn funmap(&mut self, maps: &mut Fmaps, fs: &mut FileSystem<D>) -> Result<usize>;
+    fn fmap(&mut self, map: &Map, maps: &mut Fmaps, generations: &Generations, fs: &mut FileSystem<D>) -> Result<usize>;
+    /// Writes back the mapping and releases it, even if writing back fails
+    fn funmap(&mut self, maps: &mut Fmaps, generations: &mut Generations, fs: &mut FileSystem<D>) -> Result<usize>;
     fn fchmod(&mut self, mode: u16, fs: &mut FileSystem<D>) -> Result<usize>;
     fn fchown(&mut self, uid: u32, gid: u32, fs: &mut FileSystem<D>) -> Result<usize>;
     fn fcntl(&mut self, cmd: usize, arg: usize) -> Result<usize>;
     fn path(&self, buf: &mut [u8]) -> Result<usize>;
     fn stat(&self, _stat: &mut Stat, fs: &mut FileSystem<D>) -> Result<usize>;
-    fn sync(&mut self, maps: &mut Fmaps, fs: &mut FileSystem<D>) -> Result<usize>;
+    /// Writing back a mapping bumps the generation of the file, a mapping of a file
+    /// changed since it was read fails with ESTALE instead
+    fn sync(&mut self, maps: &mut Fmaps, generations: &mut Generations, fs: &mut FileSystem<D>) -> Result<usize>;
+    /// Writes back the size and times of the short entry if they changed, leaving mappings alone
+    fn flush_entry(&mut self, fs: &mut FileSystem<D>) -> Result<usize>;
     fn truncate(&mut self, len: usize, fs: &mut FileSystem<D>) -> Result<usize>;
     fn utimens(&mut self, times: &[TimeSpec], uid: u32, fs: &mut FileSystem<D>) -> Result<usize>;
+    /// Flags given to open, shown in the resource table dump
+    fn flags(&self) -> usize;
+    /// Current seek offset, shown in the resource table dump
+    fn position(&self) -> u64;
+}
+
+// Seconds since the epoch, 0 when the time was never recorded
+fn unix_secs(time: ::Result<SystemTime>) -> u64 {
+    time.ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map_or(0, |d| d.as_secs())
+}
+
+// Fills in what `stat` takes from the entry itself, the ownership and mode come from the resource
+fn stat_metadata<D: Read + Write + Seek>(stat: &mut Stat, meta: &Metadata, fs: &FileSystem<D>) {
+    stat.st_size = meta.len();
+    stat.st_blksize = fs.bytes_per_cluster() as u32;
+    // In 512 byte blocks
+    stat.st_blocks = meta.allocated_len() / 512;
+    stat.st_mtime = unix_secs(meta.modified());
+    stat.st_atime = unix_secs(meta.accessed());
+    // FAT has no change time, the last write is the closest
+    stat.st_ctime = stat.st_mtime;
 }
 
 pub struct DirResource {
     dir: Dir,
+    flags: usize,
     data: Option<Vec<u8>>,
     seek: usize,
     uid: Option<u32>,
@@ -47,9 +78,10 @@ pub struct DirResource {
 }
 
 impl DirResource {
-    pub fn new(dir: Dir, data: Option<Vec<u8>>, uid: Option<u32>, gid: Option<u32>, mode: Option<u16>) -> DirResource {
+    pub fn new(dir: Dir, flags: usize, data: Option<Vec<u8>>, uid: Option<u32>, gid: Option<u32>, mode: Option<u16>) -> DirResource {
         DirResource {
             dir: dir,
+            flags: flags,
             data: data,
             seek: 0,
             uid: uid,
@@ -83,6 +115,7 @@ impl<D: Read + Write + Seek> Resource<D> for DirResource {
         Ok(Box::new(
            DirResource {
                dir: self.dir.clone(),
+               flags: self.flags,
                data: self.data.clone(),
                seek: self.seek,
                uid: self.uid.clone(),
@@ -92,7 +125,7 @@ impl<D: Read + Write + Seek> Resource<D> for DirResource {
         ))
     }
 
-    fn read(&mut self, buf: &mut [u8], _fs: &mut FileSystem<D>) -> Result<usize> {
+    fn read(&mut self, buf: &mut [u8], _cache: Option<&mut FileCache>, _generations: &Generations, _fs: &mut FileSystem<D>) -> Result<usize> {
         let data = self.data.as_ref().ok_or(Error::new(EISDIR))?;
         let mut i = 0;
         while i < buf.len() && self.seek < data.len() {
@@ -104,7 +137,7 @@ impl<D: Read + Write + Seek> Resource<D> for DirResource {
     }
 
     fn write(&mut self, _buf: &[u8], _fs: &mut FileSystem<D>) -> Result<usize> {
-        Err(Error::new(EBADF))
+        Err(Error::new(EISDIR))
     }
 
 
@@ -120,10 +153,10 @@ impl<D: Read + Write + Seek> Resource<D> for DirResource {
         Ok(self.seek)
     }
 
-    fn fmap(&mut self, _map: &Map, _maps: &mut Fmaps, _fs: &mut FileSystem<D>) -> Result<usize> {
-        Err(Error::new(EBADF))
+    fn fmap(&mut self, _map: &Map, _maps: &mut Fmaps, _generations: &Generations, _fs: &mut FileSystem<D>) -> Result<usize> {
+        Err(Error::new(EISDIR))
     }
-    fn funmap(&mut self, _maps: &mut Fmaps, _fs: &mut FileSystem<D>) -> Result<usize> {
+    fn funmap(&mut self, _maps: &mut Fmaps, _generations: &mut Generations, _fs: &mut FileSystem<D>) -> Result<usize> {
         Err(Error::new(EBADF))
     }
 
@@ -135,8 +168,15 @@ impl<D: Read + Write + Seek> Resource<D> for DirResource {
         Ok(0)
     }
 
-    fn fcntl(&mut self, _cmd: usize, _arg: usize) -> Result<usize> {
-        Err(Error::new(EBADF))
+    fn fcntl(&mut self, cmd: usize, arg: usize) -> Result<usize> {
+        match cmd {
+            F_GETFL => Ok(self.flags),
+            F_SETFL => {
+                self.flags = (self.flags & O_ACCMODE) | (arg & ! O_ACCMODE);
+                Ok(0)
+            },
+            _ => Err(Error::new(EINVAL))
+        }
     }
 
     fn path(&self, buf: &mut [u8]) -> Result<usize> {
@@ -161,30 +201,40 @@ impl<D: Read + Write + Seek> Resource<D> for DirResource {
             st_nlink: 1,
             st_uid: self.uid.unwrap_or(0),
             st_gid: self.gid.unwrap_or(0),
-            st_size: self.dir.size(fs),
-            st_mtime: 0, //TODO
-            st_mtime_nsec: 0,
-            st_ctime: 0,
-            st_ctime_nsec: 0,
             ..Default::default()
         };
+        let meta = DirEntry::Dir(self.dir.clone()).metadata(fs);
+        stat_metadata(stat, &meta, fs);
         println!("Dir Stat Structure: {:?}", stat);
 
         Ok(0)
     }
 
-    fn sync(&mut self, _maps: &mut Fmaps, _fs: &mut FileSystem<D>) -> Result<usize> {
-        Err(Error::new(EBADF))
+    // Entries are written as they change, so there is nothing to write back
+    fn sync(&mut self, _maps: &mut Fmaps, _generations: &mut Generations, _fs: &mut FileSystem<D>) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn flush_entry(&mut self, _fs: &mut FileSystem<D>) -> Result<usize> {
+        Ok(0)
     }
 
     fn truncate(&mut self, _len: usize, _fs: &mut FileSystem<D>) -> Result<usize> {
-        Err(Error::new(EBADF))
+        Err(Error::new(EISDIR))
     }
 
     fn utimens(&mut self, _times: &[TimeSpec], _uid: u32, _fs: &mut FileSystem<D>) -> Result<usize> {
         Err(Error::new(EBADF))
     }
 
+    fn flags(&self) -> usize {
+        self.flags
+    }
+
+    fn position(&self) -> u64 {
+        self.seek as u64
+    }
+
 }
 
 pub struct FileResource {
@@ -193,9 +243,8 @@ pub struct FileResource {
     seek: u64,
     uid: Option<u32>,
     gid: Option<u32>,
-    mode: Option<u16>
-    //TODO: FMap support
-    //fmap: Option<(usize, FmapKey)>
+    mode: Option<u16>,
+    fmap: Option<FmapKey>
 }
 
 impl FileResource {
@@ -207,10 +256,66 @@ impl FileResource {
             uid: uid,
             gid: gid,
             mode: mode,
-            //fmap: None
+            fmap: None
+        }
+    }
+
+    // The volume label has an entry like a file's but no contents to change or map
+    fn check_regular(&self) -> Result<()> {
+        if self.file.short_dir_entry.is_vol_id() {
+            Err(Error::new(EINVAL))
+        } else {
+            Ok(())
         }
     }
 
+    // Writes the mapped buffer back to the file, unless that would undo changes made since
+    // it was read
+    fn sync_fmap<D: Read + Write + Seek>(&mut self, maps: &mut Fmaps, generations: &mut Generations, fs: &mut FileSystem<D>) -> Result<()> {
+        if let Some(key) = self.fmap {
+            if self.flags & O_ACCMODE == O_RDWR || self.flags & O_ACCMODE == O_WRONLY {
+                let fmap = maps.get_mut(&key).ok_or(Error::new(EINVAL))?;
+                let cluster = self.file.first_cluster.cluster_number;
+                if fmap.generation != generations.get(cluster) {
+                    return Err(Error::new(ESTALE));
+                }
+                let buf = &fmap.pages()[..fmap.actual_size];
+                result::from(self.file.write(buf, fs, key.offset as u64))?;
+                fmap.generation = generations.bump(cluster);
+            }
+        }
+        Ok(())
+    }
+
+    // Reads from a cached copy of the whole file, reading it all in first on a miss
+    fn read_cached<D: Read + Write + Seek>(&mut self, buf: &mut [u8], cache: &mut FileCache, generation: u64, fs: &mut FileSystem<D>) -> Result<usize> {
+        let cluster = self.file.first_cluster.cluster_number;
+        let size = self.file.size() as usize;
+        if cluster == 0 || size > cache.max_file() {
+            return result::from(self.file.read(buf, fs, self.seek));
+        }
+
+        let start = min(self.seek, size as u64) as usize;
+        if let Some(data) = cache.get(cluster, generation) {
+            // Another handle may know of a different size, leave that to the disk
+            if data.len() == size {
+                let count = min(buf.len(), size - start);
+                buf[..count].copy_from_slice(&data[start..start + count]);
+                return Ok(count);
+            }
+        }
+
+        let mut data = vec![0; size];
+        let read = result::from(self.file.read(&mut data, fs, 0))?;
+        let count = min(buf.len(), read.saturating_sub(start));
+        buf[..count].copy_from_slice(&data[start..start + count]);
+        // A chain shorter than the size is read as far as it goes but not kept
+        if read == size {
+            cache.insert(cluster, generation, data);
+        }
+        Ok(count)
+    }
+
 }
 
 impl<D: Read + Write + Seek> Resource<D> for FileResource {
@@ -244,15 +349,21 @@ impl<D: Read + Write + Seek> Resource<D> for FileResource {
                 seek: self.seek,
                 uid: self.uid,
                 gid: self.gid,
-                mode: self.mode
-                //fmap: None
+                mode: self.mode,
+                fmap: None
             }
         ))
     }
 
-    fn read(&mut self, buf: &mut [u8], fs: &mut FileSystem<D>) -> Result<usize> {
+    fn read(&mut self, buf: &mut [u8], cache: Option<&mut FileCache>, generations: &Generations, fs: &mut FileSystem<D>) -> Result<usize> {
         if self.flags & O_ACCMODE == O_RDWR || self.flags & O_ACCMODE == O_RDONLY {
-            let count = result::from(self.file.read(buf, fs, self.seek))?;
+            let count = match cache {
+                Some(cache) => {
+                    let generation = generations.get(self.file.first_cluster.cluster_number);
+                    self.read_cached(buf, cache, generation, fs)?
+                },
+                None => result::from(self.file.read(buf, fs, self.seek))?
+            };
             self.seek += count as u64;
             Ok(count)
         } else {
@@ -261,6 +372,7 @@ impl<D: Read + Write + Seek> Resource<D> for FileResource {
     }
 
     fn write(&mut self, buf: &[u8], fs: &mut FileSystem<D>) -> Result<usize> {
+        self.check_regular()?;
         if self.flags & O_ACCMODE == O_RDWR || self.flags & O_ACCMODE == O_WRONLY {
             //let mtime = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
             let count = result::from(self.file.write(buf, fs, self.seek))?;
@@ -284,11 +396,74 @@ impl<D: Read + Write + Seek> Resource<D> for FileResource {
         Ok(self.seek as usize)
     }
 
-    fn fmap(&mut self, _map: &Map, _maps: &mut Fmaps, _fs: &mut FileSystem<D>) -> Result<usize> {
-        Ok(0)
+    fn fmap(&mut self, map: &Map, maps: &mut Fmaps, generations: &Generations, fs: &mut FileSystem<D>) -> Result<usize> {
+        self.check_regular()?;
+        let accmode = self.flags & O_ACCMODE;
+        // Executing a mapping needs its contents just as reading it does
+        if map.flags & (PROT_READ | PROT_EXEC) > 0 && ! (accmode == O_RDWR || accmode == O_RDONLY) {
+            return Err(Error::new(EBADF));
+        }
+        if map.flags & PROT_WRITE > 0 && ! (accmode == O_RDWR || accmode == O_WRONLY) {
+            return Err(Error::new(EBADF));
+        }
+        if map.size == 0 || map.offset % PAGE_SIZE != 0 {
+            return Err(Error::new(EINVAL));
+        }
+        if self.fmap.is_some() {
+            return Err(Error::new(EBUSY));
+        }
+
+        // The short entry offset identifies the file on disk
+        let (cluster, offset) = self.file.loc.1;
+        let key = FmapKey {
+            block: fs.cluster_offset(cluster) + offset,
+            offset: map.offset,
+            size: FmapValue::pages_len(map.size)
+        };
+
+        let generation = generations.get(self.file.first_cluster.cluster_number);
+        let fmap = match maps.get_mut(&key) {
+            Some(fmap) => {
+                // The file changed since the pages were read, which the other mappings
+                // could not write back anyway
+                if fmap.generation != generation {
+                    let read = result::from(self.file.read(fmap.pages_mut(), fs, map.offset as u64))?;
+                    for b in &mut fmap.pages_mut()[read..] {
+                        *b = 0;
+                    }
+                    fmap.actual_size = read;
+                    fmap.generation = generation;
+                }
+                fmap.refcount += 1;
+                fmap.addr()
+            },
+            None => {
+                let mut value = FmapValue::new(map.size);
+                value.actual_size = result::from(self.file.read(value.pages_mut(), fs, map.offset as u64))?;
+                value.generation = generation;
+                maps.insert(key, value)?.addr()
+            }
+        };
+
+        self.fmap = Some(key);
+        Ok(fmap)
     }
 
-    fn funmap(&mut self, _maps: &mut Fmaps, _fs: &mut FileSystem<D>) -> Result<usize> {
+    fn funmap(&mut self, maps: &mut Fmaps, generations: &mut Generations, fs: &mut FileSystem<D>) -> Result<usize> {
+        if let Some(key) = self.fmap {
+            let synced = self.sync_fmap(maps, generations, fs);
+            self.fmap = None;
+
+            let remove = {
+                let fmap = maps.get_mut(&key).ok_or(Error::new(EINVAL))?;
+                fmap.refcount -= 1;
+                fmap.refcount == 0
+            };
+            if remove {
+                maps.remove(&key);
+            }
+            synced?;
+        }
         Ok(0)
     }
 
@@ -323,7 +498,7 @@ impl<D: Read + Write + Seek> Resource<D> for FileResource {
         Ok(i)
     }
 
-    fn stat(&self, stat: &mut Stat, _fs: &mut FileSystem<D>) -> Result<usize> {
+    fn stat(&self, stat: &mut Stat, fs: &mut FileSystem<D>) -> Result<usize> {
         //let node = fs.node(self.block)?;
 
         *stat = Stat {
@@ -333,27 +508,30 @@ impl<D: Read + Write + Seek> Resource<D> for FileResource {
             st_nlink: 1,
             st_uid: self.uid.unwrap_or(0),
             st_gid: self.gid.unwrap_or(0),
-            st_size: self.file.size(),
-            //TODO: Modification time
-            st_mtime: 0,
-            st_mtime_nsec: 0,
-            st_ctime: 0,
-            st_ctime_nsec: 0,
             ..Default::default()
         };
+        let meta = Resource::<D>::get_dirent(self)?.metadata(fs);
+        stat_metadata(stat, &meta, fs);
 
         Ok(0)
     }
 
-    fn sync(&mut self, _maps: &mut Fmaps, _fs: &mut FileSystem<D>) -> Result<usize> {
-        //self.sync_fmap(maps, fs)?;
+    fn sync(&mut self, maps: &mut Fmaps, generations: &mut Generations, fs: &mut FileSystem<D>) -> Result<usize> {
+        self.sync_fmap(maps, generations, fs)?;
+        result::from(self.file.flush(fs))?;
+
+        Ok(0)
+    }
 
+    fn flush_entry(&mut self, fs: &mut FileSystem<D>) -> Result<usize> {
+        result::from(self.file.flush(fs))?;
         Ok(0)
     }
 
     fn truncate(&mut self, len: usize, fs: &mut FileSystem<D>) -> Result<usize> {
+        self.check_regular()?;
         if self.flags & O_ACCMODE == O_RDWR || self.flags & O_ACCMODE == O_WRONLY {
-            result::from(self.file.truncate(fs, len as u64))?;
+            result::from(self.file.set_len(fs, len as u64))?;
             Ok(0)
         } else {
             Err(Error::new(EBADF))
@@ -383,7 +561,13 @@ impl<D: Read + Write + Seek> Resource<D> for FileResource {
         //Ok(0)
     }
 
+    fn flags(&self) -> usize {
+        self.flags
+    }
 
+    fn position(&self) -> u64 {
+        self.seek
+    }
 
 }
 
@@ -11,7 +11,12 @@ pub fn from<T>(res: Result<T>) -> syscall::Result<T> {
                  ErrorKind::NotFound => Err(syscall::Error::new(syscall::ENOENT)),
                  ErrorKind::InvalidInput | ErrorKind::InvalidData => Err(syscall::Error::new(syscall::EINVAL)),
                  ErrorKind::PermissionDenied => Err(syscall::Error::new(syscall::EPERM)),
+                 ErrorKind::ReadOnlyFilesystem => Err(syscall::Error::new(syscall::EROFS)),
                  ErrorKind::AlreadyExists => Err(syscall::Error::new(syscall::EINVAL)),
+                 ErrorKind::DirectoryNotEmpty => Err(syscall::Error::new(syscall::ENOTEMPTY)),
+                 ErrorKind::IsADirectory => Err(syscall::Error::new(syscall::EISDIR)),
+                 ErrorKind::NotADirectory => Err(syscall::Error::new(syscall::ENOTDIR)),
+                 ErrorKind::ResourceBusy => Err(syscall::Error::new(syscall::EBUSY)),
                  _ => Err(syscall::Error::new(syscall::EIO))
              }
         }
@@ -3,75 +3,277 @@
 use std::cell::RefCell;
 use std::collections::BTreeMap;
 use std::str;
+use std::cmp::min;
 use std::sync::atomic::{AtomicUsize, Ordering};
-//use std::time::{SystemTime, UNIX_EPOCH};
-use std::io::{Read, Write, Seek};
+use std::time::SystemTime;
+use std::io::{self, Read, Write, Seek};
 
+use syscall::{Packet, SYS_FEVENT, EVENT_READ};
 use syscall::data::{Map, Stat, StatVfs, TimeSpec};
-use syscall::error::{Error, Result, EACCES, EEXIST, EISDIR, ENOTDIR, EPERM, ENOENT, EBADF, EINVAL};
-use syscall::flag::{O_APPEND, O_CREAT, O_DIRECTORY, O_EXCL, O_TRUNC, O_ACCMODE, O_RDONLY, O_WRONLY, O_RDWR, O_SYMLINK};
+use syscall::error::{Error, Result, EACCES, EBUSY, EEXIST, EISDIR, ENOTDIR, EPERM, ENOENT, EBADF, EINVAL, EXDEV, EMFILE, ENOMEM, ENOSPC};
+use syscall::flag::{O_APPEND, O_CREAT, O_DIRECTORY, O_EXCL, O_TRUNC, O_ACCMODE, O_RDONLY, O_WRONLY, O_RDWR, O_SYMLINK, PROT_WRITE};
 use syscall::scheme::Scheme;
 
 
-use filesystem::FileSystem;
-use dir_entry::Dir;
+use {IS_UMT, DUMP_RESOURCES, TOGGLE_WRITE_PROTECT};
+use filesystem::{Cluster, FileSystem, LfnCorruption, WriteVerification, retry_interrupted};
+use dir_entry::{Dir, DirEntry, File, FileAttributes, NameTranslation};
+use sort::SortBy;
+use bpb::FATType;
 use table::get_free_count;
 
+use super::MountOptions;
 use super::result::from;
-use super::resource::{Resource, DirResource, FileResource};
+use super::resource::{Resource, DirResource, FileResource, MODE_TYPE, MODE_DIR};
+use super::watch::{EventKind, Watcher, WATCH_PREFIX};
+use super::path_cache::PathCache;
+use super::file_cache::FileCache;
+use super::generation::Generations;
+use super::capabilities::{Capabilities, Features, CAPABILITIES};
+use super::debug::{OpenResource, ShutdownSummary, Snapshot, RESOURCES, STATS};
+use super::transport::Transport;
+use super::trace::OpSpan;
 use super::spin::Mutex;
 
-const FMAP_AMOUNT: usize = 1024;
-
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
 pub struct FmapKey {
     pub block: u64,
     pub offset: usize,
     pub size: usize
 }
 
-#[derive(Clone)]
+/// fmap hands out whole pages starting on a page boundary
+pub const PAGE_SIZE: usize = 4096;
+
+/// The pages of a mapping
+///
+/// The kernel maps the buffer into the caller as it is, so programs run from the volume
+/// with PROT_EXEC get their segments page aligned and zero filled past the end of the file.
 pub struct FmapValue {
+    /// The pages at `start`, with up to a page before them to align them
     pub buffer: Vec<u8>,
-    /// The actual file length. Syncing only writes &buffer[..actual_size].
+    pub start: usize,
+    /// The requested size rounded up to whole pages
+    pub len: usize,
+    /// The actual file length. Syncing only writes &pages()[..actual_size].
     pub actual_size: usize,
-    pub refcount: usize
+    pub refcount: usize,
+    /// Generation of the file the pages were read or last written back under
+    pub generation: u64
+}
+
+impl FmapValue {
+    /// Zeroed pages covering `size` bytes
+    pub fn new(size: usize) -> FmapValue {
+        let len = FmapValue::pages_len(size);
+        let buffer = vec![0; len + PAGE_SIZE];
+        let misalign = buffer.as_ptr() as usize % PAGE_SIZE;
+        FmapValue {
+            buffer: buffer,
+            start: if misalign == 0 { 0 } else { PAGE_SIZE - misalign },
+            len: len,
+            actual_size: 0,
+            refcount: 1,
+            generation: 0
+        }
+    }
+
+    /// `size` rounded up to whole pages
+    pub fn pages_len(size: usize) -> usize {
+        (size + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE
+    }
+
+    pub fn pages(&self) -> &[u8] {
+        &self.buffer[self.start..self.start + self.len]
+    }
+
+    pub fn pages_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer[self.start..self.start + self.len]
+    }
+
+    /// Address returned by fmap
+    pub fn addr(&self) -> usize {
+        self.pages().as_ptr() as usize
+    }
 }
 
 const MODE_WRITE: u16 = 0o2;
 const MODE_READ: u16 = 0o4;
 
-pub struct Fmaps(Vec<Option<(FmapKey, FmapValue)>>);
-impl Default for Fmaps {
-    fn default() -> Fmaps {
-        Fmaps(vec![None; FMAP_AMOUNT])
+#[derive(Default)]
+pub struct Fmaps {
+    maps: BTreeMap<FmapKey, FmapValue>,
+    /// Bytes held by all the fmap buffers
+    memory: usize,
+    limit: Option<usize>
+}
+
+impl Fmaps {
+    pub fn new(limit: Option<usize>) -> Fmaps {
+        Fmaps {
+            maps: BTreeMap::new(),
+            memory: 0,
+            limit: limit
+        }
+    }
+
+    pub fn get_mut(&mut self, key: &FmapKey) -> Option<&mut FmapValue> {
+        self.maps.get_mut(key)
+    }
+
+    /// Inserts a new mapping, failing with ENOMEM if it would take the
+    /// total fmap memory over the limit
+    pub fn insert(&mut self, key: FmapKey, value: FmapValue) -> Result<&mut FmapValue> {
+        let memory = self.memory + value.len;
+        if let Some(max) = self.limit {
+            if memory > max {
+                return Err(Error::new(ENOMEM));
+            }
+        }
+        self.memory = memory;
+        Ok(self.maps.entry(key).or_insert(value))
+    }
+
+    pub fn remove(&mut self, key: &FmapKey) -> Option<FmapValue> {
+        let value = self.maps.remove(key);
+        if let Some(ref v) = value {
+            self.memory -= v.len;
+        }
+        value
+    }
+
+    pub fn memory(&self) -> usize {
+        self.memory
+    }
+}
+
+// The path of a request without its surrounding slashes. Invalid UTF-8 and embedded NULs
+// give EINVAL, rather than being taken for the root
+fn request_path(url: &[u8]) -> Result<&str> {
+    match str::from_utf8(url) {
+        Ok(path) if !path.contains('\0') => Ok(path.trim_matches('/')),
+        _ => Err(Error::new(EINVAL))
     }
 }
 
+// Each handle of a file holds its own copy of the short entry, which is only written back on
+// fsync, close and by `flush_entries`. Takes the size and first cluster from `from`.
+fn take_entry(file: &mut File, from: &File) {
+    file.first_cluster = from.first_cluster;
+    file.short_dir_entry = from.short_dir_entry;
+    file.size_dirty = from.size_dirty;
+    file.times_dirty = from.times_dirty;
+}
+
+// Hands the entry of the file changed through `id` to the other handles of the same file
+fn share_entry<D: Read + Write + Seek>(files: &mut BTreeMap<usize, Box<dyn Resource<D>>>, id: usize, changed: &DirEntry) {
+    let changed = match *changed {
+        DirEntry::File(ref f) => f,
+        _ => return
+    };
+    for (_, file) in files.iter_mut().filter(|&(&other, _)| other != id) {
+        if let Ok(DirEntry::File(mut f)) = file.get_dirent() {
+            if f.loc == changed.loc {
+                take_entry(&mut f, changed);
+                let _ = file.set_dirent(DirEntry::File(f));
+            }
+        }
+    }
+}
+
+// `file` as the handles already open on it know it, rather than as its entry was last written
+fn open_entry<D: Read + Write + Seek>(files: &BTreeMap<usize, Box<dyn Resource<D>>>, mut file: File) -> File {
+    for other in files.values() {
+        if let Ok(DirEntry::File(ref f)) = other.get_dirent() {
+            if f.loc == file.loc {
+                take_entry(&mut file, f);
+                break;
+            }
+        }
+    }
+    file
+}
+
 pub struct FileScheme<D: Read + Write + Seek> {
     name: String,
     fs: RefCell<FileSystem<D>>,
     next_id: AtomicUsize,
     files: Mutex<BTreeMap<usize, Box<dyn Resource<D>>>>,
     fmaps: Mutex<Fmaps>,
+    /// Changes made to each file, see `Generations`
+    generations: Mutex<Generations>,
+    watchers: Mutex<BTreeMap<usize, Watcher>>,
+    /// Uid of the caller which opened each file
+    openers: Mutex<BTreeMap<usize, u32>>,
+    path_cache: Option<Mutex<PathCache>>,
+    file_cache: Option<Mutex<FileCache>>,
+    /// Serialized listings of directories opened for reading, kept by their first cluster
+    /// under the generation bumped by `entries_changed`
+    listing_cache: Option<Mutex<FileCache>>,
+    capabilities: Mutex<BTreeMap<usize, Capabilities>>,
+    /// Handles opened through RESOURCES and STATS
+    dumps: Mutex<BTreeMap<usize, Snapshot>>,
+    /// st_nlink of directories by first cluster, dropped whenever entries come or go
+    link_counts: Mutex<BTreeMap<u64, u32>>,
+    /// When `flush_entries` last wrote back the entries of changed files
+    last_flush: Mutex<SystemTime>,
     mount_mode: u16,
     mount_uid: u32,
-    mount_gid: u32
+    mount_gid: u32,
+    options: MountOptions
 }
 
 //Move the permission checking to the scheme
 //FAT does not have provision for permissions
 impl<D: Read + Write + Seek> FileScheme<D> {
     fn permission(&self, uid: u32, gid: u32, op: u16) -> bool {
-        let mut perm = self.mount_mode & 0o7;
+        let mode = self.dir_mode();
+        self.mode_permits(mode, uid, gid, op)
+    }
+
+    fn entry_permission(&self, e: &DirEntry, uid: u32, gid: u32, op: u16) -> bool {
+        let mode = self.entry_mode(e);
+        self.mode_permits(mode, uid, gid, op)
+    }
+
+    /// Mode of directories, which adding and removing entries is checked against
+    fn dir_mode(&self) -> u16 {
+        if self.options.attribute_modes {
+            0o755 & !self.options.umask
+        } else {
+            self.mount_mode
+        }
+    }
+
+    /// Mode shown and enforced for `e`, see `MountOptions::attribute_modes`
+    fn entry_mode(&self, e: &DirEntry) -> u16 {
+        if e.is_dir() {
+            self.dir_mode()
+        } else {
+            self.file_mode(e.attributes())
+        }
+    }
+
+    fn file_mode(&self, attributes: FileAttributes) -> u16 {
+        if !self.options.attribute_modes {
+            self.mount_mode
+        } else if attributes.contains(FileAttributes::RD_ONLY) {
+            0o444 & !self.options.umask
+        } else {
+            0o644 & !self.options.umask
+        }
+    }
+
+    fn mode_permits(&self, mode: u16, uid: u32, gid: u32, op: u16) -> bool {
+        let mut perm = mode & 0o7;
         if self.mount_uid == uid {
             // If self.mode is 101100110, >> 6 would be 000000101
             // 0o7 is octal for 111, or, when expanded to 9 digits is 000000111
-            perm |= (self.mount_mode >> 6) & 0o7;
+            perm |= (mode >> 6) & 0o7;
             // Since we erased the GID and OTHER bits when >>6'ing, |= will keep those bits in place.
         }
         if self.mount_gid == gid || gid == 0 {
-            perm |= (self.mount_mode >> 3) & 0o7;
+            perm |= (mode >> 3) & 0o7;
         }
         if uid == 0 {
             //set the `other` bits to 111
@@ -84,36 +286,531 @@ impl<D: Read + Write + Seek> FileScheme<D> {
         uid == 0 || self.mount_uid == uid
     }
 
-    pub fn new(name: String, fs: FileSystem<D>, mount_mode: u16, mount_uid: u32, mount_gid: u32) -> FileScheme<D> {
+    // The path `url` names on this mount, without the "name:" prefix. A scheme prefix comes
+    // before any '/', a ':' elsewhere belongs to a name translated with
+    // NameTranslation::Percent. Other schemes give EXDEV
+    fn local_path<'a>(&self, url: &'a [u8]) -> Result<&'a str> {
+        let path = request_path(url)?;
+        if url.starts_with(b"/") {
+            return Ok(path)
+        }
+        if path.starts_with(&*self.name) && path[self.name.len()..].starts_with(':') {
+            return Ok(path[self.name.len() + 1..].trim_matches('/'))
+        }
+        match (path.find(':'), path.find('/')) {
+            (Some(i), Some(j)) if j < i => Ok(path),
+            (Some(_), _) => Err(Error::new(EXDEV)),
+            (None, _) => Ok(path)
+        }
+    }
+
+    // Invalidates cached paths affected by a change and tells the watchers about it
+    fn entry_changed(&self, kind: EventKind, path: &str, new_path: Option<&str>) {
+        if kind != EventKind::Write {
+            self.link_counts.lock().clear();
+        }
+        if let Some(ref cache) = self.path_cache {
+            let mut cache = cache.lock();
+            match kind {
+                EventKind::Create => cache.invalidate_parent(path),
+                EventKind::Delete => cache.invalidate_tree(path),
+                EventKind::Rename => {
+                    cache.invalidate_tree(path);
+                    if let Some(p) = new_path {
+                        cache.invalidate_tree(p);
+                    }
+                },
+                EventKind::Write => cache.remove(path)
+            }
+        }
+
+        for watcher in self.watchers.lock().values_mut() {
+            watcher.push(kind, path, new_path);
+        }
+    }
+
+    // Bumps the generation of a file which is about to change or has changed, so that
+    // whatever was read from it before is no longer served
+    fn contents_changed(&self, entry: &DirEntry) {
+        if let &DirEntry::File(ref f) = entry {
+            let cluster = f.first_cluster.cluster_number;
+            self.generations.lock().bump(cluster);
+            if let Some(ref cache) = self.file_cache {
+                cache.lock().remove(cluster);
+            }
+        }
+    }
+
+    // Drops the generation of a file or directory which was removed, together with what
+    // the caches hold of it
+    fn entry_removed(&self, entry: &DirEntry) {
+        let cluster = entry.first_cluster().cluster_number;
+        self.generations.lock().forget(cluster);
+        if let Some(ref cache) = self.file_cache {
+            cache.lock().remove(cluster);
+        }
+        if let Some(ref cache) = self.listing_cache {
+            cache.lock().remove(cluster);
+        }
+    }
+
+    // Bumps the generation of the directory at `cluster` whose entries came or went, so
+    // that its cached listing is no longer served
+    fn entries_changed(&self, cluster: Cluster) {
+        let cluster = cluster.cluster_number;
+        self.generations.lock().bump(cluster);
+        if let Some(ref cache) = self.listing_cache {
+            cache.lock().remove(cluster);
+        }
+    }
+
+    // Names of the entries of `dir` shown to readers, one per line
+    fn listing(&self, dir: &Dir, fs: &mut FileSystem<D>) -> Vec<u8> {
+        let cluster = dir.first_cluster.cluster_number;
+        let generation = self.generations.lock().get(cluster);
+        if let Some(ref cache) = self.listing_cache {
+            if let Some(data) = cache.lock().get(cluster, generation) {
+                return data.to_vec();
+            }
+        }
+
+        let mut data = Vec::new();
+        let hidden = self.hidden_attributes();
+        let children: Vec<DirEntry> = if self.options.sorted_listings {
+            let mut children = dir.entries_sorted(fs, SortBy::Name);
+            children.retain(|c| !c.attributes().intersects(hidden));
+            children
+        } else {
+            dir.to_iter(fs).skip_attributes(hidden).collect()
+        };
+        for child in children {
+            let name = child.name();
+            if !data.is_empty() {
+                data.push(b'\n');
+            }
+            data.extend_from_slice(self.options.name_translation.to_shown(&name).as_bytes());
+        }
+        if let Some(ref cache) = self.listing_cache {
+            cache.lock().insert(cluster, generation, data.clone());
+        }
+        data
+    }
+
+    // 2 for the directory's own entry and ".", plus the ".." of each subdirectory
+    fn dir_links(&self, dir: &Dir, fs: &mut FileSystem<D>) -> u32 {
+        let key = dir.first_cluster.cluster_number;
+        if let Some(&links) = self.link_counts.lock().get(&key) {
+            return links;
+        }
+        let subdirs = dir.to_iter(fs).filter(|e| {
+            let name = e.name();
+            e.is_dir() && name != "." && name != ".."
+        }).count();
+        let links = 2 + subdirs as u32;
+        self.link_counts.lock().insert(key, links);
+        links
+    }
+
+    fn lookup(&self, path: &str, fs: &mut FileSystem<D>) -> Option<DirEntry> {
+        if let Some(ref cache) = self.path_cache {
+            if let Some(e) = cache.lock().get(path, &self.generations.lock()) {
+                return Some(e);
+            }
+        }
+
+        let e = Dir::get_entry_abs(path, fs).ok()?;
+        if let Some(ref cache) = self.path_cache {
+            cache.lock().insert(path, e.clone(), &self.generations.lock());
+        }
+        Some(e)
+    }
+
+    // Picks up the size written back to the short entry of a synced file
+    fn refresh_entry(&self, file: &Box<dyn Resource<D>>) {
+        if let (Some(cache), Ok(e)) = (self.path_cache.as_ref(), file.get_dirent()) {
+            cache.lock().update(&e.dir_path(), e, &self.generations.lock());
+        }
+    }
+
+    /// Handles one request, leaving the result in the packet to be sent back
+    ///
+    /// Then writes back the sizes of changed files once `MountOptions::flush_interval` has
+    /// passed and goes on with building the free map, if `MountOptions::free_map_scan` is set.
+    pub fn handle_packet(&self, packet: &mut Packet) {
+        self.handle(packet);
+        if let Some(secs) = self.options.flush_interval {
+            self.flush_entries(secs);
+        }
+        if let Some(clusters) = self.options.free_map_scan {
+            let mut fs = self.fs.borrow_mut();
+            // The allocator searches the FAT past the map, so a failed scan only costs time
+            if let Err(err) = fs.scan_free_map(clusters) {
+                warn!("Scanning the FAT for free clusters failed: {}", err);
+            }
+        }
+    }
+
+    // Writes back the short entries of the files changed through open handles, if `secs`
+    // have passed since the last time
+    fn flush_entries(&self, secs: u64) {
+        let mut fs = self.fs.borrow_mut();
+        let now = fs.now();
+        {
+            let mut last = self.last_flush.lock();
+            match now.duration_since(*last) {
+                Ok(elapsed) if elapsed.as_secs() >= secs => *last = now,
+                _ => return
+            }
+        }
+        if fs.check_writable().is_err() {
+            return;
+        }
+
+        let mut files = self.files.lock();
+        let ids: Vec<usize> = files.keys().cloned().collect();
+        for id in ids {
+            let e = match files.get_mut(&id) {
+                Some(file) => {
+                    if let Err(err) = file.flush_entry(&mut fs) {
+                        warn!("Failed to write back the entry of resource {}: {}", id, err);
+                        continue;
+                    }
+                    self.refresh_entry(file);
+                    file.get_dirent()
+                },
+                None => continue
+            };
+            if let Ok(e) = e {
+                share_entry(&mut files, id, &e);
+            }
+        }
+    }
+
+    /// Serves requests from `transport` until unmounted or the transport is closed
+    pub fn serve<T: Transport>(&self, transport: &mut T) -> io::Result<()> {
+        loop {
+            if IS_UMT.load(Ordering::SeqCst) > 0 {
+                // The volume is unmounted once the scheme is dropped
+                let summary = self.shutdown();
+                if !summary.closed.is_empty() {
+                    println!("redox-fatfs: closed {} open resources of {}", summary.closed.len(), self.name);
+                }
+                for (id, err) in summary.failed {
+                    println!("redox-fatfs: failed to write back resource {} of {}: {}", id, self.name, err);
+                }
+                return Ok(());
+            }
+            if DUMP_RESOURCES.swap(0, Ordering::SeqCst) > 0 {
+                print!("redox-fatfs: open resources of {}\n{}", self.name, self.resource_table());
+            }
+            if TOGGLE_WRITE_PROTECT.swap(0, Ordering::SeqCst) > 0 {
+                let protect = !self.fs.borrow().is_write_protected();
+                match self.set_write_protect(protect) {
+                    Ok(_) if protect => println!("redox-fatfs: {} is write protected", self.name),
+                    Ok(_) => println!("redox-fatfs: {} is writable again", self.name),
+                    Err(err) => println!("redox-fatfs: failed to write protect {}: {}", self.name, err)
+                }
+            }
+
+            let mut packet = Packet::default();
+            match transport.recv(&mut packet) {
+                Ok(0) => return Ok(()),
+                Ok(_) => (),
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err)
+            }
+
+            self.handle_packet(&mut packet);
+            // A reply lost to a signal would leave the caller waiting forever
+            retry_interrupted(|| transport.send(&packet))?;
+
+            // Wake up anyone waiting on a watcher which got new events
+            for (id, count) in self.pending_events() {
+                let event = Packet {
+                    a: SYS_FEVENT,
+                    b: id,
+                    c: EVENT_READ,
+                    d: count,
+                    ..Default::default()
+                };
+                retry_interrupted(|| transport.send(&event))?;
+            }
+        }
+    }
+
+    /// The open files and directories, one per line after a header:
+    /// "<id> <opener uid> <flags in hex> <seek> <first cluster> <path>"
+    pub fn resource_table(&self) -> String {
+        let mut table = String::from("id uid flags seek cluster path\n");
+        for r in self.open_resources() {
+            let uid = r.uid.map_or("-".to_string(), |uid| uid.to_string());
+            table.push_str(&format!("{} {} {:x} {} {} {}\n", r.id, uid, r.flags, r.seek, r.cluster, r.path));
+        }
+        table
+    }
+
+    /// The open files and directories by id
+    pub fn open_resources(&self) -> Vec<OpenResource> {
+        let files = self.files.lock();
+        let openers = self.openers.lock();
+        files.iter().map(|(&id, file)| {
+            let (cluster, path) = match file.get_dirent() {
+                Ok(DirEntry::File(f)) => (f.first_cluster.cluster_number, f.file_path),
+                Ok(DirEntry::Dir(d)) => (d.first_cluster.cluster_number, d.dir_path),
+                _ => (0, String::new())
+            };
+            OpenResource {
+                id: id,
+                uid: openers.get(&id).cloned(),
+                flags: file.flags(),
+                seek: file.position(),
+                cluster: cluster,
+                path: path
+            }
+        }).collect()
+    }
+
+    /// Closes every handle still open, writing back what was changed through them as
+    /// close does, ahead of unmounting
+    ///
+    /// Watchers and the other handles which don't refer to the volume are dropped as well.
+    /// Handles closed here give EBADF afterwards.
+    pub fn shutdown(&self) -> ShutdownSummary {
+        let mut summary = ShutdownSummary::default();
+        summary.other_handles = self.watchers.lock().len() + self.capabilities.lock().len() + self.dumps.lock().len();
+        self.watchers.lock().clear();
+        self.capabilities.lock().clear();
+        self.dumps.lock().clear();
+
+        for resource in self.open_resources() {
+            self.openers.lock().remove(&resource.id);
+            let mut file = match self.files.lock().remove(&resource.id) {
+                Some(f) => f,
+                None => continue
+            };
+            // Directories have no mapping to write back
+            let unmapped = match file.get_dirent() {
+                Ok(ref e) if e.is_dir() => Ok(0),
+                _ => file.funmap(&mut self.fmaps.lock(), &mut self.generations.lock(), &mut self.fs.borrow_mut())
+            };
+            let synced = file.sync(&mut self.fmaps.lock(), &mut self.generations.lock(), &mut self.fs.borrow_mut());
+            if let Err(err) = unmapped.and(synced) {
+                summary.failed.push((resource.id, err));
+            }
+            self.refresh_entry(&file);
+            summary.closed.push(resource);
+        }
+        summary
+    }
+
+    /// What opening STATS reads back
+    pub fn stats(&self) -> String {
+        let mut fs = self.fs.borrow_mut();
+        let fat_bits = match fs.bpb.fat_type {
+            FATType::FAT12(_) => 12,
+            FATType::FAT16(_) => 16,
+            FATType::FAT32(_) => 32
+        };
+        let free = fs.free_clusters().map_or("unknown".to_string(), |n| n.to_string());
+        let cache_stats = |stats: Option<(u64, u64)>| stats.map_or("off".to_string(), |(hits, misses)| format!("{} {}", hits, misses));
+        let lfn = fs.lfn_corruption();
+        let verification = fs.write_verification();
+        let root_entries = match fs.root_entries() {
+            Ok(Some(r)) => format!("{} {}", r.total, r.free),
+            Ok(None) => "unlimited".to_string(),
+            Err(_) => "unknown".to_string()
+        };
+
+        let mut stats = String::new();
+        stats.push_str(&format!("fat_type {}\n", fat_bits));
+        stats.push_str(&format!("bytes_per_cluster {}\n", fs.bytes_per_cluster()));
+        stats.push_str(&format!("total_clusters {}\n", fs.max_cluster_number().cluster_number - 1));
+        stats.push_str(&format!("free_clusters {}\n", free));
+        stats.push_str(&format!("free_map {}\n", fs.free_map_progress().map_or("off".to_string(), |p| format!("{} {}", p.scanned, p.total))));
+        stats.push_str(&format!("root_entries {}\n", root_entries));
+        stats.push_str(&format!("write_protected {}\n", fs.is_write_protected()));
+        stats.push_str(&format!("open_files {}\n", self.files.lock().len()));
+        stats.push_str(&format!("watchers {}\n", self.watchers.lock().len()));
+        stats.push_str(&format!("fmap_memory {}\n", self.fmaps.lock().memory()));
+        stats.push_str(&format!("generations {}\n", self.generations.lock().len()));
+        stats.push_str(&format!("path_cache {}\n", cache_stats(self.path_cache_stats())));
+        stats.push_str(&format!("file_cache {}\n", cache_stats(self.file_cache_stats())));
+        stats.push_str(&format!("listing_cache {}\n", cache_stats(self.listing_cache_stats())));
+        stats.push_str(&format!("entry_cache {}\n", cache_stats(fs.entry_cache_stats())));
+        stats.push_str(&format!("lfn_checksum_mismatches {}\n", lfn.checksum_mismatches));
+        stats.push_str(&format!("lfn_orphaned {}\n", lfn.orphaned));
+        stats.push_str(&format!("verified_writes {}\n", verification.verified));
+        stats.push_str(&format!("verify_mismatches {}\n", verification.mismatches));
+        stats.push_str(&format!("options {:?}\n", self.options));
+        stats
+    }
+
+    /// Lookups served from the path cache and from the disk, if the cache is enabled
+    pub fn path_cache_stats(&self) -> Option<(u64, u64)> {
+        self.path_cache.as_ref().map(|c| c.lock().stats())
+    }
+
+    /// Reads served from the file cache and from the disk, if the cache is enabled
+    pub fn file_cache_stats(&self) -> Option<(u64, u64)> {
+        self.file_cache.as_ref().map(|c| c.lock().stats())
+    }
+
+    /// Directory listings served from the listing cache and read from the disk, if the
+    /// cache is enabled
+    pub fn listing_cache_stats(&self) -> Option<(u64, u64)> {
+        self.listing_cache.as_ref().map(|c| c.lock().stats())
+    }
+
+    /// Damaged long names skipped so far, see `FileSystem::lfn_corruption`
+    pub fn lfn_corruption(&self) -> LfnCorruption {
+        self.fs.borrow().lfn_corruption()
+    }
+
+    /// Metadata blocks read back so far, see `FileSystem::verify_writes`
+    pub fn write_verification(&self) -> WriteVerification {
+        self.fs.borrow().write_verification()
+    }
+
+    /// Watchers registered through fevent with events not yet signalled, as (id, bytes pending)
+    /// The mount loop sends an fevent packet for each of these
+    pub fn pending_events(&self) -> Vec<(usize, usize)> {
+        let mut events = Vec::new();
+        for (id, watcher) in self.watchers.lock().iter_mut() {
+            if watcher.fevent != 0 && !watcher.notified && watcher.pending() > 0 {
+                watcher.notified = true;
+                events.push((*id, watcher.pending()));
+            }
+        }
+        events
+    }
+
+    // Below the reserved space watermark only the owner may write, `needed` is the number
+    // of clusters the change is known to allocate
+    fn check_reserved(&self, uid: u32, needed: u64, fs: &mut FileSystem<D>) -> Result<()> {
+        if let Some(reserved) = self.options.reserved_clusters {
+            if !self.owner(uid) && from(fs.free_clusters())? < reserved.saturating_add(needed) {
+                return Err(Error::new(ENOSPC));
+            }
+        }
+        Ok(())
+    }
+
+    // EROFS before changing anything on a write protected or read-only volume
+    fn check_writable(&self) -> Result<()> {
+        from(self.fs.borrow().check_writable())
+    }
+
+    /// Refuses or allows changes to the volume from now on, see `FileSystem::set_write_protect`
+    pub fn set_write_protect(&self, protect: bool) -> Result<()> {
+        from(self.fs.borrow_mut().set_write_protect(protect))
+    }
+
+    // Attributes of the entries left out of directory listings
+    fn hidden_attributes(&self) -> FileAttributes {
+        let mut hidden = FileAttributes::empty();
+        if self.options.hide_system || self.options.hide_dot_files {
+            hidden |= FileAttributes::SYSTEM;
+        }
+        if self.options.hide_hidden || self.options.hide_dot_files {
+            hidden |= FileAttributes::HIDDEN;
+        }
+        hidden
+    }
+
+    // Fails with EMFILE once the open file limit is reached
+    fn check_open_files(&self, files: &BTreeMap<usize, Box<dyn Resource<D>>>) -> Result<()> {
+        match self.options.max_open_files {
+            Some(max) if files.len() >= max => Err(Error::new(EMFILE)),
+            _ => Ok(())
+        }
+    }
+
+    pub fn new(name: String, mut fs: FileSystem<D>, mount_mode: u16, mount_uid: u32, mount_gid: u32,
+               options: MountOptions) -> FileScheme<D> {
+        fs.case_sensitive = options.case_sensitive;
+        fs.hide_dot_files = options.hide_dot_files;
+        fs.read_only_dirs = options.read_only_dirs;
+        fs.short_names_only = options.short_names_only;
+        fs.name_translation = options.name_translation;
+        fs.lazy_fat_mirroring = !options.strict_fat_mirroring;
+        fs.strict_cluster_bounds = options.strict_cluster_bounds;
+        fs.verify_writes = options.verify_writes;
+        if let Some(entries) = options.entry_cache {
+            fs.set_entry_cache(entries);
+        }
+        if options.free_map_scan.is_some() {
+            fs.start_free_map();
+        }
+        if let Some(attempts) = options.write_attempts {
+            fs.retry_policy.attempts = attempts;
+        }
+        let now = fs.now();
         FileScheme {
             name: name,
             fs: RefCell::new(fs),
             next_id: AtomicUsize::new(1),
             files: Mutex::new(BTreeMap::new()),
-            fmaps: Mutex::new(Fmaps::default()),
+            fmaps: Mutex::new(Fmaps::new(options.max_fmap_memory)),
+            generations: Mutex::new(Generations::new()),
+            watchers: Mutex::new(BTreeMap::new()),
+            openers: Mutex::new(BTreeMap::new()),
+            capabilities: Mutex::new(BTreeMap::new()),
+            dumps: Mutex::new(BTreeMap::new()),
+            link_counts: Mutex::new(BTreeMap::new()),
+            last_flush: Mutex::new(now),
+            path_cache: if options.path_cache {
+                Some(Mutex::new(PathCache::new(options.case_sensitive)))
+            } else {
+                None
+            },
+            file_cache: options.file_cache.map(|bytes| Mutex::new(FileCache::new(bytes))),
+            listing_cache: options.listing_cache.map(|bytes| Mutex::new(FileCache::new(bytes))),
             mount_mode: mount_mode,
             mount_uid: mount_uid,
-            mount_gid: mount_gid
+            mount_gid: mount_gid,
+            options: options
         }
     }
 }
 
 impl<D: Read + Write + Seek> Scheme for FileScheme<D> {
     fn open(&self, url: &[u8], flags: usize, uid: u32, gid: u32) -> Result<usize> {
-        let path = str::from_utf8(url).unwrap_or("").trim_matches('/');
+        let path = request_path(url)?;
 
-        println!("Open '{}' {:X}", path, flags);
+        scheme_debug!("Open '{}' {:X}", path, flags);
+        let span = OpSpan::enter("open");
+        span.record_path(path);
+
+        self.check_open_files(&self.files.lock())?;
+
+        if path == STATS {
+            if !self.permission(uid, gid, MODE_READ) {
+                return Err(Error::new(EACCES));
+            }
+            let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+            self.dumps.lock().insert(id, Snapshot::new(self.stats()));
+            return Ok(id);
+        }
+
+        if path.starts_with(WATCH_PREFIX) {
+            if !self.permission(uid, gid, MODE_READ) {
+                return Err(Error::new(EACCES));
+            }
+            let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+            self.watchers.lock().insert(id, Watcher::new(&path[WATCH_PREFIX.len()..]));
+            return Ok(id);
+        }
 
         let mut fs = self.fs.borrow_mut();
-        let dentry = Dir::get_entry_abs(path, &mut fs).ok();
-        println!("Found dir entry {:?} for path = {:?}", dentry, path);
+        let dentry = self.lookup(path, &mut fs);
+        scheme_debug!("Found dir entry {:?} for path = {:?}", dentry, path);
         //let node_opt = self.path_nodes(&mut fs, path, uid, gid, &mut nodes)?;
         let resource: Box<dyn Resource<D>> = match dentry {
             Some(e) => if flags & (O_CREAT | O_EXCL) == O_CREAT | O_EXCL {
                 return Err(Error::new(EEXIST));
             } else if e.is_dir() {
                 if flags & O_ACCMODE == O_RDONLY {
-                    if !self.permission(uid, gid, MODE_READ) {
+                    if !self.entry_permission(&e, uid, gid, MODE_READ) {
                         // println!("dir not readable {:o}", node.1.mode);
                         return Err(Error::new(EACCES));
                     }
@@ -121,23 +818,16 @@ impl<D: Read + Write + Seek> Scheme for FileScheme<D> {
                     //let mut children = Vec::new();
                     //fs.child_nodes(&mut children, node.0)?;
 
-                    let mut data = Vec::new();
-                    for child in e.to_dir().to_iter(&mut fs) {
-                        let name = child.name();
-                        if !data.is_empty() {
-                                data.push(b'\n');
-                        }
-                        data.extend_from_slice(&name.as_bytes());
-                    }
-                    println!("Created a dirResource for path = {:?} with data = {:?}", path, data);
-                    Box::new(DirResource::new(e.to_dir(), Some(data), Some(self.mount_uid),
-                                              Some(self.mount_gid), Some(self.mount_mode)))
+                    let data = self.listing(&e.to_dir(), &mut fs);
+                    scheme_debug!("Created a dirResource for path = {:?} with data = {:?}", path, data);
+                    Box::new(DirResource::new(e.to_dir(), flags, Some(data), Some(self.mount_uid),
+                                              Some(self.mount_gid), Some(self.entry_mode(&e))))
                 } else if flags & O_WRONLY == O_WRONLY {
                     // println!("{:X} & {:X}: EISDIR {}", flags, O_DIRECTORY, path);
                     return Err(Error::new(EISDIR));
                 } else {
-                    Box::new(DirResource::new(e.to_dir(), None, Some(self.mount_uid),
-                                              Some(self.mount_gid), Some(self.mount_mode)))
+                    Box::new(DirResource::new(e.to_dir(), flags, None, Some(self.mount_uid),
+                                              Some(self.mount_gid), Some(self.entry_mode(&e))))
                 }
             } /*else if node.1.is_symlink() && !(flags & O_STAT == O_STAT && flags & O_NOFOLLOW == O_NOFOLLOW) && flags & O_SYMLINK != O_SYMLINK {
                 let mut resolve_nodes = Vec::new();
@@ -153,33 +843,41 @@ impl<D: Read + Write + Seek> Scheme for FileScheme<D> {
                     return Err(Error::new(ENOTDIR));
                 }
 
-                if (flags & O_ACCMODE == O_RDONLY || flags & O_ACCMODE == O_RDWR) && !self.permission(uid, gid, MODE_READ) {
+                if (flags & O_ACCMODE == O_RDONLY || flags & O_ACCMODE == O_RDWR) && !self.entry_permission(&e, uid, gid, MODE_READ) {
                     // println!("file not readable {:o}", node.1.mode);
                     return Err(Error::new(EACCES));
                 }
 
-                if (flags & O_ACCMODE == O_WRONLY || flags & O_ACCMODE == O_RDWR) && !self.permission(uid, gid, MODE_WRITE) {
+                if (flags & O_ACCMODE == O_WRONLY || flags & O_ACCMODE == O_RDWR) && !self.entry_permission(&e, uid, gid, MODE_WRITE) {
                     // println!("file not writable {:o}", node.1.mode);
                     return Err(Error::new(EACCES));
                 }
 
+                let mut file = open_entry(&self.files.lock(), e.to_file());
                 if flags & O_TRUNC == O_TRUNC {
-                    if self.permission(uid, gid, MODE_WRITE) {
+                    if !self.entry_permission(&e, uid, gid, MODE_WRITE) {
                         // println!("file not writable {:o}", node.1.mode);
                         return Err(Error::new(EACCES));
                     }
+                    // The volume label has no contents to drop
+                    if e.is_vol_id() {
+                        return Err(Error::new(EINVAL));
+                    }
 
-                    from(e.to_file().truncate(&mut fs, 0))?;
+                    from(fs.check_writable())?;
+                    self.contents_changed(&e);
+                    from(file.set_len(&mut fs, 0))?;
+                    self.entry_changed(EventKind::Write, path, None);
                 }
 
                 let seek = if flags & O_APPEND == O_APPEND {
-                    e.to_file().size()
+                    file.size()
                 } else {
                     0
                 };
 
-                Box::new(FileResource::new(e.to_file(), flags,
-                                           seek, Some(self.mount_uid), Some(self.mount_gid), Some(self.mount_mode)))
+                Box::new(FileResource::new(file, flags,
+                                           seek, Some(self.mount_uid), Some(self.mount_gid), Some(self.entry_mode(&e))))
             },
             None => if flags & O_CREAT == O_CREAT {
                 let mut last_part = String::new();
@@ -192,6 +890,7 @@ impl<D: Read + Write + Seek> Scheme for FileScheme<D> {
                 if last_part.is_empty() {
                     return Err(Error::new(EPERM))
                 }
+                from(fs.check_writable())?;
                 //let parent_dir = from(Dir::get_parent(path, fs))?
 
                 let root_dir = fs.root_dir();
@@ -208,6 +907,8 @@ impl<D: Read + Write + Seek> Scheme for FileScheme<D> {
                     return Err(Error::new(EPERM))
                 }
 
+                self.check_reserved(uid, 0, &mut fs)?;
+
                         /*let ctime = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
                         let mut node = fs.create_node(mode_type | (flags as u16 & MODE_PERM), &last_part, parent.0, ctime.as_secs(), ctime.subsec_nanos())?;
                         node.1.uid = uid;
@@ -217,17 +918,23 @@ impl<D: Read + Write + Seek> Scheme for FileScheme<D> {
 
                 if dir {
                     let d = from(root_dir.create_dir(path, &mut fs))?;
-                    Box::new(DirResource::new(d, None,
-                                              Some(self.mount_uid), Some(self.mount_gid),Some(self.mount_mode)))
+                    self.entries_changed(d.parent_dir);
+                    self.entry_changed(EventKind::Create, path, None);
+                    Box::new(DirResource::new(d, flags, None,
+                                              Some(self.mount_uid), Some(self.mount_gid),Some(self.dir_mode())))
                 } else {
                     let file = from(root_dir.create_file(path, &mut fs))?;
+                    self.entries_changed(file.parent_dir);
+                    self.entry_changed(EventKind::Create, path, None);
                     let seek = if flags & O_APPEND == O_APPEND {
                         file.size()
                     } else {
                         0
                     };
 
-                    Box::new(FileResource::new(file, flags, seek, Some(self.mount_uid), Some(self.mount_gid), Some(self.mount_mode)))
+                    // Created files never have RD_ONLY
+                    let mode = self.file_mode(FileAttributes::empty());
+                    Box::new(FileResource::new(file, flags, seek, Some(self.mount_uid), Some(self.mount_gid), Some(mode)))
                 }
 
 
@@ -237,7 +944,14 @@ impl<D: Read + Write + Seek> Scheme for FileScheme<D> {
         };
 
         let id = self.next_id.fetch_add(1, Ordering::SeqCst);
-        self.files.lock().insert(id, resource);
+        let mut files = self.files.lock();
+        // O_TRUNC emptied the file under the other handles
+        if let Ok(e) = resource.get_dirent() {
+            share_entry(&mut files, id, &e);
+        }
+        files.insert(id, resource);
+        self.openers.lock().insert(id, uid);
+        span.record_id(id);
 
         Ok(id)
     }
@@ -247,13 +961,17 @@ impl<D: Read + Write + Seek> Scheme for FileScheme<D> {
     }
 
     fn rmdir(&self, url: &[u8], uid: u32, gid: u32) -> Result<usize> {
-        let path = str::from_utf8(url).unwrap_or("").trim_matches('/');
+        let path = request_path(url)?;
 
-        println!("Rmdir '{}'", path);
+        scheme_debug!("Rmdir '{}'", path);
 
+        self.check_writable()?;
+        if Dir::is_root_path(path) {
+            return Err(Error::new(EBUSY));
+        }
         let mut fs = self.fs.borrow_mut();
 
-        let dir_ent = Dir::get_entry_abs(path, &mut fs).ok();
+        let dir_ent = self.lookup(path, &mut fs);
         if let Some(child) = dir_ent {
             if ! self.permission(uid, gid, MODE_WRITE) {
                 // println!("dir not writable {:o}", parent.1.mode);
@@ -262,7 +980,11 @@ impl<D: Read + Write + Seek> Scheme for FileScheme<D> {
 
             if child.is_dir() {
                 let root_dir = fs.root_dir();
-                from(root_dir.remove(path, &mut fs, true).map(|_x| 0 as usize))
+                from(root_dir.remove(path, &mut fs, true))?;
+                self.entries_changed(child.parent_dir());
+                self.entry_removed(&child);
+                self.entry_changed(EventKind::Delete, path, None);
+                Ok(0)
             } else {
                     Err(Error::new(ENOTDIR))
             }
@@ -273,22 +995,28 @@ impl<D: Read + Write + Seek> Scheme for FileScheme<D> {
     }
 
     fn unlink(&self, url: &[u8], uid: u32, gid: u32) -> Result<usize> {
-        let path = str::from_utf8(url).unwrap_or("").trim_matches('/');
+        let path = request_path(url)?;
 
-        println!("Unlink '{}'", path);
+        scheme_debug!("Unlink '{}'", path);
 
+        self.check_writable()?;
         let mut fs = self.fs.borrow_mut();
 
 
-        if let Some(child) = Dir::get_entry_abs(path, &mut fs).ok() {
+        if let Some(child) = self.lookup(path, &mut fs) {
                 if ! self.permission(uid, gid, MODE_WRITE) {
                     // println!("dir not writable {:o}", parent.1.mode);
                     return Err(Error::new(EACCES));
                 }
 
                 if ! child.is_dir() {
+                    self.contents_changed(&child);
                     let root_dir = fs.root_dir();
-                    from(root_dir.remove(path, &mut fs, true).map(|_r| 0 as usize))
+                    from(root_dir.remove(path, &mut fs, true))?;
+                    self.entries_changed(child.parent_dir());
+                    self.entry_removed(&child);
+                    self.entry_changed(EventKind::Delete, path, None);
+                    Ok(0)
                 } else {
                     Err(Error::new(EISDIR))
                 }
@@ -301,13 +1029,37 @@ impl<D: Read + Write + Seek> Scheme for FileScheme<D> {
     /* Resource operations */
     #[allow(unused_variables)]
     fn dup(&self, old_id: usize, buf: &[u8]) -> Result<usize> {
-        println!("Dup {}", old_id);
+        scheme_debug!("Dup {}", old_id);
+
+        if buf == CAPABILITIES {
+            let valid = self.files.lock().contains_key(&old_id) ||
+                self.watchers.lock().contains_key(&old_id) ||
+                self.capabilities.lock().contains_key(&old_id);
+            if !valid {
+                return Err(Error::new(EBADF));
+            }
+            let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+            self.capabilities.lock().insert(id, Capabilities::new(Features::of_mount(&self.options)));
+            return Ok(id);
+        }
+
+        if buf == RESOURCES {
+            match self.openers.lock().get(&old_id) {
+                Some(uid) if self.owner(*uid) => {},
+                Some(_) => return Err(Error::new(EACCES)),
+                None => return Err(Error::new(EBADF))
+            }
+            let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+            self.dumps.lock().insert(id, Snapshot::new(self.resource_table()));
+            return Ok(id);
+        }
 
         if ! buf.is_empty() {
             return Err(Error::new(EINVAL));
         }
 
         let mut files = self.files.lock();
+        self.check_open_files(&files)?;
         let resource = if let Some(old_resource) = files.get(&old_id) {
             old_resource.dup()?
         } else {
@@ -316,35 +1068,73 @@ impl<D: Read + Write + Seek> Scheme for FileScheme<D> {
 
         let id = self.next_id.fetch_add(1, Ordering::SeqCst);
         files.insert(id, resource);
+        let uid = self.openers.lock().get(&old_id).cloned();
+        if let Some(uid) = uid {
+            self.openers.lock().insert(id, uid);
+        }
 
         Ok(id)
     }
 
     #[allow(unused_variables)]
     fn read(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
-        println!("Read {}, {:X} {}", id, buf.as_ptr() as usize, buf.len());
+        scheme_debug!("Read {}, {:X} {}", id, buf.as_ptr() as usize, buf.len());
+        let span = OpSpan::enter("read");
+        span.record_id(id);
+        if let Some(watcher) = self.watchers.lock().get_mut(&id) {
+            return Ok(watcher.read(buf));
+        }
+        if let Some(caps) = self.capabilities.lock().get_mut(&id) {
+            return Ok(caps.read(buf));
+        }
+        if let Some(dump) = self.dumps.lock().get_mut(&id) {
+            return Ok(dump.read(buf));
+        }
         let mut files = self.files.lock();
         let mut fs = self.fs.borrow_mut();
         if let Some(file) = files.get_mut(&id) {
-            file.read(buf, &mut fs)
+            if span.enabled() {
+                if let Ok(e) = file.get_dirent() {
+                    span.record_path(&e.dir_path());
+                }
+            }
+            let mut cache = self.file_cache.as_ref().map(|c| c.lock());
+            let count = file.read(buf, cache.as_deref_mut(), &self.generations.lock(), &mut fs)?;
+            span.record_io(count, fs.bytes_per_cluster());
+            Ok(count)
         } else {
             Err(Error::new(EBADF))
         }
     }
 
     fn write(&self, id: usize, buf: &[u8]) -> Result<usize> {
-        println!("Write {}, {:X} {}", id, buf.as_ptr() as usize, buf.len());
+        scheme_debug!("Write {}, {:X} {}", id, buf.as_ptr() as usize, buf.len());
+        let span = OpSpan::enter("write");
+        span.record_id(id);
+        self.check_writable()?;
         let mut files = self.files.lock();
         let mut fs = self.fs.borrow_mut();
         if let Some(file) = files.get_mut(&id) {
-            file.write(buf, &mut fs)
+            let uid = self.openers.lock().get(&id).cloned().unwrap_or(0);
+            self.check_reserved(uid, 0, &mut fs)?;
+            if let Ok(e) = file.get_dirent() {
+                self.contents_changed(&e);
+            }
+            let count = file.write(buf, &mut fs)?;
+            span.record_io(count, fs.bytes_per_cluster());
+            if let Ok(e) = file.get_dirent() {
+                span.record_path(&e.dir_path());
+                self.entry_changed(EventKind::Write, &e.dir_path(), None);
+                share_entry(&mut files, id, &e);
+            }
+            Ok(count)
         } else {
             Err(Error::new(EBADF))
         }
     }
 
     fn seek(&self, id: usize, pos: usize, whence: usize) -> Result<usize> {
-        println!("Seek {}, {} {}", id, pos, whence);
+        scheme_debug!("Seek {}, {} {}", id, pos, whence);
         let mut files = self.files.lock();
         let mut fs = self.fs.borrow_mut();
         if let Some(file) = files.get_mut(&id) {
@@ -372,7 +1162,7 @@ impl<D: Read + Write + Seek> Scheme for FileScheme<D> {
     }
 
     fn fpath(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
-        println!("Fpath {}, {:X} {}", id, buf.as_ptr() as usize, buf.len());
+        scheme_debug!("Fpath {}, {:X} {}", id, buf.as_ptr() as usize, buf.len());
         let files = self.files.lock();
         if let Some(file) = files.get(&id) {
             let name = self.name.as_bytes();
@@ -391,16 +1181,31 @@ impl<D: Read + Write + Seek> Scheme for FileScheme<D> {
                 i += 1;
             }
 
-            file.path(&mut buf[i..]).map(|count| i + count)
+            if self.options.name_translation != NameTranslation::Percent {
+                return file.path(&mut buf[i..]).map(|count| i + count)
+            }
+            let mut path = vec![0; 4096];
+            let count = file.path(&mut path)?;
+            let path = String::from_utf8_lossy(&path[..count]);
+            let shown = self.options.name_translation.to_shown(&path);
+            let count = min(shown.len(), buf.len() - i);
+            buf[i..i + count].copy_from_slice(&shown.as_bytes()[..count]);
+            Ok(i + count)
         } else {
             Err(Error::new(EBADF))
         }
     }
 
     fn frename(&self, id: usize, url: &[u8], uid: u32, _gid: u32) -> Result<usize> {
-        let path = str::from_utf8(url).unwrap_or("").trim_matches('/');
+        let path = request_path(url)?;
 
-        println!("Frename {}, {} from {}, {}", id, path, uid, _gid);
+        scheme_debug!("Frename {}, {} from {}, {}", id, path, uid, _gid);
+        let span = OpSpan::enter("frename");
+        span.record_id(id);
+        span.record_path(path);
+
+        let path = self.local_path(url)?;
+        self.check_writable()?;
 
         let mut files = self.files.lock();
         if let Some(file) = files.get_mut(&id) {
@@ -421,13 +1226,30 @@ impl<D: Read + Write + Seek> Scheme for FileScheme<D> {
             let mut fs = self.fs.borrow_mut();
 
             let mut orig = file.get_dirent()?;
+            if orig.is_root() {
+                return Err(Error::new(EPERM));
+            }
 
 
             if ! self.owner(uid) {
                 // println!("orig not owned by caller {}", uid);
                 return Err(Error::new(EACCES));
             }
+            let orig_path = orig.dir_path();
+            let orig_parent = orig.parent_dir();
+            self.contents_changed(&orig);
+            // A file renamed over gives up its clusters
+            let replaced = self.lookup(path, &mut fs);
+            if let Some(ref replaced) = replaced {
+                self.contents_changed(replaced);
+            }
             from(Dir::rename(&mut orig, path, &mut fs).map(|_x| 0 as usize))?;
+            if let Some(ref replaced) = replaced {
+                self.entry_removed(replaced);
+            }
+            self.entries_changed(orig_parent);
+            self.entries_changed(orig.parent_dir());
+            self.entry_changed(EventKind::Rename, &orig_path, Some(path));
             file.set_dirent(orig.clone())
             /*
             let mut nodes = Vec::new();
@@ -500,28 +1322,35 @@ impl<D: Read + Write + Seek> Scheme for FileScheme<D> {
     }
 
     fn fstat(&self, id: usize, stat: &mut Stat) -> Result<usize> {
-        println!("Fstat {}, {:X}", id, stat as *mut Stat as usize);
+        scheme_debug!("Fstat {}, {:X}", id, stat as *mut Stat as usize);
         let files = self.files.lock();
         if let Some(file) = files.get(&id) {
-            file.stat(stat, &mut self.fs.borrow_mut())
+            let mut fs = self.fs.borrow_mut();
+            file.stat(stat, &mut fs)?;
+            if stat.st_mode & MODE_TYPE == MODE_DIR {
+                stat.st_nlink = self.dir_links(&file.get_dirent()?.to_dir(), &mut fs);
+            }
+            Ok(0)
         } else {
             Err(Error::new(EBADF))
         }
     }
 
     fn fstatvfs(&self, id: usize, stat: &mut StatVfs) -> Result<usize> {
-        println!("FstatVfs {}, {:X}", id, stat as *mut StatVfs as usize);
+        scheme_debug!("FstatVfs {}, {:X}", id, stat as *mut StatVfs as usize);
         let files = self.files.lock();
         if let Some(_file) = files.get(&id) {
             let mut fs = self.fs.borrow_mut();
 
             /*let free = fs.header.1.free;
             let free_size = fs.node_len(free)?;*/
+            // Clusters are the unit of allocation and the preferred IO size
             let max_cluster = fs.max_cluster_number();
-            stat.f_bsize = max_cluster.cluster_number as u32;
+            stat.f_bsize = fs.bytes_per_cluster() as u32;
             stat.f_blocks = max_cluster.cluster_number - 1;
             stat.f_bfree = from(get_free_count(&mut fs, max_cluster).map(|x| x as usize))? as u64;
             stat.f_bavail = stat.f_bfree;
+            // StatVfs has no file counts, the slots left in a fixed root are in `stats`
 
             Ok(0)
         } else {
@@ -530,27 +1359,56 @@ impl<D: Read + Write + Seek> Scheme for FileScheme<D> {
     }
 
     fn fsync(&self, id: usize) -> Result<usize> {
-        println!("Fsync {}", id);
+        scheme_debug!("Fsync {}", id);
         let mut files = self.files.lock();
         if let Some(file) = files.get_mut(&id) {
-            file.sync(&mut self.fmaps.lock(), &mut self.fs.borrow_mut())
+            // Writes back what was changed through fmap, bumping the generation
+            let res = file.sync(&mut self.fmaps.lock(), &mut self.generations.lock(), &mut self.fs.borrow_mut());
+            self.refresh_entry(file);
+            if let Ok(e) = file.get_dirent() {
+                share_entry(&mut files, id, &e);
+            }
+            let count = res?;
+            from(self.fs.borrow_mut().sync_fat_mirrors())?;
+            Ok(count)
         } else {
             Err(Error::new(EBADF))
         }
     }
 
     fn ftruncate(&self, id: usize, len: usize) -> Result<usize> {
-        println!("Ftruncate {}, {}", id, len);
+        scheme_debug!("Ftruncate {}, {}", id, len);
+        self.check_writable()?;
         let mut files = self.files.lock();
+        let mut fs = self.fs.borrow_mut();
         if let Some(file) = files.get_mut(&id) {
-            file.truncate(len, &mut self.fs.borrow_mut())
+            if let Ok(e) = file.get_dirent() {
+                if !e.is_dir() {
+                    // Growing the file allocates the clusters up to the new length
+                    let bpc = fs.bytes_per_cluster();
+                    let needed = (len as u64 + bpc - 1) / bpc;
+                    let allocated = e.metadata(&mut fs).clusters();
+                    if needed > allocated {
+                        let uid = self.openers.lock().get(&id).cloned().unwrap_or(0);
+                        self.check_reserved(uid, needed - allocated, &mut fs)?;
+                    }
+                }
+                self.contents_changed(&e);
+            }
+            let count = file.truncate(len, &mut fs)?;
+            if let Ok(e) = file.get_dirent() {
+                self.entry_changed(EventKind::Write, &e.dir_path(), None);
+                share_entry(&mut files, id, &e);
+            }
+            Ok(count)
         } else {
             Err(Error::new(EBADF))
         }
     }
 
     fn futimens(&self, id: usize, times: &[TimeSpec]) -> Result<usize> {
-        println!("Futimens {}, {}", id, times.len());
+        scheme_debug!("Futimens {}, {}", id, times.len());
+        self.check_writable()?;
         let mut files = self.files.lock();
         if let Some(file) = files.get_mut(&id) {
             file.utimens(times, self.mount_uid, &mut self.fs.borrow_mut())
@@ -560,21 +1418,48 @@ impl<D: Read + Write + Seek> Scheme for FileScheme<D> {
     }
 
     fn fmap(&self, id: usize, map: &Map) -> Result<usize> {
-        println!("Fmap {}, {:?}", id, map);
+        scheme_debug!("Fmap {}, {:?}", id, map);
+        if map.flags & PROT_WRITE > 0 {
+            self.check_writable()?;
+        }
         let mut files = self.files.lock();
         if let Some(file) = files.get_mut(&id) {
-            file.fmap(map, &mut self.fmaps.lock(), &mut self.fs.borrow_mut())
+            file.fmap(map, &mut self.fmaps.lock(), &self.generations.lock(), &mut self.fs.borrow_mut())
+        } else {
+            Err(Error::new(EBADF))
+        }
+    }
+
+    fn fevent(&self, id: usize, flags: usize) -> Result<usize> {
+        if let Some(watcher) = self.watchers.lock().get_mut(&id) {
+            watcher.fevent = flags;
+            Ok(0)
         } else {
             Err(Error::new(EBADF))
         }
     }
 
     fn close(&self, id: usize) -> Result<usize> {
-        println!("Close {}", id);
+        scheme_debug!("Close {}", id);
+        if self.watchers.lock().remove(&id).is_some() || self.capabilities.lock().remove(&id).is_some() ||
+            self.dumps.lock().remove(&id).is_some() {
+            return Ok(0);
+        }
+        self.openers.lock().remove(&id);
         let mut files = self.files.lock();
         if let Some(mut file) = files.remove(&id) {
-            let _ = file.funmap(&mut self.fmaps.lock(), &mut self.fs.borrow_mut());
-            Ok(0)
+            // Directories have no mapping to write back
+            let unmapped = match file.get_dirent() {
+                Ok(ref e) if e.is_dir() => Ok(0),
+                _ => file.funmap(&mut self.fmaps.lock(), &mut self.generations.lock(), &mut self.fs.borrow_mut())
+            };
+            // Writes back the short entry if the size changed
+            let synced = file.sync(&mut self.fmaps.lock(), &mut self.generations.lock(), &mut self.fs.borrow_mut());
+            self.refresh_entry(&file);
+            if let Ok(e) = file.get_dirent() {
+                share_entry(&mut files, id, &e);
+            }
+            unmapped.and(synced).map(|_| 0)
         } else {
             Err(Error::new(EBADF))
         }
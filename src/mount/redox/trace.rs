@@ -0,0 +1,64 @@
+/// Span around one scheme call, carrying the handle, path and amount of IO
+///
+/// Spans are only recorded with the "trace" feature and a subscriber installed, the time
+/// spent in a call is reported by the subscriber when the span closes.
+/// Without the feature this compiles to nothing.
+pub struct OpSpan {
+    #[cfg(feature = "trace")]
+    span: tracing::span::EnteredSpan
+}
+
+impl OpSpan {
+    #[cfg(feature = "trace")]
+    pub fn enter(op: &'static str) -> OpSpan {
+        let span = tracing::info_span!("scheme", op, id = tracing::field::Empty, path = tracing::field::Empty,
+                                       bytes = tracing::field::Empty, clusters = tracing::field::Empty);
+        OpSpan { span: span.entered() }
+    }
+
+    #[cfg(not(feature = "trace"))]
+    pub fn enter(_op: &'static str) -> OpSpan {
+        OpSpan {}
+    }
+
+    /// Whether anything recorded is kept, checked before building paths only needed here
+    pub fn enabled(&self) -> bool {
+        #[cfg(feature = "trace")]
+        return !self.span.is_disabled();
+        #[cfg(not(feature = "trace"))]
+        false
+    }
+
+    #[allow(unused_variables)]
+    pub fn record_id(&self, id: usize) {
+        #[cfg(feature = "trace")]
+        self.span.record("id", &id);
+    }
+
+    #[allow(unused_variables)]
+    pub fn record_path(&self, path: &str) {
+        #[cfg(feature = "trace")]
+        self.span.record("path", &path.trim_matches('/'));
+    }
+
+    /// Bytes transferred and the number of clusters they fill
+    #[allow(unused_variables)]
+    pub fn record_io(&self, bytes: usize, bytes_per_cluster: u64) {
+        #[cfg(feature = "trace")]
+        {
+            let clusters = (bytes as u64 + bytes_per_cluster - 1) / bytes_per_cluster;
+            self.span.record("bytes", &bytes);
+            self.span.record("clusters", &clusters);
+        }
+    }
+}
+
+/// Debug output of the scheme, sent to tracing when it is enabled and to log otherwise
+macro_rules! scheme_debug {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "trace")]
+        ::tracing::debug!($($arg)*);
+        #[cfg(not(feature = "trace"))]
+        debug!($($arg)*);
+    }}
+}
@@ -0,0 +1,23 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+
+use syscall::Packet;
+
+/// Carries packets between the kernel and the scheme
+/// The scheme socket on Redox, anything else for driving the scheme without a kernel
+pub trait Transport {
+    /// Reads the next request, returning 0 once the other side has gone away
+    fn recv(&mut self, packet: &mut Packet) -> io::Result<usize>;
+    /// Sends a reply or an event
+    fn send(&mut self, packet: &Packet) -> io::Result<usize>;
+}
+
+impl Transport for File {
+    fn recv(&mut self, packet: &mut Packet) -> io::Result<usize> {
+        self.read(packet)
+    }
+
+    fn send(&mut self, packet: &Packet) -> io::Result<usize> {
+        self.write(packet)
+    }
+}
@@ -0,0 +1,80 @@
+use std::cmp::min;
+
+/// Opening a path starting with this watches everything under the rest of the path
+/// '?' is not allowed in FAT names so this never shadows a real entry
+pub const WATCH_PREFIX: &str = "?watch";
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EventKind {
+    Create,
+    Delete,
+    Rename,
+    Write
+}
+
+impl EventKind {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            EventKind::Create => "create",
+            EventKind::Delete => "delete",
+            EventKind::Rename => "rename",
+            EventKind::Write => "write"
+        }
+    }
+}
+
+/// Queues events under a path, read back one per line as "<kind> <path> [<new path>]"
+pub struct Watcher {
+    path: String,
+    pending: Vec<u8>,
+    /// Flags registered through fevent
+    pub fevent: usize,
+    /// Set once an fevent has been sent for the pending data
+    pub notified: bool
+}
+
+impl Watcher {
+    pub fn new(path: &str) -> Watcher {
+        Watcher {
+            path: path.trim_matches('/').to_string(),
+            pending: Vec::new(),
+            fevent: 0,
+            notified: false
+        }
+    }
+
+    fn watches(&self, path: &str) -> bool {
+        let path = path.trim_matches('/');
+        self.path.is_empty() || path == self.path ||
+            (path.starts_with(&self.path) && path[self.path.len()..].starts_with('/'))
+    }
+
+    pub fn push(&mut self, kind: EventKind, path: &str, new_path: Option<&str>) {
+        if !self.watches(path) && !new_path.map_or(false, |p| self.watches(p)) {
+            return;
+        }
+
+        self.pending.extend_from_slice(kind.as_str().as_bytes());
+        self.pending.push(b' ');
+        self.pending.extend_from_slice(path.trim_matches('/').as_bytes());
+        if let Some(p) = new_path {
+            self.pending.push(b' ');
+            self.pending.extend_from_slice(p.trim_matches('/').as_bytes());
+        }
+        self.pending.push(b'\n');
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let count = min(buf.len(), self.pending.len());
+        buf[..count].copy_from_slice(&self.pending[..count]);
+        self.pending.drain(..count);
+        if self.pending.is_empty() {
+            self.notified = false;
+        }
+        count
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::BTreeMap;
+use std::io::{Read, Write, Seek, SeekFrom, Error, ErrorKind};
+use std::cmp::min;
+
+use BLOCK_SIZE;
+use super::Result;
+
+/// Copy-on-write view of a disk image
+///
+/// Written blocks are kept in memory and reads of unmodified blocks go to the base image,
+/// which is never written. Useful for trying repairs on an image before committing to them.
+pub struct Overlay<D: Read + Seek> {
+    base: D,
+    base_len: u64,
+    len: u64,
+    pos: u64,
+    blocks: BTreeMap<u64, Vec<u8>>
+}
+
+impl<D: Read + Seek> Overlay<D> {
+    pub fn new(mut base: D) -> Result<Overlay<D>> {
+        let base_len = base.seek(SeekFrom::End(0))?;
+        Ok(Overlay {
+            base: base,
+            base_len: base_len,
+            len: base_len,
+            pos: 0,
+            blocks: BTreeMap::new()
+        })
+    }
+
+    /// Block numbers and contents of every block written so far, in order
+    pub fn modified_blocks(&self) -> impl Iterator<Item = (u64, &[u8])> {
+        self.blocks.iter().map(|(&n, data)| (n, data.as_slice()))
+    }
+
+    /// Drops all changes
+    pub fn discard(&mut self) {
+        self.blocks.clear();
+        self.len = self.base_len;
+    }
+
+    pub fn base(&self) -> &D {
+        &self.base
+    }
+
+    /// Returns the untouched base image
+    pub fn into_inner(self) -> D {
+        self.base
+    }
+
+    // Short reads near the end of the base are padded with zeroes
+    fn read_base_block(&mut self, block: u64, buf: &mut [u8]) -> Result<()> {
+        for b in buf.iter_mut() {
+            *b = 0;
+        }
+        let start = block * BLOCK_SIZE;
+        if start >= self.base_len {
+            return Ok(())
+        }
+        let len = min(BLOCK_SIZE, self.base_len - start) as usize;
+        self.base.seek(SeekFrom::Start(start))?;
+        self.base.read_exact(&mut buf[..len])
+    }
+}
+
+impl<D: Read + Seek> Read for Overlay<D> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.pos >= self.len {
+            return Ok(0)
+        }
+        let block = self.pos / BLOCK_SIZE;
+        let offset = (self.pos % BLOCK_SIZE) as usize;
+        let count = min(min(buf.len() as u64, BLOCK_SIZE - offset as u64), self.len - self.pos) as usize;
+
+        match self.blocks.get(&block) {
+            Some(data) => buf[..count].copy_from_slice(&data[offset..offset + count]),
+            None => {
+                let mut data = vec![0; BLOCK_SIZE as usize];
+                self.read_base_block(block, &mut data)?;
+                buf[..count].copy_from_slice(&data[offset..offset + count]);
+            }
+        }
+        self.pos += count as u64;
+        Ok(count)
+    }
+}
+
+impl<D: Read + Seek> Write for Overlay<D> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let block = self.pos / BLOCK_SIZE;
+        let offset = (self.pos % BLOCK_SIZE) as usize;
+        let count = min(buf.len(), BLOCK_SIZE as usize - offset);
+
+        if !self.blocks.contains_key(&block) {
+            let mut data = vec![0; BLOCK_SIZE as usize];
+            // Whole blocks are replaced without reading them first
+            if count < BLOCK_SIZE as usize {
+                self.read_base_block(block, &mut data)?;
+            }
+            self.blocks.insert(block, data);
+        }
+        let data = self.blocks.get_mut(&block).unwrap(); // SAFE
+        data[offset..offset + count].copy_from_slice(&buf[..count]);
+
+        self.pos += count as u64;
+        if self.pos > self.len {
+            self.len = self.pos;
+        }
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<D: Read + Seek> Seek for Overlay<D> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(p) => (self.len as i64).checked_add(p).filter(|&p| p >= 0).map(|p| p as u64),
+            SeekFrom::Current(p) => (self.pos as i64).checked_add(p).filter(|&p| p >= 0).map(|p| p as u64)
+        };
+        match new_pos {
+            Some(p) => {
+                self.pos = p;
+                Ok(p)
+            },
+            None => Err(Error::new(ErrorKind::InvalidInput, "Seek to a negative offset"))
+        }
+    }
+}
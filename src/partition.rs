@@ -0,0 +1,149 @@
+//! Finding FAT volumes on partitioned disks
+//!
+//! MBR and GPT partition tables are read with 512 byte sectors. Extended MBR partitions
+//! aren't followed.
+
+use std::io::{Read, Seek, SeekFrom, Error, ErrorKind};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use bpb::{BiosParameterBlock, FATType};
+use BLOCK_SIZE;
+
+use super::Result;
+
+const SECTOR_SIZE: u64 = 512;
+const MBR_ENTRIES_OFFSET: usize = 446;
+const MBR_ENTRY_LEN: usize = 16;
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xee;
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+/// More entries than any partitioning tool creates, bounds reads of a damaged header
+const GPT_MAX_ENTRIES: u32 = 1024;
+
+/// A FAT volume found by `probe_fat` or `enumerate_fat_volumes`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct FatProbeInfo {
+    /// Number of the partition table entry starting at 1, `None` for an unpartitioned disk
+    pub partition: Option<usize>,
+    /// 12, 16 or 32
+    pub fat_bits: u8,
+    pub serial: u32,
+    /// Size of the volume in bytes
+    pub len: u64
+}
+
+/// Looks for a FAT boot sector at byte `offset` of `disk`
+pub fn probe_fat<D: Read + Seek>(disk: &mut D, offset: u64) -> Result<Option<FatProbeInfo>> {
+    let mut sector = [0; SECTOR_SIZE as usize];
+    if !read_sector(disk, offset, &mut sector)? || &sector[510..] != &[0x55, 0xaa] {
+        return Ok(None)
+    }
+
+    disk.seek(SeekFrom::Start(offset))?;
+    let bpb = match BiosParameterBlock::populate(disk) {
+        Ok(bpb) => bpb,
+        // Whatever is there doesn't validate as a BPB
+        Err(ref e) if e.kind() == ErrorKind::Other || e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e)
+    };
+    let fat_bits = match bpb.fat_type {
+        FATType::FAT12(_) => 12,
+        FATType::FAT16(_) => 16,
+        FATType::FAT32(_) => 32
+    };
+    Ok(Some(FatProbeInfo {
+        partition: None,
+        fat_bits: fat_bits,
+        serial: bpb.get_serial(),
+        len: bpb.total_sectors() * bpb.bytes_per_sector as u64
+    }))
+}
+
+/// Byte offsets and descriptions of the FAT volumes on `disk`
+///
+/// A disk formatted without a partition table gives a single volume at offset 0.
+/// Otherwise every MBR or GPT partition is probed, whatever its type says, and those
+/// holding a FAT volume are returned in table order. Partitions not starting on a
+/// `BLOCK_SIZE` boundary can't be opened by `FileSystem::from_offset` and are left out.
+pub fn enumerate_fat_volumes<D: Read + Seek>(disk: &mut D) -> Result<Vec<(u64, FatProbeInfo)>> {
+    if let Some(info) = probe_fat(disk, 0)? {
+        return Ok(vec![(0, info)])
+    }
+
+    let mut volumes = Vec::new();
+    for (number, start) in partition_starts(disk)? {
+        if start % BLOCK_SIZE != 0 {
+            warn!("Partition {} at {:#x} is not aligned to {} bytes, skipping it", number, start, BLOCK_SIZE);
+            continue;
+        }
+        if let Some(mut info) = probe_fat(disk, start)? {
+            info.partition = Some(number);
+            volumes.push((start, info));
+        }
+    }
+    Ok(volumes)
+}
+
+// Partition numbers and byte offsets of the used entries of the partition table
+fn partition_starts<D: Read + Seek>(disk: &mut D) -> Result<Vec<(usize, u64)>> {
+    let mut mbr = [0; SECTOR_SIZE as usize];
+    if !read_sector(disk, 0, &mut mbr)? || &mbr[510..] != &[0x55, 0xaa] {
+        return Ok(Vec::new())
+    }
+
+    let mut starts = Vec::new();
+    for i in 0..4 {
+        let entry = &mbr[MBR_ENTRIES_OFFSET + i * MBR_ENTRY_LEN..MBR_ENTRIES_OFFSET + (i + 1) * MBR_ENTRY_LEN];
+        let kind = entry[4];
+        let start_lba = LittleEndian::read_u32(&entry[8..12]) as u64;
+        if kind == MBR_TYPE_GPT_PROTECTIVE {
+            return gpt_partition_starts(disk)
+        }
+        if kind != 0 && start_lba != 0 {
+            starts.push((i + 1, lba_offset(start_lba)?));
+        }
+    }
+    Ok(starts)
+}
+
+fn gpt_partition_starts<D: Read + Seek>(disk: &mut D) -> Result<Vec<(usize, u64)>> {
+    let mut header = [0; SECTOR_SIZE as usize];
+    if !read_sector(disk, SECTOR_SIZE, &mut header)? || &header[..8] != GPT_SIGNATURE {
+        return Err(Error::new(ErrorKind::InvalidData, "Protective MBR without a GPT header"))
+    }
+    let entries_lba = LittleEndian::read_u64(&header[72..80]);
+    let count = LittleEndian::read_u32(&header[80..84]);
+    let entry_len = LittleEndian::read_u32(&header[84..88]) as usize;
+    if entry_len < 128 || count > GPT_MAX_ENTRIES {
+        return Err(Error::new(ErrorKind::InvalidData, "Invalid GPT partition entry array"))
+    }
+
+    let mut entries = vec![0; count as usize * entry_len];
+    disk.seek(SeekFrom::Start(lba_offset(entries_lba)?))?;
+    disk.read_exact(&mut entries)?;
+
+    let mut starts = Vec::new();
+    for (i, entry) in entries.chunks(entry_len).enumerate() {
+        // An all zero type GUID marks an unused entry
+        if entry[..16].iter().all(|&b| b == 0) {
+            continue;
+        }
+        starts.push((i + 1, lba_offset(LittleEndian::read_u64(&entry[32..40]))?));
+    }
+    Ok(starts)
+}
+
+// Byte offset of the sector `lba`, which a corrupt table may put past any disk
+fn lba_offset(lba: u64) -> Result<u64> {
+    lba.checked_mul(SECTOR_SIZE).ok_or_else(|| Error::new(ErrorKind::InvalidData, "Partition table points past the end of the disk"))
+}
+
+// Reads the sector at `offset`, false if the disk ends before it
+fn read_sector<D: Read + Seek>(disk: &mut D, offset: u64, buf: &mut [u8]) -> Result<bool> {
+    disk.seek(SeekFrom::Start(offset))?;
+    match disk.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e)
+    }
+}
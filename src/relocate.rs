@@ -0,0 +1,69 @@
+use std::io::{Read, Write, Seek, Error, ErrorKind};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use bpb::FATType;
+use filesystem::{FileSystem, Cluster};
+use table::{get_entry, set_entry, FatEntry, RESERVED_CLUSTERS};
+
+use super::Result;
+
+/// Byte offset of BPB_RootClus in a FAT32 boot sector
+const ROOT_CLUSTER_OFFSET: u64 = 44;
+
+impl<D: Read + Write + Seek> FileSystem<D> {
+    /// Moves the first cluster of the FAT32 root directory to the free `new_cluster`
+    ///
+    /// The entries are copied over, the new cluster takes the place of the old one in the
+    /// chain, BPB_RootClus is updated in the boot sector and then its backup, and the old
+    /// cluster is freed last. A failed write leaves the volume read-only so nothing is put
+    /// back, the order keeps the old root in place until the boot sector is written and a
+    /// failure leaks at most one cluster or leaves the backup stale. Fails with InvalidInput
+    /// on FAT12/16 or if `new_cluster` isn't a free data cluster. `Dir`s of the root taken
+    /// before still name the old cluster.
+    pub fn relocate_root(&mut self, new_cluster: Cluster) -> Result<()> {
+        self.check_writable()?;
+        let old_cluster = match self.bpb.fat_type {
+            FATType::FAT32(s) => Cluster::new(s.root_cluster as u64),
+            _ => return Err(Error::new(ErrorKind::InvalidInput, "Only FAT32 keeps the root directory in clusters"))
+        };
+        if new_cluster == old_cluster {
+            return Ok(())
+        }
+        if new_cluster.cluster_number < RESERVED_CLUSTERS || new_cluster.cluster_number > self.max_cluster_number().cluster_number
+            || get_entry(self, new_cluster)? != FatEntry::Unused {
+            return Err(Error::new(ErrorKind::InvalidInput, "The new root cluster isn't a free data cluster"))
+        }
+
+        let mut buf = vec![0; self.bytes_per_cluster() as usize];
+        let old_offset = self.cluster_offset(old_cluster);
+        self.read_at(old_offset, &mut buf)?;
+        let new_offset = self.cluster_offset(new_cluster);
+        self.write_to(new_offset, &buf)?;
+
+        let next = get_entry(self, old_cluster)?;
+        set_entry(self, new_cluster, next)?;
+        self.disk.borrow_mut().flush()?;
+        self.write_root_cluster(new_cluster)?;
+        self.clear_entry_cache();
+        set_entry(self, old_cluster, FatEntry::Unused)?;
+        self.disk.borrow_mut().flush()
+    }
+
+    // Points BPB_RootClus at `cluster` in the boot sector, which is what a mount reads, and
+    // then in the backup boot sector
+    fn write_root_cluster(&mut self, cluster: Cluster) -> Result<()> {
+        let mut buf = [0u8; 4];
+        LittleEndian::write_u32(&mut buf, cluster.cluster_number as u32);
+        self.write_to(ROOT_CLUSTER_OFFSET, &buf)?;
+        self.disk.borrow_mut().flush()?;
+        if let FATType::FAT32(ref mut s) = self.bpb.fat_type {
+            s.root_cluster = cluster.cluster_number as u32;
+        }
+        if let Some(sector) = self.backup_boot_sector() {
+            let offset = sector * self.bytes_per_sec() + ROOT_CLUSTER_OFFSET;
+            self.write_to(offset, &buf)?;
+        }
+        Ok(())
+    }
+}
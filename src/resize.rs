@@ -0,0 +1,154 @@
+use std::io::{Read, Write, Seek, Error, ErrorKind};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use filesystem::{FileSystem, Cluster};
+use bpb::FATType;
+use table::RESERVED_CLUSTERS;
+use usage::ClusterState;
+
+use super::Result;
+
+// Clears FAT entry `n` in a buffer holding a whole FAT
+fn clear_entry(fat: &mut [u8], entry_bits: u64, n: u64) {
+    let (offset, mask) = match entry_bits {
+        12 => (n + n / 2, if n & 1 > 0 { 0x000f } else { 0xf000 }),
+        16 => (n * 2, 0),
+        _ => (n * 4, 0)
+    };
+    let width = if entry_bits == 32 { 4 } else { 2 };
+    if offset + width > fat.len() as u64 {
+        return
+    }
+    let at = &mut fat[offset as usize..];
+    match entry_bits {
+        32 => LittleEndian::write_u32(at, 0),
+        _ => {
+            let val = LittleEndian::read_u16(at) & mask;
+            LittleEndian::write_u16(at, val);
+        }
+    }
+}
+
+impl<D: Read + Write + Seek> FileSystem<D> {
+    /// Grows or shrinks the volume to `new_total_sectors`, after its partition was resized
+    ///
+    /// When growing past what the FATs can describe they are enlarged, and the root
+    /// directory and data area are moved up to make room, cluster numbers stay the same.
+    /// Shrinking leaves the FATs at their size and fails with InvalidInput if a cluster
+    /// past the new end is allocated. Sizes which would change the FAT type are refused
+    /// with InvalidInput as well. The volume must not be in use elsewhere, and a crash
+    /// halfway through a grow which moves data leaves it corrupt.
+    pub fn resize(&mut self, new_total_sectors: u64) -> Result<()> {
+        self.check_writable()?;
+        if new_total_sectors > u32::max_value() as u64 {
+            return Err(Error::new(ErrorKind::InvalidInput, "Volume size doesn't fit in the BPB"))
+        }
+        let bps = self.bytes_per_sec();
+        let spc = self.sectors_per_cluster();
+        let num_fats = self.bpb.num_fats as u64;
+        let rsvd = self.bpb.rsvd_sec_cnt as u64;
+        let root_sectors = (self.bpb.root_entries_cnt as u64 * 32 + bps - 1) / bps;
+        let entry_bits = match self.bpb.fat_type {
+            FATType::FAT12(_) => 12,
+            FATType::FAT16(_) => 16,
+            FATType::FAT32(_) => 32
+        };
+
+        let old_fat_sz = self.fat_size();
+        let old_max = self.max_cluster_number().cluster_number;
+        let mut fat_sz = old_fat_sz;
+        let new_clusters = loop {
+            let meta = rsvd + num_fats * fat_sz + root_sectors;
+            if new_total_sectors <= meta {
+                return Err(Error::new(ErrorKind::InvalidInput, "Volume too small for its FATs and root directory"))
+            }
+            let clusters = (new_total_sectors - meta) / spc;
+            if (clusters + RESERVED_CLUSTERS) * entry_bits <= fat_sz * bps * 8 {
+                break clusters
+            }
+            fat_sz += 1;
+        };
+        let new_bits = if new_clusters < 4085 { 12 } else if new_clusters < 65525 { 16 } else { 32 };
+        if new_bits != entry_bits || new_clusters > 0x0FFFFFF4 {
+            return Err(Error::new(ErrorKind::InvalidInput, "New size would change the FAT type"))
+        }
+        let new_max = new_clusters + RESERVED_CLUSTERS - 1;
+
+        let usage = self.usage_bitmap()?;
+        let last_used = usage.iter().enumerate()
+            .filter(|&(_, s)| s == ClusterState::Allocated)
+            .map(|(i, _)| i as u64 + RESERVED_CLUSTERS)
+            .last();
+        if last_used.map_or(false, |c| c > new_max) {
+            return Err(Error::new(ErrorKind::InvalidInput, "Clusters past the new end are in use"))
+        }
+
+        self.sync_fat_mirrors()?;
+        let mut fat = vec![0; (old_fat_sz * bps) as usize];
+        let fat_start = self.fat_start_sector() * bps;
+        self.read_at(fat_start, &mut fat)?;
+        fat.resize((fat_sz * bps) as usize, 0);
+        // Entries past either end may hold anything, the new clusters have to be free
+        let (from, to) = if new_max > old_max { (old_max + 1, new_max) } else { (new_max + 1, old_max) };
+        for n in from..to + 1 {
+            clear_entry(&mut fat, entry_bits, n);
+        }
+
+        let new_len = new_total_sectors * bps;
+        if new_len > self.partition_len {
+            self.partition_len = new_len;
+        }
+        let shift = num_fats * (fat_sz - old_fat_sz) * bps;
+        if shift > 0 {
+            // The root directory and every cluster up to the last one used, from the top down
+            let start = (rsvd + num_fats * old_fat_sz) * bps;
+            let end = last_used.map_or(self.first_data_sec * bps, |c| self.cluster_offset(Cluster::new(c)) + self.bytes_per_cluster());
+            self.move_range_up(start, end, shift)?;
+        }
+        for i in 0..num_fats {
+            self.write_to((rsvd + i * fat_sz) * bps, &fat)?;
+        }
+
+        let bk_boot_sec = match self.bpb.fat_type {
+            FATType::FAT32(ref mut s) => {
+                s.fat_size = fat_sz as u32;
+                s.bk_boot_sec as u64
+            },
+            _ => {
+                self.bpb.fat_size_16 = fat_sz as u16;
+                0
+            }
+        };
+        if entry_bits != 32 && new_total_sectors < 0x10000 {
+            self.bpb.total_sectors_16 = new_total_sectors as u16;
+            self.bpb.total_sectors_32 = 0;
+        } else {
+            self.bpb.total_sectors_16 = 0;
+            self.bpb.total_sectors_32 = new_total_sectors as u32;
+        }
+        let mut boot = [0u8; 40];
+        self.read_at(0, &mut boot)?;
+        LittleEndian::write_u16(&mut boot[19..], self.bpb.total_sectors_16);
+        LittleEndian::write_u16(&mut boot[22..], self.bpb.fat_size_16);
+        LittleEndian::write_u32(&mut boot[32..], self.bpb.total_sectors_32);
+        let len = if entry_bits == 32 {
+            LittleEndian::write_u32(&mut boot[36..], fat_sz as u32);
+            40
+        } else {
+            36
+        };
+        self.write_to(0, &boot[..len])?;
+        if bk_boot_sec != 0 {
+            self.write_to(bk_boot_sec * bps, &boot[..len])?;
+        }
+
+        self.first_data_sec = rsvd + num_fats * fat_sz + root_sectors;
+        // Cached entries are keyed by offsets which have moved
+        self.clear_entry_cache();
+        self.restart_free_map();
+        self.partition_len = new_len;
+        self.rebuild_fsinfo()?;
+        self.disk.borrow_mut().flush()
+    }
+}
@@ -0,0 +1,39 @@
+use std::io::{Read, Write, Seek};
+
+use filesystem::FileSystem;
+use dir_entry::{Dir, DirEntry};
+
+/// Order of `Dir::entries_sorted`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SortBy {
+    Name,
+    /// Smallest first
+    Size,
+    /// Least recently written first
+    Mtime
+}
+
+impl Dir {
+    /// Entries of this directory ordered by `sort_by` instead of their on-disk order
+    ///
+    /// Names compare ignoring case unless the volume is case sensitive, and break ties
+    /// for the other orders. "." and ".." stay in front.
+    pub fn entries_sorted<D: Read + Write + Seek>(&self, fs: &mut FileSystem<D>, sort_by: SortBy) -> Vec<DirEntry> {
+        let case_sensitive = fs.case_sensitive;
+        // Keys are built once per entry rather than on every comparison
+        let mut keyed: Vec<(bool, u64, String, DirEntry)> = self.to_iter(fs).map(|e| {
+            let name = e.name();
+            let is_dot = name == "." || name == "..";
+            let key = match sort_by {
+                SortBy::Name => 0,
+                SortBy::Size => e.size(),
+                SortBy::Mtime => e.modified_raw() as u64
+            };
+            let name = if case_sensitive { name } else { name.to_lowercase() };
+            (!is_dot, key, name, e)
+        }).collect();
+
+        keyed.sort_by(|a, b| (a.0, a.1, &a.2).cmp(&(b.0, b.1, &b.2)));
+        keyed.into_iter().map(|(_, _, _, e)| e).collect()
+    }
+}
@@ -0,0 +1,157 @@
+use std::cmp::{min, max};
+use std::io::{self, BufRead, Read, Write, Seek, Error, ErrorKind};
+
+use filesystem::FileSystem;
+use dir_entry::File;
+
+impl File {
+    /// Reader over the whole file, filling a buffer of `buf_size` bytes rounded up to
+    /// whole clusters at a time
+    pub fn reader<'a, D: Read + Write + Seek>(&self, fs: &'a mut FileSystem<D>, buf_size: usize) -> FileReader<'a, D> {
+        let buf_size = cluster_multiple(buf_size, fs);
+        FileReader {
+            file: self.clone(),
+            fs: fs,
+            buf: vec![0; buf_size],
+            pos: 0,
+            filled: 0,
+            offset: 0
+        }
+    }
+
+    /// Writer starting at the beginning of the file, collecting `buf_size` bytes rounded up
+    /// to whole clusters before writing them out
+    ///
+    /// Data past the end of what is written is left in place. The buffer and the size in the
+    /// directory entry are written on `flush` and when the writer is dropped, where errors
+    /// are lost, so flush before dropping to see them.
+    pub fn writer<'a, D: Read + Write + Seek>(&'a mut self, fs: &'a mut FileSystem<D>, buf_size: usize) -> FileWriter<'a, D> {
+        let buf_size = cluster_multiple(buf_size, fs);
+        FileWriter {
+            file: self,
+            fs: fs,
+            buf: Vec::with_capacity(buf_size),
+            offset: 0
+        }
+    }
+}
+
+fn cluster_multiple<D: Read + Write + Seek>(size: usize, fs: &FileSystem<D>) -> usize {
+    let bpc = fs.bytes_per_cluster() as usize;
+    max(1, (size + bpc - 1) / bpc) * bpc
+}
+
+pub struct FileReader<'a, D: Read + Write + Seek + 'a> {
+    file: File,
+    fs: &'a mut FileSystem<D>,
+    buf: Vec<u8>,
+    pos: usize,
+    filled: usize,
+    /// Offset in the file of the end of the buffered data
+    offset: u64
+}
+
+impl<'a, D: Read + Write + Seek> FileReader<'a, D> {
+    /// Offset in the file of the next byte read
+    pub fn position(&self) -> u64 {
+        self.offset - (self.filled - self.pos) as u64
+    }
+}
+
+impl<'a, D: Read + Write + Seek> Read for FileReader<'a, D> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        // Large reads skip the buffer once it is drained
+        if self.pos == self.filled && out.len() >= self.buf.len() {
+            let count = self.file.read(out, self.fs, self.offset)?;
+            self.offset += count as u64;
+            return Ok(count)
+        }
+        let count = {
+            let data = self.fill_buf()?;
+            let count = min(data.len(), out.len());
+            out[..count].copy_from_slice(&data[..count]);
+            count
+        };
+        self.consume(count);
+        Ok(count)
+    }
+}
+
+impl<'a, D: Read + Write + Seek> BufRead for FileReader<'a, D> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.filled {
+            self.filled = self.file.read(&mut self.buf, self.fs, self.offset)?;
+            self.pos = 0;
+            self.offset += self.filled as u64;
+        }
+        Ok(&self.buf[self.pos..self.filled])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = min(self.pos + amt, self.filled);
+    }
+}
+
+pub struct FileWriter<'a, D: Read + Write + Seek + 'a> {
+    file: &'a mut File,
+    fs: &'a mut FileSystem<D>,
+    buf: Vec<u8>,
+    /// Offset in the file of the start of the buffered data
+    offset: u64
+}
+
+impl<'a, D: Read + Write + Seek> FileWriter<'a, D> {
+    /// Offset in the file of the next byte written
+    pub fn position(&self) -> u64 {
+        self.offset + self.buf.len() as u64
+    }
+
+    fn write_at_offset(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut written = 0;
+        while written < data.len() {
+            let count = self.file.write(&data[written..], self.fs, self.offset)?;
+            if count == 0 {
+                return Err(Error::new(ErrorKind::WriteZero, "Failed to write to the file"));
+            }
+            written += count;
+            self.offset += count as u64;
+        }
+        Ok(())
+    }
+
+    fn flush_buf(&mut self) -> io::Result<()> {
+        let buf = ::std::mem::replace(&mut self.buf, Vec::new());
+        let res = self.write_at_offset(&buf);
+        self.buf = buf;
+        self.buf.clear();
+        res
+    }
+}
+
+impl<'a, D: Read + Write + Seek> Write for FileWriter<'a, D> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.buf.len() + data.len() > self.buf.capacity() {
+            self.flush_buf()?;
+        }
+        // Large writes go straight to the file
+        if data.len() >= self.buf.capacity() {
+            self.write_at_offset(data)?;
+        } else {
+            self.buf.extend_from_slice(data);
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_buf()?;
+        self.file.flush(self.fs)
+    }
+}
+
+impl<'a, D: Read + Write + Seek> Drop for FileWriter<'a, D> {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            warn!("Failed to flush file writer: {}", e);
+        }
+    }
+}
@@ -31,6 +31,11 @@ fn get_fat_offset(fat_type: FATType, cluster: Cluster, fat_start_sector: u64, by
     fat_sec_number * bytes_per_sec + fat_ent_offset
 }
 
+/// The FAT entry of `cluster`
+///
+/// With `FileSystem::strict_cluster_bounds` set, an entry pointing to a cluster below 2 or
+/// past `max_cluster_number` fails with InvalidData rather than being followed to wherever
+/// that cluster would lie.
 pub fn get_entry<D: Read + Seek + Write>(fs: &mut FileSystem<D>, cluster: Cluster) -> Result<FatEntry> {
     let current_cluster = cluster.cluster_number;
     /*
@@ -57,9 +62,8 @@ pub fn get_entry<D: Read + Seek + Write>(fs: &mut FileSystem<D>, cluster: Cluste
     let blk_offset = fs.get_block_offset(offset);
 
     let block_buf = get_block_buffer(fs.get_raw_offset(offset), 4);
-    fs.seek_to_block(offset)?;
     let mut cursor = Cursor::new(block_buf);
-    fs.disk.borrow_mut().read(cursor.get_mut())?;
+    fs.read_block(offset, cursor.get_mut())?;
     cursor.seek(SeekFrom::Start(blk_offset))?;
 
     let res = match fs.bpb.fat_type {
@@ -127,6 +131,11 @@ pub fn get_entry<D: Read + Seek + Write>(fs: &mut FileSystem<D>, cluster: Cluste
             }
         }
     };
+    if let FatEntry::Next(ref next) = res {
+        if fs.strict_cluster_bounds && (next.cluster_number < RESERVED_CLUSTERS || next.cluster_number > fs.max_cluster_number().cluster_number) {
+            return Err(Error::new(ErrorKind::InvalidData, "Corrupt filesystem: FAT entry points outside the data clusters"))
+        }
+    }
     Ok(res)
 }
 
@@ -156,9 +165,8 @@ pub fn get_entry_raw<D: Read + Seek + Write>(fs: &mut FileSystem<D>, cluster: Cl
     let blk_offset = fs.get_block_offset(offset);
 
     let block_buf = get_block_buffer(offset, 4);
-    fs.seek_to_block(offset)?;
     let mut cursor = Cursor::new(block_buf);
-    fs.disk.borrow_mut().read(cursor.get_mut())?;
+    fs.read_block(offset, cursor.get_mut())?;
     cursor.seek(SeekFrom::Start(blk_offset))?;
 
     let res = match fs.bpb.fat_type {
@@ -184,6 +192,7 @@ pub fn get_entry_raw<D: Read + Seek + Write>(fs: &mut FileSystem<D>, cluster: Cl
     Ok(res)
 }
 
+/// The first free cluster from `start_cluster` up to and including `end_cluster`
 pub fn get_free_cluster<D: Read + Write + Seek>(fs: &mut FileSystem<D>, start_cluster: Cluster,
                                                 end_cluster: Cluster) -> Result<Cluster> {
 
@@ -213,9 +222,8 @@ pub fn get_free_cluster<D: Read + Write + Seek>(fs: &mut FileSystem<D>, start_cl
 
             // FAT12 tables do not exceed 6K
             let block_buf = get_block_buffer(fs.get_raw_offset(offset), 6 * 1024);
-            fs.seek_to_block(offset)?;
             let mut cursor = Cursor::new(block_buf);
-            fs.disk.borrow_mut().read(cursor.get_mut())?;
+            fs.read_block(offset, cursor.get_mut())?;
             cursor.seek(SeekFrom::Start(blk_offset))?;
             let mut packed_val = cursor.read_u16::<LittleEndian>()?;
 
@@ -229,7 +237,7 @@ pub fn get_free_cluster<D: Read + Write + Seek>(fs: &mut FileSystem<D>, start_cl
                 }
 
                 cluster += 1;
-                if cluster == end_cluster.cluster_number || cluster == max_cluster.cluster_number {
+                if cluster > end_cluster.cluster_number || cluster > max_cluster.cluster_number {
                     return Err(Error::new(ErrorKind::Other, "Space Exhausted on Disk"))
                 }
 
@@ -249,13 +257,12 @@ pub fn get_free_cluster<D: Read + Write + Seek>(fs: &mut FileSystem<D>, start_cl
             // Read a block for each entry explored
             //let fat_offset = get_fat_offset(fs.bpb.fat_type, start_cluster, fs.fat_start_sector(), fs.bytes_per_sec());
             //fs.seek_to(fat_offset)?;
-            while cluster < end_cluster.cluster_number && cluster < max_cluster.cluster_number {
+            while cluster <= end_cluster.cluster_number && cluster <= max_cluster.cluster_number {
                 let offset = get_fat_offset(fs.bpb.fat_type, Cluster::new(cluster), fs.fat_start_sector(), fs.bytes_per_sec());
                 let blk_offset = fs.get_block_offset(offset);
                 let block_buf = get_block_buffer(fs.get_raw_offset(offset), 2);
-                fs.seek_to_block(offset)?;
                 let mut cursor = Cursor::new(block_buf);
-                fs.disk.borrow_mut().read(cursor.get_mut())?;
+                fs.read_block(offset, cursor.get_mut())?;
                 cursor.seek(SeekFrom::Start(blk_offset))?;
 
 
@@ -279,15 +286,14 @@ pub fn get_free_cluster<D: Read + Write + Seek>(fs: &mut FileSystem<D>, start_cl
             //let bytes_per_sec = fs.bytes_per_sec();
             //println!("[get_free] Fat Offset = {:X} for cluster = {:?}", get_fat_offset(fs.bpb.fat_type, Cluster::new(cluster), fs.fat_start_sector(), fs.bytes_per_sec()), cluster);
             //fs.seek_to(get_fat_offset(fat_type, Cluster::new(cluster), fat_start_sector, bytes_per_sec))?;
-            while cluster < end_cluster.cluster_number && cluster < max_cluster.cluster_number {
+            while cluster <= end_cluster.cluster_number && cluster <= max_cluster.cluster_number {
                 //let entry = get_entry(fs.bpb.fat_type, fs, Cluster::new(cluster)).ok();
                 let offset = get_fat_offset(fs.bpb.fat_type, Cluster::new(cluster), fs.fat_start_sector(), fs.bytes_per_sec());
                 let blk_offset = fs.get_block_offset(offset);
                 let block_buf = get_block_buffer(fs.get_raw_offset(offset), 4);
 
                 let mut cursor = Cursor::new(block_buf);
-                fs.seek_to_block(offset)?;
-                fs.disk.borrow_mut().read(cursor.get_mut())?;
+                fs.read_block(offset, cursor.get_mut())?;
                 cursor.seek(SeekFrom::Start(blk_offset))?;
 
                 let val = cursor.read_u32::<LittleEndian>()? & 0x0FFFFFFF;
@@ -305,8 +311,19 @@ pub fn get_free_cluster<D: Read + Write + Seek>(fs: &mut FileSystem<D>, start_cl
     }
 }
 
+/// Writes `fat_entry` for `cluster`, keeping the free map up to date
 pub fn set_entry<D: Read + Write + Seek>(fs: &mut FileSystem<D>, cluster: Cluster,
                                              fat_entry: FatEntry) -> Result<()> {
+    let free = fat_entry == FatEntry::Unused;
+    write_entry(fs, cluster, fat_entry)?;
+    if let Some(map) = fs.free_map_mut() {
+        map.set(cluster.cluster_number, free);
+    }
+    Ok(())
+}
+
+fn write_entry<D: Read + Write + Seek>(fs: &mut FileSystem<D>, cluster: Cluster,
+                                        fat_entry: FatEntry) -> Result<()> {
     let fat_offset = get_fat_offset(fs.bpb.fat_type, cluster, fs.fat_start_sector(), fs.bytes_per_sec());
     match fs.bpb.fat_type {
         FATType::FAT12(_) => {
@@ -316,22 +333,14 @@ pub fn set_entry<D: Read + Write + Seek>(fs: &mut FileSystem<D>, cluster: Cluste
                 FatEntry::EndOfChain => 0xfff,
                 FatEntry::Next(c) => c.cluster_number as u16
             };
-            let block_buf = get_block_buffer(fs.get_raw_offset(fat_offset), 2);
-            let blk_offset = fs.get_block_offset(fat_offset);
-
-            let mut cursor = Cursor::new(block_buf);
-            fs.seek_to_block(fat_offset)?;
-            fs.disk.borrow_mut().read(cursor.get_mut())?;
-
-            cursor.seek(SeekFrom::Start(blk_offset))?;
-            let old_val = cursor.read_u16::<LittleEndian>()?;
-            cursor.seek(SeekFrom::Start(blk_offset))?;
-            let new_val = if cluster.cluster_number & 0x0001 > 0 { (old_val & 0x000F) | (raw_val << 4) }
-                                else { old_val & 0xF000 | raw_val };
-            cursor.write_u16::<LittleEndian>(new_val)?;
-            fs.seek_to_block(fat_offset)?;
-            fs.disk.borrow_mut().write(cursor.get_ref())?;
-            Ok(())
+            update_fat_copies(fs, fat_offset, 2, |cursor, blk_offset| {
+                cursor.seek(SeekFrom::Start(blk_offset))?;
+                let old_val = cursor.read_u16::<LittleEndian>()?;
+                cursor.seek(SeekFrom::Start(blk_offset))?;
+                let new_val = if cluster.cluster_number & 0x0001 > 0 { (old_val & 0x000F) | (raw_val << 4) }
+                                    else { old_val & 0xF000 | raw_val };
+                cursor.write_u16::<LittleEndian>(new_val)
+            })
         },
         FATType::FAT16(_) => {
             let raw_val = match fat_entry {
@@ -340,62 +349,67 @@ pub fn set_entry<D: Read + Write + Seek>(fs: &mut FileSystem<D>, cluster: Cluste
                 FatEntry::EndOfChain => 0xffff,
                 FatEntry::Next(c) => c.cluster_number as u16
             };
-            let block_buf = get_block_buffer(fs.get_raw_offset(fat_offset), 2);
-            let blk_offset = fs.get_block_offset(fat_offset);
-
-            let mut cursor = Cursor::new(block_buf);
-            fs.seek_to_block(fat_offset)?;
-            fs.disk.borrow_mut().read(cursor.get_mut())?;
-
-            cursor.seek(SeekFrom::Start(blk_offset))?;
-            //fs.seek_to(fat_offset)?;
-            cursor.write_u16::<LittleEndian>(raw_val)?;
-
-            //Write-back
-            fs.seek_to_block(fat_offset)?;
-            fs.disk.borrow_mut().write(cursor.get_ref())?;
-
-            Ok(())
+            update_fat_copies(fs, fat_offset, 2, |cursor, blk_offset| {
+                cursor.seek(SeekFrom::Start(blk_offset))?;
+                cursor.write_u16::<LittleEndian>(raw_val)
+            })
         },
         FATType::FAT32(_) => {
-            //fs.seek_to(fat_offset);
-            let fat_size = fs.fat_size();
-            let bound = if fs.mirroring_enabled() { 1 } else { fs.bpb.num_fats as u64 };
-            for i in 0..bound {
-                let f_offset = fat_offset + i * fat_size;
-                let block_buf = get_block_buffer(fs.get_raw_offset(f_offset), 4);
-                let blk_offset = fs.get_block_offset(f_offset);
+            if fat_entry == FatEntry::Unused && cluster.cluster_number >= 0x0FFFFFF7 && cluster.cluster_number <= 0x0FFFFFFF {
+                warn!("Reserved Cluster {:?} cannot be marked as free", cluster);
+            }
 
-                let mut cursor = Cursor::new(block_buf);
-                fs.seek_to_block(f_offset)?;
-                fs.disk.borrow_mut().read(cursor.get_mut())?;
+            let raw_val = match fat_entry {
+                FatEntry::Unused => 0,
+                FatEntry::Bad => 0x0FFFFFF7,
+                FatEntry::EndOfChain => 0x0FFFFFFF,
+                FatEntry::Next(c) => c.cluster_number as u32
+            };
+            update_fat_copies(fs, fat_offset, 4, |cursor, blk_offset| {
                 cursor.seek(SeekFrom::Start(blk_offset))?;
-
                 let old_bits = cursor.read_u32::<LittleEndian>()? & 0xF0000000;
-                if fat_entry == FatEntry::Unused && cluster.cluster_number >= 0x0FFFFFF7 && cluster.cluster_number <= 0x0FFFFFFF {
-                    warn!("Reserved Cluster {:?} cannot be marked as free", cluster);
-                }
-
-                let mut raw_val = match fat_entry {
-                    FatEntry::Unused => 0,
-                    FatEntry::Bad => 0x0FFFFFF7,
-                    FatEntry::EndOfChain => 0x0FFFFFFF,
-                    FatEntry::Next(c) => c.cluster_number as u32
-                };
-                raw_val = raw_val | old_bits;
-                //fs.seek_to(fat_offset + i as u64 * fat_size)?;
                 cursor.seek(SeekFrom::Start(blk_offset))?;
-                cursor.write_u32::<LittleEndian>(raw_val)?;
-
-                fs.seek_to_block(f_offset)?;
-                fs.disk.borrow_mut().write(cursor.get_ref())?;
-            }
-            Ok(())
+                cursor.write_u32::<LittleEndian>(raw_val | old_bits)
+            })
         }
 
     }
 }
 
+// Calls `update` with the position of the entry at `fat_offset` in every FAT copy being written.
+// With mirroring every copy is updated, otherwise only the active one which `fat_offset`
+// already points into. Lazy mirroring also only updates the active copy and leaves the
+// others to `sync_fat_mirrors`. Copies whose entries
+// fall in the same cache block share a single read and write of that block.
+fn update_fat_copies<D, F>(fs: &mut FileSystem<D>, fat_offset: u64, entry_len: u64, mut update: F) -> Result<()>
+    where D: Read + Write + Seek, F: FnMut(&mut Cursor<Vec<u8>>, u64) -> Result<()> {
+    fs.check_writable()?;
+    let fat_size = fs.fat_size() * fs.bytes_per_sec();
+    let lazy = fs.lazy_fat_mirroring && fs.mirroring_enabled() && fs.bpb.num_fats > 1;
+    let copies = if fs.mirroring_enabled() && !lazy { fs.bpb.num_fats as u64 } else { 1 };
+
+    let mut i = 0;
+    while i < copies {
+        let f_offset = fat_offset + i * fat_size;
+        let blk_offset = fs.get_block_offset(f_offset);
+        let mut cursor = Cursor::new(get_block_buffer(fs.get_raw_offset(f_offset), entry_len));
+        fs.read_block(f_offset, cursor.get_mut())?;
+
+        let buf_len = cursor.get_ref().len() as u64;
+        let mut entry_offset = blk_offset;
+        while i < copies && entry_offset + entry_len <= buf_len {
+            update(&mut cursor, entry_offset)?;
+            entry_offset += fat_size;
+            i += 1;
+        }
+
+        fs.write_metadata_block(f_offset, cursor.get_ref())?;
+    }
+    if lazy {
+        fs.mark_fat_mirrors_stale(fat_offset, entry_len)?;
+    }
+    Ok(())
+}
 
 pub fn get_free_count<D: Read + Write + Seek>(fs: &mut FileSystem<D>, end_cluster: Cluster) -> Result<u64> {
     let mut count = 0;
@@ -408,9 +422,8 @@ pub fn get_free_count<D: Read + Write + Seek>(fs: &mut FileSystem<D>, end_cluste
 
             // FAT12 tables do not exceed 6K
             let block_buf = get_block_buffer(fs.get_raw_offset(fat_offset), 6 * 1024);
-            fs.seek_to_block(fat_offset)?;
             let mut cursor = Cursor::new(block_buf);
-            fs.disk.borrow_mut().read(cursor.get_mut())?;
+            fs.read_block(fat_offset, cursor.get_mut())?;
             cursor.seek(SeekFrom::Start(blk_offset))?;
 
             let mut packed_val = cursor.read_u16::<LittleEndian>()?;
@@ -420,7 +433,7 @@ pub fn get_free_count<D: Read + Write + Seek>(fs: &mut FileSystem<D>, end_cluste
                     count += 1;
                 }
                 cluster += 1;
-                if cluster == end_cluster.cluster_number {
+                if cluster > end_cluster.cluster_number {
                     fs.fs_info.borrow_mut().update_free_count(count);
                     return Ok(count)
                 }
@@ -442,8 +455,7 @@ pub fn get_free_count<D: Read + Write + Seek>(fs: &mut FileSystem<D>, end_cluste
                 let block_buf = get_block_buffer(fs.get_raw_offset(fat_offset), 2);
                 let mut cursor = Cursor::new(block_buf);
 
-                fs.seek_to_block(fat_offset)?;
-                fs.disk.borrow_mut().read(cursor.get_mut())?;
+                fs.read_block(fat_offset, cursor.get_mut())?;
 
                 cursor.seek(SeekFrom::Start(blk_offset))?;
                 let val = cursor.read_u16::<LittleEndian>()?;
@@ -465,8 +477,7 @@ pub fn get_free_count<D: Read + Write + Seek>(fs: &mut FileSystem<D>, end_cluste
                 let block_buf = get_block_buffer(fs.get_raw_offset(fat_offset), 2);
                 let mut cursor = Cursor::new(block_buf);
 
-                fs.seek_to_block(fat_offset)?;
-                fs.disk.borrow_mut().read(cursor.get_mut())?;
+                fs.read_block(fat_offset, cursor.get_mut())?;
 
                 cursor.seek(SeekFrom::Start(blk_offset))?;
 
@@ -483,7 +494,28 @@ pub fn get_free_count<D: Read + Write + Seek>(fs: &mut FileSystem<D>, end_cluste
 }
 
 
+/// Allocates a zeroed cluster, appending it to the chain ending at `prev_cluster`
+///
+/// The cluster is zeroed while it is still free, so if that fails neither the FAT nor
+/// FSInfo have changed.
 pub fn allocate_cluster<D: Read + Write + Seek>(fs: &mut FileSystem<D>, prev_cluster: Option<Cluster>) -> Result<Cluster> {
+    let cluster = find_free_cluster(fs)?;
+    fs.zero_cluster(cluster)?;
+    claim_cluster(fs, cluster, prev_cluster)?;
+    Ok(cluster)
+}
+
+/// Like `allocate_cluster` but leaves the old contents in place
+/// Only for file data, where the caller writes or zeroes everything below the file size
+pub fn allocate_data_cluster<D: Read + Write + Seek>(fs: &mut FileSystem<D>, prev_cluster: Option<Cluster>) -> Result<Cluster> {
+    let free_cluster = find_free_cluster(fs)?;
+    claim_cluster(fs, free_cluster, prev_cluster)?;
+    Ok(free_cluster)
+}
+
+/// The free cluster `allocate_data_cluster` would take, without taking it
+/// Lets a cluster be filled in before anything on disk refers to it
+pub fn find_free_cluster<D: Read + Write + Seek>(fs: &mut FileSystem<D>) -> Result<Cluster> {
     let end_cluster = fs.max_cluster_number();
     let start_cluster = match fs.bpb.fat_type {
         FATType::FAT32(_) => {
@@ -491,7 +523,7 @@ pub fn allocate_cluster<D: Read + Write + Seek>(fs: &mut FileSystem<D>, prev_clu
                 Some(x) => x,
                 None => 0xFFFFFFFF
             };
-            if next_free < end_cluster.cluster_number {
+            if next_free <= end_cluster.cluster_number {
                 Cluster::new(next_free)
             } else {
                 Cluster::new(RESERVED_CLUSTERS)
@@ -501,20 +533,41 @@ pub fn allocate_cluster<D: Read + Write + Seek>(fs: &mut FileSystem<D>, prev_clu
 
     };
 
-    let free_cluster = match get_free_cluster(fs, start_cluster, end_cluster) {
-        Ok(c) => c,
-        Err(_) if start_cluster.cluster_number > RESERVED_CLUSTERS => get_free_cluster(fs, Cluster::new(RESERVED_CLUSTERS), end_cluster)?,
-        Err(e) => return Err(e)
-    };
+    if let Some(scanned) = fs.free_map().map(|m| m.scanned()) {
+        if let Some(c) = fs.free_map_cluster(start_cluster.cluster_number)? {
+            return Ok(c)
+        }
+        // Every cluster the map covers is taken, only those past it can be free
+        if scanned > end_cluster.cluster_number {
+            return Err(Error::new(ErrorKind::Other, "Space Exhausted on Disk"))
+        }
+        return get_free_cluster(fs, Cluster::new(scanned), end_cluster)
+    }
+
+    match get_free_cluster(fs, start_cluster, end_cluster) {
+        Ok(c) => Ok(c),
+        Err(_) if start_cluster.cluster_number > RESERVED_CLUSTERS => get_free_cluster(fs, Cluster::new(RESERVED_CLUSTERS), end_cluster),
+        Err(e) => Err(e)
+    }
+}
 
+/// Marks the free cluster `free_cluster` as the end of a chain, appended to `prev_cluster` if given
+///
+/// FSInfo is only updated once the chain is linked. If it can't be, the cluster is marked
+/// free again as long as the volume is still writable.
+pub fn claim_cluster<D: Read + Write + Seek>(fs: &mut FileSystem<D>, free_cluster: Cluster, prev_cluster: Option<Cluster>) -> Result<()> {
     set_entry(fs, free_cluster, FatEntry::EndOfChain)?;
-    fs.fs_info.borrow_mut().delta_free_count(-1);
-    fs.fs_info.borrow_mut().update_next_free(free_cluster.cluster_number + 1);
     if let Some(prev_clus) = prev_cluster {
-        set_entry(fs, prev_clus, FatEntry::Next(free_cluster))?;
+        if let Err(e) = append_chain(fs, prev_clus, free_cluster) {
+            if let Err(e) = set_entry(fs, free_cluster, FatEntry::Unused) {
+                warn!("Failed to free cluster {} again: {}", free_cluster.cluster_number, e);
+            }
+            return Err(e)
+        }
     }
-    fs.zero_cluster(free_cluster)?;
-    Ok(free_cluster)
+    fs.fs_info.borrow_mut().delta_free_count(-1);
+    fs.fs_info.borrow_mut().update_next_free(free_cluster.cluster_number + 1);
+    Ok(())
 }
 
 pub fn deallocate_cluster<D: Read + Write + Seek>(fs: &mut FileSystem<D>, cluster: Cluster) -> Result<()> {
@@ -539,3 +592,28 @@ pub fn deallocate_cluster_chain<D: Read + Write + Seek>(fs: &mut FileSystem<D>,
     }
     Ok(())
 }
+
+/// Cuts the chain after `at`, which becomes the end of the chain
+/// Returns the first cluster of the detached tail, if there is one
+pub fn split_chain<D: Read + Write + Seek>(fs: &mut FileSystem<D>, at: Cluster) -> Result<Option<Cluster>> {
+    match get_entry(fs, at)? {
+        FatEntry::Next(tail) => {
+            set_entry(fs, at, FatEntry::EndOfChain)?;
+            Ok(Some(tail))
+        },
+        FatEntry::EndOfChain => Ok(None),
+        _ => Err(Error::new(ErrorKind::InvalidData, "Cluster is not part of a chain"))
+    }
+}
+
+/// Links the chain starting at `tail` after the last cluster of the chain starting at `head`
+pub fn append_chain<D: Read + Write + Seek>(fs: &mut FileSystem<D>, head: Cluster, tail: Cluster) -> Result<()> {
+    let last = match fs.get_last_cluster(head) {
+        Some(c) => c,
+        None => return Err(Error::new(ErrorKind::InvalidData, "Last Cluster not found"))
+    };
+    if fs.clusters(tail).contains(&last) {
+        return Err(Error::new(ErrorKind::InvalidInput, "Chains would form a cycle"));
+    }
+    set_entry(fs, last, FatEntry::Next(tail))
+}
@@ -0,0 +1,34 @@
+/// Length of a directory slot
+const SLOT_LEN: usize = 32;
+
+/// Directory slots overwritten while a transaction is open, with what they held before,
+/// see `FileSystem::with_entry_transaction`
+pub struct EntryJournal {
+    undo: Vec<(u64, [u8; SLOT_LEN])>
+}
+
+impl EntryJournal {
+    pub fn new() -> EntryJournal {
+        EntryJournal {
+            undo: Vec::new()
+        }
+    }
+
+    /// Records `old`, the slot at `offset` about to be overwritten
+    pub fn record(&mut self, offset: u64, old: &[u8]) {
+        let mut slot = [0; SLOT_LEN];
+        slot.copy_from_slice(&old[..SLOT_LEN]);
+        self.undo.push((offset, slot));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.undo.is_empty()
+    }
+
+    /// The recorded slots, the last one written first, in the order to put them back
+    pub fn into_undo(self) -> Vec<(u64, [u8; SLOT_LEN])> {
+        let mut undo = self.undo;
+        undo.reverse();
+        undo
+    }
+}
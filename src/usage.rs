@@ -0,0 +1,159 @@
+use std::cmp::min;
+use std::io::{Read, Write, Seek};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use filesystem::{FileSystem, Cluster};
+use bpb::FATType;
+use table::RESERVED_CLUSTERS;
+
+use super::Result;
+
+/// State of a data cluster as recorded in the FAT
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ClusterState {
+    Free,
+    Allocated,
+    Bad
+}
+
+impl ClusterState {
+    fn from_bits(bits: u8) -> ClusterState {
+        match bits {
+            0 => ClusterState::Free,
+            1 => ClusterState::Allocated,
+            _ => ClusterState::Bad
+        }
+    }
+
+    fn bits(self) -> u8 {
+        match self {
+            ClusterState::Free => 0,
+            ClusterState::Allocated => 1,
+            ClusterState::Bad => 2
+        }
+    }
+}
+
+/// The state of every data cluster, from `FileSystem::usage_bitmap`
+///
+/// States take 2 bits each, 0 for free, 1 for allocated and 2 for bad, packed from the
+/// low bits of each byte up starting with cluster 2.
+#[derive(Clone, Debug)]
+pub struct UsageMap {
+    bits: Vec<u8>,
+    len: u64,
+    pub free: u64,
+    pub allocated: u64,
+    pub bad: u64,
+    /// Allocated clusters whose chain continues anywhere but the next cluster
+    pub fragments: u64
+}
+
+impl UsageMap {
+    /// Number of data clusters
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// None for the reserved clusters and those past the end of the volume
+    pub fn state(&self, cluster: Cluster) -> Option<ClusterState> {
+        let n = cluster.cluster_number;
+        if n < RESERVED_CLUSTERS || n - RESERVED_CLUSTERS >= self.len {
+            return None
+        }
+        let i = n - RESERVED_CLUSTERS;
+        Some(ClusterState::from_bits((self.bits[(i / 4) as usize] >> (i % 4 * 2)) & 0x3))
+    }
+
+    /// States of the data clusters in order, the first being cluster 2
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = ClusterState> + 'a {
+        (0..self.len).map(move |i| ClusterState::from_bits((self.bits[(i / 4) as usize] >> (i % 4 * 2)) & 0x3))
+    }
+
+    /// The packed states as described on `UsageMap`
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bits
+    }
+
+    fn push(&mut self, state: ClusterState) {
+        if self.len % 4 == 0 {
+            self.bits.push(0);
+        }
+        let last = self.bits.len() - 1;
+        self.bits[last] |= state.bits() << (self.len % 4 * 2);
+        self.len += 1;
+        match state {
+            ClusterState::Free => self.free += 1,
+            ClusterState::Allocated => self.allocated += 1,
+            ClusterState::Bad => self.bad += 1
+        }
+    }
+}
+
+/// Entry slots of the fixed FAT12/16 root directory, from `FileSystem::root_entries`
+///
+/// A long name takes a slot for every 13 characters on top of its short entry.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct RootEntries {
+    pub total: u64,
+    pub free: u64
+}
+
+impl<D: Read + Write + Seek> FileSystem<D> {
+    /// How full the fixed root directory is, None on FAT32 where the root grows as needed
+    pub fn root_entries(&mut self) -> Result<Option<RootEntries>> {
+        let end = match self.root_dir_end_offset() {
+            Some(end) => end,
+            None => return Ok(None)
+        };
+        let start = self.root_dir_offset();
+        let mut root = vec![0; (end - start) as usize];
+        self.read_at(start, &mut root)?;
+
+        let total = root.len() as u64 / 32;
+        // Everything from the first never used slot on is free
+        let used = root.chunks(32).take_while(|slot| slot[0] != 0).filter(|slot| slot[0] != 0xe5).count() as u64;
+        Ok(Some(RootEntries { total: total, free: total - used }))
+    }
+
+    /// Reads the whole active FAT into a map of the state of every data cluster
+    pub fn usage_bitmap(&mut self) -> Result<UsageMap> {
+        let max_cluster = self.max_cluster_number().cluster_number;
+        let (entry_bits, bad_mark) = match self.bpb.fat_type {
+            FATType::FAT12(_) => (12, 0xff7),
+            FATType::FAT16(_) => (16, 0xfff7),
+            FATType::FAT32(_) => (32, 0x0ffffff7)
+        };
+        let fat_bytes = self.fat_size() * self.bytes_per_sec();
+        let needed = ((max_cluster + 1) * entry_bits + 7) / 8 + 1;
+        let mut fat = vec![0; min(needed, fat_bytes) as usize];
+        let offset = self.fat_start_sector() * self.bytes_per_sec();
+        self.read_at(offset, &mut fat)?;
+
+        let entries = min(max_cluster + 1, fat.len() as u64 * 8 / entry_bits);
+        let mut map = UsageMap { bits: Vec::new(), len: 0, free: 0, allocated: 0, bad: 0, fragments: 0 };
+        for cluster in RESERVED_CLUSTERS..entries {
+            let val = match entry_bits {
+                12 => {
+                    let packed = LittleEndian::read_u16(&fat[(cluster + cluster / 2) as usize..]) as u64;
+                    if cluster & 1 > 0 { packed >> 4 } else { packed & 0x0fff }
+                },
+                16 => LittleEndian::read_u16(&fat[(cluster * 2) as usize..]) as u64,
+                _ => LittleEndian::read_u32(&fat[(cluster * 4) as usize..]) as u64 & 0x0fffffff
+            };
+            let state = if val == 0 {
+                ClusterState::Free
+            } else if val == bad_mark {
+                ClusterState::Bad
+            } else {
+                if val < bad_mark && val != cluster + 1 {
+                    map.fragments += 1;
+                }
+                ClusterState::Allocated
+            };
+            map.push(state);
+        }
+        Ok(map)
+    }
+}
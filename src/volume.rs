@@ -0,0 +1,97 @@
+use std::io::{Read, Write, Seek, Error, ErrorKind};
+
+use filesystem::FileSystem;
+use dir_entry::Dir;
+use metadata::Metadata;
+
+use super::Result;
+
+/// A volume owning its `FileSystem`, with operations taking absolute paths
+///
+/// Each call looks its path up from the root, so nothing borrowed from the volume needs
+/// to be kept around between calls. The `Dir` and `File` API stays available through
+/// `filesystem()` for anything not covered here.
+pub struct FatVolume<D: Read + Write + Seek> {
+    fs: FileSystem<D>
+}
+
+impl<D: Read + Write + Seek> FatVolume<D> {
+    /// Opens the filesystem starting at the beginning of `disk`
+    pub fn open(disk: D) -> Result<FatVolume<D>> {
+        FileSystem::from_offset(0, disk, None).map(FatVolume::new)
+    }
+
+    pub fn new(fs: FileSystem<D>) -> FatVolume<D> {
+        FatVolume { fs: fs }
+    }
+
+    pub fn filesystem(&mut self) -> &mut FileSystem<D> {
+        &mut self.fs
+    }
+
+    pub fn into_filesystem(self) -> FileSystem<D> {
+        self.fs
+    }
+
+    /// Contents of the file at `path`
+    pub fn read(&mut self, path: &str) -> Result<Vec<u8>> {
+        let root = self.fs.root_dir();
+        let f = root.open_file(path, &mut self.fs)?;
+        let mut data = vec![0; f.size() as usize];
+        let mut offset = 0;
+        while offset < data.len() {
+            let count = f.read(&mut data[offset..], &mut self.fs, offset as u64)?;
+            if count == 0 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "File is shorter than its size"));
+            }
+            offset += count;
+        }
+        Ok(data)
+    }
+
+    /// Replaces the contents of the file at `path` with `data`, creating it if needed
+    pub fn write(&mut self, path: &str, data: &[u8]) -> Result<()> {
+        let root = self.fs.root_dir();
+        let mut f = root.create_file(path, &mut self.fs)?;
+        if !data.is_empty() {
+            let count = f.write(data, &mut self.fs, 0)?;
+            if count < data.len() {
+                return Err(Error::new(ErrorKind::WriteZero, "Failed to write the whole file"));
+            }
+        }
+        f.set_len(&mut self.fs, data.len() as u64)?;
+        f.flush(&mut self.fs)
+    }
+
+    /// Creates the directory at `path` along with any missing parents
+    pub fn create_dir_all(&mut self, path: &str) -> Result<()> {
+        let mut dir = self.fs.root_dir();
+        for name in path.split('/').filter(|n| !n.is_empty()) {
+            dir = match dir.find_entry(name, Some(true), None, &mut self.fs) {
+                Ok(e) => e.to_dir(),
+                Err(ref e) if e.kind() == ErrorKind::NotFound => dir.create_dir(name, &mut self.fs)?,
+                Err(e) => return Err(e)
+            };
+        }
+        Ok(())
+    }
+
+    /// Removes the file or empty directory at `path`
+    pub fn remove(&mut self, path: &str) -> Result<()> {
+        if path.trim_matches('/').is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Can't remove the root directory"));
+        }
+        let root = self.fs.root_dir();
+        root.remove(path, &mut self.fs, true)
+    }
+
+    /// Moves the entry at `from` to `to`, replacing an existing destination as `Dir::rename` does
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<()> {
+        let mut e = Dir::get_entry_abs(from, &mut self.fs)?;
+        Dir::rename(&mut e, to, &mut self.fs)
+    }
+
+    pub fn metadata(&mut self, path: &str) -> Result<Metadata> {
+        self.fs.metadata(path)
+    }
+}
@@ -0,0 +1,69 @@
+use std::collections::BTreeSet;
+use std::io::{Read, Write, Seek};
+
+use filesystem::FileSystem;
+use dir_entry::{Dir, DirEntry};
+
+/// Every file and directory below a directory, see `Dir::walk`
+pub struct Walk<'a, D: Read + Write + Seek + 'a> {
+    fs: &'a mut FileSystem<D>,
+    /// Entries still to return of each directory being walked, innermost last, in reverse
+    pending: Vec<Vec<DirEntry>>,
+    /// First clusters of the directories entered, so a loop on a damaged volume ends
+    visited: BTreeSet<u64>
+}
+
+impl Dir {
+    /// Walks the tree below this directory depth first, each directory coming right before
+    /// its contents and the entries of a directory in on-disk order
+    ///
+    /// "." and ".." and volume labels are left out. Entries are read one directory at a
+    /// time, when it is reached.
+    pub fn walk<'a, D: Read + Write + Seek>(&self, fs: &'a mut FileSystem<D>) -> Walk<'a, D> {
+        let mut walk = Walk {
+            fs: fs,
+            pending: Vec::new(),
+            visited: BTreeSet::new()
+        };
+        walk.enter(self);
+        walk
+    }
+}
+
+impl<'a, D: Read + Write + Seek> Walk<'a, D> {
+    fn enter(&mut self, dir: &Dir) {
+        if !self.visited.insert(dir.first_cluster.cluster_number) {
+            return;
+        }
+        let mut entries: Vec<DirEntry> = dir.to_iter(self.fs).filter(|e| {
+            let short_name = e.short_name();
+            !e.is_vol_id() && short_name != "." && short_name != ".."
+        }).collect();
+        entries.reverse();
+        self.pending.push(entries);
+    }
+}
+
+impl<'a, D: Read + Write + Seek> Iterator for Walk<'a, D> {
+    type Item = DirEntry;
+    fn next(&mut self) -> Option<DirEntry> {
+        loop {
+            let e = match self.pending.last_mut() {
+                Some(entries) => entries.pop(),
+                None => return None
+            };
+            match e {
+                Some(e) => {
+                    // A damaged entry without a cluster would be read as the root
+                    if e.is_dir() && e.first_cluster().cluster_number != 0 {
+                        self.enter(&e.to_dir());
+                    }
+                    return Some(e)
+                },
+                None => {
+                    self.pending.pop();
+                }
+            }
+        }
+    }
+}
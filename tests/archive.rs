@@ -0,0 +1,73 @@
+#![cfg(feature = "archive")]
+extern crate redox_fatfs;
+
+mod common;
+
+use std::str;
+
+use redox_fatfs::prelude::*;
+
+use common::open_image;
+
+fn octal(field: &[u8]) -> u64 {
+    let s = str::from_utf8(field).unwrap().trim_matches(|c| c == '\0' || c == ' ');
+    u64::from_str_radix(s, 8).unwrap()
+}
+
+fn cstr(field: &[u8]) -> &str {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    str::from_utf8(&field[..end]).unwrap()
+}
+
+// (path, type flag, contents) of every member
+fn members(tar: &[u8]) -> Vec<(String, u8, Vec<u8>)> {
+    let mut members = Vec::new();
+    let mut pos = 0;
+    while tar[pos..pos + 512].iter().any(|&b| b != 0) {
+        let header = &tar[pos..pos + 512];
+        let mut sum: u64 = header.iter().map(|&b| b as u64).sum();
+        sum = sum - header[148..156].iter().map(|&b| b as u64).sum::<u64>() + 8 * b' ' as u64;
+        assert_eq!(octal(&header[148..156]), sum, "Bad header checksum");
+        assert_eq!(&header[257..263], b"ustar\0");
+
+        let prefix = cstr(&header[345..500]);
+        let name = cstr(&header[..100]);
+        let path = if prefix.is_empty() { name.to_string() } else { format!("{}/{}", prefix, name) };
+        let size = octal(&header[124..136]) as usize;
+        pos += 512;
+        members.push((path, header[156], tar[pos..pos + size].to_vec()));
+        pos += (size + 511) / 512 * 512;
+    }
+    assert_eq!(tar.len(), pos + 1024);
+    members
+}
+
+#[test]
+fn archive_directory_tree() {
+    let mut fs = open_image("images/fat32.img");
+    let root = fs.root_dir();
+    let dir = root.create_dir("backup", &mut fs).unwrap();
+    let big: Vec<u8> = (0..10000).map(|i| (i % 251) as u8).collect();
+    let mut f = dir.create_file("big.bin", &mut fs).unwrap();
+    f.write(&big, &mut fs, 0).unwrap();
+    f.flush(&mut fs).unwrap();
+    dir.create_file("empty.txt", &mut fs).unwrap();
+    let sub = dir.create_dir("a long directory name for the prefix field of the header", &mut fs).unwrap();
+    let long_name = "a long file name which together with its directory does not fit in a hundred bytes.txt";
+    let mut f = sub.create_file(long_name, &mut fs).unwrap();
+    f.write(b"nested", &mut fs, 0).unwrap();
+    f.flush(&mut fs).unwrap();
+
+    let mut tar = Vec::new();
+    dir.archive_to(&mut fs, &mut tar, ArchiveFormat::Tar).unwrap();
+    assert_eq!(tar.len() % 512, 0);
+
+    let sub_path = "a long directory name for the prefix field of the header/";
+    let expected = vec![
+        ("big.bin".to_string(), b'0', big),
+        ("empty.txt".to_string(), b'0', Vec::new()),
+        (sub_path.to_string(), b'5', Vec::new()),
+        (format!("{}{}", sub_path, long_name), b'0', b"nested".to_vec()),
+    ];
+    assert_eq!(members(&tar), expected);
+}
@@ -0,0 +1,41 @@
+extern crate redox_fatfs;
+
+mod common;
+
+use common::open_image;
+
+#[test]
+fn boot_sector_and_code() {
+    let mut fs = open_image("images/fat16.img");
+    let sector = fs.boot_sector_bytes().unwrap();
+    assert_eq!(sector.len(), 512);
+    assert_eq!(&sector[510..], &[0x55, 0xaa]);
+    assert_eq!(fs.boot_code().unwrap(), &sector[62..510]);
+    assert_eq!(fs.compare_backup_boot_sector().unwrap(), None);
+    assert!(fs.repair_backup().is_err());
+
+    let mut fs = open_image("images/fat32.img");
+    let sector = fs.boot_sector_bytes().unwrap();
+    assert_eq!(fs.boot_code().unwrap(), &sector[90..510]);
+}
+
+#[test]
+fn stale_backup_is_found_and_repaired() {
+    let mut fs = open_image("images/fat32.img");
+    assert_eq!(fs.compare_backup_boot_sector().unwrap(), Some(vec![]));
+
+    // As if another tool had relabelled the volume, the label sits at 71 on FAT32
+    fs.write_to(71, b"ABCDEFGHI").unwrap();
+    let differences = fs.compare_backup_boot_sector().unwrap().unwrap();
+    assert_eq!(differences.len(), 1);
+    assert_eq!(differences[0].offset, 71);
+    assert_eq!(differences[0].primary, b"ABCDEFGHI".to_vec());
+    assert_eq!(differences[0].primary.len(), differences[0].backup.len());
+
+    fs.repair_backup().unwrap();
+    assert_eq!(fs.compare_backup_boot_sector().unwrap(), Some(vec![]));
+    let backup_sec = fs.backup_boot_sector().unwrap();
+    let mut label = [0; 9];
+    fs.read_at(backup_sec * 512 + 71, &mut label).unwrap();
+    assert_eq!(&label, b"ABCDEFGHI");
+}
@@ -0,0 +1,308 @@
+extern crate redox_fatfs;
+extern crate byteorder;
+
+use std::fs;
+use std::io::Cursor;
+
+use byteorder::{ByteOrder, LittleEndian};
+use redox_fatfs::prelude::*;
+use redox_fatfs::raw::{FATType, Geometry};
+
+fn open_bytes(data: Vec<u8>) -> std::io::Result<FileSystem<Cursor<Vec<u8>>>> {
+    FileSystem::from_offset(0, Cursor::new(data), None)
+}
+
+// A freshly formatted 1.44MB floppy: FAT12, 512 byte sectors, 80 cylinders,
+// 2 heads, 18 sectors per track and no partition table
+fn floppy_image() -> Vec<u8> {
+    let mut data = vec![0; 2880 * 512];
+    data[..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+    data[3..11].copy_from_slice(b"MSWIN4.1");
+    LittleEndian::write_u16(&mut data[11..], 512);
+    data[13] = 1;
+    LittleEndian::write_u16(&mut data[14..], 1);
+    data[16] = 2;
+    LittleEndian::write_u16(&mut data[17..], 224);
+    LittleEndian::write_u16(&mut data[19..], 2880);
+    data[21] = 0xF0;
+    LittleEndian::write_u16(&mut data[22..], 9);
+    LittleEndian::write_u16(&mut data[24..], 18);
+    LittleEndian::write_u16(&mut data[26..], 2);
+    data[36] = 0x00;
+    data[38] = 0x29;
+    LittleEndian::write_u32(&mut data[39..], 0x1234abcd);
+    data[43..54].copy_from_slice(b"NO NAME    ");
+    data[54..62].copy_from_slice(b"FAT12   ");
+    data[510] = 0x55;
+    data[511] = 0xAA;
+
+    for fat in 0..2 {
+        let start = (1 + fat * 9) * 512;
+        data[start..start + 3].copy_from_slice(&[0xF0, 0xFF, 0xFF]);
+    }
+    data
+}
+
+#[test]
+fn media_and_geometry_of_fixtures() {
+    for path in &["images/fat12.img", "images/fat16.img", "images/fat32.img"] {
+        let fs = open_bytes(fs::read(path).unwrap()).unwrap();
+        assert_eq!(fs.bpb.media_descriptor(), 0xF8, "{}", path);
+        assert!(!fs.bpb.is_removable());
+        assert_eq!(fs.bpb.drive_number(), 0x80, "{}", path);
+        let geometry = fs.bpb.geometry().expect("Fixture geometry should be valid");
+        assert_eq!((geometry.heads, geometry.sectors_per_track), (64, 32));
+    }
+}
+
+#[test]
+fn extended_boot_fields() {
+    for &(path, fs_type) in &[("images/fat12.img", "FAT12"), ("images/fat16.img", "FAT16"), ("images/fat32.img", "FAT32")] {
+        let fs = open_bytes(fs::read(path).unwrap()).unwrap();
+        assert_eq!(fs.bpb.volume_label(), Some("NO NAME".to_string()), "{}", path);
+        assert_eq!(fs.bpb.fs_type_label(), Some(fs_type.to_string()));
+        assert_ne!(fs.bpb.get_serial(), 0, "{}", path);
+        let serial = fs.bpb.get_serial();
+        assert!(FileSystem::from_offset(0, Cursor::new(fs::read(path).unwrap()), Some(serial)).is_ok());
+    }
+
+    let fs = open_bytes(floppy_image()).unwrap();
+    assert_eq!(fs.bpb.get_serial(), 0x1234abcd);
+    assert_eq!(fs.bpb.volume_label(), Some("NO NAME".to_string()));
+
+    // Only the serial
+    let mut data = floppy_image();
+    data[38] = 0x28;
+    let fs = open_bytes(data).unwrap();
+    assert_eq!(fs.bpb.get_serial(), 0x1234abcd);
+    assert_eq!(fs.bpb.volume_label(), None);
+    assert_eq!(fs.bpb.fs_type_label(), None);
+
+    // Boot code from where the signature would be
+    let mut data = floppy_image();
+    data[38] = 0x0e;
+    let fs = open_bytes(data).unwrap();
+    assert_eq!(fs.bpb.get_serial(), 0);
+    assert_eq!(fs.bpb.volume_label(), None);
+}
+
+#[test]
+fn invalid_media_descriptor() {
+    let mut data = floppy_image();
+    data[21] = 0x12;
+    assert!(open_bytes(data).is_err());
+}
+
+#[test]
+fn geometry_out_of_range() {
+    let mut data = floppy_image();
+    LittleEndian::write_u16(&mut data[24..], 0);
+    let fs = open_bytes(data).unwrap();
+    assert_eq!(fs.bpb.geometry(), None);
+}
+
+#[test]
+fn mount_floppy_image() {
+    let mut fs = open_bytes(floppy_image()).expect("Failed to mount floppy");
+    match fs.bpb.fat_type {
+        FATType::FAT12(_) => {},
+        t => panic!("Expected FAT12, found {:?}", t)
+    }
+    assert_eq!(fs.bpb.media_descriptor(), 0xF0);
+    assert!(fs.bpb.is_removable());
+    assert_eq!(fs.bpb.drive_number(), 0x00);
+    assert_eq!(fs.bpb.geometry(), Some(Geometry { cylinders: 80, heads: 2, sectors_per_track: 18 }));
+    // 2880 sectors less 1 reserved, 18 for the FATs and 14 for the root directory
+    assert_eq!(fs.free_clusters().unwrap(), 2847);
+
+    let root = fs.root_dir();
+    let dir = root.create_dir("games", &mut fs).unwrap();
+    let data: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+    let mut f = dir.create_file("SAVE.DAT", &mut fs).unwrap();
+    f.write(&data, &mut fs, 0).unwrap();
+    f.flush(&mut fs).unwrap();
+
+    let bytes = fs.disk.borrow().get_ref().clone();
+    let mut fs = open_bytes(bytes).unwrap();
+    let f = Dir::get_entry_abs("/games/save.dat", &mut fs).unwrap().to_file();
+    let mut buf = vec![0; data.len()];
+    assert_eq!(f.read(&mut buf, &mut fs, 0).unwrap(), data.len());
+    assert!(buf == data);
+    assert_eq!(fs.free_clusters().unwrap(), 2847 - 1 - 10);
+    assert!(check(&mut fs).unwrap().is_clean());
+}
+
+#[test]
+fn zero_divisors_are_rejected() {
+    let mut data = floppy_image();
+    data[13] = 0;
+    assert!(open_bytes(data).is_err());
+
+    let mut data = floppy_image();
+    LittleEndian::write_u16(&mut data[11..], 0);
+    assert!(open_bytes(data).is_err());
+
+    let image = fs::read("images/fat32.img").unwrap();
+    let mut data = image.clone();
+    LittleEndian::write_u32(&mut data[44..], 0);
+    assert!(open_bytes(data).is_err());
+
+    // A FAT too small to hold an entry for every cluster
+    let mut data = image.clone();
+    LittleEndian::write_u32(&mut data[36..], 1);
+    assert!(open_bytes(data).is_err());
+}
+
+// Every BPB field of both fixtures set to a few extreme values must either fail to
+// open or give a volume which can be listed
+#[test]
+fn mutated_bpbs_dont_panic() {
+    for image in &[floppy_image(), fs::read("images/fat32.img").unwrap()] {
+        for offset in 11..48 {
+            for &value in &[0x00, 0x01, 0x80, 0xff] {
+                let mut data = image.clone();
+                data[offset] = value;
+                if let Ok(mut fs) = open_bytes(data) {
+                    fs.max_cluster_number();
+                    fs.bpb.geometry();
+                    let root = fs.root_dir();
+                    let _: Vec<DirEntry> = root.to_iter(&mut fs).collect();
+                }
+            }
+        }
+    }
+}
+
+// A boot sector laid out like the ones newfs_msdos and camera firmware write, with a
+// data area of exactly `clusters` single sector clusters and FATs sized for 16 bit
+// entries (32 bit ones if `fat32`)
+fn boundary_image(clusters: u32, fat32: bool, oem: &[u8; 8], rsvd: u16, media: u8) -> Vec<u8> {
+    let bits = if fat32 { 32 } else { 16 };
+    let fat_sz = ((clusters + 2) * bits / 8 + 511) / 512;
+    let root_sectors = if fat32 { 0 } else { 32 };
+    let tot_sec = rsvd as u32 + 2 * fat_sz + root_sectors + clusters;
+    let mut data = vec![0; tot_sec as usize * 512];
+    data[..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+    data[3..11].copy_from_slice(oem);
+    LittleEndian::write_u16(&mut data[11..], 512);
+    data[13] = 1;
+    LittleEndian::write_u16(&mut data[14..], rsvd);
+    data[16] = 2;
+    data[21] = media;
+    LittleEndian::write_u16(&mut data[24..], 63);
+    LittleEndian::write_u16(&mut data[26..], 255);
+    LittleEndian::write_u32(&mut data[28..], 8192);
+    if tot_sec < 0x10000 && !fat32 {
+        LittleEndian::write_u16(&mut data[19..], tot_sec as u16);
+    } else {
+        LittleEndian::write_u32(&mut data[32..], tot_sec);
+    }
+    let ebpb = if fat32 {
+        LittleEndian::write_u32(&mut data[36..], fat_sz);
+        LittleEndian::write_u32(&mut data[44..], 2);
+        LittleEndian::write_u16(&mut data[48..], 1);
+        LittleEndian::write_u16(&mut data[50..], 6);
+        let fs_info = &mut data[512..1024];
+        LittleEndian::write_u32(&mut fs_info[0..], 0x41615252);
+        LittleEndian::write_u32(&mut fs_info[484..], 0x61417272);
+        LittleEndian::write_u32(&mut fs_info[488..], 0xFFFFFFFF);
+        LittleEndian::write_u32(&mut fs_info[492..], 0xFFFFFFFF);
+        LittleEndian::write_u32(&mut fs_info[508..], 0xAA550000);
+        64
+    } else {
+        LittleEndian::write_u16(&mut data[17..], 512);
+        LittleEndian::write_u16(&mut data[22..], fat_sz as u16);
+        36
+    };
+    data[ebpb] = 0x80;
+    data[ebpb + 2] = 0x29;
+    LittleEndian::write_u32(&mut data[ebpb + 3..], 0x5eed0001);
+    data[ebpb + 7..ebpb + 18].copy_from_slice(b"NO NAME    ");
+    data[ebpb + 18..ebpb + 26].copy_from_slice(if fat32 { b"FAT32   " } else { b"FAT16   " });
+    data[510] = 0x55;
+    data[511] = 0xAA;
+    if fat32 {
+        let boot = data[..512].to_vec();
+        data[6 * 512..7 * 512].copy_from_slice(&boot);
+    }
+
+    for fat in 0..2 {
+        let start = (rsvd as u32 + fat * fat_sz) as usize * 512;
+        if fat32 {
+            let entries = [0x0FFFFF00 | media as u32, 0x0FFFFFFF, 0x0FFFFFFF];
+            for (i, &e) in entries.iter().enumerate() {
+                LittleEndian::write_u32(&mut data[start + i * 4..], e);
+            }
+        } else if clusters < 4085 {
+            data[start..start + 3].copy_from_slice(&[media, 0xFF, 0xFF]);
+        } else {
+            LittleEndian::write_u16(&mut data[start..], 0xFF00 | media as u16);
+            LittleEndian::write_u16(&mut data[start + 2..], 0xFFFF);
+        }
+    }
+    data
+}
+
+fn fat_width(fs: &FileSystem<Cursor<Vec<u8>>>) -> u32 {
+    match fs.bpb.fat_type {
+        FATType::FAT12(_) => 12,
+        FATType::FAT16(_) => 16,
+        FATType::FAT32(_) => 32
+    }
+}
+
+#[test]
+fn fat_type_follows_cluster_count() {
+    // Odd reserved counts and OEM names as seen on camera cards and macOS volumes
+    let cases: [(u32, &[u8; 8], u16, u8, u32); 4] = [
+        (4084, b"BSD  4.4", 1, 0xF0, 12),
+        (4085, b"EOS     ", 3, 0xF8, 16),
+        (65524, b"BSD  4.4", 7, 0xF8, 16),
+        (65525, b"BSD  4.4", 33, 0xF0, 32)
+    ];
+    for &(clusters, oem, rsvd, media, width) in &cases {
+        let mut fs = open_bytes(boundary_image(clusters, width == 32, oem, rsvd, media))
+            .unwrap_or_else(|e| panic!("{} clusters: {}", clusters, e));
+        assert_eq!(fat_width(&fs), width, "{} clusters", clusters);
+        assert_eq!(fs.bpb.media_descriptor(), media);
+        assert_eq!(fs.max_cluster_number().cluster_number, clusters as u64 + 1);
+        let used = if width == 32 { 1 } else { 0 };
+        assert_eq!(fs.free_clusters().unwrap(), (clusters - used) as u64);
+
+        let root = fs.root_dir();
+        let mut f = root.create_file("DCIM0001.JPG", &mut fs).unwrap();
+        f.write(&[0xFF, 0xD8, 0xFF, 0xE0], &mut fs, 0).unwrap();
+        f.flush(&mut fs).unwrap();
+        assert!(check(&mut fs).unwrap().is_clean(), "{} clusters", clusters);
+    }
+}
+
+// A FAT16 card bigger than 32MiB only has the 32 bit sector count, and some firmware
+// fills in both
+#[test]
+fn large_fat16_sector_counts() {
+    let data = boundary_image(65524, false, b"EOS     ", 1, 0xF8);
+    assert_eq!(LittleEndian::read_u16(&data[19..]), 0);
+    let fs = open_bytes(data.clone()).unwrap();
+    assert_eq!(fat_width(&fs), 16);
+    assert_eq!(fs.bpb.total_sectors(), data.len() as u64 / 512);
+
+    let mut data = boundary_image(4085, false, b"EOS     ", 1, 0xF8);
+    let tot_sec = LittleEndian::read_u16(&data[19..]) as u32;
+    LittleEndian::write_u32(&mut data[32..], tot_sec);
+    let fs = open_bytes(data).unwrap();
+    assert_eq!(fat_width(&fs), 16);
+    assert_eq!(fs.max_cluster_number().cluster_number, 4086);
+}
+
+#[test]
+fn fat32_fields_must_agree_with_cluster_count() {
+    // Too few clusters for the FAT32 layout it claims
+    assert!(open_bytes(boundary_image(65524, true, b"BSD  4.4", 33, 0xF0)).is_err());
+
+    // Too many clusters for a BPB_FATSz16 volume
+    let mut data = boundary_image(65525, true, b"BSD  4.4", 33, 0xF0);
+    let fat_sz = LittleEndian::read_u32(&data[36..]);
+    LittleEndian::write_u16(&mut data[22..], fat_sz as u16);
+    assert!(open_bytes(data).is_err());
+}
@@ -0,0 +1,195 @@
+extern crate redox_fatfs;
+
+mod common;
+
+use std::io::{Cursor, ErrorKind};
+
+use redox_fatfs::prelude::*;
+use redox_fatfs::raw::{get_entry, set_entry, FatEntry};
+
+use common::{open_bytes, open_image};
+
+#[test]
+fn fixture_is_clean() {
+    let mut fs = open_image("images/fat32.img");
+    let report = check(&mut fs).unwrap();
+    assert!(report.is_clean(), "{:?}", report);
+}
+
+#[test]
+fn empty_file_with_clusters() {
+    let mut fs = open_image("images/fat32.img");
+    let root = fs.root_dir();
+    root.create_dir("sub", &mut fs).unwrap();
+    let mut f = root.create_file("sub/bad.bin", &mut fs).unwrap();
+    f.write(b"data", &mut fs, 0).unwrap();
+
+    // Zero the size without releasing the chain
+    f.set_size(0);
+    f.size_dirty = true;
+    f.flush(&mut fs).unwrap();
+
+    let report = check(&mut fs).unwrap();
+    assert_eq!(report.problems, vec![Problem::EmptyFileWithClusters {
+        path: f.file_path.clone(),
+        first_cluster: f.first_cluster
+    }]);
+}
+
+// Image with "Long name.tx" in the root directory, its single long name entry changed by `damage`
+fn damaged_long_name<F: Fn(&mut [u8])>(damage: F) -> FileSystem<Cursor<Vec<u8>>> {
+    let mut fs = open_image("images/fat32.img");
+    let root = fs.root_dir();
+    root.create_file("Long name.tx", &mut fs).unwrap();
+    let mut data = fs.disk.borrow().get_ref().clone();
+
+    let pattern = b"\x41L\0o\0n\0g\0 \0";
+    let pos = data.windows(pattern.len()).position(|w| w == &pattern[..]).expect("Long name entry not found");
+    assert_eq!(data[pos + 11], 0x0f);
+    damage(&mut data[pos..pos + 32]);
+    open_bytes(data)
+}
+
+#[test]
+fn damaged_long_names_are_counted() {
+    let mut fs = damaged_long_name(|e| e[13] ^= 0xff);
+    let root = fs.root_dir();
+    assert!(root.find_entry("Long name.tx", None, None, &mut fs).is_err());
+    assert_eq!(fs.lfn_corruption(), LfnCorruption { checksum_mismatches: 1, orphaned: 0 });
+    assert!(fs.take_lfn_warnings().is_empty());
+
+    let mut fs = damaged_long_name(|e| e[0] = 0x01);
+    let _: Vec<DirEntry> = fs.root_dir().to_iter(&mut fs).collect();
+    assert_eq!(fs.lfn_corruption(), LfnCorruption { checksum_mismatches: 0, orphaned: 1 });
+}
+
+#[test]
+fn strict_mode_keeps_one_warning_per_set() {
+    let mut fs = damaged_long_name(|e| e[13] ^= 0xff);
+    fs.strict_lfn = true;
+    let root = fs.root_dir();
+    for _ in 0..2 {
+        let _: Vec<DirEntry> = root.to_iter(&mut fs).collect();
+    }
+    assert_eq!(fs.lfn_corruption().checksum_mismatches, 2);
+
+    let warnings = fs.take_lfn_warnings();
+    assert_eq!(warnings.len(), 1);
+    match warnings[0] {
+        Problem::DamagedLongName { ref path, checksum_mismatch, .. } => {
+            assert_eq!(path, &root.dir_path);
+            assert!(checksum_mismatch);
+        },
+        ref p => panic!("Unexpected problem {:?}", p)
+    }
+    assert!(fs.take_lfn_warnings().is_empty());
+}
+
+#[test]
+fn check_reports_damaged_long_names() {
+    let mut fs = damaged_long_name(|e| e[13] ^= 0xff);
+    let report = check(&mut fs).unwrap();
+    assert_eq!(report.problems.len(), 1);
+    assert!(report.problems[0].offset().is_some());
+    assert!(!fs.strict_lfn);
+}
+
+// A file "sub/f.bin" of 3 clusters with `size` written into its entry
+fn file_with_size(size: u32) -> (FileSystem<Cursor<Vec<u8>>>, File) {
+    let mut fs = open_image("images/fat32.img");
+    let bpc = fs.bytes_per_cluster() as usize;
+    let root = fs.root_dir();
+    root.create_dir("sub", &mut fs).unwrap();
+    let mut f = root.create_file("sub/f.bin", &mut fs).unwrap();
+    f.write(&vec![1; 3 * bpc], &mut fs, 0).unwrap();
+    f.set_size(size);
+    f.size_dirty = true;
+    f.flush(&mut fs).unwrap();
+    (fs, f)
+}
+
+#[test]
+fn size_past_the_chain_is_cut_down() {
+    let (mut fs, f) = file_with_size(10000);
+    let bpc = fs.bytes_per_cluster();
+    let report = check(&mut fs).unwrap();
+    assert_eq!(report.problems, vec![Problem::SizeExceedsChain { path: f.file_path.clone(), size: 10000, clusters: 3 }]);
+
+    // The policy only matters for chains longer than the size
+    let changes = report.apply_fixes(&mut fs, FixPolicy::FreeExcess, true).unwrap();
+    assert_eq!(changes.len(), 1);
+    assert!(check(&mut fs).unwrap().problems == report.problems);
+    report.apply_fixes(&mut fs, FixPolicy::FreeExcess, false).unwrap();
+    assert!(check(&mut fs).unwrap().is_clean());
+    assert_eq!(fs.metadata(&f.file_path).unwrap().len(), 3 * bpc);
+}
+
+#[test]
+fn chain_past_the_size_is_freed_or_covered() {
+    let (mut fs, f) = file_with_size(100);
+    let bpc = fs.bytes_per_cluster();
+    let free = fs.free_clusters().unwrap();
+    let report = check(&mut fs).unwrap();
+    assert_eq!(report.problems, vec![Problem::ChainExceedsSize { path: f.file_path.clone(), size: 100, clusters: 3 }]);
+
+    let (mut extended, _) = file_with_size(100);
+    report.apply_fixes(&mut extended, FixPolicy::ExtendSize, false).unwrap();
+    assert!(check(&mut extended).unwrap().is_clean());
+    assert_eq!(extended.metadata(&f.file_path).unwrap().len(), 3 * bpc);
+
+    let changes = report.apply_fixes(&mut fs, FixPolicy::FreeExcess, false).unwrap();
+    assert!(changes[0].contains("free 2 clusters"), "{:?}", changes);
+    assert!(check(&mut fs).unwrap().is_clean());
+    assert_eq!(fs.free_clusters().unwrap(), free + 2);
+    assert_eq!(fs.metadata(&f.file_path).unwrap().len(), 100);
+}
+
+#[test]
+fn empty_file_with_clusters_is_fixed() {
+    let (mut fs, f) = file_with_size(0);
+    let free = fs.free_clusters().unwrap();
+    let report = check(&mut fs).unwrap();
+    report.apply_fixes(&mut fs, FixPolicy::FreeExcess, false).unwrap();
+    assert!(check(&mut fs).unwrap().is_clean());
+    assert_eq!(fs.free_clusters().unwrap(), free + 3);
+    assert_eq!(fs.metadata(&f.file_path).unwrap().first_cluster().cluster_number, 0);
+}
+
+#[test]
+fn stale_backup_boot_sector() {
+    let mut fs = open_image("images/fat32.img");
+    fs.write_to(71, b"ABC").unwrap();
+    let report = check(&mut fs).unwrap();
+    assert_eq!(report.problems, vec![Problem::StaleBackupBootSector { bytes: 3 }]);
+
+    assert_eq!(report.apply_fixes(&mut fs, FixPolicy::FreeExcess, true).unwrap().len(), 1);
+    assert!(!check(&mut fs).unwrap().is_clean());
+    report.apply_fixes(&mut fs, FixPolicy::FreeExcess, false).unwrap();
+    assert!(check(&mut fs).unwrap().is_clean());
+}
+
+#[test]
+fn cluster_out_of_range_ends_the_chain() {
+    let (mut fs, f) = file_with_size(3 * 512);
+    let bpc = fs.bytes_per_cluster();
+    let second = fs.clusters(f.first_cluster)[1];
+    let past_end = fs.max_cluster_number().cluster_number + 5;
+    set_entry(&mut fs, second, FatEntry::Next(Cluster::new(past_end))).unwrap();
+
+    let mut buf = vec![0; 3 * bpc as usize];
+    fs.strict_cluster_bounds = true;
+    assert_eq!(f.read(&mut buf, &mut fs, 0).unwrap_err().kind(), ErrorKind::InvalidData);
+
+    let report = check(&mut fs).unwrap();
+    assert_eq!(report.problems, vec![Problem::ClusterOutOfRange { path: f.file_path.clone(), cluster: second, next: past_end }]);
+    assert!(fs.strict_cluster_bounds);
+    report.apply_fixes(&mut fs, FixPolicy::FreeExcess, false).unwrap();
+    assert_eq!(get_entry(&mut fs, second).unwrap(), FatEntry::EndOfChain);
+
+    // What is left is a size past the shortened chain
+    let report = check(&mut fs).unwrap();
+    assert_eq!(report.problems, vec![Problem::SizeExceedsChain { path: f.file_path.clone(), size: 3 * bpc, clusters: 2 }]);
+    report.apply_fixes(&mut fs, FixPolicy::FreeExcess, false).unwrap();
+    assert!(check(&mut fs).unwrap().is_clean());
+    assert_eq!(fs.metadata(&f.file_path).unwrap().len(), 2 * bpc);
+}
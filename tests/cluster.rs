@@ -4,14 +4,15 @@ extern crate redox_fatfs;
 
 use std::fs::OpenOptions;
 use std::str;
-use std::io::{Seek, SeekFrom, Read, Cursor};
+use std::io::{Seek, SeekFrom, Cursor};
 
-use redox_fatfs::*;
+use redox_fatfs::prelude::*;
+use redox_fatfs::raw::{get_dir_entry_raw, get_free_cluster, get_free_count, ShortNameGen};
 
 
 fn print_fat32() {
     let f = OpenOptions::new().read(true).write(true).open("images/fat32.img").expect("Failed to open file");
-    let mut fs = redox_fatfs::FileSystem::from_offset(0, f).expect("Parsing Error");
+    let mut fs = FileSystem::from_offset(0, f, None).expect("Parsing Error");
     let root_clus = Cluster::new(2);
     let _max_cluster = fs.max_cluster_number();
     println!("Root Cluster = {:?}", fs.clusters(root_clus));
@@ -81,15 +82,15 @@ fn print_fat32() {
     let r = root_d.find_entry("heLlo.txt", None, None, &mut fs);
     println!("Trying to find heLlo.txt : {:?}", r);
 
-    println!("Attempting to remove hello.txt: {:?}", root_d.remove("/hello.txt", &mut fs));
-    println!("Attempting to remove someDir: {:?}", root_d.remove("/someDir", &mut fs));
+    println!("Attempting to remove hello.txt: {:?}", root_d.remove("/hello.txt", &mut fs, true));
+    println!("Attempting to remove someDir: {:?}", root_d.remove("/someDir", &mut fs, true));
     let hello = root_d.create_file("/hello5.txt", &mut fs).expect("Error Creating hello.txt");
     println!("Created hello1.txt");
     let r = Dir::rename(&mut DirEntry::File(hello), "/hello2.txt", &mut fs);
     println!("Attempting to move hello1.txt to hello2.txt : {:?}", r);
-    let mut dir1 = root_d.create_dir("/dir5/", &mut fs).expect("Error creating dir1");
+    let dir1 = root_d.create_dir("/dir5/", &mut fs).expect("Error creating dir1");
     println!("Created dir5");
-    let mut dir2 = dir1.create_dir("/dir6", &mut fs).expect("Error creating dir2");
+    let dir2 = dir1.create_dir("/dir6", &mut fs).expect("Error creating dir2");
     println!("Created dir6");
     let mut hello2 = dir2.create_file("/hello2.txt/", &mut fs).expect("Error creating hello2.txt");
     hello2.write("This is something in hello2".as_bytes(), &mut fs, 0).expect("Failed to write hello2.txt");
@@ -99,7 +100,7 @@ fn print_fat32() {
 
 fn print_fat12() {
     let f = OpenOptions::new().read(true).write(true).open("images/fat12.img").expect("Failed to open fat12.img");
-    let mut fs = redox_fatfs::FileSystem::from_offset(0, f).expect("Parsing Error");
+    let mut fs = FileSystem::from_offset(0, f, None).expect("Parsing Error");
     let root_sec = fs.bpb.rsvd_sec_cnt as u64 + (fs.bpb.num_fats as u64 * fs.bpb.fat_size_16 as u64);
     let root_clus = Cluster::new(root_sec / fs.bpb.sectors_per_cluster as u64);
     println!("Root Cluster = {:?}", fs.clusters(root_clus));
@@ -166,7 +167,7 @@ fn print_fat12() {
 
 fn print_fat16() {
     let f = OpenOptions::new().read(true).write(true).open("images/fat16.img").expect("Failed to open fat16.img");
-    let mut fs = redox_fatfs::FileSystem::from_offset(0, f).expect("Parsing Error");
+    let mut fs = FileSystem::from_offset(0, f, None).expect("Parsing Error");
     let max_cluster = fs.max_cluster_number();
     let root_sec = fs.bpb.rsvd_sec_cnt as u64 + (fs.bpb.num_fats as u64 * fs.bpb.fat_size_16 as u64);
     let root_clus = Cluster::new(root_sec / fs.bpb.sectors_per_cluster as u64);
@@ -266,7 +267,7 @@ fn split_path(path: &str) -> (&str, Option<&str>) {
 }
 
 fn test_cursor() {
-    let mut v = vec![1, 2, 3, 4, 5, 6];
+    let v = vec![1, 2, 3, 4, 5, 6];
     let mut cursor = Cursor::new(v);
     cursor.seek(SeekFrom::Start(2));
     for x in cursor.get_ref() {
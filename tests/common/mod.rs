@@ -0,0 +1,283 @@
+//! Images and disk wrappers shared by the integration tests
+
+#![allow(dead_code)]
+
+use std::cell::Cell;
+use std::fs;
+use std::io::{self, Cursor, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::rc::Rc;
+
+use redox_fatfs::prelude::*;
+
+/// Mounts a copy of the image at `path`, the fixture itself is never written
+pub fn open_image(path: &str) -> FileSystem<Cursor<Vec<u8>>> {
+    open_bytes(fs::read(path).expect("Failed to read image"))
+}
+
+/// Mounts an image held in memory, such as the bytes another instance left behind
+pub fn open_bytes(data: Vec<u8>) -> FileSystem<Cursor<Vec<u8>>> {
+    FileSystem::from_offset(0, Cursor::new(data), None).expect("Parsing Error")
+}
+
+fn read_image(path: &str) -> Cursor<Vec<u8>> {
+    Cursor::new(fs::read(path).expect("Failed to read image"))
+}
+
+/// Fails every write covering `fail_at`
+pub struct FailingDisk {
+    pub inner: Cursor<Vec<u8>>,
+    pub fail_at: Rc<Cell<Option<u64>>>
+}
+
+impl FailingDisk {
+    pub fn open(path: &str) -> (FailingDisk, Rc<Cell<Option<u64>>>) {
+        let fail_at = Rc::new(Cell::new(None));
+        (FailingDisk { inner: read_image(path), fail_at: fail_at.clone() }, fail_at)
+    }
+}
+
+impl Read for FailingDisk {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Write for FailingDisk {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let pos = self.inner.position();
+        if let Some(at) = self.fail_at.get() {
+            if pos <= at && at < pos + buf.len() as u64 {
+                return Err(io::Error::new(ErrorKind::Other, "Injected failure"));
+            }
+        }
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for FailingDisk {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+/// Fails the next `failures` writes at or past `fail_from`
+pub struct FlakyDisk {
+    pub inner: Cursor<Vec<u8>>,
+    pub failures: Rc<Cell<u32>>,
+    pub fail_from: Rc<Cell<u64>>
+}
+
+impl FlakyDisk {
+    pub fn open(path: &str) -> (FlakyDisk, Rc<Cell<u32>>, Rc<Cell<u64>>) {
+        let failures = Rc::new(Cell::new(0));
+        let fail_from = Rc::new(Cell::new(0));
+        let disk = FlakyDisk { inner: read_image(path), failures: failures.clone(), fail_from: fail_from.clone() };
+        (disk, failures, fail_from)
+    }
+}
+
+impl Read for FlakyDisk {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Seek for FlakyDisk {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl Write for FlakyDisk {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.inner.position() >= self.fail_from.get() && self.failures.get() > 0 {
+            self.failures.set(self.failures.get() - 1);
+            return Err(io::Error::new(ErrorKind::Other, "Injected write error"))
+        }
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Fails every write once `writes_left` runs out, as if the power went
+pub struct CrashDisk {
+    pub overlay: Overlay<Cursor<Vec<u8>>>,
+    pub writes_left: Rc<Cell<u64>>
+}
+
+impl CrashDisk {
+    pub fn open(path: &str, writes: u64) -> (CrashDisk, Rc<Cell<u64>>) {
+        let writes_left = Rc::new(Cell::new(writes));
+        let disk = CrashDisk { overlay: Overlay::new(read_image(path)).unwrap(), writes_left: writes_left.clone() };
+        (disk, writes_left)
+    }
+
+    /// The image as the writes made before the crash left it
+    pub fn image(&mut self) -> Vec<u8> {
+        let mut image = Vec::new();
+        self.overlay.seek(SeekFrom::Start(0)).unwrap();
+        self.overlay.read_to_end(&mut image).unwrap();
+        image
+    }
+}
+
+impl Read for CrashDisk {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.overlay.read(buf)
+    }
+}
+
+impl Seek for CrashDisk {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.overlay.seek(pos)
+    }
+}
+
+impl Write for CrashDisk {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.writes_left.get() == 0 {
+            return Err(io::Error::new(ErrorKind::Other, "Power lost"))
+        }
+        self.writes_left.set(self.writes_left.get() - 1);
+        self.overlay.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.overlay.flush()
+    }
+}
+
+/// Reports the next `dropped` writes as done without writing anything
+pub struct LossyDisk {
+    pub inner: Cursor<Vec<u8>>,
+    pub dropped: Rc<Cell<u32>>
+}
+
+impl LossyDisk {
+    pub fn open(path: &str) -> (LossyDisk, Rc<Cell<u32>>) {
+        let dropped = Rc::new(Cell::new(0));
+        (LossyDisk { inner: read_image(path), dropped: dropped.clone() }, dropped)
+    }
+}
+
+impl Read for LossyDisk {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Seek for LossyDisk {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl Write for LossyDisk {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.dropped.get() > 0 {
+            self.dropped.set(self.dropped.get() - 1);
+            self.inner.seek(SeekFrom::Current(buf.len() as i64))?;
+            return Ok(buf.len())
+        }
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Interrupts every third seek, read and write, or all of them with `always`, as a
+/// signal arriving mid-IO would
+pub struct InterruptingDisk {
+    pub inner: Cursor<Vec<u8>>,
+    pub calls: Rc<Cell<u32>>,
+    pub always: bool
+}
+
+impl InterruptingDisk {
+    pub fn open(path: &str) -> (InterruptingDisk, Rc<Cell<u32>>) {
+        let calls = Rc::new(Cell::new(0));
+        (InterruptingDisk { inner: read_image(path), calls: calls.clone(), always: false }, calls)
+    }
+
+    fn interrupt(&self) -> io::Result<()> {
+        self.calls.set(self.calls.get() + 1);
+        if self.always || self.calls.get() % 3 == 0 {
+            return Err(io::Error::new(ErrorKind::Interrupted, "Interrupted"))
+        }
+        Ok(())
+    }
+}
+
+impl Read for InterruptingDisk {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.interrupt()?;
+        self.inner.read(buf)
+    }
+}
+
+impl Seek for InterruptingDisk {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.interrupt()?;
+        self.inner.seek(pos)
+    }
+}
+
+impl Write for InterruptingDisk {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.interrupt()?;
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Counts the reads and writes reaching the underlying image
+pub struct CountingDisk {
+    pub inner: Cursor<Vec<u8>>,
+    pub reads: Rc<Cell<usize>>,
+    pub writes: Rc<Cell<usize>>
+}
+
+impl CountingDisk {
+    pub fn open(path: &str) -> (CountingDisk, Rc<Cell<usize>>, Rc<Cell<usize>>) {
+        let reads = Rc::new(Cell::new(0));
+        let writes = Rc::new(Cell::new(0));
+        let disk = CountingDisk { inner: read_image(path), reads: reads.clone(), writes: writes.clone() };
+        (disk, reads, writes)
+    }
+}
+
+impl Read for CountingDisk {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reads.set(self.reads.get() + 1);
+        self.inner.read(buf)
+    }
+}
+
+impl Write for CountingDisk {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writes.set(self.writes.get() + 1);
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Seek for CountingDisk {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
@@ -0,0 +1,54 @@
+extern crate redox_fatfs;
+
+use std::fs;
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+use redox_fatfs::compat::{FileSystem, FsOptions};
+
+fn open_image(path: &str) -> FileSystem<Cursor<Vec<u8>>> {
+    let data = fs::read(path).expect("Failed to read image");
+    FileSystem::new(Cursor::new(data), FsOptions::new()).expect("Parsing Error")
+}
+
+#[test]
+fn rust_fatfs_style_usage() {
+    let fs = open_image("images/fat32.img");
+    let root = fs.root_dir();
+    let dir = root.create_dir("docs").unwrap();
+    {
+        let mut f = dir.create_file("hello.txt").unwrap();
+        f.write_all(b"Hello, world!").unwrap();
+        f.seek(SeekFrom::Start(7)).unwrap();
+        f.write_all(b"FAT").unwrap();
+        f.seek(SeekFrom::End(-1)).unwrap();
+        f.truncate().unwrap();
+    }
+
+    let mut s = String::new();
+    root.open_file("docs/hello.txt").unwrap().read_to_string(&mut s).unwrap();
+    assert_eq!(s, "Hello, FATld");
+
+    // Handles work while iterating
+    for e in dir.iter() {
+        let e = e.unwrap();
+        if e.is_file() {
+            assert_eq!(e.file_name(), "hello.txt");
+            assert_eq!(e.short_file_name(), "HELLO.TXT");
+            assert_eq!(e.len(), 12);
+            let mut buf = Vec::new();
+            e.to_file().read_to_end(&mut buf).unwrap();
+            assert_eq!(buf, b"Hello, FATld");
+        }
+    }
+    let names: Vec<String> = dir.iter().map(|e| e.unwrap().file_name()).collect();
+    assert_eq!(names, vec![".", "..", "hello.txt"]);
+
+    let other = root.create_dir("other").unwrap();
+    dir.rename("hello.txt", &other, "moved.txt").unwrap();
+    assert!(root.open_file("other/moved.txt").is_ok());
+    assert!(dir.open_file("hello.txt").is_err());
+    assert!(root.remove("other").is_err());
+    other.remove("moved.txt").unwrap();
+    root.remove("other").unwrap();
+    fs.unmount().unwrap();
+}
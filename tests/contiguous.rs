@@ -0,0 +1,58 @@
+extern crate redox_fatfs;
+
+mod common;
+
+use std::io::ErrorKind;
+
+use redox_fatfs::prelude::*;
+
+use common::open_image;
+
+#[test]
+fn create_contiguous() {
+    for path in &["images/fat12.img", "images/fat16.img", "images/fat32.img"] {
+        let mut fs = open_image(path);
+        let bpc = fs.bytes_per_cluster();
+        let free = fs.free_clusters().unwrap();
+        let size = 10 * bpc + 100;
+        let mut f = fs.create_contiguous("/SWAPFILE.SYS", size, true).unwrap();
+        assert_eq!(f.size(), size);
+        assert_eq!(fs.free_clusters().unwrap(), free - 11, "{}", path);
+
+        let chain = fs.clusters(f.first_cluster);
+        assert_eq!(chain.len(), 11);
+        assert!(chain.windows(2).all(|w| w[1].cluster_number == w[0].cluster_number + 1), "{}", path);
+
+        // Written in place, the chain doesn't change
+        let data = vec![0x5a; 3 * bpc as usize];
+        f.write(&data, &mut fs, 2 * bpc).unwrap();
+        f.flush(&mut fs).unwrap();
+        assert_eq!(fs.clusters(f.first_cluster), chain);
+
+        let e = Dir::get_entry_abs("/swapfile.sys", &mut fs).unwrap();
+        assert_eq!(e.attributes(), FileAttributes::ARCHIVE | FileAttributes::SYSTEM);
+        let f = e.to_file();
+        let mut buf = vec![0xff; size as usize];
+        f.read(&mut buf, &mut fs, 0).unwrap();
+        assert!(buf[..2 * bpc as usize].iter().all(|&b| b == 0));
+        assert!(buf[2 * bpc as usize..5 * bpc as usize] == data[..]);
+        assert!(buf[5 * bpc as usize..].iter().all(|&b| b == 0));
+        assert!(check(&mut fs).unwrap().is_clean(), "{}", path);
+    }
+}
+
+#[test]
+fn create_contiguous_failures() {
+    let mut fs = open_image("images/fat16.img");
+    let free = fs.free_clusters().unwrap();
+    let bpc = fs.bytes_per_cluster();
+
+    fs.create_contiguous("/STAGE.BIN", 1000, false).unwrap();
+    let err = fs.create_contiguous("/stage.bin", 1000, false).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+    assert_eq!(fs.create_contiguous("/EMPTY.BIN", 0, false).unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert_eq!(fs.create_contiguous("/HUGE.BIN", free * bpc, false).unwrap_err().kind(), ErrorKind::Other);
+    assert_eq!(fs.free_clusters().unwrap(), free - 1);
+    assert_eq!(Dir::get_entry_abs("/stage.bin", &mut fs).unwrap().attributes(), FileAttributes::ARCHIVE);
+    assert!(check(&mut fs).unwrap().is_clean());
+}
@@ -0,0 +1,134 @@
+extern crate redox_fatfs;
+extern crate byteorder;
+
+mod common;
+
+use std::fs;
+use std::io::{Cursor, ErrorKind};
+
+use byteorder::{ByteOrder, LittleEndian};
+use redox_fatfs::prelude::*;
+use redox_fatfs::raw::FATType;
+
+use common::open_bytes;
+
+// A FAT16 volume with 2 sectors per cluster, large enough for FAT32 with 1
+fn fat16_image() -> Vec<u8> {
+    let (clusters, fat_sz) = (34000u32, 133u32);
+    let tot_sec = 1 + 2 * fat_sz + 32 + clusters * 2;
+    let mut data = vec![0; tot_sec as usize * 512];
+    data[..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+    data[3..11].copy_from_slice(b"MSWIN4.1");
+    LittleEndian::write_u16(&mut data[11..], 512);
+    data[13] = 2;
+    LittleEndian::write_u16(&mut data[14..], 1);
+    data[16] = 2;
+    LittleEndian::write_u16(&mut data[17..], 512);
+    data[21] = 0xF8;
+    LittleEndian::write_u16(&mut data[22..], fat_sz as u16);
+    LittleEndian::write_u32(&mut data[32..], tot_sec);
+    data[36] = 0x80;
+    data[38] = 0x29;
+    LittleEndian::write_u32(&mut data[39..], 0x1234abcd);
+    data[43..54].copy_from_slice(b"CONVERTME  ");
+    data[54..62].copy_from_slice(b"FAT16   ");
+    data[510] = 0x55;
+    data[511] = 0xAA;
+    for fat in 0..2 {
+        let start = (1 + fat * fat_sz) as usize * 512;
+        LittleEndian::write_u16(&mut data[start..], 0xFFF8);
+        LittleEndian::write_u16(&mut data[start + 2..], 0xFFFF);
+    }
+    data
+}
+
+fn write_file(fs: &mut FileSystem<Cursor<Vec<u8>>>, dir: &Dir, name: &str, data: &[u8]) {
+    let mut f = dir.create_file(name, fs).unwrap();
+    f.write(data, fs, 0).unwrap();
+    f.flush(fs).unwrap();
+}
+
+fn read_file(fs: &mut FileSystem<Cursor<Vec<u8>>>, path: &str) -> Vec<u8> {
+    let f = Dir::get_entry_abs(path, fs).unwrap().to_file();
+    let mut buf = vec![0; f.size() as usize];
+    f.read(&mut buf, fs, 0).unwrap();
+    buf
+}
+
+#[test]
+fn convert_fat16_to_fat32() {
+    let mut fs = open_bytes(fat16_image());
+    let root = fs.root_dir();
+    let big: Vec<u8> = (0..50000).map(|i| (i % 251) as u8).collect();
+    for i in 0..40 {
+        write_file(&mut fs, &root, &format!("File number {}.txt", i), format!("contents {}", i).as_bytes());
+    }
+    let docs = root.create_dir("Documents", &mut fs).unwrap();
+    let deeper = docs.create_dir("Deeper", &mut fs).unwrap();
+    write_file(&mut fs, &deeper, "big.bin", &big);
+    // Two files growing in turns, so that their chains interleave
+    let mut a = docs.create_file("A.BIN", &mut fs).unwrap();
+    let mut b = docs.create_file("B.BIN", &mut fs).unwrap();
+    for i in 0..8 {
+        a.write(&[b'a'; 1500], &mut fs, i * 1500).unwrap();
+        b.write(&[b'b'; 1500], &mut fs, i * 1500).unwrap();
+    }
+    a.flush(&mut fs).unwrap();
+    b.flush(&mut fs).unwrap();
+    let used = fs.usage_bitmap().unwrap().allocated;
+
+    fs.convert_to_fat32().unwrap();
+    match fs.bpb.fat_type {
+        FATType::FAT32(s) => assert_eq!(s.root_cluster, 2),
+        _ => panic!("Volume is still FAT16")
+    }
+    assert_eq!(fs.sectors_per_cluster(), 1);
+    // The root directory's 32 sectors are now clusters
+    assert_eq!(fs.clusters(Cluster::new(2)).len(), 32);
+    assert!(fs.usage_bitmap().unwrap().allocated < used * 2 + 32);
+
+    let bytes = fs.disk.borrow().get_ref().clone();
+    let mut fs = open_bytes(bytes);
+    match fs.bpb.fat_type {
+        FATType::FAT32(s) => {
+            assert_eq!(s.vol_id, 0x1234abcd);
+            assert_eq!(&s.volume_label, b"CONVERTME  ");
+        },
+        _ => panic!("Volume is read as FAT16")
+    }
+    assert_eq!(fs.compare_backup_boot_sector().unwrap(), Some(vec![]));
+    for i in 0..40 {
+        assert_eq!(read_file(&mut fs, &format!("/file number {}.txt", i)), format!("contents {}", i).as_bytes());
+    }
+    assert!(read_file(&mut fs, "/documents/deeper/big.bin") == big);
+    assert_eq!(read_file(&mut fs, "/documents/a.bin"), vec![b'a'; 12000]);
+    assert_eq!(read_file(&mut fs, "/documents/b.bin"), vec![b'b'; 12000]);
+    assert!(check(&mut fs).unwrap().is_clean());
+
+    let root = fs.root_dir();
+    write_file(&mut fs, &root, "AFTER.TXT", b"written after");
+    assert_eq!(read_file(&mut fs, "/after.txt"), b"written after");
+    assert!(check(&mut fs).unwrap().is_clean());
+}
+
+#[test]
+fn conversion_is_refused() {
+    // Too small for FAT32 even with 1 sector per cluster
+    let mut fs = open_bytes(fs::read("images/fat16.img").unwrap());
+    assert_eq!(fs.convert_to_fat32().unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert!(check(&mut fs).unwrap().is_clean());
+    for path in &["images/fat12.img", "images/fat32.img"] {
+        let mut fs = open_bytes(fs::read(path).unwrap());
+        assert_eq!(fs.convert_to_fat32().unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+
+    // The last cluster would end up past the end of the volume
+    let mut fs = open_bytes(fat16_image());
+    let last = fs.max_cluster_number();
+    redox_fatfs::raw::set_entry(&mut fs, last, redox_fatfs::raw::FatEntry::EndOfChain).unwrap();
+    assert_eq!(fs.convert_to_fat32().unwrap_err().kind(), ErrorKind::InvalidInput);
+    match fs.bpb.fat_type {
+        FATType::FAT16(_) => (),
+        _ => panic!("Volume was converted")
+    }
+}
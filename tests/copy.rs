@@ -0,0 +1,68 @@
+extern crate redox_fatfs;
+
+mod common;
+
+use std::io::{Cursor, ErrorKind};
+
+use redox_fatfs::prelude::*;
+
+use common::open_image;
+
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+#[test]
+fn copy_in_and_out() {
+    let mut fs = open_image("images/fat32.img");
+    fs.root_dir().create_dir("EFI", &mut fs).unwrap();
+    let data = pattern(200 * 1024 + 17);
+    let mut reports = Vec::new();
+    let done = fs.copy_in(&mut Cursor::new(&data), "EFI/BOOTX64.EFI", data.len() as u64, |p| {
+        reports.push(*p);
+        true
+    }).unwrap();
+    assert_eq!(done, CopyProgress { done: data.len() as u64, total: data.len() as u64 });
+    assert_eq!(reports.len(), 4);
+    assert_eq!(reports[0], CopyProgress { done: 64 * 1024, total: data.len() as u64 });
+    assert_eq!(*reports.last().unwrap(), done);
+
+    let mut out = Vec::new();
+    let mut reports = 0;
+    fs.copy_out("EFI/BOOTX64.EFI", &mut out, |_| {
+        reports += 1;
+        true
+    }).unwrap();
+    assert!(out == data);
+    assert_eq!(reports, 4);
+
+    // Copying in again replaces the contents
+    fs.copy_in(&mut Cursor::new(b"short"), "EFI/BOOTX64.EFI", 5, |_| true).unwrap();
+    let mut out = Vec::new();
+    fs.copy_out("EFI/BOOTX64.EFI", &mut out, |_| true).unwrap();
+    assert_eq!(out, b"short");
+    assert!(check(&mut fs).unwrap().is_clean());
+}
+
+#[test]
+fn copies_can_be_cancelled() {
+    let mut fs = open_image("images/fat32.img");
+    let data = pattern(300 * 1024);
+    let err = fs.copy_in(&mut Cursor::new(&data), "big", data.len() as u64, |p| p.done < 128 * 1024).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Interrupted);
+    assert_eq!(fs.metadata("big").unwrap().len(), 128 * 1024);
+    assert!(check(&mut fs).unwrap().is_clean());
+
+    let mut out = Vec::new();
+    let err = fs.copy_out("big", &mut out, |_| false).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Interrupted);
+    assert!(out[..] == data[..64 * 1024]);
+}
+
+#[test]
+fn copy_in_of_a_short_reader() {
+    let mut fs = open_image("images/fat16.img");
+    let err = fs.copy_in(&mut Cursor::new(b"only this"), "file", 100, |_| true).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    assert!(check(&mut fs).unwrap().is_clean());
+}
@@ -0,0 +1,973 @@
+extern crate redox_fatfs;
+
+mod common;
+
+use std::io::{self, Cursor, ErrorKind, IoSlice, IoSliceMut, Seek, SeekFrom, Write};
+
+use redox_fatfs::prelude::*;
+use redox_fatfs::raw::{get_dir_entry_raw, get_entry, names_match, normalize_name, set_entry, DirEntryRaw, FatEntry, DIR_ENTRY_LEN, ESCAPED_E5};
+
+use common::{open_bytes, open_image, CountingDisk, CrashDisk, FailingDisk};
+
+fn list_names(dir: &Dir, fs: &mut FileSystem<Cursor<Vec<u8>>>) -> Vec<String> {
+    dir.to_iter(fs).map(|e| e.name()).collect()
+}
+
+#[test]
+fn short_only_entries_for_8_3_names() {
+    let mut fs = open_image("images/fat32.img");
+    let root = fs.root_dir();
+
+    for name in &["README.TXT", "notes.md", "MAKEFILE", "lower"] {
+        let f = root.create_file(name, &mut fs).expect("Error creating file");
+        // A single slot is used, holding the short entry
+        assert_eq!(f.loc.0, f.loc.1, "{} should not use LFN entries", name);
+        let offset = fs.cluster_offset((f.loc.1).0) + (f.loc.1).1;
+        match get_dir_entry_raw(&mut fs, offset).unwrap() {
+            DirEntryRaw::Short(_) => {},
+            e => panic!("Expected a short entry for {}, found {:?}", name, e)
+        }
+        assert_eq!(f.fname, *name);
+    }
+
+    let names = list_names(&root, &mut fs);
+    for name in &["README.TXT", "notes.md", "MAKEFILE", "lower"] {
+        assert!(names.iter().any(|n| n == name), "{} missing from listing {:?}", name, names);
+    }
+    assert!(root.open_file("readme.txt", &mut fs).is_ok());
+}
+
+#[test]
+fn lfn_entries_for_other_names() {
+    let mut fs = open_image("images/fat32.img");
+    let root = fs.root_dir();
+
+    for name in &["ReadMe.txt", "toolongname.txt", "a.b.c", "x.html"] {
+        let f = root.create_file(name, &mut fs).expect("Error creating file");
+        assert!(f.loc.0 != f.loc.1, "{} should use LFN entries", name);
+        let offset = fs.cluster_offset((f.loc.0).0) + (f.loc.0).1;
+        match get_dir_entry_raw(&mut fs, offset).unwrap() {
+            DirEntryRaw::Long(_) => {},
+            e => panic!("Expected a long entry for {}, found {:?}", name, e)
+        }
+    }
+
+    let names = list_names(&root, &mut fs);
+    for name in &["ReadMe.txt", "toolongname.txt", "a.b.c", "x.html"] {
+        assert!(names.iter().any(|n| n == name), "{} missing from listing {:?}", name, names);
+    }
+}
+
+#[test]
+fn short_names_only_refuses_long_names() {
+    let mut fs = open_image("images/fat32.img");
+    fs.short_names_only = true;
+    let root = fs.root_dir();
+    let free = fs.free_clusters().unwrap();
+
+    for name in &["ReadMe.txt", "toolongname.txt", "a.b.c", "x.html"] {
+        assert_eq!(root.create_file(name, &mut fs).unwrap_err().kind(), ErrorKind::InvalidInput, "{}", name);
+        assert_eq!(root.create_dir(name, &mut fs).unwrap_err().kind(), ErrorKind::InvalidInput, "{}", name);
+    }
+    assert_eq!(fs.free_clusters().unwrap(), free);
+
+    // Names which fit 8.3 are written as before
+    let f = root.create_file("notes.md", &mut fs).unwrap();
+    assert_eq!(f.loc.0, f.loc.1);
+    let sub = root.create_dir("SUB", &mut fs).unwrap();
+    let mut notes = Dir::get_entry_abs("/notes.md", &mut fs).unwrap();
+    assert_eq!(Dir::rename(&mut notes, "/SUB/Long notes.md", &mut fs).unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert!(root.open_file("notes.md", &mut fs).is_ok());
+    Dir::rename(&mut notes, "/SUB/NOTES.MD", &mut fs).unwrap();
+    assert_eq!(list_names(&sub, &mut fs), vec![".", "..", "NOTES.MD"]);
+    assert!(check(&mut fs).unwrap().is_clean());
+}
+
+#[test]
+fn vectored_read_write() {
+    let mut fs = open_image("images/fat32.img");
+    let root = fs.root_dir();
+    let mut f = root.create_file("vectored.bin", &mut fs).unwrap();
+
+    // Spans several clusters so the slices cross cluster boundaries
+    let a = vec![1u8; 700];
+    let b = vec![2u8; 1500];
+    let c = vec![3u8; 10];
+    let bufs = [IoSlice::new(&a), IoSlice::new(&[]), IoSlice::new(&b), IoSlice::new(&c)];
+    assert_eq!(f.write_vectored_at(&bufs, &mut fs, 100).unwrap(), 2210);
+    assert_eq!(f.size(), 2310);
+
+    let mut x = vec![0u8; 800];
+    let mut y = vec![0u8; 2000];
+    let read = {
+        let mut bufs = [IoSliceMut::new(&mut x), IoSliceMut::new(&mut y)];
+        f.read_vectored_at(&mut bufs, &mut fs, 0).unwrap()
+    };
+    assert_eq!(read, 2310);
+    assert!(x[..100].iter().all(|&v| v == 0));
+    assert!(x[100..].iter().all(|&v| v == 1));
+    assert!(y[..1500].iter().all(|&v| v == 2));
+    assert!(y[1500..1510].iter().all(|&v| v == 3));
+}
+
+#[test]
+fn case_sensitive_names() {
+    let mut fs = open_image("images/fat32.img");
+    let root = fs.root_dir();
+    let mut upper = root.create_file("Makefile", &mut fs).unwrap();
+    upper.write(b"upper", &mut fs, 0).unwrap();
+    upper.flush(&mut fs).unwrap();
+
+    // By default lookups ignore case
+    assert_eq!(root.create_file("makefile", &mut fs).unwrap().loc, upper.loc);
+    assert!(root.open_file("MAKEFILE", &mut fs).is_ok());
+
+    fs.case_sensitive = true;
+    let lower = root.create_file("makefile", &mut fs).unwrap();
+    assert!(lower.loc != upper.loc);
+    assert_eq!(root.open_file("Makefile", &mut fs).unwrap().size(), 5);
+    assert_eq!(root.open_file("makefile", &mut fs).unwrap().size(), 0);
+    assert!(root.open_file("MAKEFILE", &mut fs).is_err());
+
+    let names = list_names(&root, &mut fs);
+    assert!(names.iter().any(|n| n == "Makefile") && names.iter().any(|n| n == "makefile"));
+}
+
+#[test]
+fn size_written_back_on_flush() {
+    let mut fs = open_image("images/fat32.img");
+    let root = fs.root_dir();
+    let mut f = root.create_file("dirty.txt", &mut fs).unwrap();
+    f.write(b"first", &mut fs, 0).unwrap();
+    f.flush(&mut fs).unwrap();
+    assert!(!f.is_dirty());
+
+    f.write(b" second", &mut fs, 5).unwrap();
+    assert!(f.size_dirty);
+    assert_eq!(f.size(), 12);
+    assert_eq!(root.open_file("dirty.txt", &mut fs).unwrap().size(), 5);
+
+    f.flush(&mut fs).unwrap();
+    assert!(!f.is_dirty());
+    assert_eq!(root.open_file("dirty.txt", &mut fs).unwrap().size(), 12);
+}
+
+#[test]
+fn truncate_to_zero_frees_chain() {
+    let mut fs = open_image("images/fat32.img");
+    let root = fs.root_dir();
+    let free = fs.free_clusters().unwrap();
+    let mut f = root.create_file("empty.bin", &mut fs).unwrap();
+    f.write(&vec![1; 3000], &mut fs, 0).unwrap();
+    let first_cluster = f.first_cluster;
+    assert!(fs.free_clusters().unwrap() < free);
+
+    f.truncate(&mut fs, 0).unwrap();
+    assert_eq!(f.first_cluster.cluster_number, 0);
+    assert_eq!(get_entry(&mut fs, first_cluster).unwrap(), FatEntry::Unused);
+    assert_eq!(fs.free_clusters().unwrap(), free);
+    let f = root.open_file("empty.bin", &mut fs).unwrap();
+    assert_eq!((f.size(), f.first_cluster.cluster_number), (0, 0));
+}
+
+#[test]
+fn set_len_grows_with_zeroes() {
+    let mut fs = open_image("images/fat32.img");
+    let root = fs.root_dir();
+    let bpc = fs.bytes_per_cluster() as usize;
+    let mut f = root.create_file("grow.bin", &mut fs).unwrap();
+    f.write(&vec![0xaa; 100], &mut fs, 0).unwrap();
+
+    // The old bytes past the new end must not come back
+    f.set_len(&mut fs, 10).unwrap();
+    f.set_len(&mut fs, 3 * bpc as u64).unwrap();
+    assert!(!f.is_dirty());
+    let f = root.open_file("grow.bin", &mut fs).unwrap();
+    assert_eq!(f.size(), 3 * bpc as u64);
+    assert_eq!(fs.clusters(f.first_cluster).len(), 3);
+
+    let mut buf = vec![0xff; 3 * bpc];
+    assert_eq!(f.read(&mut buf, &mut fs, 0).unwrap(), 3 * bpc);
+    assert!(buf[..10].iter().all(|&b| b == 0xaa));
+    assert!(buf[10..].iter().all(|&b| b == 0));
+}
+
+#[test]
+fn name_normalization() {
+    let cases = [
+        ("notes.txt", "notes.txt", true),
+        ("notes.txt", "notes.txt.", true),
+        ("notes.txt", "notes.txt  ", true),
+        ("notes.txt", "NOTES.TXT. .", true),
+        ("notes.txt", "  notes.txt", true),
+        ("notes", "notes...", true),
+        ("notes.txt", "notes.tx", false),
+        ("notes.txt", "notes. txt", false),
+        (".", ".", true),
+        ("..", "..", true),
+        (".", "..", false),
+    ];
+    for &(entry, name, expected) in cases.iter() {
+        assert_eq!(names_match(entry, name, false), expected, "{:?} vs {:?}", entry, name);
+    }
+    assert_eq!(normalize_name("trail. . "), "trail");
+    assert_eq!(normalize_name("..."), "");
+
+    let mut fs = open_image("images/fat32.img");
+    let root = fs.root_dir();
+    root.create_file("trail. ", &mut fs).unwrap();
+    assert!(list_names(&root, &mut fs).contains(&"trail. ".to_string()));
+    assert!(root.open_file("trail", &mut fs).is_ok());
+    assert!(root.open_file("TRAIL..", &mut fs).is_ok());
+    // Creating the same name with trailing dots opens the existing file
+    let f = root.create_file("trail.", &mut fs).unwrap();
+    assert_eq!(list_names(&root, &mut fs).iter().filter(|n| normalize_name(n) == "trail").count(), 1);
+    assert_eq!(f.size(), 0);
+    assert!(root.create_file("...", &mut fs).is_err());
+}
+
+#[test]
+fn trailing_dots_and_spaces_are_kept() {
+    let mut fs = open_image("images/fat32.img");
+    let root = fs.root_dir();
+    let names = ["plain", "dot.", "space ", "both. ", " lead", "a.b."];
+    for (i, name) in names.iter().enumerate() {
+        let mut f = root.create_file(name, &mut fs).unwrap();
+        f.write(&vec![b'x'; i + 1], &mut fs, 0).unwrap();
+        f.flush(&mut fs).unwrap();
+    }
+
+    // Each name is listed and opened exactly as created
+    let listed = list_names(&root, &mut fs);
+    for (i, name) in names.iter().enumerate() {
+        assert_eq!(listed.iter().filter(|n| n == name).count(), 1, "{:?} in {:?}", name, listed);
+        assert_eq!(root.open_file(name, &mut fs).unwrap().size(), i as u64 + 1, "{:?}", name);
+    }
+    // Only "plain" fits 8.3, the others get lossy short names
+    let e = root.to_iter(&mut fs).find(|e| e.name() == "dot.").unwrap();
+    assert_eq!(e.short_name(), "DOT~1");
+    let e = root.to_iter(&mut fs).find(|e| e.name() == "a.b.").unwrap();
+    assert_eq!(e.short_name(), "AB~1");
+
+    for name in &["...", " ", ". .", ".", ".."] {
+        assert_eq!(root.create_file(name, &mut fs).unwrap_err().kind(), ErrorKind::InvalidInput, "{:?}", name);
+    }
+}
+
+#[test]
+fn listings_skip_attributes() {
+    let mut fs = open_image("images/fat32.img");
+    fs.create_contiguous("/SWAP.SYS", 100, true).unwrap();
+    fs.hide_dot_files = true;
+    let root = fs.root_dir();
+    root.create_file(".hidden", &mut fs).unwrap();
+
+    let all: Vec<String> = root.to_iter(&mut fs).map(|e| e.name()).collect();
+    let shown: Vec<String> = root.to_iter(&mut fs).skip_attributes(FileAttributes::HIDDEN | FileAttributes::SYSTEM)
+        .map(|e| e.name()).collect();
+    let no_system: Vec<String> = root.to_iter(&mut fs).skip_attributes(FileAttributes::SYSTEM).map(|e| e.name()).collect();
+    assert!(all.contains(&"SWAP.SYS".to_string()) && all.contains(&".hidden".to_string()));
+    assert_eq!(shown.len(), all.len() - 2);
+    assert!(!shown.contains(&"SWAP.SYS".to_string()) && !shown.contains(&".hidden".to_string()));
+    assert!(!no_system.contains(&"SWAP.SYS".to_string()) && no_system.contains(&".hidden".to_string()));
+}
+
+#[test]
+fn spaces_dots_and_plus_signs_in_names() {
+    let mut fs = open_image("images/fat32.img");
+    let root = fs.root_dir();
+    root.create_dir("My Documents", &mut fs).unwrap();
+    root.create_file("archive.tar.gz", &mut fs).unwrap();
+    root.create_file("C++ Notes+1.txt", &mut fs).unwrap();
+    root.create_file("a=b;c,d [1].txt", &mut fs).unwrap();
+
+    let listed = list_names(&root, &mut fs);
+    for name in &["My Documents", "archive.tar.gz", "C++ Notes+1.txt", "a=b;c,d [1].txt"] {
+        assert!(listed.contains(&name.to_string()), "{:?} in {:?}", name, listed);
+        assert!(root.get_entry(name, &mut fs).is_ok(), "{:?}", name);
+    }
+    // Characters 8.3 names can't hold are replaced in the short name only
+    let short = |name: &str, fs: &mut FileSystem<Cursor<Vec<u8>>>| root.get_entry(name, fs).unwrap().short_name();
+    assert_eq!(short("My Documents", &mut fs), "MYDOCU~1");
+    assert_eq!(short("archive.tar.gz", &mut fs), "ARCHIV~1.GZ");
+    assert_eq!(short("C++ Notes+1.txt", &mut fs), "C__NOT~1.TXT");
+    assert_eq!(short("a=b;c,d [1].txt", &mut fs), "A_B_C_~1.TXT");
+
+    for name in &["a*b", "a?b", "a:b", "a<b>", "a|b", "a\"b", "a\u{1}b"] {
+        assert_eq!(root.create_file(name, &mut fs).unwrap_err().kind(), ErrorKind::InvalidInput, "{:?}", name);
+    }
+}
+
+#[test]
+fn leading_e5_is_escaped() {
+    let mut fs = open_image("images/fat32.img");
+    let root = fs.root_dir();
+    let f = root.create_file("\u{3c3}igma.txt", &mut fs).unwrap();
+    assert_eq!(f.loc.0, f.loc.1);
+    let offset = fs.cluster_offset((f.loc.1).0) + (f.loc.1).1;
+    let mut raw = [0; 1];
+    fs.read_at(offset, &mut raw).unwrap();
+    assert_eq!(raw[0], ESCAPED_E5);
+
+    assert!(list_names(&root, &mut fs).contains(&"\u{3c3}igma.txt".to_string()));
+    assert!(root.open_file("\u{3c3}IGMA.TXT", &mut fs).is_ok());
+}
+
+#[test]
+fn create_dir_rolls_back_on_full_parent() {
+    let mut fs = open_image("images/fat16.img");
+    let root = fs.root_dir();
+    // The FAT16 root directory has a fixed number of slots
+    let mut i = 0;
+    while root.create_file(&format!("F{}.TXT", i), &mut fs).is_ok() {
+        i += 1;
+        assert!(i <= fs.bpb.root_entries_cnt as usize, "Root directory never filled up");
+    }
+
+    let free = fs.free_clusters().unwrap();
+    assert!(root.create_dir("newdir", &mut fs).is_err());
+    assert_eq!(fs.free_clusters().unwrap(), free);
+    assert!(check(&mut fs).unwrap().is_clean());
+}
+
+#[test]
+fn moved_dir_points_to_new_parent() {
+    let mut fs = open_image("images/fat32.img");
+    let root = fs.root_dir();
+    let a = root.create_dir("a", &mut fs).unwrap();
+    let c = root.create_dir("c", &mut fs).unwrap();
+    a.create_dir("b", &mut fs).unwrap().create_file("f.txt", &mut fs).unwrap();
+
+    let mut b = Dir::get_entry_abs("/a/b", &mut fs).unwrap();
+    Dir::rename(&mut b, "/c/b", &mut fs).unwrap();
+    let dot_dot = Dir::get_entry_abs("/c/b/..", &mut fs).unwrap().to_dir();
+    assert_eq!(dot_dot.first_cluster, c.first_cluster);
+    assert!(Dir::get_entry_abs("/c/b/f.txt", &mut fs).is_ok());
+
+    // Back to the root, where ".." holds cluster 0
+    Dir::rename(&mut b, "/b", &mut fs).unwrap();
+    let dot_dot = Dir::get_entry_abs("/b/..", &mut fs).unwrap();
+    assert_eq!(dot_dot.to_dir().first_cluster.cluster_number, 0);
+}
+
+#[test]
+fn dir_is_not_moved_below_itself() {
+    let mut fs = open_image("images/fat32.img");
+    let root = fs.root_dir();
+    let top = root.create_dir("Top Level", &mut fs).unwrap();
+    top.create_dir("b", &mut fs).unwrap().create_dir("c", &mut fs).unwrap();
+
+    // However the destination is spelled, through "..", the short alias or another case
+    let mut top_entry = Dir::get_entry_abs("/Top Level", &mut fs).unwrap();
+    for dst in &["/Top Level/x", "/Top Level/b/c/x", "/Top Level/b/../b/x", "/TOPLEV~1/b/x", "/top level/B/c/x"] {
+        let err = Dir::rename(&mut top_entry, dst, &mut fs).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput, "{}", dst);
+    }
+    assert!(Dir::get_entry_abs("/Top Level/b/c", &mut fs).is_ok());
+    assert!(check(&mut fs).unwrap().is_clean());
+
+    // Into a sibling and back out again is fine
+    root.create_dir("other", &mut fs).unwrap();
+    Dir::rename(&mut top_entry, "/other/Top Level", &mut fs).unwrap();
+    let mut c = Dir::get_entry_abs("/other/Top Level/b/c", &mut fs).unwrap();
+    Dir::rename(&mut c, "/c", &mut fs).unwrap();
+    assert!(Dir::get_entry_abs("/c", &mut fs).is_ok());
+}
+
+#[test]
+fn failed_rename_is_undone() {
+    let mut fs = open_image("images/fat16.img");
+    let root = fs.root_dir();
+    let docs = root.create_dir("DOCS", &mut fs).unwrap();
+    let mut f = docs.create_file("A long file name.txt", &mut fs).unwrap();
+    f.write(b"keep me", &mut fs, 0).unwrap();
+    f.flush(&mut fs).unwrap();
+    // Fill the fixed root directory, the moved entry has nowhere to go
+    let mut i = 0;
+    while root.create_file(&format!("F{}", i), &mut fs).is_ok() {
+        i += 1;
+    }
+    let listing = list_names(&root, &mut fs);
+
+    let mut e = Dir::get_entry_abs("/DOCS/A long file name.txt", &mut fs).unwrap();
+    assert!(Dir::rename(&mut e, "/Moved here.txt", &mut fs).is_err());
+    assert_eq!(list_names(&root, &mut fs), listing);
+    let f = Dir::get_entry_abs("/docs/a long file name.txt", &mut fs).unwrap().to_file();
+    let mut buf = [0; 7];
+    f.read(&mut buf, &mut fs, 0).unwrap();
+    assert_eq!(&buf, b"keep me");
+    assert!(check(&mut fs).unwrap().is_clean());
+}
+
+#[test]
+fn rename_failed_by_io_error_is_undone() {
+    let (disk, fail_at) = FailingDisk::open("images/fat32.img");
+    let mut fs = FileSystem::from_offset(0, disk, None).unwrap();
+    let root = fs.root_dir();
+    let f = root.create_file("Source file.txt", &mut fs).unwrap();
+    let dst = root.create_dir("DEST", &mut fs).unwrap();
+    for i in 0..8 {
+        root.create_dir(&format!("PAD{}", i), &mut fs).unwrap();
+    }
+    let dst = dst.create_dir("INNER", &mut fs).unwrap();
+    let src = fs.cluster_offset((f.loc.1).0) + (f.loc.1).1;
+    // The first free slot, after "." and ".."
+    let target = fs.cluster_offset(dst.first_cluster) + 64;
+    assert_ne!(src / 4096, target / 4096);
+
+    // The destination entry can't be written, which also turns the volume read-only
+    fail_at.set(Some(target));
+    let mut e = Dir::get_entry_abs("/Source file.txt", &mut fs).unwrap();
+    assert!(Dir::rename(&mut e, "/DEST/INNER/Moved.txt", &mut fs).is_err());
+    assert!(fs.is_degraded());
+
+    let mut fs = open_bytes(fs.disk.borrow().inner.get_ref().clone());
+    assert!(Dir::get_entry_abs("/Source file.txt", &mut fs).is_ok());
+    assert!(Dir::get_entry_abs("/DEST/INNER/Moved.txt", &mut fs).is_err());
+}
+
+#[test]
+fn root_is_protected() {
+    for path in &["/", "", "/.", "//", "/a/..", "a/../b/.."] {
+        assert!(Dir::is_root_path(path), "{}", path);
+    }
+    for path in &["/a", "a/b/..", "/../a"] {
+        assert!(!Dir::is_root_path(path), "{}", path);
+    }
+
+    for image in &["images/fat16.img", "images/fat32.img"] {
+        let mut fs = open_image(image);
+        let root = fs.root_dir();
+        root.create_dir("a", &mut fs).unwrap();
+        root.create_file("f.txt", &mut fs).unwrap();
+        for path in &["/", "a/.."] {
+            assert_eq!(root.remove(path, &mut fs, true).unwrap_err().kind(), ErrorKind::ResourceBusy, "{}", path);
+        }
+        let mut root_entry = Dir::get_entry_abs("/", &mut fs).unwrap();
+        assert!(root_entry.is_root());
+        assert_eq!(Dir::remove_entry(&root_entry, &mut fs, true).unwrap_err().kind(), ErrorKind::ResourceBusy);
+        assert_eq!(Dir::rename(&mut root_entry, "/a/root", &mut fs).unwrap_err().kind(), ErrorKind::PermissionDenied);
+
+        let mut f = Dir::get_entry_abs("/f.txt", &mut fs).unwrap();
+        assert!(!f.is_root());
+        assert_eq!(Dir::rename(&mut f, "/a/..", &mut fs).unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert!(Dir::get_entry_abs("/a", &mut fs).is_ok());
+        assert!(Dir::get_entry_abs("/f.txt", &mut fs).is_ok());
+        assert!(check(&mut fs).unwrap().is_clean(), "{}", image);
+    }
+}
+
+// Sets the attribute byte of the short entry named `short_name` in `dir`
+fn set_attrs(dir: &Dir, short_name: &[u8; 11], attrs: u8, fs: &mut FileSystem<Cursor<Vec<u8>>>) {
+    let (offset, entry) = dir.raw_entries(fs).map(|r| r.unwrap())
+        .find(|&(_, ref e)| &e.to_bytes()[..11] == short_name).unwrap();
+    let mut slot = entry.to_bytes();
+    slot[11] = attrs;
+    dir.write_raw_entry(offset, &DirEntryRaw::from_bytes(&slot).unwrap(), fs).unwrap();
+}
+
+#[test]
+fn read_only_dirs_keep_their_entries() {
+    for image in &["images/fat12.img", "images/fat32.img"] {
+        let mut fs = open_image(image);
+        let root = fs.root_dir();
+        let ro = root.create_dir("RO", &mut fs).unwrap();
+        ro.create_file("KEPT.TXT", &mut fs).unwrap();
+        root.create_file("OUT.TXT", &mut fs).unwrap();
+        set_attrs(&root, b"RO         ", 0x11, &mut fs);
+
+        // Windows ignores the bit on directories, so by default it changes nothing
+        let ro = root.get_entry("RO", &mut fs).unwrap().to_dir();
+        ro.create_file("NEW.TXT", &mut fs).unwrap();
+        ro.remove("NEW.TXT", &mut fs, true).unwrap();
+
+        fs.read_only_dirs = true;
+        let denied = ErrorKind::PermissionDenied;
+        assert_eq!(ro.create_file("NEW.TXT", &mut fs).unwrap_err().kind(), denied, "{}", image);
+        assert_eq!(ro.create_dir("Sub dir", &mut fs).unwrap_err().kind(), denied);
+        assert_eq!(ro.remove("KEPT.TXT", &mut fs, true).unwrap_err().kind(), denied);
+        let mut kept = Dir::get_entry_abs("/RO/KEPT.TXT", &mut fs).unwrap();
+        assert_eq!(Dir::rename(&mut kept, "/KEPT.TXT", &mut fs).unwrap_err().kind(), denied);
+        let mut out = Dir::get_entry_abs("/OUT.TXT", &mut fs).unwrap();
+        assert_eq!(Dir::rename(&mut out, "/RO/OUT.TXT", &mut fs).unwrap_err().kind(), denied);
+        assert_eq!(list_names(&ro, &mut fs), vec![".", "..", "KEPT.TXT"]);
+
+        // Moving the directory itself changes its parent, not the directory
+        let mut ro_entry = root.get_entry("RO", &mut fs).unwrap();
+        Dir::rename(&mut ro_entry, "/RO2", &mut fs).unwrap();
+        fs.read_only_dirs = false;
+        root.remove("RO2/KEPT.TXT", &mut fs, true).unwrap();
+        assert!(check(&mut fs).unwrap().is_clean(), "{}", image);
+    }
+}
+
+#[test]
+fn volume_label_entry_is_protected() {
+    let mut fs = open_image("images/fat16.img");
+    let root = fs.root_dir();
+    root.create_file("MYDISK", &mut fs).unwrap();
+    set_attrs(&root, b"MYDISK     ", 0x08, &mut fs);
+
+    let mut label = root.get_entry("MYDISK", &mut fs).unwrap();
+    assert!(label.is_vol_id());
+    let mut f = label.to_file();
+    let denied = ErrorKind::PermissionDenied;
+    assert_eq!(f.write(b"data", &mut fs, 0).unwrap_err().kind(), denied);
+    assert_eq!(f.set_len(&mut fs, 100).unwrap_err().kind(), denied);
+    assert_eq!(f.truncate(&mut fs, 0).unwrap_err().kind(), denied);
+    assert_eq!(Dir::remove_entry(&label, &mut fs, true).unwrap_err().kind(), denied);
+    assert_eq!(Dir::rename(&mut label, "/OTHER", &mut fs).unwrap_err().kind(), denied);
+
+    let mut g = root.create_file("G.TXT", &mut fs).unwrap();
+    g.flush(&mut fs).unwrap();
+    let mut g = root.get_entry("G.TXT", &mut fs).unwrap();
+    assert_eq!(Dir::rename(&mut g, "/MYDISK", &mut fs).unwrap_err().kind(), denied);
+    assert!(root.get_entry("MYDISK", &mut fs).unwrap().is_vol_id());
+    assert!(root.get_entry("G.TXT", &mut fs).is_ok());
+}
+
+#[test]
+fn dot_files_are_hidden() {
+    let mut fs = open_image("images/fat32.img");
+    let root = fs.root_dir();
+    root.create_file(".plain", &mut fs).unwrap();
+
+    fs.hide_dot_files = true;
+    root.create_file(".profile", &mut fs).unwrap();
+    root.create_dir(".cache", &mut fs).unwrap();
+    root.create_file("visible.txt", &mut fs).unwrap();
+
+    let hidden = |name: &str, fs: &mut FileSystem<Cursor<Vec<u8>>>| root.get_entry(name, fs).unwrap().is_hidden();
+    assert!(hidden(".profile", &mut fs) && hidden(".cache", &mut fs));
+    assert!(!hidden(".plain", &mut fs) && !hidden("visible.txt", &mut fs));
+    assert!(root.get_entry(".cache", &mut fs).unwrap().attributes().contains(FileAttributes::DIRECTORY));
+}
+
+#[test]
+fn sequential_short_names() {
+    let options = CreateOptions { short_names: ShortNames::Sequential };
+    let build = || {
+        let mut fs = open_image("images/fat32.img");
+        let root = fs.root_dir();
+        let sub = root.create_dir_with("Sequential Names", &options, &mut fs).unwrap();
+        for i in 0..12 {
+            sub.create_file_with(&format!("long file name {}.txt", i), &options, &mut fs).unwrap();
+        }
+        (fs, sub)
+    };
+
+    let (mut fs, sub) = build();
+    let short = |name: &str, fs: &mut FileSystem<Cursor<Vec<u8>>>| sub.get_entry(name, fs).unwrap().short_name();
+    assert_eq!(fs.root_dir().get_entry("Sequential Names", &mut fs).unwrap().short_name(), "SEQUEN~1");
+    assert_eq!(short("long file name 0.txt", &mut fs), "LONGFI~1.TXT");
+    assert_eq!(short("long file name 8.txt", &mut fs), "LONGFI~9.TXT");
+    assert_eq!(short("long file name 11.txt", &mut fs), "LONGF~12.TXT");
+
+    // Freed tails are reused
+    sub.remove("long file name 1.txt", &mut fs, true).unwrap();
+    sub.create_file_with("long file name again.txt", &options, &mut fs).unwrap();
+    assert_eq!(short("long file name again.txt", &mut fs), "LONGFI~2.TXT");
+
+    let (first, _) = build();
+    let (second, _) = build();
+    assert!(first.disk.borrow().get_ref() == second.disk.borrow().get_ref());
+}
+
+#[test]
+fn undelete_foreign_deletion() {
+    let mut fs = open_image("images/fat32.img");
+    let root = fs.root_dir();
+    let data: Vec<u8> = (0..1500).map(|i| i as u8).collect();
+    let mut f = root.create_file("Deleted long name.txt", &mut fs).unwrap();
+    f.write(&data, &mut fs, 0).unwrap();
+    f.flush(&mut fs).unwrap();
+    let f = root.open_file("Deleted long name.txt", &mut fs).unwrap();
+
+    // Delete the way other implementations do: mark the slots and free the chain, keeping the data
+    let (start, end) = f.loc;
+    assert_eq!(start.0, end.0);
+    for slot in (start.1..end.1 + DIR_ENTRY_LEN).step_by(DIR_ENTRY_LEN as usize) {
+        let offset = fs.cluster_offset(start.0) + slot;
+        let mut disk = fs.disk.borrow_mut();
+        disk.seek(SeekFrom::Start(offset)).unwrap();
+        disk.write_all(&[0xe5]).unwrap();
+    }
+    let chain: Vec<Cluster> = fs.clusters(f.first_cluster);
+    assert_eq!(chain.len(), 3);
+    for c in &chain {
+        set_entry(&mut fs, *c, FatEntry::Unused).unwrap();
+    }
+    assert!(root.open_file("Deleted long name.txt", &mut fs).is_err());
+
+    let deleted = root.deleted_entries(&mut fs).unwrap();
+    let entry = deleted.iter().find(|e| e.first_cluster() == f.first_cluster).expect("Deleted entry not found");
+    assert_eq!(entry.name(), "Deleted long name.txt");
+    assert_eq!(entry.size(), 1500);
+    assert!(!entry.is_dir());
+
+    let restored = root.undelete(entry, "restored.txt", &mut fs).unwrap().to_file();
+    assert_eq!(fs.clusters(restored.first_cluster), chain);
+    let mut buf = vec![0; 2000];
+    let count = restored.read(&mut buf, &mut fs, 0).unwrap();
+    assert_eq!(&buf[..count], &data[..]);
+    assert!(root.open_file("restored.txt", &mut fs).is_ok());
+
+    // The chain is in use again
+    assert!(root.undelete(entry, "again.txt", &mut fs).is_err());
+}
+
+#[test]
+fn rename_after_ancestor_moved() {
+    let mut fs = open_image("images/fat32.img");
+    let root = fs.root_dir();
+    let a = root.create_dir("a", &mut fs).unwrap();
+    a.create_file("f.txt", &mut fs).unwrap();
+    let mut f = Dir::get_entry_abs("/a/f.txt", &mut fs).unwrap();
+    assert_eq!(f.parent_dir().cluster_number, a.first_cluster.cluster_number);
+
+    // The handle still carries the old path "/a/f.txt"
+    let mut a = Dir::get_entry_abs("/a", &mut fs).unwrap();
+    Dir::rename(&mut a, "/b", &mut fs).unwrap();
+    Dir::rename(&mut f, "/b/g.txt", &mut fs).unwrap();
+    let names = list_names(&a.to_dir(), &mut fs);
+    assert_eq!(names, vec![".", "..", "g.txt"]);
+    assert_eq!(f.parent_dir().cluster_number, a.to_dir().first_cluster.cluster_number);
+    assert!(check(&mut fs).unwrap().is_clean());
+}
+
+#[test]
+fn invalid_characters_are_translated() {
+    let percent = NameTranslation::Percent;
+    let cases = [
+        ("plain.txt", "plain.txt"),
+        ("a:b", "a%3Ab"),
+        ("what?*", "what%3F%2A"),
+        ("100%.txt", "100%.txt"),
+        ("%3A", "%253A"),
+        ("%:", "%%3A"),
+        ("emoji\u{1f600}", "emoji%F0%9F%98%80"),
+    ];
+    for &(name, stored) in cases.iter() {
+        assert_eq!(percent.to_stored(name), stored);
+        assert_eq!(percent.to_shown(stored), name);
+    }
+    assert_eq!(NameTranslation::Replace('_').to_stored("a:b\\c"), "a_b_c");
+    assert_eq!(NameTranslation::Replace('_').to_shown("a_b_c"), "a_b_c");
+
+    let mut fs = open_image("images/fat32.img");
+    let root = fs.root_dir();
+    assert_eq!(root.create_file("a:b", &mut fs).unwrap_err().kind(), ErrorKind::InvalidInput);
+
+    fs.name_translation = NameTranslation::Percent;
+    let dir = root.create_dir("c:", &mut fs).unwrap();
+    dir.create_file("x|y", &mut fs).unwrap();
+    assert_eq!(list_names(&root, &mut fs).iter().filter(|n| *n == "c%3A").count(), 1);
+    assert_eq!(list_names(&dir, &mut fs), vec![".", "..", "x%7Cy"]);
+    assert!(root.open_file("c:/x|y", &mut fs).is_ok());
+    let mut e = Dir::get_entry_abs("/c:/x|y", &mut fs).unwrap();
+    Dir::rename(&mut e, "/c:/x>y", &mut fs).unwrap();
+    assert_eq!(e.name(), "x%3Ey");
+    root.remove("c:/x>y", &mut fs, true).unwrap();
+
+    fs.name_translation = NameTranslation::Replace('_');
+    root.create_file("q?", &mut fs).unwrap();
+    assert!(root.open_file("q_", &mut fs).is_ok());
+    assert!(root.open_file("q*", &mut fs).is_ok());
+}
+
+#[test]
+fn long_name_removed_across_clusters() {
+    let mut fs = open_image("images/fat32.img");
+    let root = fs.root_dir();
+    let dir = root.create_dir("spill", &mut fs).unwrap();
+    // Each name takes 4 slots, the 16 slot clusters make one of them straddle two
+    let mut straddling = None;
+    for i in 0..8 {
+        let name = format!("a long file name number {}", i);
+        let f = dir.create_file(&name, &mut fs).unwrap();
+        if (f.loc.0).0 != (f.loc.1).0 {
+            straddling = Some((name, f));
+            break;
+        }
+    }
+    let (name, f) = straddling.expect("No name crossed into the next cluster");
+    let slots = vec![
+        fs.cluster_offset((f.loc.0).0) + (f.loc.0).1,
+        fs.cluster_offset((f.loc.1).0) + (f.loc.1).1
+    ];
+
+    dir.remove(&name, &mut fs, true).unwrap();
+    for offset in slots {
+        match get_dir_entry_raw(&mut fs, offset).unwrap() {
+            DirEntryRaw::Free => {},
+            e => panic!("Expected a freed slot at {:x}, found {:?}", offset, e)
+        }
+    }
+    assert!(!list_names(&dir, &mut fs).contains(&name));
+    assert!(check(&mut fs).unwrap().is_clean());
+}
+
+#[test]
+fn listing_reads_each_cluster_once() {
+    let (disk, reads, _) = CountingDisk::open("images/fat32.img");
+    let mut fs = FileSystem::from_offset(0, disk, None).unwrap();
+    let root = fs.root_dir();
+    let dir = root.create_dir("many", &mut fs).unwrap();
+    for i in 0..100 {
+        dir.create_file(&format!("file number {}", i), &mut fs).unwrap();
+    }
+
+    reads.set(0);
+    let names: Vec<String> = dir.to_iter(&mut fs).map(|e| e.name()).collect();
+    assert_eq!(names.len(), 102);
+    assert_eq!(names[2], "file number 0");
+    // 100 names in 3 slots each and the dot entries take 302 slots, 19 clusters of 16.
+    // Each cluster takes a read, the FAT a few more to follow the chain
+    assert!(reads.get() < 50, "{} reads", reads.get());
+}
+
+#[test]
+fn raw_entries_dump_and_restore() {
+    for &(path, name) in &[("images/fat16.img", "ORIG.TXT"), ("images/fat32.img", "Original Name.txt")] {
+        let mut fs = open_image(path);
+        let root = fs.root_dir();
+        let mut f = root.create_file(name, &mut fs).unwrap();
+        f.write(b"kept", &mut fs, 0).unwrap();
+        f.flush(&mut fs).unwrap();
+        let before = list_names(&root, &mut fs);
+
+        let dump: Vec<(u64, DirEntryRaw)> = root.raw_entries(&mut fs).map(|r| r.unwrap()).collect();
+        match dump.last() {
+            Some(&(_, DirEntryRaw::FreeRest)) => {},
+            e => panic!("Expected the end marker last, found {:?}", e)
+        }
+        for &(offset, ref entry) in &dump {
+            let mut slot = [0; 32];
+            fs.read_at(offset, &mut slot).unwrap();
+            match *entry {
+                DirEntryRaw::Free => assert_eq!(slot[0], 0xe5),
+                _ => assert_eq!(&slot[..], &entry.to_bytes()[..], "{} at {:x}", path, offset)
+            }
+            assert_eq!(DirEntryRaw::from_bytes(&slot).unwrap().to_bytes()[0], slot[0]);
+        }
+
+        let mut entry = root.get_entry(name, &mut fs).unwrap();
+        Dir::rename(&mut entry, "/RENAMED.TXT", &mut fs).unwrap();
+        assert!(list_names(&root, &mut fs).contains(&"RENAMED.TXT".to_string()));
+        for &(offset, ref entry) in &dump {
+            root.write_raw_entry(offset, entry, &mut fs).unwrap();
+        }
+        assert_eq!(list_names(&root, &mut fs), before, "{}", path);
+        assert!(check(&mut fs).unwrap().is_clean(), "{}", path);
+
+        let (offset, ref entry) = dump[0];
+        for &bad in &[offset + 16, 1, fs.partition_len - 16] {
+            assert_eq!(root.write_raw_entry(bad, entry, &mut fs).unwrap_err().kind(), ErrorKind::InvalidInput);
+        }
+    }
+    assert_eq!(DirEntryRaw::from_bytes(&[0; 16]).unwrap_err().kind(), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn longest_names_are_listed() {
+    let mut fs = open_image("images/fat32.img");
+    let root = fs.root_dir();
+    let dir = root.create_dir("longest", &mut fs).unwrap();
+    let names: Vec<String> = (0..3).map(|i| format!("{}{}", i, "n".repeat(254 - i))).collect();
+    for name in &names {
+        dir.create_file(name, &mut fs).unwrap();
+    }
+    assert_eq!(list_names(&dir, &mut fs)[2..], names[..]);
+    for name in &names {
+        assert_eq!(dir.get_entry(name, &mut fs).unwrap().name(), *name);
+    }
+    assert_eq!(fs.lfn_corruption(), LfnCorruption::default());
+}
+
+#[test]
+fn long_entry_order_out_of_range() {
+    let mut fs = open_image("images/fat32.img");
+    let root = fs.root_dir();
+    let dir = root.create_dir("order", &mut fs).unwrap();
+    dir.create_file("Mixed Case", &mut fs).unwrap();
+    let (offset, entry) = dir.raw_entries(&mut fs).map(|r| r.unwrap())
+        .find(|&(_, ref e)| match *e { DirEntryRaw::Long(_) => true, _ => false })
+        .unwrap();
+
+    // Position 0x21 must not be taken for 1
+    let mut slot = entry.to_bytes();
+    slot[0] |= 0x20;
+    dir.write_raw_entry(offset, &DirEntryRaw::from_bytes(&slot).unwrap(), &mut fs).unwrap();
+    let names = list_names(&dir, &mut fs);
+    assert!(!names.contains(&"Mixed Case".to_string()), "{:?}", names);
+    assert_eq!(fs.lfn_corruption().orphaned, 1);
+}
+
+// Runs `op` on the root of a copy of the FAT32 fixture which stops taking writes after
+// `writes` of them, returning the image left behind and the number of writes made
+fn crash_after<F>(writes: u64, op: F) -> (Vec<u8>, u64)
+    where F: Fn(&Dir, &mut FileSystem<CrashDisk>) -> io::Result<()> {
+    let (disk, writes_left) = CrashDisk::open("images/fat32.img", writes);
+    let mut fs = FileSystem::from_offset(0, disk, None).expect("Parsing Error");
+    fs.retry_policy.attempts = 1;
+    let root = fs.root_dir();
+    let _ = op(&root, &mut fs);
+    let made = writes - writes_left.get();
+    writes_left.set(0);
+
+    let left_behind = fs.disk.borrow_mut().image();
+    (left_behind, made)
+}
+
+#[test]
+fn crash_during_create_dir() {
+    let name = "Crash Test Directory";
+    let op = |root: &Dir, fs: &mut FileSystem<CrashDisk>| root.create_dir(name, fs).map(|_| ());
+    let (_, total) = crash_after(u64::max_value(), &op);
+    for writes in 0..total + 1 {
+        let (image, _) = crash_after(writes, &op);
+        let mut fs = open_bytes(image);
+        assert!(check(&mut fs).unwrap().is_clean(), "after {} writes", writes);
+        let root = fs.root_dir();
+        match root.open_dir(name, &mut fs) {
+            Ok(dir) => assert_eq!(list_names(&dir, &mut fs), vec![".", ".."], "after {} writes", writes),
+            Err(e) => {
+                assert_eq!(e.kind(), ErrorKind::NotFound);
+                assert!(writes < total);
+            }
+        }
+    }
+}
+
+#[test]
+fn crash_during_create_and_write() {
+    let name = "Crash Test File.txt";
+    let data: Vec<u8> = (0..1500).map(|i| i as u8).collect();
+    let op = |root: &Dir, fs: &mut FileSystem<CrashDisk>| {
+        let mut f = root.create_file(name, fs)?;
+        f.write(&data, fs, 0)?;
+        f.flush(fs)
+    };
+    let (_, total) = crash_after(u64::max_value(), &op);
+    for writes in 0..total + 1 {
+        let (image, _) = crash_after(writes, &op);
+        let mut fs = open_bytes(image);
+        assert!(check(&mut fs).unwrap().is_clean(), "after {} writes", writes);
+        let root = fs.root_dir();
+        match root.open_file(name, &mut fs) {
+            Ok(f) => {
+                let mut buf = vec![0; f.size() as usize];
+                f.read(&mut buf, &mut fs, 0).unwrap();
+                assert!(buf.is_empty() || buf == data, "after {} writes", writes);
+            },
+            Err(e) => {
+                assert_eq!(e.kind(), ErrorKind::NotFound);
+                assert!(writes < total);
+            }
+        }
+    }
+}
+
+// LDIR_Chksum as in the FAT specification
+fn lfn_checksum(short_name: &[u8]) -> u8 {
+    short_name.iter().fold(0u8, |sum, &c| (sum >> 1).wrapping_add(sum << 7).wrapping_add(c))
+}
+
+// A long entry as Windows writes it, `part` padded with 0x0000 and then 0xFFFF
+fn lfn_slot(ord: u8, part: &[u16], checksum: u8) -> Vec<u8> {
+    let mut units = part.to_vec();
+    if units.len() < 13 {
+        units.push(0);
+    }
+    units.resize(13, 0xffff);
+    let mut slot = vec![0; 32];
+    slot[0] = ord;
+    slot[11] = 0x0f;
+    slot[13] = checksum;
+    for (i, &u) in units.iter().enumerate() {
+        let at = match i {
+            0..=4 => 1 + 2 * i,
+            5..=10 => 14 + 2 * (i - 5),
+            _ => 28 + 2 * (i - 11)
+        };
+        slot[at] = u as u8;
+        slot[at + 1] = (u >> 8) as u8;
+    }
+    slot
+}
+
+#[test]
+fn lfn_entries_match_windows_layout() {
+    let names = ["A long file name.txt", "Exactly 26 characters long", "R\u{e9}sum\u{e9} \u{2013} final.txt"];
+    for path in &["images/fat16.img", "images/fat32.img"] {
+        let mut fs = open_image(path);
+        let dir = fs.root_dir().create_dir("golden", &mut fs).unwrap();
+        for name in names.iter() {
+            dir.create_file(name, &mut fs).unwrap();
+        }
+
+        let slots: Vec<Vec<u8>> = dir.raw_entries(&mut fs).map(|r| r.unwrap().1.to_bytes().to_vec())
+            .filter(|s| s[0] != 0).skip(2).collect();
+        let mut expected = Vec::new();
+        for name in names.iter() {
+            let units: Vec<u16> = name.encode_utf16().collect();
+            let parts: Vec<&[u16]> = units.chunks(13).collect();
+            let short_slot = slots[expected.len() + parts.len()].clone();
+            assert_eq!(short_slot[11] & 0x0f, 0, "{} on {}", name, path);
+            let checksum = lfn_checksum(&short_slot[..11]);
+            // The last part comes first, flagged 0x40, down to the first right before the short entry
+            for (i, part) in parts.iter().enumerate().rev() {
+                let ord = if i + 1 == parts.len() { 0x40 | (i as u8 + 1) } else { i as u8 + 1 };
+                expected.push(lfn_slot(ord, part, checksum));
+            }
+            expected.push(short_slot);
+        }
+        assert_eq!(slots.len(), expected.len());
+        for (i, (slot, golden)) in slots.iter().zip(&expected).enumerate() {
+            assert_eq!(slot, golden, "slot {} on {}", i, path);
+        }
+        assert_eq!(&expected[2][..11], b"ALONGF~1TXT");
+        assert_eq!(&expected[5][..11], b"EXACTL~1   ");
+
+        // Read back through the long entries
+        let listed = list_names(&dir, &mut fs);
+        for name in names.iter() {
+            assert!(listed.contains(&name.to_string()), "{} on {}", name, path);
+            assert!(Dir::get_entry_abs(&format!("/golden/{}", name), &mut fs).is_ok());
+        }
+    }
+}
+
+#[test]
+fn writes_set_the_archive_bit() {
+    let mut fs = open_image("images/fat16.img");
+    let root = fs.root_dir();
+    root.create_file("backup.me", &mut fs).unwrap();
+    let archived = |fs: &mut FileSystem<Cursor<Vec<u8>>>| {
+        Dir::get_entry_abs("/backup.me", fs).unwrap().attributes().contains(FileAttributes::ARCHIVE)
+    };
+    assert!(archived(&mut fs));
+
+    Dir::get_entry_abs("/backup.me", &mut fs).unwrap().clear_archive(&mut fs).unwrap();
+    assert!(!archived(&mut fs));
+    // Reading leaves it clear
+    let mut f = root.open_file("backup.me", &mut fs).unwrap();
+    f.read(&mut [0; 16], &mut fs, 0).unwrap();
+    f.flush(&mut fs).unwrap();
+    assert!(!archived(&mut fs));
+
+    f.write(b"changed", &mut fs, 0).unwrap();
+    f.flush(&mut fs).unwrap();
+    assert!(archived(&mut fs));
+
+    Dir::get_entry_abs("/backup.me", &mut fs).unwrap().clear_archive(&mut fs).unwrap();
+    let mut f = root.open_file("backup.me", &mut fs).unwrap();
+    f.truncate(&mut fs, 1).unwrap();
+    assert!(archived(&mut fs));
+
+    let mut e = Dir::get_entry_abs("/backup.me", &mut fs).unwrap();
+    e.set_archive(&mut fs, false).unwrap();
+    e.set_archive(&mut fs, true).unwrap();
+    assert!(archived(&mut fs));
+    assert_eq!(DirEntry::Dir(root).set_archive(&mut fs, false).unwrap_err().kind(), ErrorKind::InvalidInput);
+}
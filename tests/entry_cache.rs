@@ -0,0 +1,96 @@
+extern crate redox_fatfs;
+
+mod common;
+
+use std::io::{Cursor, ErrorKind};
+
+use redox_fatfs::prelude::*;
+
+use common::open_image;
+
+fn fill(fs: &mut FileSystem<Cursor<Vec<u8>>>, dir: &Dir) -> Vec<String> {
+    let names: Vec<String> = (0..30).map(|i| format!("Cached entry number {}.txt", i)).collect();
+    for (i, name) in names.iter().enumerate() {
+        let mut f = dir.create_file(name, fs).unwrap();
+        f.write(&vec![b'x'; i], fs, 0).unwrap();
+        f.flush(fs).unwrap();
+    }
+    names
+}
+
+#[test]
+fn lookups_after_a_listing_are_served_from_the_cache() {
+    for path in &["images/fat12.img", "images/fat32.img"] {
+        let mut fs = open_image(path);
+        let root = fs.root_dir();
+        let sub = root.create_dir("Sub Directory", &mut fs).unwrap();
+        let names = fill(&mut fs, &sub);
+        assert_eq!(fs.entry_cache_stats(), None);
+        fs.set_entry_cache(64);
+
+        let listed: Vec<String> = sub.to_iter(&mut fs).map(|e| e.name()).collect();
+        let (hits, misses) = fs.entry_cache_stats().unwrap();
+        for (i, name) in names.iter().enumerate() {
+            let e = Dir::get_entry_abs(&format!("/sub directory/{}", name), &mut fs).unwrap();
+            assert_eq!(e.size(), i as u64);
+            assert_eq!(e.name(), *name);
+        }
+        // Every entry before the one looked up comes from the cache
+        let (new_hits, new_misses) = fs.entry_cache_stats().unwrap();
+        assert!(new_hits - hits >= (names.len() * (names.len() - 1) / 2) as u64, "{}", path);
+        assert!(new_misses - misses < 2 * names.len() as u64, "{}", path);
+        let relisted: Vec<String> = sub.to_iter(&mut fs).map(|e| e.name()).collect();
+        assert_eq!(relisted, listed);
+    }
+}
+
+#[test]
+fn changes_drop_cached_entries() {
+    let mut fs = open_image("images/fat16.img");
+    fs.set_entry_cache(8);
+    let root = fs.root_dir();
+    let names = fill(&mut fs, &root);
+    let count = root.to_iter(&mut fs).count();
+
+    let mut f = root.open_file(&names[3], &mut fs).unwrap();
+    f.write(&[b'y'; 100], &mut fs, 0).unwrap();
+    f.flush(&mut fs).unwrap();
+    assert_eq!(root.get_entry(&names[3], &mut fs).unwrap().size(), 100);
+
+    root.remove(&names[4], &mut fs, true).unwrap();
+    assert_eq!(root.get_entry(&names[4], &mut fs).unwrap_err().kind(), ErrorKind::NotFound);
+    let mut e = root.get_entry(&names[5], &mut fs).unwrap();
+    Dir::rename(&mut e, "/Renamed.txt", &mut fs).unwrap();
+    assert!(root.get_entry(&names[5], &mut fs).is_err());
+    assert_eq!(root.get_entry("renamed.txt", &mut fs).unwrap().size(), 5);
+
+    // A new entry taking over the freed slots
+    root.create_file("New", &mut fs).unwrap();
+    let listed: Vec<String> = root.to_iter(&mut fs).map(|e| e.name()).collect();
+    assert!(listed.contains(&"New".to_string()));
+    assert!(!listed.contains(&names[4]));
+    assert_eq!(listed.len(), count);
+    assert!(check(&mut fs).unwrap().is_clean());
+}
+
+#[test]
+fn raw_writes_drop_cached_entries() {
+    let mut fs = open_image("images/fat32.img");
+    fs.set_entry_cache(8);
+    let root = fs.root_dir();
+    let names = fill(&mut fs, &root);
+    let offset = |fs: &mut FileSystem<Cursor<Vec<u8>>>, name: &str| {
+        let loc = root.get_entry(name, fs).unwrap().to_file().loc.0;
+        fs.cluster_offset(loc.0) + loc.1
+    };
+    let first = offset(&mut fs, &names[1]);
+    let second = offset(&mut fs, &names[2]);
+    assert!(fs.cached_dir_entry(first).is_some() && fs.cached_dir_entry(second).is_some());
+
+    let mut slot = [0; 32];
+    fs.read_at(first, &mut slot).unwrap();
+    fs.write_to(first, &slot).unwrap();
+    assert!(fs.cached_dir_entry(first).is_none());
+    fs.zero_range(second, 32).unwrap();
+    assert!(fs.cached_dir_entry(second).is_none());
+}
@@ -0,0 +1,564 @@
+extern crate redox_fatfs;
+extern crate byteorder;
+
+mod common;
+
+use std::cell::Cell;
+use std::cmp::{max, min};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Cursor, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::rc::Rc;
+use std::time::Duration;
+
+use byteorder::{ByteOrder, LittleEndian};
+use redox_fatfs::prelude::*;
+use redox_fatfs::raw::{get_free_count, set_entry, BiosParameterBlock, FATType, FatEntry};
+
+use common::{open_bytes, open_image, FlakyDisk, InterruptingDisk, LossyDisk};
+
+fn reopen(fs: FileSystem<Cursor<Vec<u8>>>) -> FileSystem<Cursor<Vec<u8>>> {
+    let data = image_bytes(fs);
+    open_bytes(data)
+}
+
+fn image_bytes(fs: FileSystem<Cursor<Vec<u8>>>) -> Vec<u8> {
+    let data = fs.disk.borrow().get_ref().clone();
+    data
+}
+
+fn fat_copy(data: &[u8], fs_bpb: &BiosParameterBlock, n: usize) -> Vec<u8> {
+    let fat_size = match fs_bpb.fat_type {
+        FATType::FAT32(s) => s.fat_size as usize,
+        _ => fs_bpb.fat_size_16 as usize
+    };
+    let bps = fs_bpb.bytes_per_sector as usize;
+    let start = (fs_bpb.rsvd_sec_cnt as usize + n * fat_size) * bps;
+    data[start..start + fat_size * bps].to_vec()
+}
+
+#[test]
+fn set_active_fat_updates_boot_sectors() {
+    let mut fs = open_image("images/fat32.img");
+    assert_eq!(fs.ext_flags(), Some(0));
+    assert!(fs.mirroring_enabled());
+
+    fs.set_active_fat(1).expect("Failed to switch active FAT");
+    assert_eq!(fs.ext_flags(), Some(0x81));
+    assert!(!fs.mirroring_enabled());
+    assert_eq!(fs.active_fat(), 1);
+
+    let fs = reopen(fs);
+    assert_eq!(fs.ext_flags(), Some(0x81));
+    assert_eq!(fs.active_fat(), 1);
+
+    let bk_boot_sec = match fs.bpb.fat_type {
+        FATType::FAT32(s) => s.bk_boot_sec as usize,
+        _ => unreachable!()
+    };
+    let bps = fs.bpb.bytes_per_sector as usize;
+    let data = image_bytes(fs);
+    assert_eq!(LittleEndian::read_u16(&data[bk_boot_sec * bps + 40..]), 0x81);
+}
+
+#[test]
+fn set_active_fat_refuses_inconsistent_copy() {
+    let mut fs = open_image("images/fat32.img");
+    assert!(fs.set_active_fat(2).is_err());
+
+    // Point an entry of the second FAT outside the volume
+    let max_cluster = fs.max_cluster_number().cluster_number as u32;
+    let fat_size = fs.fat_size();
+    let offset = (fs.bpb.rsvd_sec_cnt as u64 + fat_size) * fs.bytes_per_sec() + 5 * 4;
+    let mut buf = [0; 4];
+    LittleEndian::write_u32(&mut buf, max_cluster + 10);
+    fs.write_to(offset, &buf).unwrap();
+
+    assert!(fs.set_active_fat(1).is_err());
+    assert_eq!(fs.ext_flags(), Some(0));
+}
+
+#[test]
+fn mirroring_keeps_fat_copies_in_sync() {
+    let mut fs = open_image("images/fat32.img");
+    fs.set_mirroring(false).unwrap();
+    assert_eq!(fs.ext_flags(), Some(0x80));
+
+    let root = fs.root_dir();
+    let mut f = root.create_file("unmirrored.txt", &mut fs).unwrap();
+    f.write(b"Only in the active FAT", &mut fs, 0).unwrap();
+    let bpb = fs.bpb;
+    let data = image_bytes(fs);
+    assert!(fat_copy(&data, &bpb, 0) != fat_copy(&data, &bpb, 1), "FAT copies should differ");
+
+    let mut fs = open_bytes(data);
+    fs.set_mirroring(true).unwrap();
+    assert_eq!(fs.ext_flags(), Some(0));
+    let root = fs.root_dir();
+    let mut f = root.create_file("mirrored.txt", &mut fs).unwrap();
+    f.write(b"In every FAT", &mut fs, 0).unwrap();
+
+    let bpb = fs.bpb;
+    let data = image_bytes(fs);
+    assert!(fat_copy(&data, &bpb, 0) == fat_copy(&data, &bpb, 1), "FAT copies should match");
+}
+
+#[test]
+fn lazy_mirroring_syncs_fat_copies_later() {
+    for path in &["images/fat16.img", "images/fat32.img"] {
+        let mut fs = open_image(path);
+        fs.lazy_fat_mirroring = true;
+        let root = fs.root_dir();
+        let mut f = root.create_file("lazy.txt", &mut fs).unwrap();
+        f.write(b"Only in the first FAT for now", &mut fs, 0).unwrap();
+        let bpb = fs.bpb;
+        {
+            let data = fs.disk.borrow();
+            assert!(fat_copy(data.get_ref(), &bpb, 0) != fat_copy(data.get_ref(), &bpb, 1), "{}", path);
+        }
+
+        fs.sync_fat_mirrors().unwrap();
+        {
+            let data = fs.disk.borrow();
+            assert!(fat_copy(data.get_ref(), &bpb, 0) == fat_copy(data.get_ref(), &bpb, 1), "{}", path);
+        }
+
+        // Unmounting syncs as well
+        let mut f = root.create_file("lazy2.txt", &mut fs).unwrap();
+        f.write(&vec![3; 8192], &mut fs, 0).unwrap();
+        fs.unmount().unwrap();
+        let data = image_bytes(fs);
+        assert!(fat_copy(&data, &bpb, 0) == fat_copy(&data, &bpb, 1), "{}", path);
+    }
+}
+
+#[test]
+fn write_protect_refuses_writes_until_lifted() {
+    let mut fs = open_image("images/fat32.img");
+    fs.lazy_fat_mirroring = true;
+    let root = fs.root_dir();
+    let mut f = root.create_file("before.txt", &mut fs).unwrap();
+    f.write(b"written before", &mut fs, 0).unwrap();
+    f.flush(&mut fs).unwrap();
+
+    // The image is consistent while protected
+    fs.set_write_protect(true).unwrap();
+    assert!(fs.is_write_protected());
+    let bpb = fs.bpb;
+    let before = fs.disk.borrow().get_ref().clone();
+    assert!(fat_copy(&before, &bpb, 0) == fat_copy(&before, &bpb, 1));
+
+    let err = root.create_file("during.txt", &mut fs).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::ReadOnlyFilesystem);
+    assert_eq!(f.write(b"more", &mut fs, 0).unwrap_err().kind(), ErrorKind::ReadOnlyFilesystem);
+    fs.unmount().unwrap();
+    assert!(*fs.disk.borrow().get_ref() == before);
+    // Reading still works
+    let mut buf = [0; 14];
+    assert_eq!(f.read(&mut buf, &mut fs, 0).unwrap(), 14);
+
+    fs.set_write_protect(false).unwrap();
+    assert!(root.create_file("after.txt", &mut fs).is_ok());
+    let mut fs = reopen(fs);
+    assert!(check(&mut fs).unwrap().is_clean());
+
+    // Lifting it doesn't make a read-only instance writable
+    let data = fs::read("images/fat32.img").unwrap();
+    let mut ro = FileSystem::open_read_only(0, Cursor::new(data), None).unwrap();
+    ro.set_write_protect(true).unwrap();
+    ro.set_write_protect(false).unwrap();
+    assert!(ro.check_writable().is_err());
+}
+
+#[test]
+fn lazy_mirroring_syncs_once_many_sectors_are_stale() {
+    let mut fs = open_image("images/fat32.img");
+    fs.lazy_fat_mirroring = true;
+    let bpb = fs.bpb;
+    // One entry in each of 100 FAT sectors
+    let entries_per_sector = fs.bytes_per_sec() / 4;
+    for i in 1..101 {
+        set_entry(&mut fs, Cluster::new(i * entries_per_sector), FatEntry::Bad).unwrap();
+    }
+    let data = fs.disk.borrow().get_ref().clone();
+    let (first, second) = (fat_copy(&data, &bpb, 0), fat_copy(&data, &bpb, 1));
+    let bps = fs.bytes_per_sec() as usize;
+    let synced = (1..101).filter(|i| first[i * bps..(i + 1) * bps] == second[i * bps..(i + 1) * bps]).count();
+    assert!(synced >= 64, "{} sectors synced", synced);
+    assert!(synced < 100);
+}
+
+fn corrupt_fsinfo(path: &str) -> Vec<u8> {
+    let mut data = fs::read(path).expect("Failed to read image");
+    let fs = open_bytes(data.clone());
+    let fs_info_sec = match fs.bpb.fat_type {
+        FATType::FAT32(s) => s.fs_info as usize,
+        _ => unreachable!()
+    };
+    let offset = fs_info_sec * fs.bpb.bytes_per_sector as usize;
+    LittleEndian::write_u32(&mut data[offset..], 0xdeadbeef);
+    data
+}
+
+#[test]
+fn invalid_fsinfo_is_rebuilt() {
+    let data = corrupt_fsinfo("images/fat32.img");
+    let mut fs = FileSystem::from_offset(0, Cursor::new(data), None).expect("Invalid FSInfo should not fail mount");
+    assert!(fs.fs_info.borrow().needs_rebuild());
+
+    let root = fs.root_dir();
+    root.create_file("after.txt", &mut fs).unwrap().write(b"still works", &mut fs, 0).unwrap();
+    fs.rebuild_fsinfo().unwrap();
+    assert!(!fs.fs_info.borrow().needs_rebuild());
+    let free = fs.free_clusters().unwrap();
+
+    let mut fs = reopen(fs);
+    assert!(!fs.fs_info.borrow().needs_rebuild());
+    let max_cluster = fs.max_cluster_number();
+    assert_eq!(fs.fs_info.borrow().get_free_count(max_cluster), Some(free));
+    assert_eq!(get_free_count(&mut fs, max_cluster).unwrap(), free);
+}
+
+#[test]
+fn invalid_fsinfo_is_repaired_on_unmount() {
+    let data = corrupt_fsinfo("images/fat32.img");
+    let mut fs = open_bytes(data);
+    fs.unmount().unwrap();
+    let fs = reopen(fs);
+    assert!(!fs.fs_info.borrow().needs_rebuild());
+}
+
+#[test]
+fn io_stays_within_the_partition() {
+    let mut data = fs::read("images/fat32.img").expect("Failed to read image");
+    let image_len = data.len();
+    // Whatever follows the volume on the device, e.g. the next partition
+    data.extend_from_slice(&[0xaa; 64 * 1024]);
+    let mut fs = open_bytes(data);
+    let end = fs.partition_len;
+    assert!(end <= image_len as u64);
+
+    assert_eq!(fs.write_to(end - 4, &[0; 8]).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    assert_eq!(fs.read_at(end, &mut [0; 1]).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    assert!(fs.read_at(end - 8, &mut [0; 8]).is_ok());
+
+    // A chain pointing past the last cluster must not reach the bytes after the volume
+    let root = fs.root_dir();
+    let bpc = fs.bytes_per_cluster() as usize;
+    let mut f = root.create_file("corrupt.bin", &mut fs).unwrap();
+    f.write(&vec![1; bpc], &mut fs, 0).unwrap();
+    let bogus = Cluster::new(fs.max_cluster_number().cluster_number + 1000);
+    set_entry(&mut fs, f.first_cluster, FatEntry::Next(bogus)).unwrap();
+    let err = f.write(&vec![2; bpc], &mut fs, bpc as u64).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+    let data = image_bytes(fs);
+    assert!(data[image_len..].iter().all(|&b| b == 0xaa));
+}
+
+#[test]
+fn read_only_instances_leave_a_locked_image_alone() {
+    let mut fs = open_image("images/fat32.img");
+    assert!(!fs.in_use().unwrap());
+    fs.lock(false).unwrap();
+    let mut locked = fs.disk.borrow().get_ref().clone();
+    let expected = locked.clone();
+
+    {
+        // A second writer is refused and can't release the lock when dropped
+        let mut other = FileSystem::from_offset(0, Cursor::new(&mut locked), None).unwrap();
+        assert_eq!(other.lock(false).unwrap_err().kind(), ErrorKind::WouldBlock);
+        assert!(other.is_read_only());
+    }
+    {
+        let mut ro = FileSystem::open_read_only(0, Cursor::new(&mut locked), None).unwrap();
+        assert!(ro.in_use().unwrap());
+        let root = ro.root_dir();
+        assert_eq!(root.create_file("new.txt", &mut ro).unwrap_err().kind(), ErrorKind::ReadOnlyFilesystem);
+        assert!(root.open_file("new.txt", &mut ro).is_err());
+    }
+    assert!(locked == expected);
+
+    // Unmounting releases the lock
+    fs.unmount().unwrap();
+    assert!(!fs.in_use().unwrap());
+}
+
+#[test]
+fn dropping_a_write_protected_volume_releases_the_lock() {
+    let mut data = fs::read("images/fat32.img").unwrap();
+    {
+        let mut fs = FileSystem::from_offset(0, Cursor::new(&mut data), None).unwrap();
+        fs.lazy_fat_mirroring = true;
+        fs.lock(false).unwrap();
+        fs.set_write_protect(true).unwrap();
+    }
+    let mut fs = FileSystem::from_offset(0, Cursor::new(&mut data), None).unwrap();
+    assert!(!fs.in_use().unwrap());
+    fs.lock(false).unwrap();
+    fs.unmount().unwrap();
+    drop(fs);
+    let bpb = open_bytes(data.clone()).bpb;
+    assert!(fat_copy(&data, &bpb, 0) == fat_copy(&data, &bpb, 1));
+}
+
+#[test]
+fn forced_lock_takes_over_a_dirty_volume() {
+    let mut fs = open_image("images/fat32.img");
+    fs.lock(false).unwrap();
+    let mut dirty = fs.disk.borrow().get_ref().clone();
+    // Left locked as after a crash
+    std::mem::forget(fs);
+
+    let mut other = FileSystem::from_offset(0, Cursor::new(&mut dirty), None).unwrap();
+    other.lock(true).unwrap();
+    assert!(!other.is_read_only());
+    assert!(other.in_use().unwrap());
+    let root = other.root_dir();
+    root.create_file("new.txt", &mut other).unwrap();
+
+    other.unmount().unwrap();
+    assert!(!other.in_use().unwrap());
+}
+
+fn open_flaky() -> (FileSystem<FlakyDisk>, Rc<Cell<u32>>, Rc<Cell<u64>>) {
+    let (disk, failures, fail_from) = FlakyDisk::open("images/fat32.img");
+    let mut fs = FileSystem::from_offset(0, disk, None).expect("Parsing Error");
+    fs.retry_policy.delay = Duration::from_millis(0);
+    (fs, failures, fail_from)
+}
+
+#[test]
+fn transient_write_errors_are_retried() {
+    let (mut fs, failures, _) = open_flaky();
+    let root = fs.root_dir();
+    let mut f = root.create_file("retried.txt", &mut fs).unwrap();
+
+    failures.set(2);
+    f.write(b"still written", &mut fs, 0).unwrap();
+    f.flush(&mut fs).unwrap();
+    assert_eq!(failures.get(), 0);
+    assert!(!fs.is_degraded());
+
+    let mut buf = [0; 32];
+    let count = root.open_file("retried.txt", &mut fs).unwrap().read(&mut buf, &mut fs, 0).unwrap();
+    assert_eq!(&buf[..count], b"still written");
+}
+
+#[test]
+fn persistent_write_errors_degrade_to_read_only() {
+    let (mut fs, failures, fail_from) = open_flaky();
+    let root = fs.root_dir();
+    let mut f = root.create_file("lost.txt", &mut fs).unwrap();
+    assert!(fs.hard_error_bit().unwrap());
+
+    // Only the data region fails, the FAT still takes the hard error mark
+    fail_from.set(fs.cluster_offset(Cluster::new(2)));
+    failures.set(u32::max_value());
+    assert!(f.write(b"never written", &mut fs, 0).is_err());
+    assert!(fs.is_degraded());
+    assert!(fs.is_read_only());
+    assert!(!fs.hard_error_bit().unwrap());
+
+    failures.set(0);
+    assert_eq!(root.create_file("after.txt", &mut fs).unwrap_err().kind(), ErrorKind::ReadOnlyFilesystem);
+}
+
+fn open_lossy() -> (FileSystem<LossyDisk>, Rc<Cell<u32>>) {
+    let (disk, dropped) = LossyDisk::open("images/fat32.img");
+    let mut fs = FileSystem::from_offset(0, disk, None).expect("Parsing Error");
+    fs.retry_policy.delay = Duration::from_millis(0);
+    fs.verify_writes = true;
+    (fs, dropped)
+}
+
+#[test]
+fn verified_writes_are_rewritten() {
+    let (mut fs, dropped) = open_lossy();
+    let root = fs.root_dir();
+    root.create_file("first.txt", &mut fs).unwrap();
+    let before = fs.write_verification();
+    assert!(before.verified > 0);
+    assert_eq!(before.mismatches, 0);
+
+    dropped.set(1);
+    root.create_file("second.txt", &mut fs).unwrap();
+    assert_eq!(fs.write_verification().mismatches, 1);
+    assert!(!fs.is_degraded());
+    assert!(root.open_file("second.txt", &mut fs).is_ok());
+    assert!(check(&mut fs).unwrap().is_clean());
+}
+
+#[test]
+fn blocks_which_never_read_back_degrade_to_read_only() {
+    let (mut fs, dropped) = open_lossy();
+    let root = fs.root_dir();
+    root.create_file("first.txt", &mut fs).unwrap();
+
+    dropped.set(u32::max_value());
+    assert!(root.create_file("lost.txt", &mut fs).is_err());
+    assert!(fs.is_degraded());
+    // The hard error mark doesn't read back either
+    assert_eq!(fs.write_verification().mismatches, 2 * fs.retry_policy.attempts as u64);
+}
+
+#[test]
+fn interrupted_io_is_restarted() {
+    let (disk, calls) = InterruptingDisk::open("images/fat32.img");
+    calls.set(1);
+    let mut fs = FileSystem::from_offset(0, disk, None).expect("Parsing Error");
+    fs.retry_policy.attempts = 1;
+    let data: Vec<u8> = (0..10000).map(|i| (i % 253) as u8).collect();
+    let root = fs.root_dir();
+    let mut f = root.create_file("Interrupted.bin", &mut fs).unwrap();
+    f.write(&data, &mut fs, 0).unwrap();
+    f.flush(&mut fs).unwrap();
+    assert!(!fs.is_degraded());
+
+    let f = root.open_file("Interrupted.bin", &mut fs).unwrap();
+    let mut buf = vec![0; data.len()];
+    assert_eq!(f.read(&mut buf, &mut fs, 0).unwrap(), data.len());
+    assert!(buf == data);
+
+    // A device which never gets through gives up rather than spinning
+    fs.disk.borrow_mut().always = true;
+    assert_eq!(fs.read_block(0, &mut [0; 512]).unwrap_err().kind(), ErrorKind::Interrupted);
+}
+
+#[test]
+fn short_disk_fails_reads_within_the_partition() {
+    let mut data = fs::read("images/fat16.img").unwrap();
+    let len = data.len() as u64;
+    data.truncate(len as usize - 1000);
+    let mut fs = open_bytes(data);
+    assert_eq!(fs.partition_len, len);
+
+    let mut buf = [0; 4096];
+    assert_eq!(fs.read_block(len - 4096, &mut buf).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    assert_eq!(fs.read_at(len - 10, &mut buf[..10]).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    assert!(fs.read_block(0, &mut buf).is_ok());
+}
+
+// Holds only the chunks written to, the rest reads as zeroes
+struct SparseDisk {
+    chunks: BTreeMap<u64, Vec<u8>>,
+    len: u64,
+    pos: u64
+}
+
+const SPARSE_CHUNK: u64 = 4096;
+
+impl Read for SparseDisk {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let in_chunk = self.pos % SPARSE_CHUNK;
+        let count = min(min(buf.len() as u64, SPARSE_CHUNK - in_chunk), self.len.saturating_sub(self.pos)) as usize;
+        match self.chunks.get(&(self.pos / SPARSE_CHUNK)) {
+            Some(chunk) => buf[..count].copy_from_slice(&chunk[in_chunk as usize..in_chunk as usize + count]),
+            None => buf[..count].iter_mut().for_each(|b| *b = 0)
+        }
+        self.pos += count as u64;
+        Ok(count)
+    }
+}
+
+impl Write for SparseDisk {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let in_chunk = self.pos % SPARSE_CHUNK;
+        let count = min(buf.len() as u64, SPARSE_CHUNK - in_chunk) as usize;
+        let chunk = self.chunks.entry(self.pos / SPARSE_CHUNK).or_insert_with(|| vec![0; SPARSE_CHUNK as usize]);
+        chunk[in_chunk as usize..in_chunk as usize + count].copy_from_slice(&buf[..count]);
+        self.pos += count as u64;
+        self.len = max(self.len, self.pos);
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for SparseDisk {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = match pos {
+            SeekFrom::Start(p) => p,
+            SeekFrom::End(d) => (self.len as i64 + d) as u64,
+            SeekFrom::Current(d) => (self.pos as i64 + d) as u64
+        };
+        Ok(self.pos)
+    }
+}
+
+// A FAT16 volume with 128 sectors, 64KiB, per cluster
+fn open_64k_clusters() -> FileSystem<SparseDisk> {
+    let (clusters, fat_sz) = (4200u32, 17u32);
+    let tot_sec = 1 + 2 * fat_sz + 32 + clusters * 128;
+    let mut boot = vec![0; 512];
+    boot[..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+    boot[3..11].copy_from_slice(b"MSWIN4.1");
+    LittleEndian::write_u16(&mut boot[11..], 512);
+    boot[13] = 128;
+    LittleEndian::write_u16(&mut boot[14..], 1);
+    boot[16] = 2;
+    LittleEndian::write_u16(&mut boot[17..], 512);
+    boot[21] = 0xF8;
+    LittleEndian::write_u16(&mut boot[22..], fat_sz as u16);
+    LittleEndian::write_u32(&mut boot[32..], tot_sec);
+    boot[38] = 0x29;
+    boot[43..54].copy_from_slice(b"BIGCLUSTERS");
+    boot[54..62].copy_from_slice(b"FAT16   ");
+    boot[510] = 0x55;
+    boot[511] = 0xAA;
+
+    let mut disk = SparseDisk { chunks: BTreeMap::new(), len: tot_sec as u64 * 512, pos: 0 };
+    disk.write_all(&boot).unwrap();
+    for fat in 0..2 {
+        disk.seek(SeekFrom::Start((1 + fat * fat_sz) as u64 * 512)).unwrap();
+        disk.write_all(&[0xF8, 0xFF, 0xFF, 0xFF]).unwrap();
+    }
+    FileSystem::from_offset(0, disk, None).expect("Parsing Error")
+}
+
+#[test]
+fn clusters_of_64k() {
+    let mut fs = open_64k_clusters();
+    assert_eq!(fs.bytes_per_cluster(), 65536);
+    match fs.bpb.fat_type {
+        FATType::FAT16(_) => (),
+        _ => panic!("Not read as FAT16")
+    }
+
+    let root = fs.root_dir();
+    let data: Vec<u8> = (0..200000).map(|i| (i % 251) as u8).collect();
+    let mut f = root.create_file("big.bin", &mut fs).unwrap();
+    assert_eq!(f.write(&data, &mut fs, 0).unwrap(), data.len());
+    // Across the end of the first cluster
+    assert_eq!(f.write(b"boundary", &mut fs, 65532).unwrap(), 8);
+    f.flush(&mut fs).unwrap();
+    assert_eq!(fs.clusters(f.first_cluster).len(), 4);
+
+    let f = Dir::get_entry_abs("/big.bin", &mut fs).unwrap().to_file();
+    let mut buf = vec![0; 200000];
+    assert_eq!(f.read(&mut buf, &mut fs, 0).unwrap(), 200000);
+    assert!(buf[..65532] == data[..65532] && buf[65540..] == data[65540..]);
+    assert_eq!(&buf[65532..65540], b"boundary");
+
+    let mut f = f;
+    f.set_len(&mut fs, 70000).unwrap();
+    assert_eq!(fs.clusters(f.first_cluster).len(), 2);
+    f.set_len(&mut fs, 300000).unwrap();
+    assert_eq!(fs.clusters(f.first_cluster).len(), 5);
+    let mut tail = vec![0xff; 300000 - 70000];
+    assert_eq!(f.read(&mut tail, &mut fs, 70000).unwrap(), tail.len());
+    assert!(tail.iter().all(|&b| b == 0));
+    f.flush(&mut fs).unwrap();
+
+    // A directory cluster holds 2048 entries
+    let dir = root.create_dir("Many Entries", &mut fs).unwrap();
+    for i in 0..300 {
+        dir.create_file(&format!("A long file name {}.txt", i), &mut fs).unwrap();
+    }
+    assert_eq!(fs.clusters(dir.first_cluster).len(), 1);
+    assert_eq!(dir.to_iter(&mut fs).count(), 302);
+    dir.remove("A long file name 7.txt", &mut fs, true).unwrap();
+    assert!(check(&mut fs).unwrap().is_clean());
+}
@@ -0,0 +1,91 @@
+extern crate redox_fatfs;
+
+mod common;
+
+use std::io::Cursor;
+
+use redox_fatfs::prelude::*;
+use redox_fatfs::raw::{FatEntry, set_entry};
+
+use common::open_image;
+
+fn write_file(fs: &mut FileSystem<Cursor<Vec<u8>>>, name: &str, len: usize) -> File {
+    let root = fs.root_dir();
+    let mut f = root.create_file(name, fs).unwrap();
+    f.write(&vec![0x5a; len], fs, 0).unwrap();
+    f.flush(fs).unwrap();
+    f
+}
+
+#[test]
+fn scan_builds_free_map() {
+    for path in &["images/fat12.img", "images/fat16.img", "images/fat32.img"] {
+        let mut fs = open_image(path);
+        write_file(&mut fs, "BEFORE.BIN", 20000);
+        assert_eq!(fs.scan_free_map(100).unwrap(), None);
+
+        fs.start_free_map();
+        let mut last = FreeMapProgress::default();
+        loop {
+            let progress = fs.scan_free_map(1000).unwrap().unwrap();
+            assert!(progress.scanned > last.scanned && progress.scanned <= progress.total);
+            last = progress;
+            if progress.scanned == progress.total {
+                break
+            }
+        }
+        let usage = fs.usage_bitmap().unwrap();
+        assert_eq!(last.total, usage.len(), "{}", path);
+        assert_eq!(last.free, usage.free, "{}", path);
+
+        // Allocations and frees keep it up to date
+        write_file(&mut fs, "AFTER.BIN", 30000);
+        fs.root_dir().remove("BEFORE.BIN", &mut fs, true).unwrap();
+        let free = fs.free_map_progress().unwrap().free;
+        assert_eq!(free, fs.usage_bitmap().unwrap().free, "{}", path);
+        assert!(check(&mut fs).unwrap().is_clean(), "{}", path);
+    }
+}
+
+#[test]
+fn allocation_goes_past_a_partial_map() {
+    let mut fs = open_image("images/fat16.img");
+    fs.start_free_map();
+    fs.scan_free_map(40).unwrap();
+    let f = write_file(&mut fs, "BIG.BIN", 100000);
+    assert_eq!(fs.clusters(f.first_cluster).len(), 49);
+    assert_eq!(fs.free_map_progress().unwrap().free, 0);
+    assert!(check(&mut fs).unwrap().is_clean());
+}
+
+#[test]
+fn map_is_checked_against_the_fat() {
+    let mut fs = open_image("images/fat32.img");
+    fs.start_free_map();
+    fs.scan_free_map(u64::max_value()).unwrap();
+    let taken = fs.free_map_cluster(2).unwrap().unwrap();
+    set_entry(&mut fs, taken, FatEntry::EndOfChain).unwrap();
+    // As if the cluster had been taken behind the map's back
+    fs.free_map_mut().unwrap().set(taken.cluster_number, true);
+
+    let f = write_file(&mut fs, "NEW.BIN", 100);
+    assert!(f.first_cluster != taken);
+    assert!(fs.free_map_cluster(2).unwrap() != Some(taken));
+}
+
+#[test]
+fn allocation_finds_the_last_cluster_past_the_map() {
+    let mut fs = open_image("images/fat12.img");
+    fs.start_free_map();
+    // The map covers every entry but the last cluster's
+    let total = fs.scan_free_map(0).unwrap().unwrap().total;
+    fs.scan_free_map(total - 1).unwrap();
+    let max = fs.max_cluster_number().cluster_number;
+    assert_eq!(max, 503);
+    for cluster in 2..max {
+        set_entry(&mut fs, Cluster::new(cluster), FatEntry::EndOfChain).unwrap();
+    }
+
+    let f = write_file(&mut fs, "LAST.BIN", 100);
+    assert_eq!(f.first_cluster.cluster_number, max);
+}
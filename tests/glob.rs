@@ -0,0 +1,43 @@
+extern crate redox_fatfs;
+
+mod common;
+
+use std::io::Cursor;
+
+use redox_fatfs::prelude::*;
+
+use common::open_image;
+
+#[test]
+fn wildcards() {
+    assert!(wildcard_match("*.JPG", "img_0001.jpg", false));
+    assert!(!wildcard_match("*.JPG", "img_0001.jpg", true));
+    assert!(wildcard_match("IMG_????.*", "IMG_0001.JPG", false));
+    assert!(!wildcard_match("IMG_????.*", "IMG_001.JPG", false));
+    assert!(wildcard_match("*a*b*", "xxaxxbxx", false));
+    assert!(!wildcard_match("*a*b", "xxaxxbxx", false));
+    assert!(wildcard_match("*.*", "README", false));
+    assert!(!wildcard_match("*.txt", "README", false));
+}
+
+#[test]
+fn glob_long_and_short_names() {
+    let mut fs = open_image("images/fat32.img");
+    let root = fs.root_dir();
+    let dcim = root.create_dir("DCIM", &mut fs).unwrap();
+    for name in &["IMG_0001.JPG", "img_0002.jpg", "Holiday picture.jpeg", "notes.txt"] {
+        dcim.create_file(name, &mut fs).unwrap();
+    }
+    dcim.create_dir("Thumbs.jpg", &mut fs).unwrap();
+    dcim.open_dir("Thumbs.jpg", &mut fs).unwrap().create_file("small.jpg", &mut fs).unwrap();
+
+    let names = |pattern: &str, fs: &mut FileSystem<Cursor<Vec<u8>>>| -> Vec<String> {
+        root.glob(fs, pattern).unwrap().iter().map(|e| e.name()).collect()
+    };
+    assert_eq!(names("DCIM/*.JPG", &mut fs), vec!["IMG_0001.JPG", "img_0002.jpg", "Thumbs.jpg"]);
+    // "Holiday picture.jpeg" is HOLIDA~1.JPE in 8.3
+    assert_eq!(names("dcim/*.jpe", &mut fs), vec!["Holiday picture.jpeg"]);
+    assert_eq!(names("*/*/*.jpg", &mut fs), vec!["small.jpg"]);
+    assert!(names("DCIM/*.png", &mut fs).is_empty());
+    assert!(root.glob(&mut fs, "/").is_err());
+}
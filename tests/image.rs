@@ -0,0 +1,118 @@
+extern crate redox_fatfs;
+
+mod common;
+
+use std::io::{self, Cursor, ErrorKind, Write};
+
+use redox_fatfs::prelude::*;
+
+use common::open_image;
+
+fn write_file(fs: &mut FileSystem<Cursor<Vec<u8>>>, name: &str, data: &[u8]) {
+    let root = fs.root_dir();
+    let mut f = root.create_file(name, fs).unwrap();
+    f.write(data, fs, 0).unwrap();
+    f.flush(fs).unwrap();
+}
+
+fn read_file(fs: &mut FileSystem<Cursor<Vec<u8>>>, path: &str) -> Vec<u8> {
+    let f = Dir::get_entry_abs(path, fs).unwrap().to_file();
+    let mut buf = vec![0; f.size() as usize];
+    f.read(&mut buf, fs, 0).unwrap();
+    buf
+}
+
+fn restore(image: &[u8], len: usize) -> FileSystem<Cursor<Vec<u8>>> {
+    // Free clusters are left as they were on the target
+    let mut disk = Cursor::new(vec![0xAA; len]);
+    restore_image(&mut Cursor::new(image), &mut disk, 0).unwrap();
+    FileSystem::from_offset(0, disk, None).expect("Parsing Error")
+}
+
+// Fails every write after the first `left` bytes
+struct FailingWriter {
+    data: Vec<u8>,
+    left: usize
+}
+
+impl Write for FailingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() > self.left {
+            return Err(io::Error::new(ErrorKind::Other, "Disk full"))
+        }
+        self.left -= buf.len();
+        self.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn image_and_restore() {
+    for path in &["images/fat12.img", "images/fat16.img", "images/fat32.img"] {
+        let mut fs = open_image(path);
+        let data: Vec<u8> = (0..300000).map(|i| (i % 251) as u8).collect();
+        write_file(&mut fs, "BIG.BIN", &data);
+        write_file(&mut fs, "small.txt", b"small");
+        let len = fs.partition_len as usize;
+
+        let mut image = Vec::new();
+        let mut reports = 0;
+        let done = fs.image_used_blocks(&mut image, |p| {
+            assert!(p.copied <= p.total);
+            reports += 1;
+        }).unwrap();
+        assert_eq!(done.copied, done.total);
+        assert_eq!(done.image_len, image.len() as u64);
+        assert!(reports > 1);
+        assert!(image.len() < len / 2, "{}", path);
+
+        let mut fs = restore(&image, len);
+        assert!(read_file(&mut fs, "/big.bin") == data, "{}", path);
+        assert_eq!(read_file(&mut fs, "/small.txt"), b"small");
+        assert!(check(&mut fs).unwrap().is_clean(), "{}", path);
+    }
+}
+
+#[test]
+fn interrupted_image_is_resumed() {
+    let mut fs = open_image("images/fat32.img");
+    let data: Vec<u8> = (0..500000).map(|i| (i % 253) as u8).collect();
+    write_file(&mut fs, "BIG.BIN", &data);
+    let len = fs.partition_len as usize;
+
+    let mut w = FailingWriter { data: Vec::new(), left: 400000 };
+    let mut last = ImageProgress::default();
+    assert!(fs.image_used_blocks(&mut w, |p| last = *p).is_err());
+    assert!(last.copied > 0 && last.copied < last.total);
+
+    let mut image = w.data;
+    image.truncate(last.image_len as usize);
+    let done = fs.image_used_blocks_from(&mut image, last.offset, last.image_len, |_| ()).unwrap();
+    assert_eq!(done.copied, done.total);
+    assert_eq!(done.image_len, image.len() as u64);
+
+    let mut whole = Vec::new();
+    fs.image_used_blocks(&mut whole, |_| ()).unwrap();
+    assert!(image == whole);
+    let mut fs = restore(&image, len);
+    assert!(read_file(&mut fs, "/big.bin") == data);
+}
+
+#[test]
+fn restore_rejects_other_data() {
+    let mut disk = Cursor::new(vec![0; 4096]);
+    let err = restore_image(&mut Cursor::new(vec![0; 64]), &mut disk, 0).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+    let mut fs = open_image("images/fat12.img");
+    let mut image = Vec::new();
+    fs.image_used_blocks(&mut image, |_| ()).unwrap();
+    let cut = image.len() - 100;
+    let mut disk = Cursor::new(vec![0; fs.partition_len as usize]);
+    let err = restore_image(&mut Cursor::new(&image[..cut]), &mut disk, 0).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+}
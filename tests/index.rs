@@ -0,0 +1,33 @@
+extern crate redox_fatfs;
+
+mod common;
+
+use redox_fatfs::prelude::*;
+
+use common::open_image;
+
+#[test]
+fn snapshot_index() {
+    let mut fs = open_image("images/fat32.img");
+    let root = fs.root_dir();
+    root.create_dir("docs", &mut fs).unwrap();
+    let mut f = root.create_file("docs/readme.txt", &mut fs).unwrap();
+    f.write(b"hello index", &mut fs, 0).unwrap();
+    f.flush(&mut fs).unwrap();
+
+    let index = fs.snapshot_index();
+    let walked = root.walk(&mut fs).count();
+    assert_eq!(index.len(), walked);
+
+    let docs = index.entries().iter().find(|e| index.path(e) == "/docs").unwrap();
+    assert!(docs.is_dir);
+    assert_eq!(docs.size, 0);
+    let readme = index.entries().iter().find(|e| index.path(e) == "/docs/readme.txt").unwrap();
+    assert!(!readme.is_dir);
+    assert_eq!(readme.size, 11);
+    assert_eq!(readme.first_cluster, f.first_cluster);
+    let meta = fs.metadata("/docs/readme.txt").unwrap();
+    assert_eq!(readme.first_cluster, meta.first_cluster());
+    let e = Dir::get_entry_abs("/docs/readme.txt", &mut fs).unwrap();
+    assert_eq!(readme.modified, e.modified_raw());
+}
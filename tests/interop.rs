@@ -0,0 +1,84 @@
+//! Runs operations against images produced by mkfs.vfat and checks the
+//! result with `fsck.vfat -n`. Needs dosfstools, so it only builds with
+//! `cargo test --features interop`.
+#![cfg(feature = "interop")]
+
+extern crate redox_fatfs;
+
+mod common;
+
+use std::env;
+use std::fs;
+use std::io::Cursor;
+use std::path::PathBuf;
+use std::process::Command;
+
+use redox_fatfs::prelude::*;
+
+use common::open_image;
+
+// Writes the modified image out and runs a read-only fsck over it
+fn fsck(fs: FileSystem<Cursor<Vec<u8>>>, name: &str) {
+    let data = fs.disk.borrow().get_ref().clone();
+    let path: PathBuf = env::temp_dir().join(format!("redox-fatfs-interop-{}.img", name));
+    fs::write(&path, data).expect("Failed to write image");
+
+    let output = Command::new("fsck.vfat").arg("-n").arg(&path).output()
+        .expect("Failed to run fsck.vfat, is dosfstools installed?");
+    let _ = fs::remove_file(&path);
+    assert!(output.status.success(), "fsck.vfat reported errors for {}:\n{}{}", name,
+            String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
+}
+
+#[test]
+fn lfn_entries() {
+    let mut fs = open_image("images/fat32.img");
+    let root = fs.root_dir();
+    for name in &["A long file name.txt", "ReadMe.md", "README.TXT", "a.b.c"] {
+        let mut f = root.create_file(name, &mut fs).expect("Error creating file");
+        f.write(name.as_bytes(), &mut fs, 0).unwrap();
+        f.flush(&mut fs).unwrap();
+    }
+    root.remove("a.b.c", &mut fs, true).unwrap();
+    fsck(fs, "lfn");
+}
+
+#[test]
+fn dotdot_entries() {
+    let mut fs = open_image("images/fat32.img");
+    let root = fs.root_dir();
+    let parent = root.create_dir("parent directory", &mut fs).expect("Error creating dir");
+    parent.create_dir("child", &mut fs).expect("Error creating dir");
+    let mut f = root.create_file("parent directory/child/file.txt", &mut fs).unwrap();
+    f.write(b"nested", &mut fs, 0).unwrap();
+    f.flush(&mut fs).unwrap();
+
+    let mut entry = Dir::get_entry_abs("parent directory/child/file.txt", &mut fs).unwrap();
+    Dir::rename(&mut entry, "moved.txt", &mut fs).unwrap();
+    fsck(fs, "dotdot");
+}
+
+#[test]
+fn fsinfo_free_count() {
+    let mut fs = open_image("images/fat32.img");
+    let root = fs.root_dir();
+    let mut f = root.create_file("big.bin", &mut fs).unwrap();
+    let data = vec![0xA5; 64 * 1024];
+    f.write(&data, &mut fs, 0).unwrap();
+    f.truncate(&mut fs, 1024).unwrap();
+    fsck(fs, "fsinfo");
+}
+
+#[test]
+fn lfn_entries_across_clusters() {
+    // 16 slots to a cluster, so most of these sets of long entries are split between two
+    let mut fs = open_image("images/fat32.img");
+    let root = fs.root_dir();
+    let dir = root.create_dir("Long names", &mut fs).expect("Error creating dir");
+    for i in 0..12 {
+        let name = format!("A name spanning three long entries {:02}.txt", i);
+        dir.create_file(&name, &mut fs).expect("Error creating file");
+    }
+    dir.create_file("Exactly 26 characters long", &mut fs).unwrap();
+    fsck(fs, "lfn-clusters");
+}
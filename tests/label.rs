@@ -0,0 +1,89 @@
+extern crate redox_fatfs;
+
+mod common;
+
+use std::io::{Cursor, ErrorKind, Read, Seek, Write};
+
+use redox_fatfs::prelude::*;
+
+use common::{open_bytes, open_image, FailingDisk};
+
+fn remount(fs: &FileSystem<Cursor<Vec<u8>>>) -> FileSystem<Cursor<Vec<u8>>> {
+    open_bytes(fs.disk.borrow().get_ref().clone())
+}
+
+fn volume_id_entries<D: Read + Write + Seek>(fs: &mut FileSystem<D>) -> usize {
+    let root = fs.root_dir();
+    root.to_iter(fs).filter(|e| e.is_vol_id()).count()
+}
+
+#[test]
+fn set_volume_label() {
+    for image in &["images/fat12.img", "images/fat16.img", "images/fat32.img"] {
+        let mut fs = open_image(image);
+        fs.set_volume_label("Redox EFI").unwrap();
+        assert_eq!(fs.volume_label(), Some("REDOX EFI".to_string()));
+        assert_eq!(fs.bpb.volume_label(), Some("REDOX EFI".to_string()));
+
+        // Renamed in place
+        fs.set_volume_label("BOOT").unwrap();
+        assert_eq!(volume_id_entries(&mut fs), 1);
+
+        let mut fs = remount(&fs);
+        assert_eq!(fs.volume_label(), Some("BOOT".to_string()));
+        assert_eq!(fs.bpb.volume_label(), Some("BOOT".to_string()));
+        if fs.backup_boot_sector().is_some() {
+            assert_eq!(fs.compare_backup_boot_sector().unwrap(), Some(Vec::new()));
+        }
+        assert!(check(&mut fs).unwrap().is_clean());
+    }
+}
+
+#[test]
+fn invalid_volume_labels() {
+    let mut fs = open_image("images/fat16.img");
+    let before = fs.volume_label();
+    for label in &["", "TWELVE CHARS", "A.B", "A*", "caf\u{e9}"] {
+        assert_eq!(fs.set_volume_label(label).unwrap_err().kind(), ErrorKind::InvalidInput, "{:?}", label);
+    }
+    assert_eq!(fs.volume_label(), before);
+}
+
+#[test]
+fn failed_label_write_keeps_both_in_sync() {
+    let (disk, fail_at) = FailingDisk::open("images/fat32.img");
+    let mut fs = FileSystem::from_offset(0, disk, None).unwrap();
+    fs.set_volume_label("FIRST").unwrap();
+
+    // The boot sectors aren't touched once the entry can't be written
+    let root = fs.root_dir();
+    let entry = root.volume_id_entry(&mut fs).unwrap();
+    fail_at.set(Some(fs.cluster_offset((entry.loc.1).0) + (entry.loc.1).1));
+    assert!(fs.set_volume_label("SECOND").is_err());
+    assert_eq!(fs.volume_label(), Some("FIRST".to_string()));
+    assert_eq!(fs.bpb.volume_label(), Some("FIRST".to_string()));
+
+    let mut fs = open_bytes(fs.disk.borrow().inner.get_ref().clone());
+    assert_eq!(fs.volume_label(), Some("FIRST".to_string()));
+    assert_eq!(fs.bpb.volume_label(), Some("FIRST".to_string()));
+    assert_eq!(fs.compare_backup_boot_sector().unwrap(), Some(Vec::new()));
+}
+
+#[test]
+fn failed_boot_sector_write_puts_back_the_entry() {
+    let (disk, fail_at) = FailingDisk::open("images/fat32.img");
+    let mut fs = FileSystem::from_offset(0, disk, None).unwrap();
+    fs.set_volume_label("FIRST").unwrap();
+
+    // BS_VolLab of the FAT32 boot sector, the write fails for good
+    fail_at.set(Some(71));
+    assert!(fs.set_volume_label("SECOND").is_err());
+    assert!(fs.is_degraded());
+    assert_eq!(fs.volume_label(), Some("FIRST".to_string()));
+    assert_eq!(fs.bpb.volume_label(), Some("FIRST".to_string()));
+
+    let mut fs = open_bytes(fs.disk.borrow().inner.get_ref().clone());
+    assert_eq!(fs.volume_label(), Some("FIRST".to_string()));
+    assert_eq!(fs.bpb.volume_label(), Some("FIRST".to_string()));
+    assert_eq!(fs.compare_backup_boot_sector().unwrap(), Some(Vec::new()));
+}
@@ -0,0 +1,136 @@
+extern crate redox_fatfs;
+extern crate byteorder;
+
+mod common;
+
+use std::io::ErrorKind;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use byteorder::{ByteOrder, LittleEndian};
+use redox_fatfs::prelude::*;
+use redox_fatfs::raw::{allocate_cluster, civil_from_days};
+
+use common::{open_bytes, open_image};
+
+#[test]
+fn file_and_dir_metadata() {
+    let mut fs = open_image("images/fat32.img");
+    let root = fs.root_dir();
+    let mut f = root.create_file("STAMP.TXT", &mut fs).unwrap();
+    f.write(&[7; 1000], &mut fs, 0).unwrap();
+    f.flush(&mut fs).unwrap();
+    root.create_dir("sub", &mut fs).unwrap();
+    let mut data = fs.disk.borrow().get_ref().clone();
+    drop(fs);
+
+    // Stamp 2024-02-29 13:45:58 as the write time and 2023-12-31 as the access date
+    let pos = data.windows(11).position(|w| w == b"STAMP   TXT").unwrap();
+    LittleEndian::write_u16(&mut data[pos + 18..], (43 << 9) | (12 << 5) | 31);
+    LittleEndian::write_u16(&mut data[pos + 22..], (13 << 11) | (45 << 5) | 29);
+    LittleEndian::write_u16(&mut data[pos + 24..], (44 << 9) | (2 << 5) | 29);
+    let mut fs = open_bytes(data);
+
+    let meta = fs.metadata("/STAMP.TXT").unwrap();
+    assert!(meta.is_file() && !meta.readonly());
+    assert_eq!(meta.len(), 1000);
+    assert_eq!(meta.clusters(), 2);
+    assert_eq!(meta.allocated_len(), 2 * fs.bytes_per_cluster());
+    assert!(meta.first_cluster().cluster_number >= 2);
+    assert!(meta.attributes().contains(FileAttributes::ARCHIVE));
+    assert_eq!(meta.modified().unwrap(), UNIX_EPOCH + Duration::from_secs(1709214358));
+    assert_eq!(meta.accessed().unwrap(), UNIX_EPOCH + Duration::from_secs(1703980800));
+    assert!(meta.created().is_err());
+
+    let meta = fs.metadata("/sub").unwrap();
+    assert!(meta.is_dir());
+    assert_eq!(meta.clusters(), 1);
+    assert_eq!(meta.len(), fs.bytes_per_cluster());
+    assert!(fs.metadata("/").unwrap().is_dir());
+    assert_eq!(fs.metadata("/missing").unwrap_err().kind(), ErrorKind::NotFound);
+}
+
+#[test]
+fn allocated_len_follows_the_chain() {
+    let mut fs = open_image("images/fat32.img");
+    let bpc = fs.bytes_per_cluster();
+    let root = fs.root_dir();
+    let mut f = root.create_file("spare.bin", &mut fs).unwrap();
+    assert_eq!(fs.metadata("/spare.bin").unwrap().allocated_len(), 0);
+
+    f.write(&[1; 10], &mut fs, 0).unwrap();
+    f.flush(&mut fs).unwrap();
+    // A cluster past the end of the file still takes up space
+    allocate_cluster(&mut fs, Some(f.first_cluster)).unwrap();
+    let meta = fs.metadata("/spare.bin").unwrap();
+    assert_eq!(meta.len(), 10);
+    assert_eq!(meta.clusters(), 2);
+    assert_eq!(meta.allocated_len(), 2 * bpc);
+}
+
+#[test]
+fn fixed_root_dir_owns_no_clusters() {
+    let mut fs = open_image("images/fat16.img");
+    let meta = fs.metadata("/").unwrap();
+    assert!(meta.is_dir());
+    assert_eq!(meta.first_cluster().cluster_number, 0);
+}
+
+#[test]
+fn set_times() {
+    let mut fs = open_image("images/fat32.img");
+    let root = fs.root_dir();
+    root.create_file("touched.txt", &mut fs).unwrap();
+    root.create_dir("dir", &mut fs).unwrap();
+
+    // Odd seconds are rounded down, the access date keeps only the day
+    let t = UNIX_EPOCH + Duration::from_secs(1709214359);
+    fs.set_times("/touched.txt", Some(t), Some(t)).unwrap();
+    fs.set_times("/dir", Some(t), None).unwrap();
+    let data = fs.disk.borrow().get_ref().clone();
+    drop(fs);
+
+    let mut fs = open_bytes(data);
+    let meta = fs.metadata("/touched.txt").unwrap();
+    assert_eq!(meta.modified().unwrap(), UNIX_EPOCH + Duration::from_secs(1709214358));
+    assert_eq!(meta.accessed().unwrap(), UNIX_EPOCH + Duration::from_secs(1709164800));
+    let meta = fs.metadata("/dir").unwrap();
+    assert_eq!(meta.modified().unwrap(), UNIX_EPOCH + Duration::from_secs(1709214358));
+    assert!(meta.accessed().is_err());
+
+    assert_eq!(fs.set_times("/", Some(t), None).unwrap_err().kind(), ErrorKind::InvalidInput);
+    let too_early = UNIX_EPOCH + Duration::from_secs(86400);
+    assert_eq!(fs.set_times("/touched.txt", Some(too_early), None).unwrap_err().kind(), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn touch_uses_the_clock() {
+    let mut fs = open_image("images/fat16.img");
+    let now = fs.now();
+    assert!(now <= SystemTime::now() && now + Duration::from_secs(60) > SystemTime::now());
+
+    let t = UNIX_EPOCH + Duration::from_secs(1577934246);
+    fs.set_clock(FixedClock(t));
+    assert_eq!(fs.now(), t);
+    let root = fs.root_dir();
+    root.create_file("clocked.txt", &mut fs).unwrap();
+    let mut e = Dir::get_entry_abs("/clocked.txt", &mut fs).unwrap();
+    e.touch(&mut fs).unwrap();
+    let meta = fs.metadata("/clocked.txt").unwrap();
+    assert_eq!(meta.modified().unwrap(), t);
+    assert_eq!(meta.accessed().unwrap(), UNIX_EPOCH + Duration::from_secs(1577923200));
+}
+
+#[test]
+fn civil_dates() {
+    let cases = [
+        (0, (1970, 1, 1)),
+        (-1, (1969, 12, 31)),
+        (3652, (1980, 1, 1)),
+        (11016, (2000, 2, 29)),
+        (11017, (2000, 3, 1)),
+        (50038, (2107, 1, 1))
+    ];
+    for &(days, date) in cases.iter() {
+        assert_eq!(civil_from_days(days), date, "{}", days);
+    }
+}
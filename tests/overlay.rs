@@ -0,0 +1,57 @@
+extern crate redox_fatfs;
+
+use std::fs;
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+use redox_fatfs::prelude::*;
+
+#[test]
+fn unaligned_writes_keep_base_untouched() {
+    let base: Vec<u8> = (0..3 * BLOCK_SIZE).map(|i| i as u8).collect();
+    let mut overlay = Overlay::new(Cursor::new(base.clone())).unwrap();
+
+    overlay.seek(SeekFrom::Start(BLOCK_SIZE - 2)).unwrap();
+    overlay.write_all(b"abcd").unwrap();
+    let blocks: Vec<u64> = overlay.modified_blocks().map(|(n, _)| n).collect();
+    assert_eq!(blocks, vec![0, 1]);
+
+    let mut buf = vec![0; 8];
+    overlay.seek(SeekFrom::Start(BLOCK_SIZE - 4)).unwrap();
+    overlay.read_exact(&mut buf).unwrap();
+    let mut expected = base[BLOCK_SIZE as usize - 4..BLOCK_SIZE as usize + 4].to_vec();
+    expected[2..6].copy_from_slice(b"abcd");
+    assert_eq!(buf, expected);
+
+    // Writes past the end grow the overlay only
+    overlay.seek(SeekFrom::End(2)).unwrap();
+    overlay.write_all(b"tail").unwrap();
+    assert_eq!(overlay.seek(SeekFrom::End(0)).unwrap(), 3 * BLOCK_SIZE + 6);
+    overlay.seek(SeekFrom::End(-6)).unwrap();
+    overlay.read_exact(&mut buf[..6]).unwrap();
+    assert_eq!(&buf[..6], b"\0\0tail");
+
+    overlay.discard();
+    assert_eq!(overlay.modified_blocks().count(), 0);
+    assert!(overlay.into_inner().into_inner() == base);
+}
+
+#[test]
+fn filesystem_changes_stay_in_overlay() {
+    let image = fs::read("images/fat32.img").expect("Failed to read image");
+    let overlay = Overlay::new(Cursor::new(image.clone())).unwrap();
+    let mut fs = FileSystem::from_offset(0, overlay, None).expect("Parsing Error");
+    let root = fs.root_dir();
+    let mut f = root.create_file("scratch.txt", &mut fs).unwrap();
+    f.write(b"overlay data", &mut fs, 0).unwrap();
+    f.flush(&mut fs).unwrap();
+
+    let mut buf = [0; 32];
+    let f = root.open_file("scratch.txt", &mut fs).unwrap();
+    let count = f.read(&mut buf, &mut fs, 0).unwrap();
+    assert_eq!(&buf[..count], b"overlay data");
+    assert!(fs.disk.borrow().modified_blocks().count() > 0);
+    assert!(fs.disk.borrow().base().get_ref() == &image);
+
+    let mut fs = FileSystem::from_offset(0, Cursor::new(image), None).expect("Parsing Error");
+    assert!(fs.root_dir().open_file("scratch.txt", &mut fs).is_err());
+}
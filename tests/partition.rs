@@ -0,0 +1,100 @@
+extern crate redox_fatfs;
+extern crate byteorder;
+
+use std::fs;
+use std::io::{Cursor, ErrorKind};
+
+use byteorder::{ByteOrder, LittleEndian};
+use redox_fatfs::prelude::*;
+
+const START: usize = 1024 * 1024;
+
+fn fixture() -> Vec<u8> {
+    fs::read("images/fat32.img").expect("Failed to read image")
+}
+
+// The fixture as the partition at 1MiB of a disk, after a table made by `table`
+fn partitioned<F: Fn(&mut [u8])>(table: F) -> Vec<u8> {
+    let mut disk = vec![0; START];
+    table(&mut disk[..START]);
+    disk.extend(fixture());
+    disk
+}
+
+fn mbr(disk: &mut [u8], slot: usize, kind: u8, start_lba: u32) {
+    let entry = 446 + slot * 16;
+    disk[entry + 4] = kind;
+    LittleEndian::write_u32(&mut disk[entry + 8..], start_lba);
+    disk[510] = 0x55;
+    disk[511] = 0xaa;
+}
+
+// A GPT whose entry array is at `entries_lba`, with the third entry used and starting at `start_lba`
+fn gpt(disk: &mut [u8], entries_lba: u64, start_lba: u64) {
+    mbr(disk, 0, 0xee, 1);
+    let header = &mut disk[512..1024];
+    header[..8].copy_from_slice(b"EFI PART");
+    LittleEndian::write_u64(&mut header[72..], entries_lba);
+    LittleEndian::write_u32(&mut header[80..], 128);
+    LittleEndian::write_u32(&mut header[84..], 128);
+    // The third entry, after two unused ones
+    let entry = &mut disk[1024 + 2 * 128..1024 + 3 * 128];
+    entry[..16].copy_from_slice(&[0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11,
+                                  0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b]);
+    LittleEndian::write_u64(&mut entry[32..], start_lba);
+}
+
+#[test]
+fn unpartitioned_disk() {
+    let volumes = enumerate_fat_volumes(&mut Cursor::new(fixture())).unwrap();
+    assert_eq!(volumes.len(), 1);
+    assert_eq!(volumes[0].0, 0);
+    assert_eq!(volumes[0].1.partition, None);
+    assert_eq!(volumes[0].1.fat_bits, 32);
+}
+
+#[test]
+fn mbr_partitions() {
+    // A non-FAT partition in the first slot, the fixture in the second
+    let disk = partitioned(|d| {
+        mbr(d, 0, 0x83, 8);
+        mbr(d, 1, 0x0c, (START / 512) as u32);
+    });
+    let mut cursor = Cursor::new(disk);
+    let volumes = enumerate_fat_volumes(&mut cursor).unwrap();
+    assert_eq!(volumes.len(), 1);
+    let (offset, info) = volumes[0];
+    assert_eq!(offset, START as u64);
+    assert_eq!(info.partition, Some(2));
+    assert_eq!(info.len, fixture().len() as u64);
+
+    let mut fs = FileSystem::from_offset(offset, cursor, None).unwrap();
+    assert_eq!(fs.bpb.get_serial(), info.serial);
+    let root = fs.root_dir();
+    root.create_file("new.txt", &mut fs).unwrap();
+}
+
+#[test]
+fn gpt_partitions() {
+    let disk = partitioned(|d| gpt(d, 2, (START / 512) as u64));
+    let volumes = enumerate_fat_volumes(&mut Cursor::new(disk)).unwrap();
+    assert_eq!(volumes.len(), 1);
+    assert_eq!(volumes[0].0, START as u64);
+    assert_eq!(volumes[0].1.partition, Some(3));
+}
+
+#[test]
+fn gpt_offsets_past_any_disk() {
+    for &(entries_lba, start_lba) in &[(u64::max_value() / 256, 2048), (2, u64::max_value() / 256)] {
+        let disk = partitioned(|d| gpt(d, entries_lba, start_lba));
+        let err = enumerate_fat_volumes(&mut Cursor::new(disk)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData, "{:x} {:x}", entries_lba, start_lba);
+    }
+}
+
+#[test]
+fn disk_without_fat_volumes() {
+    assert!(enumerate_fat_volumes(&mut Cursor::new(vec![0; START])).unwrap().is_empty());
+    let garbage: Vec<u8> = (0..START).map(|i| (i * 7 + i / 512) as u8).collect();
+    assert!(probe_fat(&mut Cursor::new(garbage), 0).unwrap().is_none());
+}
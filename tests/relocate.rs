@@ -0,0 +1,132 @@
+extern crate redox_fatfs;
+
+mod common;
+
+use std::cell::Cell;
+use std::io::{Cursor, ErrorKind, Read, Seek, Write};
+use std::rc::Rc;
+
+use redox_fatfs::prelude::*;
+use redox_fatfs::raw::{get_entry, FATType, FatEntry};
+
+use common::{open_bytes, open_image, FailingDisk};
+
+fn root_cluster<D: Read + Write + Seek>(fs: &FileSystem<D>) -> u64 {
+    match fs.bpb.fat_type {
+        FATType::FAT32(s) => s.root_cluster as u64,
+        _ => panic!("Not FAT32")
+    }
+}
+
+fn root_names<D: Read + Write + Seek>(fs: &mut FileSystem<D>) -> Vec<String> {
+    let root = fs.root_dir();
+    let mut names: Vec<String> = root.to_iter(fs).map(|e| e.name()).collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn relocate_root() {
+    let mut fs = open_image("images/fat32.img");
+    let root = fs.root_dir();
+    for i in 0..40 {
+        root.create_file(&format!("A file in the root {}", i), &mut fs).unwrap();
+    }
+    let names = root_names(&mut fs);
+    let old = root_cluster(&fs);
+    let chain = fs.clusters(Cluster::new(old));
+    assert!(chain.len() > 1);
+
+    let new = fs.max_cluster_number().cluster_number - 10;
+    fs.relocate_root(Cluster::new(new)).unwrap();
+    assert_eq!(root_cluster(&fs), new);
+    assert_eq!(get_entry(&mut fs, Cluster::new(old)).unwrap(), FatEntry::Unused);
+    let moved = fs.clusters(Cluster::new(new));
+    assert_eq!(moved.len(), chain.len());
+    assert!(moved[1..] == chain[1..]);
+    assert_eq!(root_names(&mut fs), names);
+    assert_eq!(fs.compare_backup_boot_sector().unwrap(), Some(Vec::new()));
+    assert!(check(&mut fs).unwrap().is_clean());
+
+    // The new root is read from the boot sector once mounted again
+    let mut fs = open_bytes(fs.disk.borrow().get_ref().clone());
+    assert_eq!(root_cluster(&fs), new);
+    assert_eq!(root_names(&mut fs), names);
+    let root = fs.root_dir();
+    root.create_file("Created after the move", &mut fs).unwrap();
+    assert!(Dir::get_entry_abs("/Created after the move", &mut fs).is_ok());
+    assert!(check(&mut fs).unwrap().is_clean());
+}
+
+#[test]
+fn relocate_root_checks_the_target() {
+    let mut fs = open_image("images/fat32.img");
+    let old = root_cluster(&fs);
+    let mut f = fs.root_dir().create_file("taken", &mut fs).unwrap();
+    f.write(b"data", &mut fs, 0).unwrap();
+    f.flush(&mut fs).unwrap();
+
+    let max = fs.max_cluster_number().cluster_number;
+    for &c in &[0, 1, max + 1, f.first_cluster.cluster_number] {
+        assert_eq!(fs.relocate_root(Cluster::new(c)).unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+    fs.relocate_root(Cluster::new(old)).unwrap();
+    assert_eq!(root_cluster(&fs), old);
+
+    let mut fs = open_image("images/fat16.img");
+    assert_eq!(fs.relocate_root(Cluster::new(100)).unwrap_err().kind(), ErrorKind::InvalidInput);
+}
+
+fn failing_fs() -> (FileSystem<FailingDisk>, Rc<Cell<Option<u64>>>) {
+    let (disk, fail_at) = FailingDisk::open("images/fat32.img");
+    let mut fs = FileSystem::from_offset(0, disk, None).unwrap();
+    fs.root_dir().create_file("still here", &mut fs).unwrap();
+    (fs, fail_at)
+}
+
+fn remount(fs: FileSystem<FailingDisk>) -> FileSystem<Cursor<Vec<u8>>> {
+    open_bytes(fs.disk.borrow().inner.get_ref().clone())
+}
+
+#[test]
+fn failed_relocation_keeps_the_root() {
+    let (fs, _) = failing_fs();
+    let new = Cluster::new(fs.max_cluster_number().cluster_number - 10);
+    // Copying the entries, pointing the new cluster on in the FAT, writing the boot sector
+    let points = [fs.cluster_offset(new), fs.fat_start_sector() * fs.bytes_per_sec() + new.cluster_number * 4, 44];
+
+    for &at in points.iter() {
+        let (mut fs, fail_at) = failing_fs();
+        let names = root_names(&mut fs);
+        let old = root_cluster(&fs);
+
+        fail_at.set(Some(at));
+        assert!(fs.relocate_root(new).is_err());
+        assert!(fs.is_degraded());
+        assert_eq!(root_cluster(&fs), old);
+
+        let mut fs = remount(fs);
+        assert_eq!(root_cluster(&fs), old);
+        assert_eq!(root_names(&mut fs), names);
+        assert_eq!(fs.compare_backup_boot_sector().unwrap(), Some(Vec::new()));
+    }
+}
+
+#[test]
+fn relocation_failing_after_the_boot_sector_moves_the_root() {
+    let (mut fs, fail_at) = failing_fs();
+    let names = root_names(&mut fs);
+    let old = root_cluster(&fs);
+    let new = fs.max_cluster_number().cluster_number - 10;
+
+    // Freeing the old cluster is the last step
+    fail_at.set(Some(fs.fat_start_sector() * fs.bytes_per_sec() + old * 4));
+    assert!(fs.relocate_root(Cluster::new(new)).is_err());
+    assert_eq!(root_cluster(&fs), new);
+
+    let mut fs = remount(fs);
+    assert_eq!(root_cluster(&fs), new);
+    assert_eq!(root_names(&mut fs), names);
+    assert_eq!(fs.compare_backup_boot_sector().unwrap(), Some(Vec::new()));
+    assert_ne!(get_entry(&mut fs, Cluster::new(old)).unwrap(), FatEntry::Unused);
+}
@@ -0,0 +1,111 @@
+extern crate redox_fatfs;
+
+mod common;
+
+use std::fs;
+use std::io::{Cursor, ErrorKind};
+
+use redox_fatfs::prelude::*;
+use redox_fatfs::raw::{set_entry, FatEntry};
+
+use common::open_bytes;
+
+// Opens `path` with room for `extra` more sectors after it, as if its partition was grown
+fn open_grown(path: &str, extra: usize) -> FileSystem<Cursor<Vec<u8>>> {
+    let mut data = fs::read(path).expect("Failed to read image");
+    let len = data.len();
+    data.resize(len + extra * 512, 0);
+    open_bytes(data)
+}
+
+fn write_file(fs: &mut FileSystem<Cursor<Vec<u8>>>, name: &str, data: &[u8]) {
+    let root = fs.root_dir();
+    let mut f = root.create_file(name, fs).unwrap();
+    f.write(data, fs, 0).unwrap();
+    f.flush(fs).unwrap();
+}
+
+fn read_file(fs: &mut FileSystem<Cursor<Vec<u8>>>, path: &str) -> Vec<u8> {
+    let f = Dir::get_entry_abs(path, fs).unwrap().to_file();
+    let mut buf = vec![0; f.size() as usize];
+    f.read(&mut buf, fs, 0).unwrap();
+    buf
+}
+
+#[test]
+fn grow_moves_the_data_area() {
+    // Each grows past the slack in the FATs
+    for &(path, extra) in &[("images/fat12.img", 2048), ("images/fat16.img", 20480), ("images/fat32.img", 4096)] {
+        let mut fs = open_grown(path, extra);
+        let data: Vec<u8> = (0..20000).map(|i| (i % 251) as u8).collect();
+        write_file(&mut fs, "KEEP.BIN", &data);
+        let total = fs.bpb.total_sectors();
+        let fat_size = fs.fat_size();
+        let first_data_sec = fs.first_data_sec;
+        let free = fs.free_clusters().unwrap();
+        let max = fs.max_cluster_number().cluster_number;
+
+        fs.resize(total + extra as u64).unwrap();
+        assert!(fs.fat_size() > fat_size, "{}", path);
+        assert_eq!(fs.first_data_sec, first_data_sec + fs.bpb.num_fats as u64 * (fs.fat_size() - fat_size));
+        let added = fs.max_cluster_number().cluster_number - max;
+        assert_eq!(fs.free_clusters().unwrap(), free + added, "{}", path);
+
+        let bytes = fs.disk.borrow().get_ref().clone();
+        let mut fs = open_bytes(bytes);
+        assert_eq!(fs.bpb.total_sectors(), total + extra as u64);
+        assert!(read_file(&mut fs, "/keep.bin") == data, "{}", path);
+        assert!(check(&mut fs).unwrap().is_clean(), "{}", path);
+        write_file(&mut fs, "MORE.BIN", &data);
+        assert!(read_file(&mut fs, "/more.bin") == data);
+    }
+}
+
+#[test]
+fn small_grow_keeps_the_layout() {
+    let mut fs = open_grown("images/fat16.img", 8);
+    let first_data_sec = fs.first_data_sec;
+    let max = fs.max_cluster_number().cluster_number;
+    let total = fs.bpb.total_sectors();
+    fs.resize(total + 8).unwrap();
+    assert_eq!(fs.first_data_sec, first_data_sec);
+    assert_eq!(fs.max_cluster_number().cluster_number, max + 2);
+    assert!(check(&mut fs).unwrap().is_clean());
+}
+
+#[test]
+fn shrink() {
+    let mut fs = open_bytes(fs::read("images/fat32.img").unwrap());
+    let total = fs.bpb.total_sectors();
+    write_file(&mut fs, "SMALL.TXT", b"still here");
+    fs.resize(total - 1000).unwrap();
+    assert_eq!(fs.partition_len, (total - 1000) * 512);
+
+    let mut bytes = fs.disk.borrow().get_ref().clone();
+    bytes.truncate(((total - 1000) * 512) as usize);
+    let mut fs = open_bytes(bytes);
+    assert_eq!(read_file(&mut fs, "/small.txt"), b"still here");
+    assert!(check(&mut fs).unwrap().is_clean());
+}
+
+#[test]
+fn shrink_refuses_used_clusters() {
+    let mut fs = open_bytes(fs::read("images/fat16.img").unwrap());
+    let total = fs.bpb.total_sectors();
+    let last = fs.max_cluster_number();
+    set_entry(&mut fs, last, FatEntry::EndOfChain).unwrap();
+    let err = fs.resize(total - 100).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert_eq!(fs.bpb.total_sectors(), total);
+    assert_eq!(fs.max_cluster_number(), last);
+    assert_eq!(fs.usage_bitmap().unwrap().state(last), Some(ClusterState::Allocated));
+}
+
+#[test]
+fn fat_type_changes_are_refused() {
+    // About 2400 clusters, which would be FAT12
+    let mut fs = open_bytes(fs::read("images/fat16.img").unwrap());
+    assert_eq!(fs.resize(10000).unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert_eq!(fs.resize(10).unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert!(check(&mut fs).unwrap().is_clean());
+}
@@ -0,0 +1,1190 @@
+extern crate byteorder;
+extern crate redox_fatfs;
+extern crate syscall;
+#[cfg(feature = "trace")]
+extern crate tracing;
+#[cfg(feature = "trace")]
+extern crate tracing_subscriber;
+
+mod common;
+
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, Cursor};
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use byteorder::{ByteOrder, LittleEndian};
+use redox_fatfs::prelude::*;
+use redox_fatfs::raw::{DirEntryRaw};
+use syscall::data::{Map, Stat, StatVfs};
+use syscall::error::{EACCES, EBADF, EBUSY, EINVAL, EISDIR, EMFILE, ENOENT, ENOMEM, ENOSPC, ENOTDIR, ENOTEMPTY, EPERM, EROFS, ESTALE, EXDEV};
+use syscall::flag::{EVENT_READ, F_GETFL, O_CREAT, O_DIRECTORY, O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY, PROT_EXEC, PROT_READ, PROT_WRITE, SEEK_CUR, SEEK_SET};
+use syscall::scheme::Scheme;
+use syscall::{Error, Packet, SYS_CLOSE, SYS_FEVENT, SYS_LSEEK, SYS_OPEN, SYS_READ, SYS_WRITE};
+
+use common::{open_bytes, open_image, FailingDisk};
+
+fn open_scheme(name: &str, path: &str) -> FileScheme<Cursor<Vec<u8>>> {
+    open_scheme_with(name, path, MountOptions::default())
+}
+
+fn open_scheme_with(name: &str, path: &str, options: MountOptions) -> FileScheme<Cursor<Vec<u8>>> {
+    let fs = open_image(path);
+    FileScheme::new(name.to_string(), fs, 0o777, 0, 0, options)
+}
+
+#[test]
+fn rename_to_other_mount_fails_with_exdev() {
+    let disk0 = open_scheme("disk0", "images/fat32.img");
+    let disk1 = open_scheme("disk1", "images/fat32.img");
+    let id = disk0.open(b"/cross.txt", O_CREAT | O_RDWR, 0, 0).expect("Failed to create file");
+
+    let err = disk0.frename(id, b"disk1:/cross.txt", 0, 0).unwrap_err();
+    assert_eq!(err.errno, EXDEV);
+
+    // Nothing was created on either volume and the source is untouched
+    assert!(disk0.open(b"/cross.txt", O_RDWR, 0, 0).is_ok());
+    assert!(disk1.open(b"/cross.txt", O_RDWR, 0, 0).is_err());
+}
+
+#[test]
+fn rename_with_own_scheme_prefix() {
+    let disk0 = open_scheme("disk0", "images/fat32.img");
+    let id = disk0.open(b"/local.txt", O_CREAT | O_RDWR, 0, 0).expect("Failed to create file");
+
+    disk0.frename(id, b"disk0:/renamed.txt", 0, 0).expect("Rename within the mount failed");
+    assert!(disk0.open(b"/renamed.txt", O_RDWR, 0, 0).is_ok());
+    assert!(disk0.open(b"/local.txt", O_RDWR, 0, 0).is_err());
+}
+
+#[test]
+fn root_cannot_be_removed_or_renamed() {
+    let disk0 = open_scheme("disk0", "images/fat32.img");
+    disk0.open(b"/docs", O_CREAT | O_DIRECTORY, 0, 0).unwrap();
+    for path in &[&b"/"[..], b"", b"/docs/.."] {
+        assert_eq!(disk0.rmdir(path, 0, 0).unwrap_err().errno, EBUSY, "{:?}", path);
+    }
+    let root = disk0.open(b"/", O_DIRECTORY, 0, 0).unwrap();
+    assert_eq!(disk0.frename(root, b"/docs/root", 0, 0).unwrap_err().errno, EPERM);
+    let f = disk0.open(b"/f.txt", O_CREAT | O_RDWR, 0, 0).unwrap();
+    assert_eq!(disk0.frename(f, b"/docs/..", 0, 0).unwrap_err().errno, EPERM);
+    assert!(disk0.open(b"/docs", O_DIRECTORY, 0, 0).is_ok());
+    assert!(disk0.open(b"/f.txt", O_RDWR, 0, 0).is_ok());
+}
+
+#[test]
+fn open_file_limit() {
+    let options = MountOptions { max_open_files: Some(2), ..Default::default() };
+    let disk0 = open_scheme_with("disk0", "images/fat32.img", options);
+    let a = disk0.open(b"/a.txt", O_CREAT | O_RDWR, 0, 0).unwrap();
+    disk0.open(b"/b.txt", O_CREAT | O_RDWR, 0, 0).unwrap();
+
+    assert_eq!(disk0.open(b"/c.txt", O_CREAT | O_RDWR, 0, 0).unwrap_err().errno, EMFILE);
+    assert_eq!(disk0.dup(a, b"").unwrap_err().errno, EMFILE);
+    // The refused create must not leave an entry behind
+    disk0.close(a).unwrap();
+    assert!(disk0.open(b"/c.txt", O_RDWR, 0, 0).is_err());
+    assert!(disk0.open(b"/c.txt", O_CREAT | O_RDWR, 0, 0).is_ok());
+}
+
+#[test]
+fn fmap_memory_limit() {
+    let options = MountOptions { max_fmap_memory: Some(4096), ..Default::default() };
+    let disk0 = open_scheme_with("disk0", "images/fat32.img", options);
+    let a = disk0.open(b"/a.txt", O_CREAT | O_RDWR, 0, 0).unwrap();
+    let b = disk0.open(b"/b.txt", O_CREAT | O_RDWR, 0, 0).unwrap();
+    let map = Map { offset: 0, size: 4096, flags: PROT_READ };
+
+    assert!(disk0.fmap(a, &map).is_ok());
+    assert_eq!(disk0.fmap(b, &map).unwrap_err().errno, ENOMEM);
+    // Closing the first file releases its buffer
+    disk0.close(a).unwrap();
+    assert!(disk0.fmap(b, &map).is_ok());
+}
+
+#[test]
+fn fmap_exec_maps_whole_pages() {
+    let disk0 = open_scheme("disk0", "images/fat32.img");
+    // Stands in for a small static binary, an ELF header followed by code
+    let mut binary = b"\x7fELF\x02\x01\x01\0".to_vec();
+    binary.extend((0..5000).map(|i| i as u8));
+    let f = disk0.open(b"/prog", O_CREAT | O_RDWR, 0, 0).unwrap();
+    disk0.write(f, &binary).unwrap();
+    disk0.close(f).unwrap();
+
+    let f = disk0.open(b"/prog", O_RDONLY, 0, 0).unwrap();
+    assert_eq!(disk0.fmap(f, &Map { offset: 100, size: 4096, flags: PROT_READ | PROT_EXEC }).unwrap_err().errno, EINVAL);
+    assert_eq!(disk0.fmap(f, &Map { offset: 0, size: 0, flags: PROT_READ | PROT_EXEC }).unwrap_err().errno, EINVAL);
+
+    // The second page holds the tail of the file followed by zeros
+    let addr = disk0.fmap(f, &Map { offset: 0, size: binary.len(), flags: PROT_READ | PROT_EXEC }).unwrap();
+    assert_eq!(addr % 4096, 0);
+    let pages = unsafe { std::slice::from_raw_parts(addr as *const u8, 8192) };
+    assert_eq!(&pages[..binary.len()], &binary[..]);
+    assert!(pages[binary.len()..].iter().all(|&b| b == 0));
+    disk0.close(f).unwrap();
+
+    let f = disk0.open(b"/prog", O_RDONLY, 0, 0).unwrap();
+    let addr = disk0.fmap(f, &Map { offset: 4096, size: 100, flags: PROT_READ | PROT_EXEC }).unwrap();
+    assert_eq!(addr % 4096, 0);
+    let page = unsafe { std::slice::from_raw_parts(addr as *const u8, 4096) };
+    assert_eq!(&page[..binary.len() - 4096], &binary[4096..]);
+    disk0.close(f).unwrap();
+
+    // Executing needs read access
+    let f = disk0.open(b"/prog", O_WRONLY, 0, 0).unwrap();
+    assert_eq!(disk0.fmap(f, &Map { offset: 0, size: 4096, flags: PROT_EXEC }).unwrap_err().errno, EBADF);
+}
+
+#[test]
+fn fmap_of_changed_file_is_stale() {
+    let options = MountOptions { max_fmap_memory: Some(4096), ..Default::default() };
+    let disk0 = open_scheme_with("disk0", "images/fat32.img", options);
+    let f = disk0.open(b"/mapped.txt", O_CREAT | O_RDWR, 0, 0).unwrap();
+    disk0.write(f, b"original data").unwrap();
+    disk0.close(f).unwrap();
+    let map = Map { offset: 0, size: 4096, flags: PROT_READ | PROT_WRITE };
+
+    let a = disk0.open(b"/mapped.txt", O_RDWR, 0, 0).unwrap();
+    let addr = disk0.fmap(a, &map).unwrap();
+    let pages = unsafe { std::slice::from_raw_parts_mut(addr as *mut u8, 4096) };
+    pages[..8].copy_from_slice(b"ORIGINAL");
+    disk0.fsync(a).unwrap();
+
+    // Writing the pages back now would undo the write through the other handle
+    let b = disk0.open(b"/mapped.txt", O_RDWR, 0, 0).unwrap();
+    disk0.write(b, b"written").unwrap();
+    pages[8..13].copy_from_slice(b" DATA");
+    assert_eq!(disk0.fsync(a).unwrap_err().errno, ESTALE);
+    disk0.seek(b, 0, SEEK_SET).unwrap();
+    assert_eq!(read_all(&disk0, b), b"writtenL data");
+
+    // Closing reports the refused write back but releases the mapping all the same,
+    // a new one reads the file again
+    assert_eq!(disk0.close(a).unwrap_err().errno, ESTALE);
+    let a = disk0.open(b"/mapped.txt", O_RDONLY, 0, 0).unwrap();
+    let addr = disk0.fmap(a, &Map { offset: 0, size: 4096, flags: PROT_READ }).unwrap();
+    let pages = unsafe { std::slice::from_raw_parts(addr as *const u8, 4096) };
+    assert_eq!(&pages[..13], b"writtenL data");
+
+    // A mapping shared with another handle is refreshed once the file changes
+    disk0.ftruncate(b, 7).unwrap();
+    let c = disk0.open(b"/mapped.txt", O_RDONLY, 0, 0).unwrap();
+    assert_eq!(disk0.fmap(c, &Map { offset: 0, size: 4096, flags: PROT_READ }).unwrap(), addr);
+    assert_eq!(&pages[..7], b"written");
+    assert!(pages[7..].iter().all(|&b| b == 0));
+}
+
+#[test]
+fn watcher_receives_events_under_path() {
+    let disk0 = open_scheme("disk0", "images/fat32.img");
+    let watch = disk0.open(b"/?watch/docs", O_RDWR, 0, 0).expect("Failed to open watcher");
+    disk0.fevent(watch, EVENT_READ).unwrap();
+    assert!(disk0.pending_events().is_empty());
+
+    disk0.open(b"/docs", O_CREAT | O_DIRECTORY, 0, 0).unwrap();
+    let f = disk0.open(b"/docs/a.txt", O_CREAT | O_RDWR, 0, 0).unwrap();
+    disk0.write(f, b"hello").unwrap();
+    disk0.frename(f, b"/docs/b.txt", 0, 0).unwrap();
+    disk0.unlink(b"/docs/b.txt", 0, 0).unwrap();
+    // Outside the watched directory
+    disk0.open(b"/other.txt", O_CREAT | O_RDWR, 0, 0).unwrap();
+
+    let pending = disk0.pending_events();
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].0, watch);
+    // Signalled only once until the events are read
+    assert!(disk0.pending_events().is_empty());
+
+    let mut buf = [0; 256];
+    let count = disk0.read(watch, &mut buf).unwrap();
+    assert_eq!(count, pending[0].1);
+    assert_eq!(std::str::from_utf8(&buf[..count]).unwrap(),
+               "create docs\ncreate docs/a.txt\nwrite docs/a.txt\nrename docs/a.txt docs/b.txt\ndelete docs/b.txt\n");
+    assert_eq!(disk0.read(watch, &mut buf).unwrap(), 0);
+    disk0.close(watch).unwrap();
+}
+
+#[test]
+fn reserved_space_for_owner() {
+    let options = MountOptions { reserved_clusters: Some(1 << 40), ..Default::default() };
+    let disk0 = open_scheme_with("disk0", "images/fat32.img", options);
+    let user = 1000;
+
+    assert_eq!(disk0.open(b"/user.txt", O_CREAT | O_RDWR, user, user).unwrap_err().errno, ENOSPC);
+    let owned = disk0.open(b"/owner.txt", O_CREAT | O_RDWR, 0, 0).unwrap();
+    assert_eq!(disk0.write(owned, b"owner").unwrap(), 5);
+    disk0.close(owned).unwrap();
+
+    // Other users can still read and clean up but not write
+    let f = disk0.open(b"/owner.txt", O_RDWR, user, user).unwrap();
+    assert_eq!(disk0.write(f, b"user").unwrap_err().errno, ENOSPC);
+    let mut buf = [0; 5];
+    assert_eq!(disk0.read(f, &mut buf).unwrap(), 5);
+    disk0.close(f).unwrap();
+    assert!(disk0.unlink(b"/owner.txt", user, user).is_ok());
+}
+
+#[test]
+fn ftruncate_keeps_the_reserve() {
+    let mut fs = open_image("images/fat32.img");
+    let free = fs.free_clusters().unwrap();
+    let bpc = fs.bytes_per_cluster() as usize;
+    let options = MountOptions { reserved_clusters: Some(free - 4), ..Default::default() };
+    let disk0 = FileScheme::new("disk0".to_string(), fs, 0o777, 0, 0, options);
+    let user = 1000;
+
+    let f = disk0.open(b"/grown.bin", O_CREAT | O_RDWR, user, user).unwrap();
+    assert_eq!(disk0.ftruncate(f, 8 * bpc).unwrap_err().errno, ENOSPC);
+    disk0.ftruncate(f, 2 * bpc).unwrap();
+    // Within the clusters the file already has
+    disk0.ftruncate(f, 2 * bpc - 1).unwrap();
+    assert_eq!(disk0.ftruncate(f, 8 * bpc).unwrap_err().errno, ENOSPC);
+    disk0.ftruncate(f, 0).unwrap();
+
+    // The owner may use the reserve
+    let owned = disk0.open(b"/grown.bin", O_RDWR, 0, 0).unwrap();
+    disk0.ftruncate(owned, 8 * bpc).unwrap();
+}
+
+#[test]
+fn path_cache_hits_and_invalidation() {
+    let options = MountOptions { path_cache: true, ..Default::default() };
+    let disk0 = open_scheme_with("disk0", "images/fat32.img", options);
+    let f = disk0.open(b"/cached.txt", O_CREAT | O_RDWR, 0, 0).unwrap();
+    disk0.write(f, b"cached").unwrap();
+    disk0.close(f).unwrap();
+
+    let (hits, misses) = disk0.path_cache_stats().unwrap();
+    disk0.close(disk0.open(b"/cached.txt", O_RDWR, 0, 0).unwrap()).unwrap();
+    disk0.close(disk0.open(b"/CACHED.TXT", O_RDWR, 0, 0).unwrap()).unwrap();
+    let f = disk0.open(b"/cached.txt", O_RDWR, 0, 0).unwrap();
+    assert_eq!(disk0.path_cache_stats().unwrap(), (hits + 2, misses + 1));
+    // The cached entry carries the size written back on close
+    let mut buf = [0; 16];
+    assert_eq!(disk0.read(f, &mut buf).unwrap(), 6);
+
+    disk0.frename(f, b"/moved.txt", 0, 0).unwrap();
+    disk0.close(f).unwrap();
+    assert!(disk0.open(b"/cached.txt", O_RDWR, 0, 0).is_err());
+    disk0.unlink(b"/moved.txt", 0, 0).unwrap();
+    assert!(disk0.open(b"/moved.txt", O_RDWR, 0, 0).is_err());
+}
+
+#[test]
+fn path_cache_disabled_by_default() {
+    let disk0 = open_scheme("disk0", "images/fat32.img");
+    assert!(disk0.path_cache_stats().is_none());
+}
+
+#[test]
+fn file_cache_serves_repeated_reads() {
+    let options = MountOptions { file_cache: Some(4096), ..Default::default() };
+    let disk0 = open_scheme_with("disk0", "images/fat32.img", options);
+    let f = disk0.open(b"/small.txt", O_CREAT | O_RDWR, 0, 0).unwrap();
+    disk0.write(f, b"first contents").unwrap();
+    disk0.close(f).unwrap();
+
+    let mut buf = [0; 32];
+    for _ in 0..3 {
+        let f = disk0.open(b"/small.txt", O_RDONLY, 0, 0).unwrap();
+        assert_eq!(disk0.read(f, &mut buf[..5]).unwrap(), 5);
+        assert_eq!(disk0.read(f, &mut buf[5..]).unwrap(), 9);
+        assert_eq!(&buf[..14], b"first contents");
+        disk0.close(f).unwrap();
+    }
+    assert_eq!(disk0.file_cache_stats().unwrap(), (5, 1));
+
+    // A write through another handle drops the cached copy
+    let reader = disk0.open(b"/small.txt", O_RDONLY, 0, 0).unwrap();
+    let writer = disk0.open(b"/small.txt", O_RDWR, 0, 0).unwrap();
+    disk0.write(writer, b"FIRST").unwrap();
+    assert_eq!(disk0.read(reader, &mut buf).unwrap(), 14);
+    assert_eq!(&buf[..14], b"FIRST contents");
+    disk0.close(reader).unwrap();
+
+    // As does truncating it
+    disk0.ftruncate(writer, 5).unwrap();
+    disk0.close(writer).unwrap();
+    let f = disk0.open(b"/small.txt", O_RDONLY, 0, 0).unwrap();
+    assert_eq!(disk0.read(f, &mut buf).unwrap(), 5);
+    assert_eq!(&buf[..5], b"FIRST");
+    disk0.close(f).unwrap();
+
+    // A file reusing the clusters of a removed one doesn't see its contents
+    disk0.unlink(b"/small.txt", 0, 0).unwrap();
+    let f = disk0.open(b"/other.txt", O_CREAT | O_RDWR, 0, 0).unwrap();
+    disk0.write(f, b"other").unwrap();
+    disk0.close(f).unwrap();
+    let f = disk0.open(b"/other.txt", O_RDONLY, 0, 0).unwrap();
+    assert_eq!(disk0.read(f, &mut buf).unwrap(), 5);
+    assert_eq!(&buf[..5], b"other");
+}
+
+#[test]
+fn file_cache_skips_large_files() {
+    let options = MountOptions { file_cache: Some(1024), ..Default::default() };
+    let disk0 = open_scheme_with("disk0", "images/fat32.img", options);
+    let f = disk0.open(b"/large.bin", O_CREAT | O_RDWR, 0, 0).unwrap();
+    disk0.write(f, &[7; 2048]).unwrap();
+    disk0.close(f).unwrap();
+
+    let mut buf = [0; 4096];
+    for _ in 0..2 {
+        let f = disk0.open(b"/large.bin", O_RDONLY, 0, 0).unwrap();
+        assert_eq!(disk0.read(f, &mut buf).unwrap(), 2048);
+        disk0.close(f).unwrap();
+    }
+    assert_eq!(disk0.file_cache_stats().unwrap(), (0, 0));
+}
+
+#[test]
+fn ftruncate_grows_and_o_trunc_empties() {
+    let disk0 = open_scheme("disk0", "images/fat32.img");
+    let f = disk0.open(b"/sparse.bin", O_CREAT | O_RDWR, 0, 0).unwrap();
+    disk0.write(f, b"data").unwrap();
+    disk0.ftruncate(f, 8192).unwrap();
+    disk0.seek(f, 8192, SEEK_SET).unwrap();
+    disk0.write(f, b"end").unwrap();
+    disk0.close(f).unwrap();
+
+    let f = disk0.open(b"/sparse.bin", O_RDWR, 0, 0).unwrap();
+    let mut buf = vec![0xff; 8200];
+    assert_eq!(disk0.read(f, &mut buf).unwrap(), 8195);
+    assert_eq!(&buf[..4], b"data");
+    assert!(buf[4..8192].iter().all(|&b| b == 0));
+    assert_eq!(&buf[8192..8195], b"end");
+    disk0.close(f).unwrap();
+
+    // The handle opened with O_TRUNC sees the empty file
+    let f = disk0.open(b"/sparse.bin", O_RDWR | O_TRUNC, 0, 0).unwrap();
+    assert_eq!(disk0.read(f, &mut buf).unwrap(), 0);
+    disk0.write(f, b"new").unwrap();
+    disk0.close(f).unwrap();
+    let f = disk0.open(b"/sparse.bin", O_RDWR, 0, 0).unwrap();
+    assert_eq!(disk0.read(f, &mut buf).unwrap(), 3);
+}
+
+#[test]
+fn operations_check_the_entry_type() {
+    let mut fs = open_image("images/fat16.img");
+    let root = fs.root_dir();
+    root.create_file("MYDISK", &mut fs).unwrap();
+    root.create_dir("dir", &mut fs).unwrap();
+    // Turns MYDISK into a volume label entry
+    let (offset, entry) = root.raw_entries(&mut fs).map(|r| r.unwrap())
+        .find(|&(_, ref e)| &e.to_bytes()[..11] == b"MYDISK     ").unwrap();
+    let mut slot = entry.to_bytes();
+    slot[11] = 0x08;
+    root.write_raw_entry(offset, &DirEntryRaw::from_bytes(&slot).unwrap(), &mut fs).unwrap();
+    let disk0 = FileScheme::new("disk0".to_string(), fs, 0o777, 0, 0, MountOptions::default());
+
+    let d = disk0.open(b"/dir", O_RDONLY, 0, 0).unwrap();
+    assert_eq!(disk0.ftruncate(d, 0).unwrap_err().errno, EISDIR);
+    assert_eq!(disk0.write(d, b"data").unwrap_err().errno, EISDIR);
+    assert_eq!(disk0.fmap(d, &Map { offset: 0, size: 4096, flags: PROT_READ }).unwrap_err().errno, EISDIR);
+    assert_eq!(disk0.fcntl(d, F_GETFL, 0).unwrap(), O_RDONLY);
+    assert!(disk0.fsync(d).is_ok());
+    disk0.close(d).unwrap();
+
+    let label = disk0.open(b"/MYDISK", O_RDWR, 0, 0).unwrap();
+    assert_eq!(disk0.write(label, b"data").unwrap_err().errno, EINVAL);
+    assert_eq!(disk0.ftruncate(label, 100).unwrap_err().errno, EINVAL);
+    assert_eq!(disk0.fmap(label, &Map { offset: 0, size: 4096, flags: PROT_READ }).unwrap_err().errno, EINVAL);
+    disk0.close(label).unwrap();
+    assert_eq!(disk0.open(b"/MYDISK", O_RDWR | O_TRUNC, 0, 0).unwrap_err().errno, EINVAL);
+}
+
+#[test]
+fn capabilities_query() {
+    let options = MountOptions { case_sensitive: true, ..Default::default() };
+    let disk0 = open_scheme_with("disk0", "images/fat32.img", options);
+    let root = disk0.open(b"/", O_DIRECTORY, 0, 0).unwrap();
+    assert_eq!(disk0.dup(12345, CAPABILITIES).unwrap_err().errno, EBADF);
+
+    let caps = disk0.dup(root, CAPABILITIES).unwrap();
+    let mut buf = [0; 64];
+    let count = disk0.read(caps, &mut buf).unwrap();
+    let features = Features::WATCH | Features::FMAP | Features::CASE_SENSITIVE;
+    assert_eq!(std::str::from_utf8(&buf[..count]).unwrap(),
+               format!("version {}\nfeatures {:x}\n", API_VERSION, features.bits()));
+    assert_eq!(disk0.read(caps, &mut buf).unwrap(), 0);
+    disk0.close(caps).unwrap();
+    assert_eq!(disk0.read(caps, &mut buf).unwrap_err().errno, EBADF);
+}
+
+#[test]
+fn hidden_entries_are_not_listed() {
+    let options = MountOptions { hide_dot_files: true, ..Default::default() };
+    let disk0 = open_scheme_with("disk0", "images/fat32.img", options);
+    disk0.open(b"/.config", O_CREAT | O_DIRECTORY, 0, 0).unwrap();
+    let dot = disk0.open(b"/.profile", O_CREAT | O_RDWR, 0, 0).unwrap();
+    disk0.write(dot, b"hidden").unwrap();
+    disk0.close(dot).unwrap();
+    disk0.open(b"/shown.txt", O_CREAT | O_RDWR, 0, 0).unwrap();
+
+    let root = disk0.open(b"/", O_DIRECTORY | O_RDONLY, 0, 0).unwrap();
+    let mut buf = [0; 4096];
+    let count = disk0.read(root, &mut buf).unwrap();
+    let names: Vec<&str> = std::str::from_utf8(&buf[..count]).unwrap().split('\n').collect();
+    assert!(names.contains(&"shown.txt"));
+    assert!(!names.contains(&".config") && !names.contains(&".profile"));
+
+    // Hidden entries are still reachable by name
+    let dot = disk0.open(b"/.profile", O_RDWR, 0, 0).unwrap();
+    assert_eq!(disk0.read(dot, &mut buf).unwrap(), 6);
+    assert!(disk0.open(b"/.config", O_DIRECTORY, 0, 0).is_ok());
+}
+
+fn root_listing(disk0: &FileScheme<Cursor<Vec<u8>>>) -> Vec<String> {
+    let root = disk0.open(b"/", O_DIRECTORY | O_RDONLY, 0, 0).unwrap();
+    let mut buf = [0; 4096];
+    let count = disk0.read(root, &mut buf).unwrap();
+    disk0.close(root).unwrap();
+    std::str::from_utf8(&buf[..count]).unwrap().split('\n').map(|n| n.to_string()).collect()
+}
+
+#[test]
+fn system_and_hidden_entries_are_listed_as_configured() {
+    let mut fs = open_image("images/fat32.img");
+    fs.create_contiguous("/pagefile.sys", 4096, true).unwrap();
+    fs.hide_dot_files = true;
+    let root = fs.root_dir();
+    root.create_file(".hidden", &mut fs).unwrap();
+    fs.hide_dot_files = false;
+    let image = fs.disk.borrow().get_ref().clone();
+
+    let cases = [(false, false, true, true), (true, false, false, true), (false, true, true, false), (true, true, false, false)];
+    for &(hide_system, hide_hidden, system_listed, hidden_listed) in cases.iter() {
+        let options = MountOptions { hide_system: hide_system, hide_hidden: hide_hidden, ..Default::default() };
+        let fs = open_bytes(image.clone());
+        let disk0 = FileScheme::new("disk0".to_string(), fs, 0o777, 0, 0, options);
+        let names = root_listing(&disk0);
+        assert_eq!(names.contains(&"pagefile.sys".to_string()), system_listed, "{:?}", names);
+        assert_eq!(names.contains(&".hidden".to_string()), hidden_listed, "{:?}", names);
+        assert!(disk0.open(b"/pagefile.sys", O_RDONLY, 0, 0).is_ok());
+    }
+}
+
+#[test]
+fn cluster_size_is_preferred_io_size() {
+    let disk0 = open_scheme("disk0", "images/fat32.img");
+    let id = disk0.open(b"/sized.bin", O_CREAT | O_RDWR, 0, 0).unwrap();
+    disk0.write(id, &[7; 5000]).unwrap();
+
+    let mut stat = Stat::default();
+    disk0.fstat(id, &mut stat).unwrap();
+    assert_eq!(stat.st_blksize, 512);
+    assert_eq!(stat.st_blocks, 10);
+
+    let mut statvfs = StatVfs::default();
+    disk0.fstatvfs(id, &mut statvfs).unwrap();
+    assert_eq!(statvfs.f_bsize, 512);
+}
+
+// Feeds queued requests to the scheme and collects what it sends back
+struct QueueTransport {
+    requests: VecDeque<Packet>,
+    sent: Vec<Packet>
+}
+
+impl Transport for QueueTransport {
+    fn recv(&mut self, packet: &mut Packet) -> io::Result<usize> {
+        match self.requests.pop_front() {
+            Some(p) => {
+                *packet = p;
+                Ok(std::mem::size_of::<Packet>())
+            },
+            None => Ok(0)
+        }
+    }
+
+    fn send(&mut self, packet: &Packet) -> io::Result<usize> {
+        self.sent.push(*packet);
+        Ok(std::mem::size_of::<Packet>())
+    }
+}
+
+fn serve(scheme: &FileScheme<Cursor<Vec<u8>>>, requests: Vec<Packet>) -> Vec<Packet> {
+    let mut transport = QueueTransport { requests: requests.into_iter().collect(), sent: Vec::new() };
+    scheme.serve(&mut transport).expect("Transport failed");
+    transport.sent
+}
+
+fn request(a: usize, b: usize, c: usize, d: usize) -> Packet {
+    Packet { id: 1, a: a, b: b, c: c, d: d, ..Default::default() }
+}
+
+#[test]
+fn serve_packets_without_kernel() {
+    let disk0 = open_scheme("disk0", "images/fat32.img");
+    let path = b"/wire.txt";
+    let missing = b"/missing.txt";
+    let replies = serve(&disk0, vec![
+        request(SYS_OPEN, path.as_ptr() as usize, path.len(), O_CREAT | O_RDWR),
+        request(SYS_OPEN, missing.as_ptr() as usize, missing.len(), O_RDWR),
+    ]);
+    assert_eq!(replies.len(), 2);
+    let id = Error::demux(replies[0].a).expect("Open failed");
+    assert_eq!(Error::demux(replies[1].a).unwrap_err().errno, ENOENT);
+
+    let data = b"over the wire";
+    let mut buf = [0; 32];
+    let replies = serve(&disk0, vec![
+        request(SYS_WRITE, id, data.as_ptr() as usize, data.len()),
+        request(SYS_LSEEK, id, 0, SEEK_SET),
+        request(SYS_READ, id, buf.as_mut_ptr() as usize, buf.len()),
+        request(SYS_CLOSE, id, 0, 0),
+        request(SYS_READ, id, buf.as_mut_ptr() as usize, buf.len()),
+    ]);
+    let results: Vec<_> = replies.iter().map(|p| Error::demux(p.a).map_err(|e| e.errno)).collect();
+    assert_eq!(results, vec![Ok(data.len()), Ok(0), Ok(data.len()), Ok(0), Err(EBADF)]);
+    assert_eq!(&buf[..data.len()], data);
+}
+
+#[test]
+fn serve_sends_watcher_events() {
+    let disk0 = open_scheme("disk0", "images/fat32.img");
+    let watch = disk0.open(b"/?watch", O_RDWR, 0, 0).unwrap();
+    disk0.fevent(watch, EVENT_READ).unwrap();
+
+    let path = b"/event.txt";
+    let replies = serve(&disk0, vec![request(SYS_OPEN, path.as_ptr() as usize, path.len(), O_CREAT | O_RDWR)]);
+    assert_eq!(replies.len(), 2);
+    assert!(Error::demux(replies[0].a).is_ok());
+    let event = replies[1];
+    assert_eq!((event.a, event.b, event.c, event.d), (SYS_FEVENT, watch, EVENT_READ, "create event.txt\n".len()));
+}
+
+#[test]
+fn rename_over_existing_entries() {
+    let cases: &[(&str, &str, std::result::Result<(), i32>)] = &[
+        ("f1", "f2", Ok(())),
+        ("f1", "f1", Ok(())),
+        ("d1", "d2", Ok(())),
+        ("d1", "d3", Err(ENOTEMPTY)),
+        ("f1", "d2", Err(EISDIR)),
+        ("d1", "f2", Err(ENOTDIR)),
+        ("d1", "d1/sub/inner", Err(EINVAL)),
+    ];
+
+    for &(src, dst, ref expected) in cases.iter() {
+        let disk0 = open_scheme("disk0", "images/fat32.img");
+        for f in &["/f1", "/f2", "/d3/x"] {
+            if f.starts_with("/d3") {
+                disk0.open(b"/d3", O_CREAT | O_DIRECTORY, 0, 0).unwrap();
+            }
+            let id = disk0.open(f.as_bytes(), O_CREAT | O_RDWR, 0, 0).unwrap();
+            disk0.write(id, f.as_bytes()).unwrap();
+            disk0.close(id).unwrap();
+        }
+        for d in &["/d1", "/d1/sub", "/d2"] {
+            disk0.open(d.as_bytes(), O_CREAT | O_DIRECTORY, 0, 0).unwrap();
+        }
+
+        let flags = if src.starts_with('d') { O_DIRECTORY } else { O_RDWR };
+        let id = disk0.open(format!("/{}", src).as_bytes(), flags, 0, 0).unwrap();
+        let res = disk0.frename(id, dst.as_bytes(), 0, 0).map(|_| ()).map_err(|e| e.errno);
+        assert_eq!(&res, expected, "rename {} to {}", src, dst);
+        disk0.close(id).unwrap();
+
+        let exists = |p: &str| disk0.open(format!("/{}", p).as_bytes(), 0, 0, 0).is_ok();
+        if res.is_ok() && src != dst {
+            assert!(!exists(src) && exists(dst), "rename {} to {}", src, dst);
+        } else {
+            assert!(exists(src), "rename {} to {}", src, dst);
+        }
+        if src == "f1" && dst == "f2" {
+            let id = disk0.open(b"/f2", O_RDWR, 0, 0).unwrap();
+            let mut buf = [0; 8];
+            let count = disk0.read(id, &mut buf).unwrap();
+            assert_eq!(&buf[..count], b"/f1");
+        }
+    }
+}
+
+#[cfg(feature = "trace")]
+#[test]
+fn scheme_calls_are_traced() {
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::fmt::format::FmtSpan;
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let captured = Captured::default();
+    let writer = captured.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_span_events(FmtSpan::CLOSE)
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+
+    tracing::subscriber::with_default(subscriber, || {
+        let disk0 = open_scheme("disk0", "images/fat32.img");
+        let id = disk0.open(b"/traced.txt", O_CREAT | O_RDWR, 0, 0).unwrap();
+        disk0.write(id, &[1; 5000]).unwrap();
+    });
+
+    let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+    let write = output.lines().find(|l| l.contains("op=\"write\"")).expect("No write span");
+    assert!(write.contains("path=\"traced.txt\"") && write.contains("bytes=5000") && write.contains("clusters=10"));
+    assert!(write.contains("time.busy"));
+    assert!(output.lines().any(|l| l.contains("op=\"open\"") && l.contains("path=\"traced.txt\"")));
+}
+
+#[test]
+fn sorted_listings() {
+    let options = MountOptions { sorted_listings: true, ..Default::default() };
+    let disk0 = open_scheme_with("disk0", "images/fat32.img", options);
+    disk0.open(b"/listed", O_CREAT | O_DIRECTORY, 0, 0).unwrap();
+    for name in &["/listed/delta", "/listed/Alpha", "/listed/charlie", "/listed/bravo"] {
+        disk0.open(name.as_bytes(), O_CREAT | O_RDWR, 0, 0).unwrap();
+    }
+
+    let dir = disk0.open(b"/listed", O_DIRECTORY | O_RDONLY, 0, 0).unwrap();
+    let mut buf = [0; 4096];
+    let count = disk0.read(dir, &mut buf).unwrap();
+    let names: Vec<&str> = std::str::from_utf8(&buf[..count]).unwrap().split('\n').collect();
+    assert_eq!(names, vec![".", "..", "Alpha", "bravo", "charlie", "delta"]);
+}
+
+#[test]
+fn listing_cache_serves_repeated_opens() {
+    let options = MountOptions { listing_cache: Some(4096), ..Default::default() };
+    let disk0 = open_scheme_with("disk0", "images/fat32.img", options);
+    let listing = |path: &[u8]| {
+        let dir = disk0.open(path, O_DIRECTORY | O_RDONLY, 0, 0).unwrap();
+        let mut buf = [0; 4096];
+        let count = disk0.read(dir, &mut buf).unwrap();
+        disk0.close(dir).unwrap();
+        std::str::from_utf8(&buf[..count]).unwrap().split('\n').map(|n| n.to_string()).collect::<Vec<String>>()
+    };
+    disk0.open(b"/listed", O_CREAT | O_DIRECTORY, 0, 0).unwrap();
+    disk0.open(b"/listed/first", O_CREAT | O_RDWR, 0, 0).unwrap();
+
+    for _ in 0..3 {
+        assert_eq!(listing(b"/listed"), vec![".", "..", "first"]);
+    }
+    assert_eq!(disk0.listing_cache_stats().unwrap(), (2, 1));
+
+    // Entries coming or going drop the cached listing
+    disk0.open(b"/listed/second", O_CREAT | O_RDWR, 0, 0).unwrap();
+    assert_eq!(listing(b"/listed"), vec![".", "..", "first", "second"]);
+    disk0.unlink(b"/listed/first", 0, 0).unwrap();
+    assert_eq!(listing(b"/listed"), vec![".", "..", "second"]);
+
+    // As does renaming out of the directory, for both ends
+    assert!(!listing(b"/").contains(&"moved".to_string()));
+    let f = disk0.open(b"/listed/second", O_RDWR, 0, 0).unwrap();
+    disk0.frename(f, b"/moved", 0, 0).unwrap();
+    disk0.close(f).unwrap();
+    assert_eq!(listing(b"/listed"), vec![".", ".."]);
+    assert!(listing(b"/").contains(&"moved".to_string()));
+}
+
+#[test]
+fn resource_table_dump() {
+    let disk0 = open_scheme("disk0", "images/fat32.img");
+    let root = disk0.open(b"/", O_DIRECTORY | O_RDONLY, 0, 0).unwrap();
+    let file = disk0.open(b"/dumped.txt", O_CREAT | O_RDWR, 1000, 1000).unwrap();
+    disk0.write(file, b"twelve bytes").unwrap();
+
+    // Only the mount owner gets to see every open path
+    assert_eq!(disk0.dup(file, RESOURCES).unwrap_err().errno, EACCES);
+    let dump = disk0.dup(root, RESOURCES).unwrap();
+    let mut buf = [0; 4096];
+    let count = disk0.read(dump, &mut buf).unwrap();
+    let table = std::str::from_utf8(&buf[..count]).unwrap();
+    let lines: Vec<&str> = table.lines().collect();
+    assert_eq!(lines[0], "id uid flags seek cluster path");
+    assert!(lines[1].starts_with(&format!("{} 0 {:x} 0 ", root, O_DIRECTORY | O_RDONLY)));
+
+    let fields: Vec<&str> = lines[2].split(' ').collect();
+    assert_eq!(&fields[..4], &[file.to_string(), "1000".to_string(), format!("{:x}", O_CREAT | O_RDWR), "12".to_string()][..]);
+    assert!(fields[4].parse::<u64>().unwrap() >= 2);
+    assert_eq!(fields[5], "/dumped.txt/");
+    assert_eq!(lines.len(), 3);
+
+    disk0.close(dump).unwrap();
+    assert_eq!(disk0.read(dump, &mut buf).unwrap_err().errno, EBADF);
+}
+
+#[test]
+fn shutdown_closes_open_resources() {
+    let disk0 = open_scheme("disk0", "images/fat32.img");
+    let root = disk0.open(b"/", O_DIRECTORY | O_RDONLY, 0, 0).unwrap();
+    let file = disk0.open(b"/unsynced.txt", O_CREAT | O_RDWR, 1000, 1000).unwrap();
+    disk0.write(file, b"twelve bytes").unwrap();
+    let copy = disk0.dup(file, b"").unwrap();
+    let watch = disk0.open(b"/?watch/", O_RDWR, 0, 0).unwrap();
+
+    let open = disk0.open_resources();
+    assert_eq!(open.iter().map(|r| r.id).collect::<Vec<usize>>(), vec![root, file, copy]);
+    assert_eq!((open[1].uid, open[1].seek, open[1].path.as_str()), (Some(1000), 12, "/unsynced.txt/"));
+
+    let summary = disk0.shutdown();
+    assert_eq!(summary.closed, open);
+    assert!(summary.failed.is_empty());
+    assert_eq!(summary.other_handles, 1);
+    assert!(disk0.open_resources().is_empty());
+    for &id in &[root, file, copy, watch] {
+        assert_eq!(disk0.close(id).unwrap_err().errno, EBADF);
+    }
+
+    // The size was written back as on close
+    let f = disk0.open(b"/unsynced.txt", O_RDONLY, 0, 0).unwrap();
+    let mut stat = Stat::default();
+    disk0.fstat(f, &mut stat).unwrap();
+    assert_eq!(stat.st_size, 12);
+}
+
+#[test]
+fn malformed_paths_are_einval() {
+    let disk0 = open_scheme("disk0", "images/fat32.img");
+    let f = disk0.open(b"/victim.txt", O_CREAT | O_RDWR, 0, 0).unwrap();
+    let malformed: [&[u8]; 4] = [b"\xff\xfe", b"/bad\xc3(name", b"/victim.txt\0", b"\0"];
+    for url in malformed.iter() {
+        assert_eq!(disk0.open(url, O_RDONLY, 0, 0).unwrap_err().errno, EINVAL, "{:?}", url);
+        assert_eq!(disk0.open(url, O_DIRECTORY | O_RDONLY, 0, 0).unwrap_err().errno, EINVAL, "{:?}", url);
+        assert_eq!(disk0.unlink(url, 0, 0).unwrap_err().errno, EINVAL, "{:?}", url);
+        assert_eq!(disk0.rmdir(url, 0, 0).unwrap_err().errno, EINVAL, "{:?}", url);
+        assert_eq!(disk0.frename(f, url, 0, 0).unwrap_err().errno, EINVAL, "{:?}", url);
+    }
+    // Nothing was touched
+    assert!(disk0.open(b"/victim.txt", O_RDWR, 0, 0).is_ok());
+}
+
+#[test]
+fn write_protected_mount_gives_erofs() {
+    let disk0 = open_scheme("disk0", "images/fat32.img");
+    let f = disk0.open(b"/kept.txt", O_CREAT | O_RDWR, 0, 0).unwrap();
+    disk0.write(f, b"kept").unwrap();
+    disk0.open(b"/dir", O_CREAT | O_DIRECTORY, 0, 0).unwrap();
+
+    disk0.set_write_protect(true).unwrap();
+    assert_eq!(disk0.write(f, b"more").unwrap_err().errno, EROFS);
+    assert_eq!(disk0.ftruncate(f, 0).unwrap_err().errno, EROFS);
+    assert_eq!(disk0.frename(f, b"/moved.txt", 0, 0).unwrap_err().errno, EROFS);
+    assert_eq!(disk0.open(b"/new.txt", O_CREAT | O_RDWR, 0, 0).unwrap_err().errno, EROFS);
+    assert_eq!(disk0.open(b"/kept.txt", O_RDWR | O_TRUNC, 0, 0).unwrap_err().errno, EROFS);
+    assert_eq!(disk0.unlink(b"/kept.txt", 0, 0).unwrap_err().errno, EROFS);
+    assert_eq!(disk0.rmdir(b"/dir", 0, 0).unwrap_err().errno, EROFS);
+    let map = Map { offset: 0, size: 4096, flags: PROT_READ | PROT_WRITE };
+    assert_eq!(disk0.fmap(f, &map).unwrap_err().errno, EROFS);
+
+    // Reads go on as before
+    let mut buf = [0; 8];
+    disk0.seek(f, 0, SEEK_SET).unwrap();
+    assert_eq!(disk0.read(f, &mut buf).unwrap(), 4);
+
+    disk0.set_write_protect(false).unwrap();
+    assert_eq!(disk0.write(f, b"more").unwrap(), 4);
+    assert!(disk0.unlink(b"/kept.txt", 0, 0).is_ok());
+}
+
+#[test]
+fn stats_node() {
+    let options = MountOptions { path_cache: true, ..Default::default() };
+    let disk0 = open_scheme_with("disk0", "images/fat32.img", options);
+    let f = disk0.open(b"/stats.txt", O_CREAT | O_RDWR, 0, 0).unwrap();
+    disk0.write(f, b"stats").unwrap();
+
+    let stats = disk0.open(STATS.as_bytes(), O_RDONLY, 0, 0).unwrap();
+    let mut buf = [0; 4096];
+    let count = disk0.read(stats, &mut buf).unwrap();
+    let text = std::str::from_utf8(&buf[..count]).unwrap();
+    let value = |name: &str| text.lines().find(|l| l.starts_with(&format!("{} ", name)))
+        .map(|l| l[name.len() + 1..].to_string()).unwrap();
+    assert_eq!(value("fat_type"), "32");
+    assert_eq!(value("bytes_per_cluster"), "512");
+    assert_eq!(value("open_files"), "1");
+    assert_eq!(value("file_cache"), "off");
+    assert_eq!(value("listing_cache"), "off");
+    assert_eq!(value("free_map"), "off");
+    assert_eq!(value("path_cache").split(' ').count(), 2);
+    let free: u64 = value("free_clusters").parse().unwrap();
+    assert!(free < value("total_clusters").parse().unwrap());
+    assert!(value("options").contains("path_cache: true"));
+    let generations = value("generations");
+    assert_eq!(disk0.read(stats, &mut buf).unwrap(), 0);
+    disk0.close(stats).unwrap();
+
+    // Removed files no longer count
+    for i in 0..20 {
+        let path = format!("/gone{}.txt", i);
+        let g = disk0.open(path.as_bytes(), O_CREAT | O_RDWR, 0, 0).unwrap();
+        disk0.write(g, b"short lived").unwrap();
+        disk0.close(g).unwrap();
+        disk0.unlink(path.as_bytes(), 0, 0).unwrap();
+    }
+    let text = disk0.stats();
+    let now = text.lines().find(|l| l.starts_with("generations ")).unwrap();
+    assert_eq!(now, format!("generations {}", generations));
+
+    // Not a real entry, nothing was created
+    let root = disk0.open(b"/", O_DIRECTORY | O_RDONLY, 0, 0).unwrap();
+    let count = disk0.read(root, &mut buf).unwrap();
+    assert!(!std::str::from_utf8(&buf[..count]).unwrap().lines().any(|l| l.contains('?')));
+}
+
+#[test]
+fn free_map_is_built_between_requests() {
+    let options = MountOptions { free_map_scan: Some(30000), ..Default::default() };
+    let disk0 = open_scheme_with("disk0", "images/fat32.img", options);
+    let free_map = || {
+        let stats = disk0.stats();
+        let line = stats.lines().find(|l| l.starts_with("free_map ")).unwrap().to_string();
+        line[9..].split(' ').map(|n| n.parse().unwrap()).collect::<Vec<u64>>()
+    };
+    assert_eq!(free_map()[0], 0);
+
+    let mut scanned = Vec::new();
+    for _ in 0..3 {
+        let mut packet = request(SYS_CLOSE, 12345, 0, 0);
+        disk0.handle_packet(&mut packet);
+        assert_eq!(Error::demux(packet.a).unwrap_err().errno, EBADF);
+        scanned.push(free_map()[0]);
+    }
+    let total = free_map()[1];
+    assert_eq!(scanned, vec![30000, 60000, total]);
+}
+
+// Deterministic scheduler for several clients working in one directory
+//
+// Each client runs a fixed script of scheme calls, a seeded generator picks which client
+// makes the next call. Every seed gives a different but reproducible interleaving, a
+// failure names the seed to replay. Listings are taken in between to catch entries seen
+// half created or renamed.
+struct Client {
+    n: usize,
+    step: usize,
+    handle: Option<usize>,
+    name: String,
+    expected: Vec<u8>
+}
+
+const CLIENT_STEPS: usize = 8;
+
+fn xorshift(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+fn read_all(disk: &FileScheme<Cursor<Vec<u8>>>, id: usize) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut buf = [0; 64];
+    loop {
+        let count = disk.read(id, &mut buf).unwrap();
+        if count == 0 {
+            return data
+        }
+        data.extend_from_slice(&buf[..count]);
+    }
+}
+
+fn list(disk: &FileScheme<Cursor<Vec<u8>>>, path: &[u8]) -> Vec<String> {
+    let id = disk.open(path, O_DIRECTORY | O_RDONLY, 0, 0).unwrap();
+    let data = read_all(disk, id);
+    disk.close(id).unwrap();
+    let mut names: Vec<String> = String::from_utf8(data).unwrap().split('\n')
+        .filter(|n| !n.is_empty() && *n != "." && *n != "..").map(String::from).collect();
+    names.sort();
+    names
+}
+
+impl Client {
+    fn run_step(&mut self, disk: &FileScheme<Cursor<Vec<u8>>>, seed: u64) {
+        let ctx = format!("seed {} client {} step {}", seed, self.n, self.step);
+        match self.step {
+            0 => {
+                self.name = format!("/shared/c{}.txt", self.n);
+                self.handle = Some(disk.open(self.name.as_bytes(), O_CREAT | O_RDWR, 0, 0).expect(&ctx));
+            },
+            1 | 2 | 4 => {
+                let data = format!("{}:{};", self.n, self.step).repeat(40 * self.step);
+                disk.write(self.handle.unwrap(), data.as_bytes()).expect(&ctx);
+                self.expected.extend_from_slice(data.as_bytes());
+            },
+            3 => {
+                self.name = format!("/shared/r{}.txt", self.n);
+                disk.frename(self.handle.unwrap(), self.name.as_bytes(), 0, 0).expect(&ctx);
+            },
+            5 => {
+                disk.fsync(self.handle.unwrap()).expect(&ctx);
+                let id = disk.open(self.name.as_bytes(), O_RDONLY, 0, 0).expect(&ctx);
+                assert!(read_all(disk, id) == self.expected, "{}", ctx);
+                disk.close(id).expect(&ctx);
+            },
+            6 => {
+                disk.close(self.handle.take().unwrap()).expect(&ctx);
+            },
+            _ => {
+                let id = disk.open(self.name.as_bytes(), O_RDONLY, 0, 0).expect(&ctx);
+                assert!(read_all(disk, id) == self.expected, "{}", ctx);
+                disk.close(id).expect(&ctx);
+            }
+        }
+        self.step += 1;
+    }
+}
+
+#[test]
+fn interleaved_clients_in_one_directory() {
+    const CLIENTS: usize = 4;
+    for seed in 1..41u64 {
+        let disk0 = open_scheme("disk0", "images/fat32.img");
+        disk0.open(b"/shared", O_CREAT | O_DIRECTORY, 0, 0).unwrap();
+        let mut clients: Vec<Client> = (0..CLIENTS).map(|n| Client {
+            n: n, step: 0, handle: None, name: String::new(), expected: Vec::new()
+        }).collect();
+
+        let mut state = seed;
+        loop {
+            let running: Vec<usize> = (0..CLIENTS).filter(|&n| clients[n].step < CLIENT_STEPS).collect();
+            if running.is_empty() {
+                break;
+            }
+            let pick = xorshift(&mut state) as usize % (running.len() + 1);
+            if pick == running.len() {
+                // Every entry is listed once, under its old or its new name
+                let names = list(&disk0, b"/shared");
+                for c in &clients {
+                    let count = names.iter().filter(|n| **n == format!("c{}.txt", c.n) || **n == format!("r{}.txt", c.n)).count();
+                    assert_eq!(count, if c.step == 0 { 0 } else { 1 }, "seed {} listing {:?}", seed, names);
+                }
+            } else {
+                clients[running[pick]].run_step(&disk0, seed);
+            }
+        }
+
+        let expected: Vec<String> = (0..CLIENTS).map(|n| format!("r{}.txt", n)).collect();
+        assert_eq!(list(&disk0, b"/shared"), expected, "seed {}", seed);
+    }
+}
+
+#[test]
+fn translated_names_are_listed_as_given() {
+    let options = MountOptions { name_translation: NameTranslation::Percent, ..Default::default() };
+    let disk0 = open_scheme_with("disk0", "images/fat32.img", options);
+    disk0.open(b"/12:00", O_CREAT | O_DIRECTORY, 0, 0).unwrap();
+    let id = disk0.open(b"/12:00/a?b.txt", O_CREAT | O_RDWR, 0, 0).unwrap();
+    assert_eq!(list(&disk0, b"/12:00"), vec!["a?b.txt"]);
+
+    let mut buf = [0; 64];
+    let count = disk0.fpath(id, &mut buf).unwrap();
+    assert!(std::str::from_utf8(&buf[..count]).unwrap().trim_end_matches('/').ends_with("/12:00/a?b.txt"));
+
+    // A ':' after the first '/' is part of a name, not a scheme prefix
+    disk0.frename(id, b"/12:00/f.txt", 0, 0).unwrap();
+    disk0.frename(id, b"/a:b.txt", 0, 0).unwrap();
+    disk0.frename(id, b"disk0:/12:00/c:d.txt", 0, 0).unwrap();
+    assert_eq!(list(&disk0, b"/12:00"), vec!["c:d.txt"]);
+    assert_eq!(disk0.frename(id, b"disk1:/a:b.txt", 0, 0).unwrap_err().errno, EXDEV);
+}
+
+#[test]
+fn directory_link_counts() {
+    let disk0 = open_scheme("disk0", "images/fat32.img");
+    let d = disk0.open(b"/d", O_CREAT | O_DIRECTORY, 0, 0).unwrap();
+    disk0.open(b"/d/a", O_CREAT | O_DIRECTORY, 0, 0).unwrap();
+    disk0.open(b"/d/b", O_CREAT | O_DIRECTORY, 0, 0).unwrap();
+    let f = disk0.open(b"/d/f", O_CREAT | O_RDWR, 0, 0).unwrap();
+    let nlink = |id: usize| {
+        let mut stat = Stat::default();
+        disk0.fstat(id, &mut stat).unwrap();
+        stat.st_nlink
+    };
+    assert_eq!(nlink(d), 4);
+    assert_eq!(nlink(f), 1);
+
+    // Cached counts follow creates, removals and moves
+    disk0.rmdir(b"/d/a", 0, 0).unwrap();
+    assert_eq!(nlink(d), 3);
+    disk0.open(b"/d/c", O_CREAT | O_DIRECTORY, 0, 0).unwrap();
+    assert_eq!(nlink(d), 4);
+    let b = disk0.open(b"/d/b", O_DIRECTORY, 0, 0).unwrap();
+    disk0.frename(b, b"/b", 0, 0).unwrap();
+    assert_eq!(nlink(d), 3);
+    assert_eq!(nlink(b), 2);
+}
+
+// A FAT12 volume of 16 clusters with 128 sectors, 64KiB, each
+fn open_64k_clusters() -> FileScheme<Cursor<Vec<u8>>> {
+    let tot_sec = 1 + 2 + 32 + 16 * 128;
+    let mut data = vec![0; tot_sec * 512];
+    data[..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+    data[3..11].copy_from_slice(b"MSWIN4.1");
+    LittleEndian::write_u16(&mut data[11..], 512);
+    data[13] = 128;
+    LittleEndian::write_u16(&mut data[14..], 1);
+    data[16] = 2;
+    LittleEndian::write_u16(&mut data[17..], 512);
+    LittleEndian::write_u16(&mut data[19..], tot_sec as u16);
+    data[21] = 0xF8;
+    LittleEndian::write_u16(&mut data[22..], 1);
+    data[510] = 0x55;
+    data[511] = 0xAA;
+    for fat in 1..3 {
+        data[fat * 512..fat * 512 + 3].copy_from_slice(&[0xF8, 0xFF, 0xFF]);
+    }
+    let fs = open_bytes(data);
+    FileScheme::new("disk0".to_string(), fs, 0o777, 0, 0, MountOptions::default())
+}
+
+#[test]
+fn large_io_on_64k_clusters() {
+    let disk0 = open_64k_clusters();
+    let data: Vec<u8> = (0..200000).map(|i| (i % 251) as u8).collect();
+    let f = disk0.open(b"/big.bin", O_CREAT | O_RDWR, 0, 0).unwrap();
+    assert_eq!(disk0.write(f, &data).unwrap(), data.len());
+    let mut stat = Stat::default();
+    disk0.fstat(f, &mut stat).unwrap();
+    assert_eq!(stat.st_blksize, 65536);
+    assert_eq!(stat.st_blocks, 4 * 128);
+    disk0.close(f).unwrap();
+
+    let f = disk0.open(b"/big.bin", O_RDONLY, 0, 0).unwrap();
+    let mut buf = vec![0; 300000];
+    assert_eq!(disk0.read(f, &mut buf[..70000]).unwrap(), 70000);
+    assert_eq!(disk0.read(f, &mut buf[70000..]).unwrap(), 130000);
+    assert!(buf[..200000] == data[..]);
+    disk0.close(f).unwrap();
+
+    // The rest of the volume, 12 clusters, in one write
+    let f = disk0.open(b"/fill.bin", O_CREAT | O_RDWR, 0, 0).unwrap();
+    assert_eq!(disk0.write(f, &vec![0x5a; 12 * 65536]).unwrap(), 12 * 65536);
+    assert!(disk0.write(f, b"x").is_err());
+    let mut statvfs = StatVfs::default();
+    disk0.fstatvfs(f, &mut statvfs).unwrap();
+    assert_eq!((statvfs.f_bsize, statvfs.f_blocks, statvfs.f_bfree), (65536, 16, 0));
+    disk0.close(f).unwrap();
+}
+
+#[test]
+fn attribute_modes() {
+    let mut fs = open_image("images/fat32.img");
+    let root = fs.root_dir();
+    root.create_file("/open.txt", &mut fs).unwrap();
+    let locked = root.create_file("/locked.txt", &mut fs).unwrap();
+    // RD_ONLY | ARCHIVE
+    let attrs = fs.cluster_offset((locked.loc.1).0) + (locked.loc.1).1 + 11;
+    let mut data = fs.disk.borrow().get_ref().clone();
+    data[attrs as usize] = 0x21;
+    let fs = open_bytes(data);
+
+    let owner = 1000;
+    let other = 2000;
+    let options = MountOptions { attribute_modes: true, umask: 0o022, ..Default::default() };
+    let disk0 = FileScheme::new("disk0".to_string(), fs, 0o777, owner, owner, options);
+    let mode = |path: &[u8]| {
+        let id = disk0.open(path, O_RDONLY, owner, owner).unwrap();
+        let mut stat = Stat::default();
+        disk0.fstat(id, &mut stat).unwrap();
+        disk0.close(id).unwrap();
+        stat.st_mode & 0o777
+    };
+    assert_eq!(mode(b"/"), 0o755);
+    assert_eq!(mode(b"/open.txt"), 0o644);
+    assert_eq!(mode(b"/locked.txt"), 0o444);
+
+    assert!(disk0.open(b"/open.txt", O_WRONLY, owner, owner).is_ok());
+    assert_eq!(disk0.open(b"/locked.txt", O_WRONLY, owner, owner).unwrap_err().errno, EACCES);
+    assert_eq!(disk0.open(b"/locked.txt", O_RDONLY | O_TRUNC, owner, owner).unwrap_err().errno, EACCES);
+    assert!(disk0.open(b"/open.txt", O_RDONLY, other, other).is_ok());
+    assert_eq!(disk0.open(b"/open.txt", O_RDWR, other, other).unwrap_err().errno, EACCES);
+    assert_eq!(disk0.open(b"/new.txt", O_CREAT | O_RDWR, other, other).unwrap_err().errno, EACCES);
+    let created = disk0.open(b"/new.txt", O_CREAT | O_RDWR, owner, owner).unwrap();
+    let mut stat = Stat::default();
+    disk0.fstat(created, &mut stat).unwrap();
+    assert_eq!(stat.st_mode & 0o777, 0o644);
+}
+
+#[test]
+fn other_handles_see_the_size_before_close() {
+    let disk0 = open_scheme("disk0", "images/fat32.img");
+    let w = disk0.open(b"/w.txt", O_CREAT | O_RDWR, 0, 0).unwrap();
+    disk0.write(w, b"hello world").unwrap();
+
+    let r = disk0.open(b"/w.txt", O_RDONLY, 0, 0).unwrap();
+    assert_eq!(read_all(&disk0, r), b"hello world");
+    let mut stat = Stat::default();
+    disk0.fstat(r, &mut stat).unwrap();
+    assert_eq!(stat.st_size, 11);
+
+    // Handles opened before the write and dups see it as well
+    let copy = disk0.dup(r, b"").unwrap();
+    disk0.write(w, b", again").unwrap();
+    assert_eq!(read_all(&disk0, r), b", again");
+    disk0.fstat(copy, &mut stat).unwrap();
+    assert_eq!(stat.st_size, 18);
+    disk0.close(w).unwrap();
+    disk0.seek(copy, 0, SEEK_SET).unwrap();
+    assert_eq!(read_all(&disk0, copy), b"hello world, again");
+}
+
+#[test]
+fn close_reports_a_failed_write_back() {
+    let (disk, fail_at) = FailingDisk::open("images/fat32.img");
+    let mut fs = FileSystem::from_offset(0, disk, None).unwrap();
+    let f = fs.root_dir().create_file("w.txt", &mut fs).unwrap();
+    let entry = fs.cluster_offset((f.loc.1).0) + (f.loc.1).1;
+    let disk0 = FileScheme::new("disk0".to_string(), fs, 0o777, 0, 0, MountOptions::default());
+
+    let w = disk0.open(b"/w.txt", O_RDWR, 0, 0).unwrap();
+    disk0.write(w, b"first").unwrap();
+    disk0.write(w, b" and second").unwrap();
+    fail_at.set(Some(entry));
+    assert!(disk0.close(w).is_err());
+}
+
+// A clock the test moves forward by hand
+struct ManualClock(Rc<Cell<SystemTime>>);
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        self.0.get()
+    }
+}
+
+#[test]
+fn sizes_are_written_back_periodically() {
+    let mut data = fs::read("images/fat32.img").unwrap();
+    let now = Rc::new(Cell::new(UNIX_EPOCH + Duration::from_secs(1_500_000_000)));
+    {
+        let mut fs = FileSystem::from_offset(0, Cursor::new(&mut data), None).unwrap();
+        fs.set_clock(ManualClock(now.clone()));
+        let options = MountOptions { flush_interval: Some(5), ..Default::default() };
+        let disk0 = FileScheme::new("disk0".to_string(), fs, 0o777, 0, 0, options);
+        let w = disk0.open(b"/w.txt", O_CREAT | O_RDWR, 0, 0).unwrap();
+        disk0.write(w, b"first").unwrap();
+        disk0.write(w, b" write").unwrap();
+        let mut packet = request(SYS_LSEEK, w, 0, SEEK_CUR);
+        disk0.handle_packet(&mut packet);
+        now.set(now.get() + Duration::from_secs(5));
+        disk0.handle_packet(&mut packet);
+
+        // Not written back, the scheme goes away without closing the handle
+        disk0.write(w, b" and the next").unwrap();
+        now.set(now.get() + Duration::from_secs(4));
+        disk0.handle_packet(&mut packet);
+    }
+    let mut fs = open_bytes(data);
+    let root = fs.root_dir();
+    assert_eq!(root.open_file("w.txt", &mut fs).unwrap().size(), 11);
+}
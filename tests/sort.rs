@@ -0,0 +1,32 @@
+extern crate redox_fatfs;
+
+mod common;
+
+use std::io::Cursor;
+
+use redox_fatfs::prelude::*;
+
+use common::open_image;
+
+#[test]
+fn entries_sorted_by_name_and_size() {
+    let mut fs = open_image("images/fat32.img");
+    let dir = fs.root_dir().create_dir("sorted", &mut fs).unwrap();
+    for &(name, size) in &[("b.txt", 300), ("Zeta", 0), ("A.txt", 10), ("c.txt", 100)] {
+        let mut f = dir.create_file(name, &mut fs).unwrap();
+        f.write(&vec![1; size], &mut fs, 0).unwrap();
+        f.flush(&mut fs).unwrap();
+    }
+    dir.create_dir("sub", &mut fs).unwrap();
+
+    let names = |sort_by: SortBy, fs: &mut FileSystem<Cursor<Vec<u8>>>| -> Vec<String> {
+        dir.entries_sorted(fs, sort_by).iter().map(|e| e.name()).collect()
+    };
+    assert_eq!(names(SortBy::Name, &mut fs), vec![".", "..", "A.txt", "b.txt", "c.txt", "sub", "Zeta"]);
+    assert_eq!(names(SortBy::Size, &mut fs), vec![".", "..", "sub", "Zeta", "A.txt", "c.txt", "b.txt"]);
+    // Nothing here sets write times, so they all tie and names decide
+    assert_eq!(names(SortBy::Mtime, &mut fs), names(SortBy::Name, &mut fs));
+
+    fs.case_sensitive = true;
+    assert_eq!(names(SortBy::Name, &mut fs), vec![".", "..", "A.txt", "Zeta", "b.txt", "c.txt", "sub"]);
+}
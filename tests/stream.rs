@@ -0,0 +1,50 @@
+extern crate redox_fatfs;
+
+mod common;
+
+use std::io::{BufRead, Read, Write};
+
+use common::open_image;
+
+#[test]
+fn buffered_copy_round_trip() {
+    let mut fs = open_image("images/fat32.img");
+    let root = fs.root_dir();
+    let lines: Vec<String> = (0..2000).map(|i| format!("line {}", i)).collect();
+    let text = lines.join("\n");
+
+    let mut f = root.create_file("log.txt", &mut fs).unwrap();
+    {
+        let mut w = f.writer(&mut fs, 1000);
+        // Small writes collect in the buffer, the large one bypasses it
+        for chunk in text.as_bytes()[..5000].chunks(7) {
+            w.write_all(chunk).unwrap();
+        }
+        w.write_all(&text.as_bytes()[5000..]).unwrap();
+        assert_eq!(w.position(), text.len() as u64);
+        w.flush().unwrap();
+    }
+    let f = root.open_file("log.txt", &mut fs).unwrap();
+    assert_eq!(f.size(), text.len() as u64);
+
+    let read: Vec<String> = f.reader(&mut fs, 1).lines().map(|l| l.unwrap()).collect();
+    assert_eq!(read, lines);
+    let mut all = Vec::new();
+    let mut r = f.reader(&mut fs, 4096);
+    r.read_to_end(&mut all).unwrap();
+    assert_eq!(all, text.as_bytes());
+    assert_eq!(r.position(), text.len() as u64);
+}
+
+#[test]
+fn dropped_writer_flushes() {
+    let mut fs = open_image("images/fat32.img");
+    let root = fs.root_dir();
+    let mut f = root.create_file("short.txt", &mut fs).unwrap();
+    f.writer(&mut fs, 512).write_all(b"buffered").unwrap();
+
+    let f = root.open_file("short.txt", &mut fs).unwrap();
+    let mut s = String::new();
+    f.reader(&mut fs, 512).read_to_string(&mut s).unwrap();
+    assert_eq!(s, "buffered");
+}
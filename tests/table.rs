@@ -0,0 +1,212 @@
+extern crate redox_fatfs;
+
+mod common;
+
+use std::io::{Cursor, Read, Seek, Write};
+
+use redox_fatfs::prelude::*;
+use redox_fatfs::raw::{allocate_cluster, append_chain, find_free_cluster, get_entry, set_entry, split_chain, FatEntry};
+
+use common::{open_image, CountingDisk, FailingDisk};
+
+fn new_chain(fs: &mut FileSystem<Cursor<Vec<u8>>>, len: usize) -> Vec<Cluster> {
+    let mut chain = vec![allocate_cluster(fs, None).unwrap()];
+    for _ in 1..len {
+        let last = *chain.last().unwrap();
+        chain.push(allocate_cluster(fs, Some(last)).unwrap());
+    }
+    chain
+}
+
+#[test]
+fn split_and_append_chains() {
+    let mut fs = open_image("images/fat32.img");
+    let chain = new_chain(&mut fs, 4);
+    assert_eq!(fs.clusters(chain[0]), chain);
+
+    let tail = split_chain(&mut fs, chain[1]).unwrap();
+    assert_eq!(tail, Some(chain[2]));
+    assert_eq!(fs.clusters(chain[0]), &chain[..2]);
+    assert_eq!(get_entry(&mut fs, chain[1]).unwrap(), FatEntry::EndOfChain);
+    assert_eq!(fs.clusters(chain[2]), &chain[2..]);
+
+    // Splitting at the end of a chain leaves it alone
+    assert_eq!(split_chain(&mut fs, chain[3]).unwrap(), None);
+
+    append_chain(&mut fs, chain[0], chain[2]).unwrap();
+    assert_eq!(fs.clusters(chain[0]), chain);
+
+    // A chain can not be appended onto itself
+    assert!(append_chain(&mut fs, chain[0], chain[1]).is_err());
+    let unused = fs.max_cluster_number();
+    assert!(split_chain(&mut fs, unused).is_err());
+}
+
+#[test]
+fn truncate_ends_chain() {
+    let mut fs = open_image("images/fat32.img");
+    let bpc = fs.bytes_per_cluster();
+    let root = fs.root_dir();
+    let mut f = root.create_file("chain.bin", &mut fs).unwrap();
+    f.write(&vec![7; (bpc * 4) as usize], &mut fs, 0).unwrap();
+    let chain = fs.clusters(f.first_cluster);
+    assert_eq!(chain.len(), 4);
+    let free = fs.free_clusters().unwrap();
+
+    // An exact multiple of the cluster size keeps just the clusters needed
+    f.truncate(&mut fs, bpc * 2).unwrap();
+    assert_eq!(fs.clusters(f.first_cluster), &chain[..2]);
+    assert_eq!(get_entry(&mut fs, chain[1]).unwrap(), FatEntry::EndOfChain);
+    assert_eq!(get_entry(&mut fs, chain[2]).unwrap(), FatEntry::Unused);
+    assert_eq!(fs.free_clusters().unwrap(), free + 2);
+}
+
+fn fat_copies<D: Read + Write + Seek>(fs: &mut FileSystem<D>) -> Vec<Vec<u8>> {
+    let fat_bytes = fs.fat_size() * fs.bytes_per_sec();
+    (0..fs.bpb.num_fats as u64).map(|i| {
+        let mut buf = vec![0; fat_bytes as usize];
+        let offset = (fs.bpb.rsvd_sec_cnt as u64 + i * fs.fat_size()) * fs.bytes_per_sec();
+        fs.read_at(offset, &mut buf).unwrap();
+        buf
+    }).collect()
+}
+
+#[test]
+fn fat16_copies_stay_mirrored() {
+    let mut fs = open_image("images/fat16.img");
+    assert_eq!(fs.bytes_per_sec(), 512);
+    let root = fs.root_dir();
+    let mut f = root.create_file("mirror.bin", &mut fs).unwrap();
+    let data: Vec<u8> = (0..20000).map(|i| i as u8).collect();
+    f.write(&data, &mut fs, 0).unwrap();
+    f.flush(&mut fs).unwrap();
+
+    let copies = fat_copies(&mut fs);
+    assert!(copies[0] == copies[1], "FAT copies should match");
+
+    let f = root.open_file("mirror.bin", &mut fs).unwrap();
+    let mut buf = vec![0; data.len()];
+    assert_eq!(f.read(&mut buf, &mut fs, 0).unwrap(), data.len());
+    assert!(buf == data);
+}
+
+#[test]
+fn fat_copies_in_one_block_share_a_write() {
+    let (disk, _, writes) = CountingDisk::open("images/fat12.img");
+    let mut fs = FileSystem::from_offset(0, disk, None).unwrap();
+    // Both FATs of the fixture fit in the first cache block
+    let fat_bytes = fs.fat_size() * fs.bytes_per_sec();
+    assert!((fs.bpb.rsvd_sec_cnt as u64 * fs.bytes_per_sec()) + 2 * fat_bytes <= BLOCK_SIZE);
+
+    let cluster = Cluster::new(40);
+    set_entry(&mut fs, cluster, FatEntry::EndOfChain).unwrap();
+    assert_eq!(writes.get(), 1);
+    assert_eq!(get_entry(&mut fs, cluster).unwrap(), FatEntry::EndOfChain);
+    let copies = fat_copies(&mut fs);
+    assert!(copies[0] == copies[1], "FAT copies should match");
+}
+
+#[test]
+fn whole_blocks_are_zeroed_without_reading() {
+    let (disk, reads, writes) = CountingDisk::open("images/fat32.img");
+    let mut fs = FileSystem::from_offset(0, disk, None).unwrap();
+    let offset = fs.cluster_offset(Cluster::new(100));
+    let aligned = (offset + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+    fs.write_to(aligned, &vec![0xaa; 3 * BLOCK_SIZE as usize]).unwrap();
+
+    reads.set(0);
+    writes.set(0);
+    fs.zero_range(aligned, 2 * BLOCK_SIZE).unwrap();
+    assert_eq!((reads.get(), writes.get()), (0, 2));
+    // A partial block still goes through read-modify-write
+    fs.zero_range(aligned + 2 * BLOCK_SIZE, 16).unwrap();
+    assert_eq!((reads.get(), writes.get()), (1, 3));
+
+    let mut buf = vec![0xff; 3 * BLOCK_SIZE as usize];
+    fs.read_at(aligned, &mut buf).unwrap();
+    let zeroed = 2 * BLOCK_SIZE as usize + 16;
+    assert!(buf[..zeroed].iter().all(|&b| b == 0));
+    assert!(buf[zeroed..].iter().all(|&b| b == 0xaa));
+}
+
+#[test]
+fn consecutive_clusters_are_written_in_whole_blocks() {
+    let (disk, _, writes) = CountingDisk::open("images/fat32.img");
+    let mut fs = FileSystem::from_offset(0, disk, None).unwrap();
+    let root = fs.root_dir();
+    let mut f = root.create_file("run.bin", &mut fs).unwrap();
+    let data: Vec<u8> = (0..16 * BLOCK_SIZE).map(|i| (i / 7) as u8).collect();
+    f.write(&data, &mut fs, 0).unwrap();
+    f.flush(&mut fs).unwrap();
+    assert!(fs.bytes_per_cluster() < BLOCK_SIZE);
+
+    // Overwriting touches no metadata, the data run is split at blocks rather than clusters
+    writes.set(0);
+    f.write(&data, &mut fs, 0).unwrap();
+    assert!(writes.get() <= 17, "{} writes", writes.get());
+
+    let mut buf = vec![0; data.len()];
+    assert_eq!(f.read(&mut buf, &mut fs, 0).unwrap(), data.len());
+    assert!(buf == data);
+}
+
+#[test]
+fn data_clusters_are_zeroed_only_below_file_size() {
+    let mut fs = open_image("images/fat32.img");
+    let bpc = fs.bytes_per_cluster() as usize;
+    // Leave old contents in every free cluster the file could get
+    let max_cluster = fs.max_cluster_number().cluster_number;
+    for c in 2..max_cluster + 1 {
+        if get_entry(&mut fs, Cluster::new(c)).unwrap() == FatEntry::Unused {
+            let offset = fs.cluster_offset(Cluster::new(c));
+            fs.write_to(offset, &vec![0xaa; bpc]).unwrap();
+        }
+    }
+
+    let root = fs.root_dir();
+    let mut f = root.create_file("sparse.bin", &mut fs).unwrap();
+    f.write(b"end", &mut fs, 2 * bpc as u64 + 10).unwrap();
+    let mut buf = vec![0xff; 2 * bpc + 13];
+    assert_eq!(f.read(&mut buf, &mut fs, 0).unwrap(), buf.len());
+    assert!(buf[..2 * bpc + 10].iter().all(|&b| b == 0));
+    assert_eq!(&buf[2 * bpc + 10..], b"end");
+
+    // Directory clusters are still cleared when allocated
+    let dir = root.create_dir("fresh", &mut fs).unwrap();
+    let names: Vec<String> = dir.to_iter(&mut fs).map(|e| e.name()).collect();
+    assert_eq!(names, vec![".".to_string(), "..".to_string()]);
+}
+
+fn fs_info_counters<D: Read + Write + Seek>(fs: &FileSystem<D>) -> (Option<u64>, Option<u64>) {
+    let max_cluster = fs.max_cluster_number();
+    let fs_info = fs.fs_info.borrow();
+    (fs_info.get_free_count(max_cluster), fs_info.get_next_free())
+}
+
+#[test]
+fn failed_zeroing_leaves_the_cluster_free() {
+    let (disk, fail_at) = FailingDisk::open("images/fat32.img");
+    let mut fs = FileSystem::from_offset(0, disk, None).unwrap();
+    fs.free_clusters().unwrap();
+    let before = fs_info_counters(&fs);
+
+    let free = find_free_cluster(&mut fs).unwrap();
+    fail_at.set(Some(fs.cluster_offset(free)));
+    assert!(allocate_cluster(&mut fs, None).is_err());
+    assert_eq!(get_entry(&mut fs, free).unwrap(), FatEntry::Unused);
+    assert_eq!(fs_info_counters(&fs), before);
+}
+
+#[test]
+fn failed_link_frees_the_cluster_again() {
+    let mut fs = open_image("images/fat32.img");
+    fs.free_clusters().unwrap();
+    let before = fs_info_counters(&fs);
+    let free = find_free_cluster(&mut fs).unwrap();
+
+    // Appending the cluster to itself would form a cycle
+    assert!(allocate_cluster(&mut fs, Some(free)).is_err());
+    assert_eq!(get_entry(&mut fs, free).unwrap(), FatEntry::Unused);
+    assert_eq!(fs_info_counters(&fs), before);
+    assert!(fs.check_writable().is_ok());
+}
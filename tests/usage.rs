@@ -0,0 +1,82 @@
+extern crate redox_fatfs;
+
+mod common;
+
+use redox_fatfs::prelude::*;
+use redox_fatfs::raw::{set_entry, FatEntry};
+
+use common::open_image;
+
+#[test]
+fn counts_match_the_free_count() {
+    for path in &["images/fat12.img", "images/fat16.img", "images/fat32.img"] {
+        let mut fs = open_image(path);
+        let usage = fs.usage_bitmap().unwrap();
+        assert_eq!(usage.len(), fs.max_cluster_number().cluster_number - 1, "{}", path);
+        assert_eq!(usage.free + usage.allocated + usage.bad, usage.len());
+        assert_eq!(usage.free, fs.free_clusters().unwrap(), "{}", path);
+        assert_eq!(usage.as_bytes().len() as u64, (usage.len() + 3) / 4);
+        assert_eq!(usage.iter().filter(|&s| s == ClusterState::Free).count() as u64, usage.free);
+    }
+}
+
+#[test]
+fn bytes_free_and_total() {
+    for path in &["images/fat12.img", "images/fat16.img", "images/fat32.img"] {
+        let mut fs = open_image(path);
+        let bpc = fs.bytes_per_cluster();
+        let usage = fs.usage_bitmap().unwrap();
+        assert_eq!(fs.bytes_total(), usage.len() * bpc, "{}", path);
+        assert_eq!(fs.bytes_free().unwrap(), usage.free * bpc, "{}", path);
+
+        let root = fs.root_dir();
+        let mut f = root.create_file("data.bin", &mut fs).unwrap();
+        f.write(&vec![1; 2 * bpc as usize], &mut fs, 0).unwrap();
+        assert_eq!(fs.bytes_free().unwrap(), (usage.free - 2) * bpc, "{}", path);
+    }
+}
+
+#[test]
+fn states_of_written_and_bad_clusters() {
+    let mut fs = open_image("images/fat32.img");
+    let bpc = fs.bytes_per_cluster() as usize;
+    let before = fs.usage_bitmap().unwrap();
+
+    let root = fs.root_dir();
+    let mut f = root.create_file("data.bin", &mut fs).unwrap();
+    f.write(&vec![1; 3 * bpc], &mut fs, 0).unwrap();
+    let free = (2..fs.max_cluster_number().cluster_number)
+        .map(Cluster::new)
+        .find(|&c| before.state(c) == Some(ClusterState::Free) && !fs.clusters(f.first_cluster).contains(&c))
+        .unwrap();
+    set_entry(&mut fs, free, FatEntry::Bad).unwrap();
+
+    let usage = fs.usage_bitmap().unwrap();
+    for c in fs.clusters(f.first_cluster) {
+        assert_eq!(usage.state(c), Some(ClusterState::Allocated));
+    }
+    assert_eq!(usage.state(free), Some(ClusterState::Bad));
+    assert_eq!(usage.bad, before.bad + 1);
+    assert_eq!(usage.allocated, before.allocated + 3);
+    assert_eq!(usage.state(Cluster::new(1)), None);
+    assert_eq!(usage.state(Cluster::new(usage.len() + 2)), None);
+}
+
+#[test]
+fn root_entries() {
+    assert_eq!(open_image("images/fat32.img").root_entries().unwrap(), None);
+
+    let mut fs = open_image("images/fat16.img");
+    let before = fs.root_entries().unwrap().unwrap();
+    assert_eq!(before.total, fs.bpb.root_entries_cnt as u64);
+    let root = fs.root_dir();
+    root.create_file("SHORT.TXT", &mut fs).unwrap();
+    // Two long entries and the short one
+    root.create_file("A rather long name.txt", &mut fs).unwrap();
+    let after = fs.root_entries().unwrap().unwrap();
+    assert_eq!(after.total, before.total);
+    assert_eq!(after.free, before.free - 4);
+
+    root.remove("SHORT.TXT", &mut fs, true).unwrap();
+    assert_eq!(fs.root_entries().unwrap().unwrap().free, before.free - 3);
+}
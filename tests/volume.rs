@@ -0,0 +1,43 @@
+extern crate redox_fatfs;
+
+use std::fs;
+use std::io::{Cursor, ErrorKind};
+
+use redox_fatfs::prelude::*;
+
+fn open_volume(path: &str) -> FatVolume<Cursor<Vec<u8>>> {
+    let data = fs::read(path).expect("Failed to read image");
+    FatVolume::open(Cursor::new(data)).expect("Parsing Error")
+}
+
+#[test]
+fn path_operations() {
+    let mut vol = open_volume("images/fat32.img");
+    vol.create_dir_all("/docs/2024/drafts").unwrap();
+    vol.create_dir_all("docs/2024").unwrap();
+    assert!(vol.metadata("/docs/2024/drafts").unwrap().is_dir());
+
+    let long: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+    vol.write("/docs/2024/report.txt", &long).unwrap();
+    assert_eq!(vol.read("/docs/2024/report.txt").unwrap(), long);
+    // Overwriting with less data shrinks the file
+    vol.write("/docs/2024/report.txt", b"short").unwrap();
+    assert_eq!(vol.read("/docs/2024/report.txt").unwrap(), b"short");
+    assert_eq!(vol.metadata("/docs/2024/report.txt").unwrap().len(), 5);
+    vol.write("/empty", b"").unwrap();
+    assert!(vol.read("/empty").unwrap().is_empty());
+
+    vol.rename("/docs/2024/report.txt", "/docs/final.txt").unwrap();
+    assert_eq!(vol.read("/docs/final.txt").unwrap(), b"short");
+    assert_eq!(vol.read("/docs/2024/report.txt").unwrap_err().kind(), ErrorKind::NotFound);
+
+    assert_eq!(vol.create_dir_all("/docs/final.txt/sub").unwrap_err().kind(), ErrorKind::NotADirectory);
+    assert_eq!(vol.remove("/docs/2024").unwrap_err().kind(), ErrorKind::DirectoryNotEmpty);
+    vol.remove("/docs/2024/drafts").unwrap();
+    vol.remove("/docs/2024").unwrap();
+    vol.remove("/docs/final.txt").unwrap();
+    assert!(vol.remove("/").is_err());
+
+    let fs = vol.filesystem();
+    assert!(check(fs).unwrap().is_clean());
+}
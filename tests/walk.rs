@@ -0,0 +1,28 @@
+extern crate redox_fatfs;
+
+mod common;
+
+use common::open_image;
+
+#[test]
+fn walk_visits_directories_before_their_contents() {
+    let mut fs = open_image("images/fat16.img");
+    let root = fs.root_dir();
+    root.create_dir("a", &mut fs).unwrap();
+    root.create_dir("a/b", &mut fs).unwrap();
+    root.create_file("a/b/deep.txt", &mut fs).unwrap();
+    root.create_file("a/top.txt", &mut fs).unwrap();
+    root.create_file("z.txt", &mut fs).unwrap();
+
+    let paths: Vec<String> = root.walk(&mut fs).map(|e| e.dir_path().trim_end_matches('/').to_string()).collect();
+    let pos = |p: &str| paths.iter().position(|x| x == p).expect(p);
+    assert!(pos("/a") < pos("/a/b"));
+    assert!(pos("/a/b") < pos("/a/b/deep.txt"));
+    assert!(pos("/a") < pos("/a/top.txt"));
+    pos("/z.txt");
+    assert!(paths.iter().all(|p| !p.ends_with("/.") && !p.ends_with("/..")));
+
+    let sub = root.open_dir("a", &mut fs).unwrap();
+    let below: Vec<String> = sub.walk(&mut fs).map(|e| e.dir_path()).collect();
+    assert_eq!(below.len(), 3);
+}